@@ -0,0 +1,342 @@
+use alloc::vec::Vec;
+
+use crate::interpreter::Interpreter;
+use crate::name::AmlName;
+use crate::value::{AmlValue, FieldKind, FieldUnit, RegionSpace, UpdateRule};
+use crate::{AmlError, Handler, PciAddress};
+
+impl<H: Handler> Interpreter<H> {
+    /// Read a field one access unit at a time
+    pub(crate) fn read_field(&mut self, field: &FieldUnit) -> Result<AmlValue, AmlError> {
+        let width = field.flags.access_width() as u64;
+        let mut bytes = alloc::vec![0; bytes_for(field.bit_length)];
+        let end = field.bit_offset + field.bit_length;
+
+        for unit in units(field) {
+            let unit_start = unit * width;
+            let value = self.read_unit(field, unit_start / 8)?;
+            for bit in field.bit_offset.max(unit_start)..end.min(unit_start + width) {
+                if value >> (bit - unit_start) & 1 != 0 {
+                    let index = bit - field.bit_offset;
+                    bytes[(index / 8) as usize] |= 1 << (index % 8);
+                }
+            }
+        }
+        Ok(bits_to_value(bytes, field.bit_length))
+    }
+
+    /// Write a field one access unit at a time, bits of a unit outside the field follow
+    /// the update rule
+    pub(crate) fn write_field(
+        &mut self,
+        field: &FieldUnit,
+        value: AmlValue,
+    ) -> Result<(), AmlError> {
+        let source = self.deref(value)?.as_buffer(64)?;
+        let width = field.flags.access_width() as u64;
+        let mask = u64::MAX >> (64 - width);
+        let end = field.bit_offset + field.bit_length;
+
+        for unit in units(field) {
+            let unit_start = unit * width;
+            let start = field.bit_offset.max(unit_start);
+            let stop = end.min(unit_start + width);
+            let whole = start == unit_start && stop == unit_start + width;
+            let mut raw = match field.flags.update_rule() {
+                _ if whole => 0,
+                UpdateRule::Preserve => self.read_unit(field, unit_start / 8)?,
+                UpdateRule::WriteAsOnes => mask,
+                UpdateRule::WriteAsZeros => 0,
+            };
+            for bit in start..stop {
+                let index = bit - field.bit_offset;
+                let set = source
+                    .get((index / 8) as usize)
+                    .is_some_and(|byte| byte >> (index % 8) & 1 != 0);
+                match set {
+                    true => raw |= 1 << (bit - unit_start),
+                    false => raw &= !(1 << (bit - unit_start)),
+                }
+            }
+            self.write_unit(field, unit_start / 8, raw & mask)?;
+        }
+        Ok(())
+    }
+
+    /// Read the access unit `offset` bytes into the field's region
+    fn read_unit(&mut self, field: &FieldUnit, offset: u64) -> Result<u64, AmlError> {
+        let width = field.flags.access_width();
+        match &field.kind {
+            FieldKind::Normal { region } => self.read_region(region, offset, width),
+            FieldKind::Bank {
+                region,
+                bank,
+                value,
+            } => {
+                self.store_name(bank, AmlValue::Integer(*value), true)?;
+                self.read_region(region, offset, width)
+            }
+            FieldKind::Index { index, data } => {
+                self.store_name(index, AmlValue::Integer(offset), true)?;
+                self.read_object(data)?.as_integer()
+            }
+        }
+    }
+
+    fn write_unit(&mut self, field: &FieldUnit, offset: u64, value: u64) -> Result<(), AmlError> {
+        let width = field.flags.access_width();
+        match &field.kind {
+            FieldKind::Normal { region } => self.write_region(region, offset, width, value),
+            FieldKind::Bank {
+                region,
+                bank,
+                value: bank_value,
+            } => {
+                self.store_name(bank, AmlValue::Integer(*bank_value), true)?;
+                self.write_region(region, offset, width, value)
+            }
+            FieldKind::Index { index, data } => {
+                self.store_name(index, AmlValue::Integer(offset), true)?;
+                self.store_name(data, AmlValue::Integer(value), true)
+            }
+        }
+    }
+
+    fn read_region(&mut self, region: &AmlName, offset: u64, width: u8) -> Result<u64, AmlError> {
+        let (space, address) = self.region_address(region, offset, width)?;
+        match space {
+            RegionSpace::SystemMemory => Ok(self.handler.read_memory(address, width)),
+            RegionSpace::SystemIo => Ok(self.handler.read_io(address as u16, width)),
+            RegionSpace::PciConfig => {
+                let pci = self.pci_address(region)?;
+                Ok(self.handler.read_pci(pci, address as u16, width))
+            }
+            space => Err(AmlError::UnsupportedRegion(space)),
+        }
+    }
+
+    fn write_region(
+        &mut self,
+        region: &AmlName,
+        offset: u64,
+        width: u8,
+        value: u64,
+    ) -> Result<(), AmlError> {
+        let (space, address) = self.region_address(region, offset, width)?;
+        match space {
+            RegionSpace::SystemMemory => self.handler.write_memory(address, width, value),
+            RegionSpace::SystemIo => self.handler.write_io(address as u16, width, value),
+            RegionSpace::PciConfig => {
+                let pci = self.pci_address(region)?;
+                self.handler.write_pci(pci, address as u16, width, value);
+            }
+            space => return Err(AmlError::UnsupportedRegion(space)),
+        }
+        Ok(())
+    }
+
+    /// The space and address of an access, which has to be inside the region
+    fn region_address(
+        &self,
+        region: &AmlName,
+        offset: u64,
+        width: u8,
+    ) -> Result<(RegionSpace, u64), AmlError> {
+        let region = match self.namespace().get(region) {
+            Some(AmlValue::OpRegion(region)) => *region,
+            _ => return Err(AmlError::TypeMismatch),
+        };
+        if offset + width as u64 / 8 > region.length {
+            return Err(AmlError::IndexOutOfBounds);
+        }
+        Ok((region.space, region.offset + offset))
+    }
+
+    /// The pci function a config space region belongs to, from `_ADR` of its device and
+    /// `_BBN` and `_SEG` of the closest bridge that has them
+    fn pci_address(&mut self, region: &AmlName) -> Result<PciAddress, AmlError> {
+        let device = region.parent().ok_or(AmlError::InvalidName)?;
+        let adr = self.integer_in(&device, "_ADR")?.unwrap_or(0);
+        let bus = self.closest_integer(&device, "_BBN")?;
+        let segment = self.closest_integer(&device, "_SEG")?;
+        Ok(PciAddress {
+            segment: segment as u16,
+            bus: bus as u8,
+            device: (adr >> 16) as u8,
+            function: adr as u8,
+        })
+    }
+
+    /// `name` in `scope` or the closest scope above it that has one, zero if none do
+    fn closest_integer(&mut self, scope: &AmlName, name: &str) -> Result<u64, AmlError> {
+        let mut scope = Some(scope.clone());
+        while let Some(current) = scope {
+            if let Some(value) = self.integer_in(&current, name)? {
+                return Ok(value);
+            }
+            scope = current.parent();
+        }
+        Ok(0)
+    }
+}
+
+/// Indices of the access units a field touches
+fn units(field: &FieldUnit) -> core::ops::RangeInclusive<u64> {
+    let width = field.flags.access_width() as u64;
+    let last = (field.bit_offset + field.bit_length.max(1) - 1) / width;
+    field.bit_offset / width..=last
+}
+
+fn bytes_for(bits: u64) -> usize {
+    bits.div_ceil(8) as usize
+}
+
+/// Fields of up to 64 bits read as integers, longer ones as buffers
+pub(crate) fn bits_to_value(bytes: Vec<u8>, bit_length: u64) -> AmlValue {
+    match bit_length {
+        0..=64 => AmlValue::Integer(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, byte| value << 8 | *byte as u64),
+        ),
+        _ => AmlValue::Buffer(bytes),
+    }
+}
+
+/// Copy `length` bits starting at bit `offset` of `bytes`
+pub(crate) fn get_bits(bytes: &[u8], offset: u64, length: u64) -> Result<Vec<u8>, AmlError> {
+    if offset + length > bytes.len() as u64 * 8 {
+        return Err(AmlError::IndexOutOfBounds);
+    }
+    let mut bits = alloc::vec![0; bytes_for(length)];
+    for index in 0..length {
+        let bit = offset + index;
+        if bytes[(bit / 8) as usize] >> (bit % 8) & 1 != 0 {
+            bits[(index / 8) as usize] |= 1 << (index % 8);
+        }
+    }
+    Ok(bits)
+}
+
+/// Overwrite `length` bits starting at bit `offset` of `bytes`, missing source bits are zero
+pub(crate) fn set_bits(
+    bytes: &mut [u8],
+    offset: u64,
+    length: u64,
+    source: &[u8],
+) -> Result<(), AmlError> {
+    if offset + length > bytes.len() as u64 * 8 {
+        return Err(AmlError::IndexOutOfBounds);
+    }
+    for index in 0..length {
+        let bit = offset + index;
+        let set = source
+            .get((index / 8) as usize)
+            .is_some_and(|byte| byte >> (index % 8) & 1 != 0);
+        let byte = &mut bytes[(bit / 8) as usize];
+        match set {
+            true => *byte |= 1 << (bit % 8),
+            false => *byte &= !(1 << (bit % 8)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn bit_copies() {
+        let mut bytes = [0xFF, 0x00];
+        assert_eq!(get_bits(&bytes, 4, 8), Ok(vec![0x0F]));
+        set_bits(&mut bytes, 6, 4, &[0b0110]).unwrap();
+        assert_eq!(bytes, [0b1011_1111, 0b0000_0001]);
+        assert_eq!(get_bits(&bytes, 12, 8), Err(AmlError::IndexOutOfBounds));
+    }
+
+    #[test]
+    fn io_and_index_fields() {
+        let code = [
+            // OperationRegion (REG0, SystemIO, 0x100, 4)
+            // Field (REG0, ByteAcc, NoLock, Preserve) { LOW, 4, HIGH, 4, WORD, 16 }
+            &region(b"REG0", 1, 0x100, 4)[..],
+            &field(
+                b"REG0",
+                0x01,
+                &[&b"LOW_\x04HIGH\x04"[..], b"WORD\x10"].concat(),
+            ),
+            // OperationRegion (REG1, SystemIO, 0x200, 2)
+            // Field (REG1, ByteAcc, NoLock, WriteAsZeros) { IDX, 8, DAT, 8 }
+            // IndexField (IDX, DAT, ByteAcc, NoLock, Preserve) { Offset (2), FLD, 8 }
+            &region(b"REG1", 1, 0x200, 2),
+            &field(b"REG1", 0x41, b"IDX_\x08DAT_\x08"),
+            &[EXT_PREFIX, INDEX_FIELD_OP],
+            &pkg(b"IDX_DAT_\x01\x00\x10FLD_\x08"),
+            // Method (SETH, 1) { HIGH = Arg0 }
+            &method(b"SETH", 1, &[&[STORE_OP, ARG0_OP][..], b"HIGH"].concat()),
+        ]
+        .concat();
+        let mut handler = TestHandler::default();
+        handler.io.insert(0x100, 0x05);
+        handler.io.insert(0x101, 0x34);
+        handler.io.insert(0x102, 0x12);
+        let mut aml = Interpreter::new(handler);
+        aml.load_table(&table(b"DSDT", &code)).unwrap();
+
+        assert_eq!(eval(&mut aml, "\\LOW", vec![]).as_integer(), Ok(5));
+        eval(&mut aml, "\\SETH", vec![AmlValue::Integer(0xA)]);
+        assert_eq!(aml.handler().io[&0x100], 0xA5);
+        // a word spanning two byte accesses
+        assert_eq!(eval(&mut aml, "\\WORD", vec![]).as_integer(), Ok(0x1234));
+
+        aml.handler_mut().io.insert(0x201, 0x42);
+        assert_eq!(eval(&mut aml, "\\FLD", vec![]).as_integer(), Ok(0x42));
+        assert_eq!(aml.handler().io_writes, [(0x100, 0xA5), (0x200, 2)]);
+    }
+
+    #[test]
+    fn pci_config_region() {
+        let code = [
+            // Device (PCI0) { Name (_BBN, 1) Device (ISA) { Name (_ADR, 0x00010002)
+            //     OperationRegion (PIRQ, PCI_Config, 0x60, 4)
+            //     Field (PIRQ, AnyAcc, NoLock, Preserve) { PRQA, 8, PRQB, 8 } } }
+            &[EXT_PREFIX, DEVICE_OP][..],
+            &pkg(&[
+                &b"PCI0"[..],
+                &[NAME_OP],
+                b"_BBN",
+                &[ONE_OP],
+                &[EXT_PREFIX, DEVICE_OP],
+                &pkg(&[
+                    &b"ISA_"[..],
+                    &[NAME_OP],
+                    b"_ADR",
+                    &[DWORD_PREFIX, 0x02, 0x00, 0x01, 0x00],
+                    &region(b"PIRQ", 2, 0x60, 4),
+                    &field(b"PIRQ", 0x00, b"PRQA\x08PRQB\x08"),
+                ]
+                .concat()),
+            ]
+            .concat()),
+        ]
+        .concat();
+        let mut aml = Interpreter::new(TestHandler::default());
+        aml.load_table(&table(b"DSDT", &code)).unwrap();
+
+        let function = PciAddress {
+            segment: 0,
+            bus: 1,
+            device: 1,
+            function: 2,
+        };
+        aml.handler_mut().pci.insert((function, 0x61), 11);
+        assert_eq!(
+            eval(&mut aml, "\\PCI0.ISA.PRQB", vec![]).as_integer(),
+            Ok(11)
+        );
+    }
+}
@@ -0,0 +1,1668 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+use crate::field::{bits_to_value, get_bits, set_bits};
+use crate::name::{AmlName, NameSeg};
+use crate::namespace::Namespace;
+use crate::opcode::*;
+use crate::stream::Stream;
+use crate::value::{AmlValue, FieldFlags, FieldKind, FieldUnit, Method, MethodCode};
+use crate::value::{OpRegion, Reference, RegionSpace};
+use crate::{AmlError, Handler};
+
+/// Size of the acpi table header, the code follows it
+const TABLE_HEADER_SIZE: usize = 36;
+/// How deep methods can call each other
+const MAX_DEPTH: usize = 64;
+/// Iterations of a while loop before it is given up on
+const MAX_LOOP_ITERATIONS: u64 = 1 << 20;
+/// Largest buffer or package the code can create
+const MAX_OBJECT_SIZE: u64 = 1 << 20;
+/// Aml revision this implements, what the revision opcode returns
+const INTERPRETER_REVISION: u64 = 2;
+
+/// Interfaces `_OSI` says the os supports, firmware mostly tests for windows versions
+const SUPPORTED_INTERFACES: [&str; 13] = [
+    "Windows 2000",
+    "Windows 2001",
+    "Windows 2001 SP1",
+    "Windows 2001.1",
+    "Windows 2006",
+    "Windows 2009",
+    "Windows 2012",
+    "Windows 2013",
+    "Windows 2015",
+    "Module Device",
+    "Processor Device",
+    "3.0 Thermal Model",
+    "Extended Address Space Descriptor",
+];
+
+/// How running a term ended
+enum Flow {
+    Normal,
+    Return(AmlValue),
+    Break,
+    Continue,
+}
+
+/// Where the result of an operator goes
+enum Target {
+    Null,
+    Debug,
+    Name(AmlName),
+    Local(usize),
+    Arg(usize),
+    /// An element of a buffer or package
+    Element(Box<Target>, usize),
+}
+
+/// State of the code being run
+struct Frame {
+    scope: AmlName,
+    args: Vec<AmlValue>,
+    locals: [AmlValue; 8],
+    /// Objects a method created, they are removed when it returns
+    created: Option<Vec<AmlName>>,
+}
+
+impl Frame {
+    fn new(scope: AmlName, args: Vec<AmlValue>, in_method: bool) -> Self {
+        Self {
+            scope,
+            args,
+            locals: Default::default(),
+            created: in_method.then(Vec::new),
+        }
+    }
+}
+
+/// What `_STA` returns, devices without one are present and working
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceStatus(pub u64);
+
+impl DeviceStatus {
+    pub fn present(&self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    pub fn functioning(&self) -> bool {
+        self.0 & (1 << 3) != 0
+    }
+}
+
+pub struct Interpreter<H> {
+    namespace: Namespace,
+    pub(crate) handler: H,
+    /// Integers are 64 bit from dsdt revision 2, 32 bit before
+    integer_64: bool,
+    depth: usize,
+}
+
+impl<H: Handler> Interpreter<H> {
+    /// An interpreter with only the predefined objects
+    pub fn new(handler: H) -> Self {
+        let mut namespace = Namespace::new();
+        let root = AmlName::root();
+        let mut predefine = |name: &str, value| {
+            let seg: NameSeg = name.parse().unwrap();
+            namespace.add(root.join(seg), value).unwrap();
+        };
+        predefine("_GPE", AmlValue::Scope);
+        predefine("_PR", AmlValue::Scope);
+        predefine("_SB", AmlValue::Device);
+        predefine("_SI", AmlValue::Scope);
+        predefine("_TZ", AmlValue::Device);
+        predefine("_GL", AmlValue::Mutex { sync_level: 0 });
+        predefine("_OS", AmlValue::String("Microsoft Windows NT".into()));
+        predefine("_REV", AmlValue::Integer(INTERPRETER_REVISION));
+        predefine(
+            "_OSI",
+            AmlValue::Method(Method {
+                arg_count: 1,
+                serialized: false,
+                code: MethodCode::Native(osi),
+            }),
+        );
+
+        Self {
+            namespace,
+            handler,
+            integer_64: true,
+            depth: 0,
+        }
+    }
+
+    pub fn namespace(&self) -> &Namespace {
+        &self.namespace
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Run the top level code of a dsdt or ssdt, `table` starts with the table header
+    pub fn load_table(&mut self, table: &[u8]) -> Result<(), AmlError> {
+        if table.len() < TABLE_HEADER_SIZE {
+            return Err(AmlError::InvalidTable);
+        }
+        if &table[0..4] == b"DSDT" {
+            self.integer_64 = table[8] >= 2;
+        }
+
+        let mut stream = Stream::new(table);
+        stream.seek(TABLE_HEADER_SIZE);
+        let mut frame = Frame::new(AmlName::root(), Vec::new(), false);
+        self.run_term_list(&mut stream, table.len(), &mut frame)?;
+        Ok(())
+    }
+
+    /// Value of the object at `path`, methods are called with `args` and fields are read
+    pub fn evaluate(&mut self, path: &AmlName, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+        let path = path.resolve(&AmlName::root())?;
+        match self.namespace.get(&path) {
+            Some(AmlValue::Method(_)) => self.call(&path, args),
+            Some(_) => self.read_object(&path),
+            None => Err(AmlError::UndefinedName(path)),
+        }
+    }
+
+    /// Like [`Self::evaluate`], but none if there is no such object
+    pub fn evaluate_if_present(
+        &mut self,
+        path: &AmlName,
+        args: Vec<AmlValue>,
+    ) -> Result<Option<AmlValue>, AmlError> {
+        match self.namespace.contains(&path.resolve(&AmlName::root())?) {
+            true => self.evaluate(path, args).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    /// The value behind a reference or a name in a package
+    pub fn resolve(&mut self, value: AmlValue) -> Result<AmlValue, AmlError> {
+        self.deref(value)
+    }
+
+    /// The pm1a and pm1b sleep types of sleep state `state`, from `\_Sx_`
+    pub fn sleep_type(&mut self, state: u8) -> Result<(u8, u8), AmlError> {
+        let path: AmlName = alloc::format!("\\_S{}", state).parse()?;
+        let package = self.evaluate(&path, Vec::new())?;
+        match package.as_package()? {
+            [a, b, ..] => {
+                let a = self.deref(a.clone())?.as_integer()?;
+                let b = self.deref(b.clone())?.as_integer()?;
+                Ok((a as u8, b as u8))
+            }
+            // old firmware puts both in one integer
+            [both] => {
+                let both = self.deref(both.clone())?.as_integer()?;
+                Ok((both as u8, (both >> 8) as u8))
+            }
+            [] => Err(AmlError::IndexOutOfBounds),
+        }
+    }
+
+    /// What `_STA` of `device` says
+    pub fn device_status(&mut self, device: &AmlName) -> Result<DeviceStatus, AmlError> {
+        let status = self.integer_in(device, "_STA")?;
+        Ok(DeviceStatus(status.unwrap_or(0x0F)))
+    }
+
+    /// Tell the firmware interrupts are routed through the io apic, with `\_PIC(1)`
+    pub fn set_apic_mode(&mut self) -> Result<(), AmlError> {
+        let path: AmlName = "\\_PIC".parse()?;
+        self.evaluate_if_present(&path, alloc::vec![AmlValue::Integer(1)])?;
+        Ok(())
+    }
+
+    /// Run `_INI` of every present device, returns how many there were
+    pub fn initialize_devices(&mut self) -> Result<usize, AmlError> {
+        let mut count = 0;
+        for path in ["\\_INI", "\\_SB._INI"] {
+            if self
+                .evaluate_if_present(&path.parse()?, Vec::new())?
+                .is_some()
+            {
+                count += 1;
+            }
+        }
+        self.initialize_scope(&AmlName::root(), &mut count)?;
+        Ok(count)
+    }
+
+    fn initialize_scope(&mut self, scope: &AmlName, count: &mut usize) -> Result<(), AmlError> {
+        let devices: Vec<AmlName> = self
+            .namespace
+            .children(scope)
+            .filter(|(_, value)| matches!(value, AmlValue::Device | AmlValue::Scope))
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for device in devices {
+            let status = self.device_status(&device)?;
+            if status.present() {
+                if self.call_if_present(&device, "_INI")? {
+                    *count += 1;
+                }
+            } else if !status.functioning() {
+                // nothing below a missing device is there either
+                continue;
+            }
+            self.initialize_scope(&device, count)?;
+        }
+        Ok(())
+    }
+
+    /// Call the method `name` in `scope` if it exists
+    fn call_if_present(&mut self, scope: &AmlName, name: &str) -> Result<bool, AmlError> {
+        let path = scope.join(name.parse()?);
+        Ok(self.evaluate_if_present(&path, Vec::new())?.is_some())
+    }
+
+    /// Evaluate `name` in `scope` as an integer if it exists
+    pub(crate) fn integer_in(
+        &mut self,
+        scope: &AmlName,
+        name: &str,
+    ) -> Result<Option<u64>, AmlError> {
+        let path = scope.join(name.parse()?);
+        match self.evaluate_if_present(&path, Vec::new())? {
+            Some(value) => Ok(Some(self.deref(value)?.as_integer()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// All ones in the integer width of the dsdt
+    fn ones(&self) -> u64 {
+        match self.integer_64 {
+            true => u64::MAX,
+            false => u32::MAX as u64,
+        }
+    }
+
+    /// Integer width in bits
+    pub(crate) fn width(&self) -> u8 {
+        match self.integer_64 {
+            true => 64,
+            false => 32,
+        }
+    }
+
+    fn boolean(&self, value: bool) -> AmlValue {
+        AmlValue::Integer(if value { self.ones() } else { 0 })
+    }
+
+    fn call(&mut self, path: &AmlName, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+        let code = match self.namespace.get(path) {
+            Some(AmlValue::Method(method)) => method.code.clone(),
+            _ => return Err(AmlError::TypeMismatch),
+        };
+        let code = match code {
+            MethodCode::Aml(code) => code,
+            MethodCode::Native(native) => {
+                return Ok(match native(&args)? {
+                    AmlValue::Integer(value) => AmlValue::Integer(value & self.ones()),
+                    value => value,
+                });
+            }
+        };
+
+        if self.depth >= MAX_DEPTH {
+            return Err(AmlError::DepthLimit);
+        }
+        self.depth += 1;
+        let mut frame = Frame::new(path.clone(), args, true);
+        let mut stream = Stream::new(&code);
+        let result = self.run_term_list(&mut stream, code.len(), &mut frame);
+        for name in frame.created.iter().flatten().rev() {
+            self.namespace.remove(name);
+        }
+        self.depth -= 1;
+
+        match result? {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(AmlValue::Uninitialized),
+        }
+    }
+
+    /// Add a named object in the scope of `frame`
+    fn add(&mut self, name: &AmlName, value: AmlValue, frame: &mut Frame) -> Result<(), AmlError> {
+        let name = name.resolve(&frame.scope)?;
+        self.namespace.add(name.clone(), value)?;
+        if let Some(created) = &mut frame.created {
+            created.push(name);
+        }
+        Ok(())
+    }
+
+    fn run_term_list(
+        &mut self,
+        stream: &mut Stream,
+        end: usize,
+        frame: &mut Frame,
+    ) -> Result<Flow, AmlError> {
+        while stream.pos() < end {
+            match self.run_term(stream, frame)? {
+                Flow::Normal => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Run the code up to `end` with `scope` as the current scope
+    fn run_in_scope(
+        &mut self,
+        stream: &mut Stream,
+        end: usize,
+        scope: AmlName,
+        frame: &mut Frame,
+    ) -> Result<Flow, AmlError> {
+        let outer = core::mem::replace(&mut frame.scope, scope);
+        let flow = self.run_term_list(stream, end, frame);
+        frame.scope = outer;
+        flow
+    }
+
+    /// Run a statement or named object definition, or evaluate an expression
+    fn run_term(&mut self, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        match s.peek()? {
+            NAME_OP => {
+                s.byte()?;
+                let name = s.name_string()?;
+                let value = self.eval(s, f)?;
+                self.add(&name, value, f)?;
+            }
+            SCOPE_OP => {
+                s.byte()?;
+                let end = s.pkg_length()?;
+                let name = s.name_string()?;
+                let scope = match name.resolve(&f.scope)? {
+                    root if root.is_root() => root,
+                    _ => self.namespace.search(&name, &f.scope)?,
+                };
+                return self.run_in_scope(s, end, scope, f);
+            }
+            METHOD_OP => {
+                s.byte()?;
+                let end = s.pkg_length()?;
+                let name = s.name_string()?;
+                let flags = s.byte()?;
+                let code = s.take_until(end)?;
+                let method = Method {
+                    arg_count: flags & 0b111,
+                    serialized: flags & (1 << 3) != 0,
+                    code: MethodCode::Aml(Arc::from(code)),
+                };
+                self.add(&name, AmlValue::Method(method), f)?;
+            }
+            ALIAS_OP => {
+                s.byte()?;
+                let source = s.name_string()?;
+                let alias = s.name_string()?;
+                let target = self.namespace.search(&source, &f.scope)?;
+                self.add(&alias, AmlValue::Alias(target), f)?;
+            }
+            EXTERNAL_OP => {
+                // only a hint for the compiler
+                s.byte()?;
+                s.name_string()?;
+                s.bytes(2)?;
+            }
+            CREATE_BIT_FIELD_OP
+            | CREATE_BYTE_FIELD_OP
+            | CREATE_WORD_FIELD_OP
+            | CREATE_DWORD_FIELD_OP
+            | CREATE_QWORD_FIELD_OP => self.create_field(s, f)?,
+            IF_OP => return self.run_if(s, f),
+            WHILE_OP => return self.run_while(s, f),
+            RETURN_OP => {
+                s.byte()?;
+                return Ok(Flow::Return(self.eval(s, f)?));
+            }
+            BREAK_OP => {
+                s.byte()?;
+                return Ok(Flow::Break);
+            }
+            CONTINUE_OP => {
+                s.byte()?;
+                return Ok(Flow::Continue);
+            }
+            NOOP_OP | BREAKPOINT_OP => {
+                s.byte()?;
+            }
+            NOTIFY_OP => {
+                // nothing listens for notifications
+                s.byte()?;
+                self.parse_target(s, f)?;
+                self.eval_integer(s, f)?;
+            }
+            EXT_PREFIX => return self.run_ext_term(s, f),
+            _ => {
+                self.eval(s, f)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn run_ext_term(&mut self, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        match s.peek_at(1) {
+            Some(MUTEX_OP) => {
+                s.bytes(2)?;
+                let name = s.name_string()?;
+                let sync_level = s.byte()? & 0xF;
+                self.add(&name, AmlValue::Mutex { sync_level }, f)?;
+            }
+            Some(EVENT_OP) => {
+                s.bytes(2)?;
+                let name = s.name_string()?;
+                self.add(&name, AmlValue::Event, f)?;
+            }
+            Some(OP_REGION_OP) => {
+                s.bytes(2)?;
+                let name = s.name_string()?;
+                let space = RegionSpace::from(s.byte()?);
+                let offset = self.eval_integer(s, f)?;
+                let length = self.eval_integer(s, f)?;
+                let region = OpRegion {
+                    space,
+                    offset,
+                    length,
+                };
+                self.add(&name, AmlValue::OpRegion(region), f)?;
+            }
+            Some(FIELD_OP) => {
+                s.bytes(2)?;
+                let end = s.pkg_length()?;
+                let region = self.search(s, f)?;
+                let flags = FieldFlags(s.byte()?);
+                self.parse_field_list(s, end, FieldKind::Normal { region }, flags, f)?;
+            }
+            Some(INDEX_FIELD_OP) => {
+                s.bytes(2)?;
+                let end = s.pkg_length()?;
+                let index = self.search(s, f)?;
+                let data = self.search(s, f)?;
+                let flags = FieldFlags(s.byte()?);
+                self.parse_field_list(s, end, FieldKind::Index { index, data }, flags, f)?;
+            }
+            Some(BANK_FIELD_OP) => {
+                s.bytes(2)?;
+                let end = s.pkg_length()?;
+                let region = self.search(s, f)?;
+                let bank = self.search(s, f)?;
+                let value = self.eval_integer(s, f)?;
+                let flags = FieldFlags(s.byte()?);
+                let kind = FieldKind::Bank {
+                    region,
+                    bank,
+                    value,
+                };
+                self.parse_field_list(s, end, kind, flags, f)?;
+            }
+            Some(DEVICE_OP) => {
+                s.bytes(2)?;
+                let end = s.pkg_length()?;
+                let name = s.name_string()?;
+                return self.define_scope(s, end, &name, AmlValue::Device, f);
+            }
+            Some(PROCESSOR_OP) => {
+                s.bytes(2)?;
+                let end = s.pkg_length()?;
+                let name = s.name_string()?;
+                let processor = AmlValue::Processor {
+                    id: s.byte()?,
+                    block_address: s.integer(4)? as u32,
+                    block_length: s.byte()?,
+                };
+                return self.define_scope(s, end, &name, processor, f);
+            }
+            Some(POWER_RES_OP) => {
+                s.bytes(2)?;
+                let end = s.pkg_length()?;
+                let name = s.name_string()?;
+                let resource = AmlValue::PowerResource {
+                    system_level: s.byte()?,
+                    resource_order: s.integer(2)? as u16,
+                };
+                return self.define_scope(s, end, &name, resource, f);
+            }
+            Some(THERMAL_ZONE_OP) => {
+                s.bytes(2)?;
+                let end = s.pkg_length()?;
+                let name = s.name_string()?;
+                return self.define_scope(s, end, &name, AmlValue::ThermalZone, f);
+            }
+            Some(CREATE_FIELD_OP) => self.create_field(s, f)?,
+            Some(RELEASE_OP | SIGNAL_OP | RESET_OP) => {
+                // only one thread runs aml, so mutexes and events are never contended
+                s.bytes(2)?;
+                self.parse_target(s, f)?;
+            }
+            Some(SLEEP_OP) => {
+                s.bytes(2)?;
+                let millis = self.eval_integer(s, f)?;
+                self.handler.sleep(millis);
+            }
+            Some(STALL_OP) => {
+                s.bytes(2)?;
+                let micros = self.eval_integer(s, f)?;
+                self.handler.stall(micros);
+            }
+            Some(FATAL_OP) => {
+                s.bytes(2)?;
+                let kind = s.byte()?;
+                let code = s.integer(4)? as u32;
+                let argument = self.eval_integer(s, f)?;
+                return Err(AmlError::Fatal {
+                    kind,
+                    code,
+                    argument,
+                });
+            }
+            _ => {
+                self.eval(s, f)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Add an object that is also a scope and run the code in it
+    fn define_scope(
+        &mut self,
+        s: &mut Stream,
+        end: usize,
+        name: &AmlName,
+        value: AmlValue,
+        f: &mut Frame,
+    ) -> Result<Flow, AmlError> {
+        self.add(name, value, f)?;
+        let scope = name.resolve(&f.scope)?;
+        self.run_in_scope(s, end, scope, f)
+    }
+
+    /// Look up the name string at the stream
+    fn search(&mut self, s: &mut Stream, f: &Frame) -> Result<AmlName, AmlError> {
+        let name = s.name_string()?;
+        self.namespace.search(&name, &f.scope)
+    }
+
+    fn parse_field_list(
+        &mut self,
+        s: &mut Stream,
+        end: usize,
+        kind: FieldKind,
+        mut flags: FieldFlags,
+        f: &mut Frame,
+    ) -> Result<(), AmlError> {
+        let mut bit_offset = 0;
+        while s.pos() < end {
+            match s.peek()? {
+                RESERVED_FIELD => {
+                    s.byte()?;
+                    bit_offset += s.pkg_length_value()?;
+                }
+                ACCESS_FIELD => {
+                    s.byte()?;
+                    flags = flags.with_access_type(s.byte()?);
+                    // access attributes only matter for smbus and serial bus regions
+                    s.byte()?;
+                }
+                EXTENDED_ACCESS_FIELD => {
+                    s.byte()?;
+                    flags = flags.with_access_type(s.byte()?);
+                    s.bytes(2)?;
+                }
+                CONNECT_FIELD => return Err(AmlError::UnsupportedOpcode(CONNECT_FIELD as u16)),
+                _ => {
+                    let seg = s.name_seg()?;
+                    let bit_length = s.pkg_length_value()?;
+                    let field = FieldUnit {
+                        kind: kind.clone(),
+                        flags,
+                        bit_offset,
+                        bit_length,
+                    };
+                    let name = AmlName::new(false, 0, alloc::vec![seg]);
+                    self.add(&name, AmlValue::Field(field), f)?;
+                    bit_offset += bit_length;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The create field operators, which name bits of a buffer
+    fn create_field(&mut self, s: &mut Stream, f: &mut Frame) -> Result<(), AmlError> {
+        let (size, index_in_bits) = match s.byte()? {
+            CREATE_BIT_FIELD_OP => (Some(1), true),
+            CREATE_BYTE_FIELD_OP => (Some(8), false),
+            CREATE_WORD_FIELD_OP => (Some(16), false),
+            CREATE_DWORD_FIELD_OP => (Some(32), false),
+            CREATE_QWORD_FIELD_OP => (Some(64), false),
+            _ => {
+                s.byte()?;
+                (None, true)
+            }
+        };
+        let buffer = match self.parse_target(s, f)? {
+            Target::Name(buffer) => buffer,
+            _ => return Err(AmlError::TypeMismatch),
+        };
+        let index = self.eval_integer(s, f)?;
+        let bit_offset = if index_in_bits { index } else { index * 8 };
+        let bit_length = match size {
+            Some(size) => size,
+            None => self.eval_integer(s, f)?,
+        };
+        let name = s.name_string()?;
+        let field = AmlValue::BufferField {
+            buffer,
+            bit_offset,
+            bit_length,
+        };
+        self.add(&name, field, f)
+    }
+
+    fn run_if(&mut self, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.byte()?;
+        let end = s.pkg_length()?;
+        let predicate = self.eval_integer(s, f)? != 0;
+        let flow = match predicate {
+            true => self.run_term_list(s, end, f)?,
+            false => Flow::Normal,
+        };
+        s.seek(end);
+
+        if s.pos() < s.len() && s.peek()? == ELSE_OP {
+            s.byte()?;
+            let else_end = s.pkg_length()?;
+            if !predicate {
+                return self.run_term_list(s, else_end, f);
+            }
+            s.seek(else_end);
+        }
+        Ok(flow)
+    }
+
+    fn run_while(&mut self, s: &mut Stream, f: &mut Frame) -> Result<Flow, AmlError> {
+        s.byte()?;
+        let end = s.pkg_length()?;
+        let predicate = s.pos();
+        let mut iterations = 0;
+        loop {
+            s.seek(predicate);
+            if self.eval_integer(s, f)? == 0 {
+                break;
+            }
+            match self.run_term_list(s, end, f)? {
+                Flow::Break => break,
+                Flow::Return(value) => {
+                    s.seek(end);
+                    return Ok(Flow::Return(value));
+                }
+                Flow::Normal | Flow::Continue => {}
+            }
+            iterations += 1;
+            if iterations > MAX_LOOP_ITERATIONS {
+                return Err(AmlError::LoopLimit);
+            }
+        }
+        s.seek(end);
+        Ok(Flow::Normal)
+    }
+
+    /// Evaluate a term argument, references are kept
+    fn eval(&mut self, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        if s.at_name() {
+            return self.eval_name(s, f);
+        }
+
+        let op = s.byte()?;
+        let value = match op {
+            ZERO_OP => AmlValue::Integer(0),
+            ONE_OP => AmlValue::Integer(1),
+            ONES_OP => AmlValue::Integer(self.ones()),
+            BYTE_PREFIX => AmlValue::Integer(s.integer(1)?),
+            WORD_PREFIX => AmlValue::Integer(s.integer(2)?),
+            DWORD_PREFIX => AmlValue::Integer(s.integer(4)?),
+            QWORD_PREFIX => AmlValue::Integer(s.integer(8)?),
+            STRING_PREFIX => AmlValue::String(s.string()?.into()),
+            BUFFER_OP => self.eval_buffer(s, f)?,
+            PACKAGE_OP => {
+                let end = s.pkg_length()?;
+                let count = s.byte()? as u64;
+                self.eval_package(s, end, count, f)?
+            }
+            VAR_PACKAGE_OP => {
+                let end = s.pkg_length()?;
+                let count = self.eval_integer(s, f)?;
+                self.eval_package(s, end, count, f)?
+            }
+            LOCAL0_OP..=LOCAL7_OP => f.locals[(op - LOCAL0_OP) as usize].clone(),
+            ARG0_OP..=ARG6_OP => f
+                .args
+                .get((op - ARG0_OP) as usize)
+                .cloned()
+                .unwrap_or_default(),
+            STORE_OP | COPY_OBJECT_OP => {
+                let value = match self.eval(s, f)? {
+                    name @ AmlValue::Name { .. } => self.deref(name)?,
+                    value => value,
+                };
+                let target = self.parse_target(s, f)?;
+                self.store(&target, value.clone(), op == STORE_OP, f)?;
+                value
+            }
+            ADD_OP => self.binary(s, f, |a, b| Ok(a.wrapping_add(b)))?,
+            SUBTRACT_OP => self.binary(s, f, |a, b| Ok(a.wrapping_sub(b)))?,
+            MULTIPLY_OP => self.binary(s, f, |a, b| Ok(a.wrapping_mul(b)))?,
+            SHIFT_LEFT_OP => self.binary(s, f, |a, b| Ok(a.checked_shl(b as u32).unwrap_or(0)))?,
+            SHIFT_RIGHT_OP => self.binary(s, f, |a, b| Ok(a.checked_shr(b as u32).unwrap_or(0)))?,
+            AND_OP => self.binary(s, f, |a, b| Ok(a & b))?,
+            NAND_OP => self.binary(s, f, |a, b| Ok(!(a & b)))?,
+            OR_OP => self.binary(s, f, |a, b| Ok(a | b))?,
+            NOR_OP => self.binary(s, f, |a, b| Ok(!(a | b)))?,
+            XOR_OP => self.binary(s, f, |a, b| Ok(a ^ b))?,
+            MOD_OP => self.binary(s, f, |a, b| a.checked_rem(b).ok_or(AmlError::DivideByZero))?,
+            DIVIDE_OP => {
+                let dividend = self.eval_integer(s, f)?;
+                let divisor = self.eval_integer(s, f)?;
+                let remainder_target = self.parse_target(s, f)?;
+                let quotient_target = self.parse_target(s, f)?;
+                if divisor == 0 {
+                    return Err(AmlError::DivideByZero);
+                }
+                let remainder = AmlValue::Integer(dividend % divisor);
+                let quotient = AmlValue::Integer(dividend / divisor);
+                self.store(&remainder_target, remainder, true, f)?;
+                self.store(&quotient_target, quotient.clone(), true, f)?;
+                quotient
+            }
+            NOT_OP => self.unary(s, f, |this, value| {
+                Ok(AmlValue::Integer(!value.as_integer()? & this.ones()))
+            })?,
+            FIND_SET_LEFT_BIT_OP => self.unary(s, f, |_, value| {
+                let value = value.as_integer()?;
+                Ok(AmlValue::Integer(match value {
+                    0 => 0,
+                    value => 64 - value.leading_zeros() as u64,
+                }))
+            })?,
+            FIND_SET_RIGHT_BIT_OP => self.unary(s, f, |_, value| {
+                let value = value.as_integer()?;
+                Ok(AmlValue::Integer(match value {
+                    0 => 0,
+                    value => value.trailing_zeros() as u64 + 1,
+                }))
+            })?,
+            TO_INTEGER_OP => self.unary(s, f, |this, value| {
+                let integer = match &value {
+                    AmlValue::String(string) => parse_integer(string),
+                    value => value.as_integer()?,
+                };
+                Ok(AmlValue::Integer(integer & this.ones()))
+            })?,
+            TO_BUFFER_OP => self.unary(s, f, |this, value| {
+                Ok(AmlValue::Buffer(value.as_buffer(this.width())?))
+            })?,
+            TO_HEX_STRING_OP => self.unary(s, f, |_, value| {
+                Ok(AmlValue::String(match value {
+                    AmlValue::Integer(value) => alloc::format!("0x{:X}", value),
+                    AmlValue::Buffer(bytes) => {
+                        join(bytes.iter().map(|b| alloc::format!("0x{:02X}", b)))
+                    }
+                    value => value.as_string()?,
+                }))
+            })?,
+            TO_DECIMAL_STRING_OP => self.unary(s, f, |_, value| {
+                Ok(AmlValue::String(match value {
+                    AmlValue::Integer(value) => alloc::format!("{}", value),
+                    AmlValue::Buffer(bytes) => join(bytes.iter().map(|b| alloc::format!("{}", b))),
+                    value => value.as_string()?,
+                }))
+            })?,
+            TO_STRING_OP => {
+                let bytes = self.eval_value(s, f)?.as_buffer(self.width())?;
+                let length = self.eval_integer(s, f)?;
+                let target = self.parse_target(s, f)?;
+                let end = bytes
+                    .iter()
+                    .position(|byte| *byte == 0)
+                    .unwrap_or(bytes.len())
+                    .min(length.try_into().unwrap_or(usize::MAX));
+                let string =
+                    core::str::from_utf8(&bytes[..end]).map_err(|_| AmlError::TypeMismatch)?;
+                let string = AmlValue::String(string.into());
+                self.store(&target, string.clone(), true, f)?;
+                string
+            }
+            MID_OP => {
+                let source = self.eval_value(s, f)?;
+                let index = self.eval_integer(s, f)? as usize;
+                let length = self.eval_integer(s, f)? as usize;
+                let target = self.parse_target(s, f)?;
+                let range = |len: usize| index.min(len)..index.saturating_add(length).min(len);
+                let result = match source {
+                    AmlValue::String(string) => {
+                        let bytes = &string.as_bytes()[range(string.len())];
+                        let string =
+                            core::str::from_utf8(bytes).map_err(|_| AmlError::TypeMismatch)?;
+                        AmlValue::String(string.into())
+                    }
+                    AmlValue::Buffer(bytes) => AmlValue::Buffer(bytes[range(bytes.len())].to_vec()),
+                    _ => return Err(AmlError::TypeMismatch),
+                };
+                self.store(&target, result.clone(), true, f)?;
+                result
+            }
+            LAND_OP => {
+                let a = self.eval_integer(s, f)?;
+                let b = self.eval_integer(s, f)?;
+                self.boolean(a != 0 && b != 0)
+            }
+            LOR_OP => {
+                let a = self.eval_integer(s, f)?;
+                let b = self.eval_integer(s, f)?;
+                self.boolean(a != 0 || b != 0)
+            }
+            // not equal, less equal and greater equal are encoded as not of the opposite
+            LNOT_OP => {
+                let value = self.eval_integer(s, f)?;
+                self.boolean(value == 0)
+            }
+            LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+                let a = self.eval_value(s, f)?;
+                let b = self.eval_value(s, f)?;
+                let ordering = self.compare(&a, &b)?;
+                self.boolean(match op {
+                    LEQUAL_OP => ordering == Ordering::Equal,
+                    LGREATER_OP => ordering == Ordering::Greater,
+                    _ => ordering == Ordering::Less,
+                })
+            }
+            CONCAT_OP => {
+                let a = self.eval_value(s, f)?;
+                let b = self.eval_value(s, f)?;
+                let target = self.parse_target(s, f)?;
+                let result = self.concat(a, &b)?;
+                self.store(&target, result.clone(), true, f)?;
+                result
+            }
+            CONCAT_RES_OP => {
+                let mut a = self.eval_value(s, f)?.as_buffer(self.width())?;
+                let b = self.eval_value(s, f)?.as_buffer(self.width())?;
+                let target = self.parse_target(s, f)?;
+                // drop the end tag of the first template
+                if a.len() >= 2 && a[a.len() - 2] == crate::resource::END_TAG {
+                    a.truncate(a.len() - 2);
+                }
+                a.extend_from_slice(&b);
+                let result = AmlValue::Buffer(a);
+                self.store(&target, result.clone(), true, f)?;
+                result
+            }
+            SIZE_OF_OP => {
+                let target = self.parse_target(s, f)?;
+                let value = self.read_target(&target, f)?;
+                let size = match self.deref(value)? {
+                    AmlValue::String(string) => string.len(),
+                    AmlValue::Buffer(bytes) => bytes.len(),
+                    AmlValue::Package(elements) => elements.len(),
+                    _ => return Err(AmlError::TypeMismatch),
+                };
+                AmlValue::Integer(size as u64)
+            }
+            INDEX_OP => {
+                let source = self.eval_value(s, f)?;
+                let index = self.eval_integer(s, f)? as usize;
+                let target = self.parse_target(s, f)?;
+                let element = element(&source, index)?;
+                let reference = AmlValue::Reference(Reference::Value(Box::new(element)));
+                self.store(&target, reference.clone(), false, f)?;
+                reference
+            }
+            MATCH_OP => self.eval_match(s, f)?,
+            DEREF_OF_OP => match self.eval(s, f)? {
+                AmlValue::String(path) => {
+                    let path = path.parse()?;
+                    let name = self.namespace.search(&path, &f.scope)?;
+                    self.read_object(&name)?
+                }
+                value @ (AmlValue::Reference(_) | AmlValue::Name { .. }) => self.deref(value)?,
+                _ => return Err(AmlError::TypeMismatch),
+            },
+            REF_OF_OP => {
+                let target = self.parse_target(s, f)?;
+                self.reference_to(&target, f)?
+            }
+            INCREMENT_OP | DECREMENT_OP => {
+                let target = self.parse_target(s, f)?;
+                let value = self.read_target(&target, f)?;
+                let value = self.deref(value)?.as_integer()?;
+                let value = match op {
+                    INCREMENT_OP => value.wrapping_add(1),
+                    _ => value.wrapping_sub(1),
+                };
+                let value = AmlValue::Integer(value & self.ones());
+                self.store(&target, value.clone(), true, f)?;
+                value
+            }
+            OBJECT_TYPE_OP => {
+                let target = self.parse_target(s, f)?;
+                let type_code = match &target {
+                    Target::Null => 0,
+                    Target::Debug => 16,
+                    Target::Name(name) => self
+                        .namespace
+                        .get(name)
+                        .map_or(0, |value| value.type_code()),
+                    target => self.read_target(target, f)?.type_code(),
+                };
+                AmlValue::Integer(type_code)
+            }
+            EXT_PREFIX => self.eval_ext(s, f)?,
+            op => return Err(AmlError::UnsupportedOpcode(op as u16)),
+        };
+        Ok(value)
+    }
+
+    fn eval_ext(&mut self, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let op = s.byte()?;
+        let value = match op {
+            REVISION_OP => AmlValue::Integer(INTERPRETER_REVISION),
+            DEBUG_OP => AmlValue::Uninitialized,
+            COND_REF_OF_OP => {
+                let reference = match s.at_name() {
+                    // a missing name is not an error here, that is what is being tested
+                    true => {
+                        let name = s.name_string()?;
+                        self.namespace
+                            .search(&name, &f.scope)
+                            .ok()
+                            .map(|name| AmlValue::Reference(Reference::Name(name)))
+                    }
+                    false => {
+                        let target = self.parse_target(s, f)?;
+                        Some(self.reference_to(&target, f)?)
+                    }
+                };
+                let target = self.parse_target(s, f)?;
+                match reference {
+                    Some(reference) => {
+                        self.store(&target, reference, false, f)?;
+                        self.boolean(true)
+                    }
+                    None => self.boolean(false),
+                }
+            }
+            ACQUIRE_OP | WAIT_OP => {
+                // nothing else runs aml, so this never has to wait and never times out
+                self.parse_target(s, f)?;
+                match op {
+                    ACQUIRE_OP => s.integer(2)?,
+                    _ => self.eval_integer(s, f)?,
+                };
+                AmlValue::Integer(0)
+            }
+            FROM_BCD_OP => self.unary(s, f, |_, value| {
+                let mut bcd = value.as_integer()?;
+                let mut result = 0;
+                let mut scale = 1;
+                while bcd != 0 {
+                    result += (bcd & 0xF) * scale;
+                    scale *= 10;
+                    bcd >>= 4;
+                }
+                Ok(AmlValue::Integer(result))
+            })?,
+            TO_BCD_OP => self.unary(s, f, |_, value| {
+                let mut value = value.as_integer()?;
+                let mut result = 0;
+                let mut shift = 0;
+                while value != 0 && shift < 64 {
+                    result |= (value % 10) << shift;
+                    shift += 4;
+                    value /= 10;
+                }
+                Ok(AmlValue::Integer(result))
+            })?,
+            op => return Err(AmlError::UnsupportedOpcode(0x5B00 | op as u16)),
+        };
+        Ok(value)
+    }
+
+    /// Evaluate a term argument and resolve references
+    fn eval_value(&mut self, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let value = self.eval(s, f)?;
+        self.deref(value)
+    }
+
+    fn eval_integer(&mut self, s: &mut Stream, f: &mut Frame) -> Result<u64, AmlError> {
+        self.eval_value(s, f)?.as_integer()
+    }
+
+    /// A name in a term, a method is called with the arguments that follow it
+    fn eval_name(&mut self, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let path = self.search(s, f)?;
+        let arg_count = match self.namespace.get(&path) {
+            Some(AmlValue::Method(method)) => method.arg_count,
+            _ => return self.read_object(&path),
+        };
+        let args = (0..arg_count)
+            .map(|_| self.eval(s, f))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.call(&path, args)
+    }
+
+    fn eval_buffer(&mut self, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let end = s.pkg_length()?;
+        let size = self.eval_integer(s, f)?;
+        if size > MAX_OBJECT_SIZE {
+            return Err(AmlError::IndexOutOfBounds);
+        }
+        let initial = s.take_until(end)?;
+        // the initializer can be longer than the size
+        let mut bytes = alloc::vec![0; (size as usize).max(initial.len())];
+        bytes[..initial.len()].copy_from_slice(initial);
+        Ok(AmlValue::Buffer(bytes))
+    }
+
+    fn eval_package(
+        &mut self,
+        s: &mut Stream,
+        end: usize,
+        count: u64,
+        f: &mut Frame,
+    ) -> Result<AmlValue, AmlError> {
+        if count > MAX_OBJECT_SIZE {
+            return Err(AmlError::IndexOutOfBounds);
+        }
+        let mut elements = Vec::new();
+        while s.pos() < end {
+            let element = match s.at_name() {
+                // names in packages are references, looked up when used
+                true => AmlValue::Name {
+                    scope: f.scope.clone(),
+                    path: s.name_string()?,
+                },
+                false => self.eval(s, f)?,
+            };
+            elements.push(element);
+        }
+        if elements.len() < count as usize {
+            elements.resize(count as usize, AmlValue::Uninitialized);
+        }
+        Ok(AmlValue::Package(elements))
+    }
+
+    fn eval_match(&mut self, s: &mut Stream, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        let package = self.eval_value(s, f)?;
+        let first_op = s.byte()?;
+        let first = self.eval_value(s, f)?;
+        let second_op = s.byte()?;
+        let second = self.eval_value(s, f)?;
+        let start = self.eval_integer(s, f)? as usize;
+
+        let elements = package.as_package()?.to_vec();
+        for (index, element) in elements.into_iter().enumerate().skip(start) {
+            let element = self.deref(element)?;
+            if self.matches(&element, first_op, &first)?
+                && self.matches(&element, second_op, &second)?
+            {
+                return Ok(AmlValue::Integer(index as u64));
+            }
+        }
+        Ok(AmlValue::Integer(self.ones()))
+    }
+
+    /// One condition of the match operator
+    fn matches(&self, element: &AmlValue, op: u8, operand: &AmlValue) -> Result<bool, AmlError> {
+        // true matches anything, the others only work on integers, strings and buffers
+        if op == 0 {
+            return Ok(true);
+        }
+        let ordering = match element {
+            AmlValue::Integer(_) | AmlValue::String(_) | AmlValue::Buffer(_) => {
+                self.compare(element, operand)?
+            }
+            _ => return Ok(false),
+        };
+        Ok(match op {
+            1 => ordering == Ordering::Equal,
+            2 => ordering != Ordering::Greater,
+            3 => ordering == Ordering::Less,
+            4 => ordering != Ordering::Less,
+            5 => ordering == Ordering::Greater,
+            _ => return Err(AmlError::TypeMismatch),
+        })
+    }
+
+    /// An operator on two integers with a target
+    fn binary(
+        &mut self,
+        s: &mut Stream,
+        f: &mut Frame,
+        op: impl FnOnce(u64, u64) -> Result<u64, AmlError>,
+    ) -> Result<AmlValue, AmlError> {
+        let a = self.eval_integer(s, f)?;
+        let b = self.eval_integer(s, f)?;
+        let target = self.parse_target(s, f)?;
+        let result = AmlValue::Integer(op(a, b)? & self.ones());
+        self.store(&target, result.clone(), true, f)?;
+        Ok(result)
+    }
+
+    /// An operator on one value with a target
+    fn unary(
+        &mut self,
+        s: &mut Stream,
+        f: &mut Frame,
+        op: impl FnOnce(&Self, AmlValue) -> Result<AmlValue, AmlError>,
+    ) -> Result<AmlValue, AmlError> {
+        let value = self.eval_value(s, f)?;
+        let target = self.parse_target(s, f)?;
+        let result = op(self, value)?;
+        self.store(&target, result.clone(), true, f)?;
+        Ok(result)
+    }
+
+    /// Compare as the type of `a`
+    fn compare(&self, a: &AmlValue, b: &AmlValue) -> Result<Ordering, AmlError> {
+        match a {
+            AmlValue::Integer(a) => Ok(a.cmp(&b.as_integer()?)),
+            AmlValue::String(a) => Ok(a.as_bytes().cmp(b.as_string()?.as_bytes())),
+            AmlValue::Buffer(a) => Ok(a.as_slice().cmp(b.as_buffer(self.width())?.as_slice())),
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    /// Concatenate as the type of `a`, integers make a buffer
+    fn concat(&self, a: AmlValue, b: &AmlValue) -> Result<AmlValue, AmlError> {
+        let width = self.width();
+        match a {
+            AmlValue::Integer(_) => {
+                let mut bytes = a.as_buffer(width)?;
+                bytes.extend_from_slice(&AmlValue::Integer(b.as_integer()?).as_buffer(width)?);
+                Ok(AmlValue::Buffer(bytes))
+            }
+            AmlValue::String(mut a) => {
+                a.push_str(&b.as_string()?);
+                Ok(AmlValue::String(a))
+            }
+            AmlValue::Buffer(mut a) => {
+                a.extend_from_slice(&b.as_buffer(width)?);
+                Ok(AmlValue::Buffer(a))
+            }
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    /// Parse a target or super name
+    fn parse_target(&mut self, s: &mut Stream, f: &mut Frame) -> Result<Target, AmlError> {
+        let target = match s.peek()? {
+            ZERO_OP => {
+                s.byte()?;
+                Target::Null
+            }
+            op @ LOCAL0_OP..=LOCAL7_OP => {
+                s.byte()?;
+                Target::Local((op - LOCAL0_OP) as usize)
+            }
+            op @ ARG0_OP..=ARG6_OP => {
+                s.byte()?;
+                Target::Arg((op - ARG0_OP) as usize)
+            }
+            EXT_PREFIX if s.peek_at(1) == Some(DEBUG_OP) => {
+                s.bytes(2)?;
+                Target::Debug
+            }
+            INDEX_OP => {
+                s.byte()?;
+                let source = self.parse_target(s, f)?;
+                let index = self.eval_integer(s, f)? as usize;
+                // a reference stored by the inner index is not needed
+                self.parse_target(s, f)?;
+                Target::Element(Box::new(source), index)
+            }
+            DEREF_OF_OP => {
+                s.byte()?;
+                match self.eval(s, f)? {
+                    AmlValue::Reference(Reference::Name(name)) => Target::Name(name),
+                    AmlValue::Name { scope, path } => {
+                        Target::Name(self.namespace.search(&path, &scope)?)
+                    }
+                    AmlValue::String(path) => {
+                        Target::Name(self.namespace.search(&path.parse()?, &f.scope)?)
+                    }
+                    _ => return Err(AmlError::TypeMismatch),
+                }
+            }
+            _ if s.at_name() => Target::Name(self.search(s, f)?),
+            op => return Err(AmlError::UnsupportedOpcode(op as u16)),
+        };
+        Ok(target)
+    }
+
+    fn reference_to(&mut self, target: &Target, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        match target {
+            Target::Name(name) => Ok(AmlValue::Reference(Reference::Name(name.clone()))),
+            Target::Null | Target::Debug => Err(AmlError::TypeMismatch),
+            target => {
+                let value = self.read_target(target, f)?;
+                Ok(AmlValue::Reference(Reference::Value(Box::new(value))))
+            }
+        }
+    }
+
+    fn read_target(&mut self, target: &Target, f: &mut Frame) -> Result<AmlValue, AmlError> {
+        match target {
+            Target::Null | Target::Debug => Ok(AmlValue::Uninitialized),
+            Target::Local(index) => Ok(f.locals[*index].clone()),
+            Target::Arg(index) => Ok(f.args.get(*index).cloned().unwrap_or_default()),
+            Target::Name(name) => self.read_object(name),
+            Target::Element(container, index) => {
+                let container = self.read_target(container, f)?;
+                element(&self.deref(container)?, *index)
+            }
+        }
+    }
+
+    /// Store a result, `convert` makes it the type of a named integer, string or buffer
+    fn store(
+        &mut self,
+        target: &Target,
+        value: AmlValue,
+        convert: bool,
+        f: &mut Frame,
+    ) -> Result<(), AmlError> {
+        match target {
+            Target::Null | Target::Debug => Ok(()),
+            Target::Local(index) => {
+                f.locals[*index] = value;
+                Ok(())
+            }
+            Target::Arg(index) => {
+                // arguments passed by reference are written through
+                if let Some(AmlValue::Reference(Reference::Name(name))) = f.args.get(*index) {
+                    let name = name.clone();
+                    return self.store_name(&name, value, convert);
+                }
+                if f.args.len() <= *index {
+                    f.args.resize(*index + 1, AmlValue::Uninitialized);
+                }
+                f.args[*index] = value;
+                Ok(())
+            }
+            Target::Name(name) => self.store_name(name, value, convert),
+            Target::Element(container, index) => {
+                let outer = self.read_target(container, f)?;
+                let mut outer = self.deref(outer)?;
+                match &mut outer {
+                    AmlValue::Buffer(bytes) => {
+                        let byte = bytes.get_mut(*index).ok_or(AmlError::IndexOutOfBounds)?;
+                        *byte = self.deref(value)?.as_integer()? as u8;
+                    }
+                    AmlValue::Package(elements) => {
+                        let element = elements.get_mut(*index).ok_or(AmlError::IndexOutOfBounds)?;
+                        *element = value;
+                    }
+                    _ => return Err(AmlError::TypeMismatch),
+                }
+                self.store(container, outer, false, f)
+            }
+        }
+    }
+
+    pub(crate) fn store_name(
+        &mut self,
+        name: &AmlName,
+        value: AmlValue,
+        convert: bool,
+    ) -> Result<(), AmlError> {
+        let name = self.namespace.target(name);
+        let current = self
+            .namespace
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| AmlError::UndefinedName(name.clone()))?;
+        let value = match current {
+            AmlValue::Field(field) => return self.write_field(&field, value),
+            AmlValue::BufferField {
+                buffer,
+                bit_offset,
+                bit_length,
+            } => {
+                let mut bytes = self.read_object(&buffer)?.as_buffer(self.width())?;
+                let source = self.deref(value)?.as_buffer(64)?;
+                set_bits(&mut bytes, bit_offset, bit_length, &source)?;
+                return self.store_name(&buffer, AmlValue::Buffer(bytes), false);
+            }
+            AmlValue::Method(_)
+            | AmlValue::OpRegion(_)
+            | AmlValue::Device
+            | AmlValue::Scope
+            | AmlValue::Processor { .. }
+            | AmlValue::PowerResource { .. }
+            | AmlValue::ThermalZone
+            | AmlValue::Mutex { .. }
+            | AmlValue::Event => return Err(AmlError::TypeMismatch),
+            AmlValue::Integer(_) if convert => AmlValue::Integer(self.deref(value)?.as_integer()?),
+            AmlValue::String(_) if convert => AmlValue::String(self.deref(value)?.as_string()?),
+            AmlValue::Buffer(old) if convert => {
+                // a buffer keeps its size
+                let mut bytes = self.deref(value)?.as_buffer(self.width())?;
+                bytes.resize(old.len(), 0);
+                AmlValue::Buffer(bytes)
+            }
+            _ => value,
+        };
+        *self
+            .namespace
+            .get_mut(&name)
+            .ok_or(AmlError::UndefinedName(name))? = value;
+        Ok(())
+    }
+
+    /// The value of a named object, fields are read from their region
+    pub(crate) fn read_object(&mut self, name: &AmlName) -> Result<AmlValue, AmlError> {
+        let value = self
+            .namespace
+            .get(name)
+            .cloned()
+            .ok_or_else(|| AmlError::UndefinedName(name.clone()))?;
+        match value {
+            AmlValue::Field(field) => self.read_field(&field),
+            AmlValue::BufferField {
+                buffer,
+                bit_offset,
+                bit_length,
+            } => {
+                let bytes = self.read_object(&buffer)?.as_buffer(self.width())?;
+                let bits = get_bits(&bytes, bit_offset, bit_length)?;
+                Ok(bits_to_value(bits, bit_length))
+            }
+            value => Ok(value),
+        }
+    }
+
+    /// The value behind a reference or a name in a package
+    pub(crate) fn deref(&mut self, value: AmlValue) -> Result<AmlValue, AmlError> {
+        match value {
+            AmlValue::Reference(Reference::Name(name)) => self.read_object(&name),
+            AmlValue::Reference(Reference::Value(value)) => Ok(*value),
+            AmlValue::Name { scope, path } => {
+                let name = self.namespace.search(&path, &scope)?;
+                self.read_object(&name)
+            }
+            value => Ok(value),
+        }
+    }
+}
+
+/// Element `index` of a buffer, package or string
+fn element(container: &AmlValue, index: usize) -> Result<AmlValue, AmlError> {
+    match container {
+        AmlValue::Buffer(bytes) => bytes.get(index).map(|byte| AmlValue::Integer(*byte as u64)),
+        AmlValue::String(string) => string
+            .as_bytes()
+            .get(index)
+            .map(|byte| AmlValue::Integer(*byte as u64)),
+        AmlValue::Package(elements) => elements.get(index).cloned(),
+        _ => return Err(AmlError::TypeMismatch),
+    }
+    .ok_or(AmlError::IndexOutOfBounds)
+}
+
+/// An integer from a string, hex with a `0x` prefix and decimal otherwise
+fn parse_integer(string: &str) -> u64 {
+    let (digits, radix) = match string
+        .strip_prefix("0x")
+        .or_else(|| string.strip_prefix("0X"))
+    {
+        Some(hex) => (hex, 16),
+        None => (string, 10),
+    };
+    let end = digits
+        .find(|c: char| !c.is_digit(radix))
+        .unwrap_or(digits.len());
+    u64::from_str_radix(&digits[..end], radix).unwrap_or(0)
+}
+
+fn join(strings: impl Iterator<Item = String>) -> String {
+    strings.collect::<Vec<_>>().join(",")
+}
+
+/// `_OSI`, does the os support an interface
+fn osi(args: &[AmlValue]) -> Result<AmlValue, AmlError> {
+    let interface = args.first().ok_or(AmlError::TypeMismatch)?.as_string()?;
+    let supported = SUPPORTED_INTERFACES.contains(&interface.as_str());
+    Ok(AmlValue::Integer(if supported { u64::MAX } else { 0 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use std::string::ToString;
+
+    #[test]
+    fn methods_and_control_flow() {
+        let code = [
+            // Name (CNT, 0)
+            &[NAME_OP][..],
+            b"CNT_\x00",
+            // Method (ADD2, 2) { Return (Add (Arg0, Arg1)) }
+            &method(
+                b"ADD2",
+                2,
+                &[RETURN_OP, ADD_OP, ARG0_OP, ARG0_OP + 1, ZERO_OP],
+            ),
+            // Method (LOOP, 1) {
+            //     Local0 = 0
+            //     While (Local0 < Arg0) { Local0++; If (Local0 == 5) { Break } }
+            //     CNT += Local0
+            //     Return (Local0)
+            // }
+            &method(
+                b"LOOP",
+                1,
+                &[
+                    &[STORE_OP, ZERO_OP, LOCAL0_OP][..],
+                    &[WHILE_OP],
+                    &pkg(&[
+                        &[LLESS_OP, LOCAL0_OP, ARG0_OP, INCREMENT_OP, LOCAL0_OP][..],
+                        &[IF_OP],
+                        &pkg(&[LEQUAL_OP, LOCAL0_OP, BYTE_PREFIX, 5, BREAK_OP]),
+                    ]
+                    .concat()),
+                    &[ADD_OP],
+                    b"CNT_",
+                    &[LOCAL0_OP],
+                    b"CNT_",
+                    &[RETURN_OP, LOCAL0_OP],
+                ]
+                .concat(),
+            ),
+            // Method (PICK, 1) { If (Arg0) { Return ("yes") } Else { Return ("no") } }
+            &method(
+                b"PICK",
+                1,
+                &[
+                    &[IF_OP][..],
+                    &pkg(&[&[ARG0_OP, RETURN_OP][..], &string("yes")].concat()),
+                    &[ELSE_OP],
+                    &pkg(&[&[RETURN_OP][..], &string("no")].concat()),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat();
+        let mut aml = Interpreter::new(TestHandler::default());
+        aml.load_table(&table(b"DSDT", &code)).unwrap();
+
+        let int = AmlValue::Integer;
+        assert_eq!(
+            eval(&mut aml, "\\ADD2", vec![int(3), int(4)]).as_integer(),
+            Ok(7)
+        );
+        assert_eq!(eval(&mut aml, "\\LOOP", vec![int(3)]).as_integer(), Ok(3));
+        assert_eq!(eval(&mut aml, "\\LOOP", vec![int(10)]).as_integer(), Ok(5));
+        assert_eq!(eval(&mut aml, "\\CNT", vec![]).as_integer(), Ok(8));
+        assert_eq!(
+            eval(&mut aml, "\\PICK", vec![int(1)]).as_string(),
+            Ok("yes".to_string())
+        );
+        assert_eq!(
+            eval(&mut aml, "\\PICK", vec![int(0)]).as_string(),
+            Ok("no".to_string())
+        );
+    }
+
+    #[test]
+    fn packages_and_buffers() {
+        let code = [
+            // Name (_S5, Package (4) { 5, 5, 0, 0 })
+            &[NAME_OP][..],
+            b"_S5_",
+            &[PACKAGE_OP],
+            &pkg(&[4, BYTE_PREFIX, 5, BYTE_PREFIX, 5, ZERO_OP, ZERO_OP]),
+            // Method (BUFM, 1) {
+            //     Name (BUF, Buffer (8) {})
+            //     CreateDWordField (BUF, 4, DW1)
+            //     DW1 = Arg0
+            //     Return (BUF)
+            // }
+            &method(
+                b"BUFM",
+                1,
+                &[
+                    &[NAME_OP][..],
+                    b"BUF_",
+                    &[BUFFER_OP],
+                    &pkg(&[BYTE_PREFIX, 8]),
+                    &[CREATE_DWORD_FIELD_OP],
+                    b"BUF_",
+                    &[BYTE_PREFIX, 4],
+                    b"DW1_",
+                    &[STORE_OP, ARG0_OP],
+                    b"DW1_",
+                    &[RETURN_OP],
+                    b"BUF_",
+                ]
+                .concat(),
+            ),
+            // Method (IDXM) {
+            //     Name (PKG, Package () { 1, "two", Buffer () { 3 } })
+            //     Return (SizeOf (PKG) + DerefOf (DerefOf (PKG[2])[0]))
+            // }
+            &method(
+                b"IDXM",
+                0,
+                &[
+                    &[NAME_OP][..],
+                    b"PKG_",
+                    &[PACKAGE_OP],
+                    &pkg(&[
+                        &[3, ONE_OP][..],
+                        &string("two"),
+                        &[BUFFER_OP],
+                        &pkg(&[BYTE_PREFIX, 1, 3]),
+                    ]
+                    .concat()),
+                    &[RETURN_OP, ADD_OP, SIZE_OF_OP],
+                    b"PKG_",
+                    &[DEREF_OF_OP, INDEX_OP, DEREF_OF_OP, INDEX_OP],
+                    b"PKG_",
+                    &[BYTE_PREFIX, 2, ZERO_OP, ZERO_OP, ZERO_OP, ZERO_OP],
+                ]
+                .concat(),
+            ),
+            // Method (OSIM) { Return (_OSI ("Windows 2015")) }
+            &method(
+                b"OSIM",
+                0,
+                &[&[RETURN_OP][..], b"_OSI", &string("Windows 2015")].concat(),
+            ),
+        ]
+        .concat();
+        let mut aml = Interpreter::new(TestHandler::default());
+        aml.load_table(&table(b"DSDT", &code)).unwrap();
+
+        assert_eq!(aml.sleep_type(5), Ok((5, 5)));
+        let buffer = eval(&mut aml, "\\BUFM", vec![AmlValue::Integer(0x12345678)]);
+        assert_eq!(
+            buffer.as_buffer(64),
+            Ok(vec![0, 0, 0, 0, 0x78, 0x56, 0x34, 0x12])
+        );
+        // objects created by a method are gone when it returns
+        assert!(!aml.namespace().contains(&"\\BUFM.BUF".parse().unwrap()));
+        assert_eq!(eval(&mut aml, "\\IDXM", vec![]).as_integer(), Ok(6));
+        assert_eq!(eval(&mut aml, "\\OSIM", vec![]).as_integer(), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn devices_and_scopes() {
+        let code = [
+            // Scope (\_SB) { Device (PCI0) { Name (_STA, 0xF) Method (_INI) { \INIT = 1 } } }
+            &[NAME_OP][..],
+            b"INIT\x00",
+            &[SCOPE_OP],
+            &pkg(&[
+                &b"\\_SB_"[..],
+                &[EXT_PREFIX, DEVICE_OP],
+                &pkg(&[
+                    &b"PCI0"[..],
+                    &[NAME_OP],
+                    b"_STA",
+                    &[BYTE_PREFIX, 0xF],
+                    &method(b"_INI", 0, &[&[STORE_OP, ONE_OP][..], b"\\INIT"].concat()),
+                ]
+                .concat()),
+                // Device (GONE) { Name (_STA, 0) Method (_INI) { \INIT = 2 } }
+                &[EXT_PREFIX, DEVICE_OP],
+                &pkg(&[
+                    &b"GONE"[..],
+                    &[NAME_OP],
+                    b"_STA",
+                    &[ZERO_OP],
+                    &method(
+                        b"_INI",
+                        0,
+                        &[&[STORE_OP, BYTE_PREFIX, 2][..], b"\\INIT"].concat(),
+                    ),
+                ]
+                .concat()),
+            ]
+            .concat()),
+        ]
+        .concat();
+        let mut aml = Interpreter::new(TestHandler::default());
+        aml.load_table(&table(b"DSDT", &code)).unwrap();
+
+        let pci: AmlName = "\\_SB.PCI0".parse().unwrap();
+        assert!(matches!(aml.namespace().get(&pci), Some(AmlValue::Device)));
+        assert!(aml.device_status(&pci).unwrap().present());
+        assert_eq!(aml.initialize_devices(), Ok(1));
+        assert_eq!(eval(&mut aml, "\\INIT", vec![]).as_integer(), Ok(1));
+
+        // a table can not define the same object twice
+        let again = table(b"SSDT", &[&[NAME_OP][..], b"INIT\x00"].concat());
+        assert!(matches!(
+            aml.load_table(&again),
+            Err(AmlError::AlreadyExists(_))
+        ));
+    }
+}
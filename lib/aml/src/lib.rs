@@ -0,0 +1,85 @@
+//! Parser and interpreter for aml, the bytecode of the dsdt and ssdt acpi tables.
+//!
+//! Loading a table runs its top level code, which builds the acpi namespace of devices,
+//! methods, operation regions and fields. Objects can then be evaluated by path, the
+//! operation regions that methods touch are accessed through a [`Handler`].
+#![no_std]
+
+extern crate alloc;
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+mod field;
+mod interpreter;
+mod name;
+mod namespace;
+mod opcode;
+pub mod pci_routing;
+pub mod resource;
+mod stream;
+#[cfg(test)]
+mod test_util;
+mod value;
+
+pub use interpreter::{DeviceStatus, Interpreter};
+pub use name::{AmlName, NameSeg};
+pub use namespace::Namespace;
+pub use value::{
+    AmlValue, FieldFlags, FieldKind, FieldUnit, Method, OpRegion, Reference, RegionSpace,
+    UpdateRule,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmlError {
+    /// The code ends in the middle of something
+    UnexpectedEnd,
+    /// Shorter than a table header
+    InvalidTable,
+    /// An opcode this interpreter can not run, extended ones are `0x5bxx`
+    UnsupportedOpcode(u16),
+    InvalidName,
+    UndefinedName(AmlName),
+    AlreadyExists(AmlName),
+    /// An object of the wrong type for the operation
+    TypeMismatch,
+    IndexOutOfBounds,
+    DivideByZero,
+    /// An operation region in a space the [`Handler`] can not access
+    UnsupportedRegion(RegionSpace),
+    InvalidResource,
+    /// A while loop ran too long
+    LoopLimit,
+    /// Methods called each other too deep
+    DepthLimit,
+    /// The code ran the fatal opcode
+    Fatal {
+        kind: u8,
+        code: u32,
+        argument: u64,
+    },
+}
+
+/// Location of a pci function, for operation regions in pci config space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+/// Accesses the hardware for the interpreter, widths are in bits and are 8, 16, 32 or 64
+pub trait Handler {
+    fn read_memory(&mut self, address: u64, width: u8) -> u64;
+    fn write_memory(&mut self, address: u64, width: u8, value: u64);
+    fn read_io(&mut self, port: u16, width: u8) -> u64;
+    fn write_io(&mut self, port: u16, width: u8, value: u64);
+    fn read_pci(&mut self, address: PciAddress, offset: u16, width: u8) -> u64;
+    fn write_pci(&mut self, address: PciAddress, offset: u16, width: u8, value: u64);
+    /// Busy wait for `micros` microseconds
+    fn stall(&mut self, micros: u64);
+    /// Wait for `millis` milliseconds, may let other work run
+    fn sleep(&mut self, millis: u64);
+}
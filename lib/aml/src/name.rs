@@ -0,0 +1,206 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use crate::AmlError;
+
+/// A four character name, shorter names are padded with `_`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NameSeg(pub(crate) [u8; 4]);
+
+impl NameSeg {
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.0).unwrap_or("????")
+    }
+}
+
+impl FromStr for NameSeg {
+    type Err = AmlError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let bytes = name.as_bytes();
+        let valid = matches!(bytes.first(), Some(lead) if is_lead_char(*lead))
+            && bytes.len() <= 4
+            && bytes.iter().all(|byte| is_name_char(*byte));
+        if !valid {
+            return Err(AmlError::InvalidName);
+        }
+        let mut seg = [b'_'; 4];
+        seg[..bytes.len()].copy_from_slice(bytes);
+        Ok(Self(seg))
+    }
+}
+
+impl fmt::Debug for NameSeg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for NameSeg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+pub(crate) fn is_lead_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte == b'_'
+}
+
+pub(crate) fn is_name_char(byte: u8) -> bool {
+    is_lead_char(byte) || byte.is_ascii_digit()
+}
+
+/// A path in the namespace, absolute or relative to a scope
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AmlName {
+    absolute: bool,
+    /// How many scopes to go up before following the segments
+    parents: u8,
+    segments: Vec<NameSeg>,
+}
+
+impl AmlName {
+    pub fn root() -> Self {
+        Self::new(true, 0, Vec::new())
+    }
+
+    pub(crate) fn new(absolute: bool, parents: u8, segments: Vec<NameSeg>) -> Self {
+        Self {
+            absolute,
+            parents,
+            segments,
+        }
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        self.absolute
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.absolute && self.segments.is_empty()
+    }
+
+    pub fn segments(&self) -> &[NameSeg] {
+        &self.segments
+    }
+
+    pub fn last(&self) -> Option<NameSeg> {
+        self.segments.last().copied()
+    }
+
+    /// Single segment names are searched for in every parent scope
+    pub(crate) fn uses_search_rules(&self) -> bool {
+        !self.absolute && self.parents == 0 && self.segments.len() == 1
+    }
+
+    /// The scope an absolute name is in, none for the root
+    pub fn parent(&self) -> Option<AmlName> {
+        if !self.absolute || self.segments.is_empty() {
+            return None;
+        }
+        let segments = self.segments[..self.segments.len() - 1].to_vec();
+        Some(Self::new(true, 0, segments))
+    }
+
+    pub fn join(&self, seg: NameSeg) -> AmlName {
+        let mut segments = self.segments.clone();
+        segments.push(seg);
+        Self::new(self.absolute, self.parents, segments)
+    }
+
+    /// Is this below `scope`, at any depth
+    pub fn is_in(&self, scope: &AmlName) -> bool {
+        self.absolute == scope.absolute
+            && self.segments.len() > scope.segments.len()
+            && self.segments.starts_with(&scope.segments)
+    }
+
+    /// The absolute name this refers to from `scope`
+    pub fn resolve(&self, scope: &AmlName) -> Result<AmlName, AmlError> {
+        if self.absolute {
+            return Ok(self.clone());
+        }
+        let mut segments = scope.segments.clone();
+        for _ in 0..self.parents {
+            segments.pop().ok_or(AmlError::InvalidName)?;
+        }
+        segments.extend_from_slice(&self.segments);
+        Ok(Self::new(true, 0, segments))
+    }
+}
+
+impl FromStr for AmlName {
+    type Err = AmlError;
+
+    /// Parse a path like `\_SB.PCI0._PRT` or `^_STA`
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let (absolute, mut rest) = match path.strip_prefix('\\') {
+            Some(rest) => (true, rest),
+            None => (false, path),
+        };
+        let mut parents = 0u8;
+        while let Some(stripped) = rest.strip_prefix('^') {
+            if absolute {
+                return Err(AmlError::InvalidName);
+            }
+            parents = parents.checked_add(1).ok_or(AmlError::InvalidName)?;
+            rest = stripped;
+        }
+        let segments = match rest {
+            "" => Vec::new(),
+            rest => rest
+                .split('.')
+                .map(NameSeg::from_str)
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(Self::new(absolute, parents, segments))
+    }
+}
+
+impl fmt::Display for AmlName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.absolute {
+            f.write_str("\\")?;
+        }
+        for _ in 0..self.parents {
+            f.write_str("^")?;
+        }
+        for (i, seg) in self.segments.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            f.write_str(seg.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for AmlName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn parse_and_resolve() {
+        let path: AmlName = "\\_SB.PCI0._PRT".parse().unwrap();
+        assert!(path.is_absolute());
+        assert_eq!(path.to_string(), "\\_SB_.PCI0._PRT");
+        assert_eq!(path.parent().unwrap().to_string(), "\\_SB_.PCI0");
+
+        let scope: AmlName = "\\_SB.PCI0.ISA".parse().unwrap();
+        let relative: AmlName = "^^LNKA".parse().unwrap();
+        assert_eq!(relative.resolve(&scope).unwrap().to_string(), "\\_SB_.LNKA");
+        assert!(path.is_in(&"\\_SB".parse().unwrap()));
+
+        assert!("\\^A".parse::<AmlName>().is_err());
+        assert!("1ABC".parse::<AmlName>().is_err());
+        assert!("TOOLONG".parse::<AmlName>().is_err());
+    }
+}
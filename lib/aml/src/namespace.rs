@@ -0,0 +1,156 @@
+use alloc::collections::BTreeMap;
+
+use crate::name::AmlName;
+use crate::value::AmlValue;
+use crate::AmlError;
+
+/// Every object the loaded tables defined, by absolute name
+#[derive(Debug, Default)]
+pub struct Namespace {
+    objects: BTreeMap<AmlName, AmlValue>,
+}
+
+impl Namespace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an object, its scope has to exist already
+    pub fn add(&mut self, name: AmlName, value: AmlValue) -> Result<(), AmlError> {
+        if let Some(parent) = name.parent() {
+            if !parent.is_root() && !self.objects.contains_key(&parent) {
+                return Err(AmlError::UndefinedName(parent));
+            }
+        }
+        if self.objects.contains_key(&name) {
+            return Err(AmlError::AlreadyExists(name));
+        }
+        self.objects.insert(name, value);
+        Ok(())
+    }
+
+    /// Remove an object and everything in its scope
+    pub fn remove(&mut self, name: &AmlName) {
+        self.objects.remove(name);
+        self.objects.retain(|object, _| !object.is_in(name));
+    }
+
+    pub fn contains(&self, name: &AmlName) -> bool {
+        self.objects.contains_key(name)
+    }
+
+    /// The name an alias stands for, or the name itself
+    pub fn target(&self, name: &AmlName) -> AmlName {
+        match self.objects.get(name) {
+            Some(AmlValue::Alias(target)) => target.clone(),
+            _ => name.clone(),
+        }
+    }
+
+    /// The object at an absolute name, following aliases
+    pub fn get(&self, name: &AmlName) -> Option<&AmlValue> {
+        self.objects.get(&self.target(name))
+    }
+
+    pub fn get_mut(&mut self, name: &AmlName) -> Option<&mut AmlValue> {
+        let target = self.target(name);
+        self.objects.get_mut(&target)
+    }
+
+    /// Find `path` from `scope`, single segment names are looked for in `scope` and then
+    /// every scope above it. Returns the absolute name.
+    pub fn search(&self, path: &AmlName, scope: &AmlName) -> Result<AmlName, AmlError> {
+        if !path.uses_search_rules() {
+            let name = path.resolve(scope)?;
+            return match self.contains(&name) {
+                true => Ok(name),
+                false => Err(AmlError::UndefinedName(name)),
+            };
+        }
+
+        let seg = path.last().ok_or(AmlError::InvalidName)?;
+        let mut scope = Some(scope.clone());
+        while let Some(current) = scope {
+            let name = current.join(seg);
+            if self.contains(&name) {
+                return Ok(name);
+            }
+            scope = current.parent();
+        }
+        Err(AmlError::UndefinedName(path.clone()))
+    }
+
+    /// Every object, parents come before their children
+    pub fn iter(&self) -> impl Iterator<Item = (&AmlName, &AmlValue)> {
+        self.objects.iter()
+    }
+
+    /// The objects directly in `scope`
+    pub fn children<'a>(
+        &'a self,
+        scope: &'a AmlName,
+    ) -> impl Iterator<Item = (&'a AmlName, &'a AmlValue)> + 'a {
+        let depth = scope.segments().len() + 1;
+        self.objects
+            .range(scope.clone()..)
+            .skip_while(move |(name, _)| *name == scope)
+            .take_while(move |(name, _)| name.is_in(scope))
+            .filter(move |(name, _)| name.segments().len() == depth)
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn name(path: &str) -> AmlName {
+        path.parse().unwrap()
+    }
+
+    #[test]
+    fn search_rules() {
+        let mut namespace = Namespace::new();
+        namespace.add(name("\\_SB"), AmlValue::Device).unwrap();
+        namespace.add(name("\\_SB.PCI0"), AmlValue::Device).unwrap();
+        namespace.add(name("\\_SB.LNKA"), AmlValue::Device).unwrap();
+        namespace
+            .add(name("\\_SB.PCI0.ISA"), AmlValue::Device)
+            .unwrap();
+        assert_eq!(
+            namespace.add(name("\\_SB.PCI0"), AmlValue::Device),
+            Err(AmlError::AlreadyExists(name("\\_SB.PCI0")))
+        );
+        assert!(namespace
+            .add(name("\\NONE.THIS"), AmlValue::Device)
+            .is_err());
+
+        let scope = name("\\_SB.PCI0.ISA");
+        assert_eq!(
+            namespace.search(&name("LNKA"), &scope),
+            Ok(name("\\_SB.LNKA"))
+        );
+        // only single segments are searched for
+        assert!(namespace
+            .search(&name("PCI0.LNKA"), &name("\\_SB"))
+            .is_err());
+        assert_eq!(namespace.search(&name("^ISA"), &scope), Ok(scope.clone()));
+
+        let children: Vec<_> = namespace
+            .children(&name("\\_SB"))
+            .map(|(n, _)| n.clone())
+            .collect();
+        assert_eq!(children, [name("\\_SB.LNKA"), name("\\_SB.PCI0")]);
+
+        namespace.remove(&name("\\_SB.PCI0"));
+        assert!(!namespace.contains(&scope));
+    }
+}
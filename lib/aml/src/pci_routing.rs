@@ -0,0 +1,242 @@
+//! Pci interrupt routing, from the `_PRT` of a pci root bridge
+
+use alloc::vec::Vec;
+
+use crate::name::AmlName;
+use crate::resource::{self, Resource};
+use crate::value::{AmlValue, Reference};
+use crate::{AmlError, Handler, Interpreter};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteSource {
+    /// Wired straight to a global system interrupt
+    Gsi(u32),
+    /// Goes through an interrupt link device, `index` is its resource descriptor
+    Link { device: AmlName, index: u32 },
+}
+
+/// Where pin `pin` (0 is INTA) of a pci device goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PciRoute {
+    pub device: u16,
+    pub pin: u8,
+    pub source: RouteSource,
+}
+
+/// The interrupt a pci pin ends up at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciIrq {
+    pub gsi: u32,
+    pub edge_triggered: bool,
+    pub active_low: bool,
+}
+
+impl<H: Handler> Interpreter<H> {
+    /// The routing table of a pci root bridge, like `\_SB.PCI0`
+    pub fn pci_routing(&mut self, bridge: &AmlName) -> Result<Vec<PciRoute>, AmlError> {
+        let table = self.evaluate(&bridge.join("_PRT".parse()?), Vec::new())?;
+        let entries = self.resolve(table)?.as_package()?.to_vec();
+
+        let mut routes = Vec::new();
+        for entry in entries {
+            let entry = self.resolve(entry)?;
+            let [address, pin, source, index] = entry.as_package()? else {
+                return Err(AmlError::TypeMismatch);
+            };
+            let (address, pin, source) = (address.clone(), pin.clone(), source.clone());
+            let index = self.resolve(index.clone())?.as_integer()? as u32;
+            let source = match source {
+                AmlValue::Name { scope, path } => RouteSource::Link {
+                    device: self.namespace().search(&path, &scope)?,
+                    index,
+                },
+                AmlValue::Reference(Reference::Name(device)) => RouteSource::Link { device, index },
+                AmlValue::String(path) => RouteSource::Link {
+                    device: self.namespace().search(&path.parse()?, bridge)?,
+                    index,
+                },
+                source => match self.resolve(source)?.as_integer()? {
+                    0 => RouteSource::Gsi(index),
+                    _ => return Err(AmlError::TypeMismatch),
+                },
+            };
+            routes.push(PciRoute {
+                // the function part is always all ones
+                device: (self.resolve(address)?.as_integer()? >> 16) as u16,
+                pin: self.resolve(pin)?.as_integer()? as u8,
+                source,
+            });
+        }
+        Ok(routes)
+    }
+
+    /// The interrupt a route ends at, link devices are asked for their current setting
+    pub fn route_irq(&mut self, route: &PciRoute) -> Result<PciIrq, AmlError> {
+        let (device, index) = match &route.source {
+            // hardwired pci interrupts are level triggered and active low
+            RouteSource::Gsi(gsi) => {
+                return Ok(PciIrq {
+                    gsi: *gsi,
+                    edge_triggered: false,
+                    active_low: true,
+                })
+            }
+            RouteSource::Link { device, index } => (device, *index as usize),
+        };
+
+        let resources = self.evaluate(&device.join("_CRS".parse()?), Vec::new())?;
+        let resources = self.resolve(resources)?.as_buffer(64)?;
+        let irq = resource::parse(&resources)?
+            .into_iter()
+            .filter_map(|resource| match resource {
+                Resource::Irq(irq) => Some(irq),
+                _ => None,
+            })
+            .nth(index)
+            .ok_or(AmlError::InvalidResource)?;
+        Ok(PciIrq {
+            gsi: *irq.interrupts.first().ok_or(AmlError::InvalidResource)?,
+            edge_triggered: irq.edge_triggered,
+            active_low: irq.active_low,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::*;
+    use crate::test_util::*;
+    use crate::PciAddress;
+
+    #[test]
+    fn routes_and_links() {
+        // Interrupt (ResourceConsumer, Level, ActiveHigh, Shared) { 0 }
+        let template = [
+            0x89, 0x06, 0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x79, 0x00,
+        ];
+        let linked = [
+            &[4, DWORD_PREFIX][..],
+            &0x1FFFFu32.to_le_bytes(),
+            &[ZERO_OP],
+            b"LNKA",
+            &[ZERO_OP],
+        ];
+        let hardwired = [
+            &[4, DWORD_PREFIX][..],
+            &0x2FFFFu32.to_le_bytes(),
+            &[ONE_OP, ZERO_OP, BYTE_PREFIX, 17],
+        ];
+        let code = [
+            &[SCOPE_OP][..],
+            &pkg(&[
+                &b"\\_SB_"[..],
+                // Device (PCI0) {
+                //     Name (_PRT, Package () {
+                //         Package () { 0x1FFFF, 0, LNKA, 0 },
+                //         Package () { 0x2FFFF, 1, 0, 17 } })
+                //     Device (ISA) { Name (_ADR, 0x00010000)
+                //         OperationRegion (PIRQ, PCI_Config, 0x60, 4)
+                //         Field (PIRQ, ByteAcc, NoLock, Preserve) { PRQA, 8 } } }
+                &[EXT_PREFIX, DEVICE_OP],
+                &pkg(&[
+                    &b"PCI0"[..],
+                    &[NAME_OP],
+                    b"_PRT",
+                    &[PACKAGE_OP],
+                    &pkg(&[
+                        &[2, PACKAGE_OP][..],
+                        &pkg(&linked.concat()),
+                        &[PACKAGE_OP],
+                        &pkg(&hardwired.concat()),
+                    ]
+                    .concat()),
+                    &[EXT_PREFIX, DEVICE_OP],
+                    &pkg(&[
+                        &b"ISA_"[..],
+                        &[NAME_OP],
+                        b"_ADR",
+                        &[DWORD_PREFIX, 0x00, 0x00, 0x01, 0x00],
+                        &region(b"PIRQ", 2, 0x60, 4),
+                        &field(b"PIRQ", 0x01, b"PRQA\x08"),
+                    ]
+                    .concat()),
+                ]
+                .concat()),
+                // Device (LNKA) { Method (_CRS) {
+                //     Name (RES, ResourceTemplate () { Interrupt (...) { 0 } })
+                //     CreateDWordField (RES, 5, IRQ)
+                //     IRQ = \_SB.PCI0.ISA.PRQA
+                //     Return (RES) } }
+                &[EXT_PREFIX, DEVICE_OP],
+                &pkg(&[
+                    &b"LNKA"[..],
+                    &method(
+                        b"_CRS",
+                        0,
+                        &[
+                            &[NAME_OP][..],
+                            b"RES_",
+                            &[BUFFER_OP],
+                            &pkg(&[&[BYTE_PREFIX, template.len() as u8][..], &template].concat()),
+                            &[CREATE_DWORD_FIELD_OP],
+                            b"RES_",
+                            &[BYTE_PREFIX, 5],
+                            b"IRQ_",
+                            &[STORE_OP, ROOT_CHAR, MULTI_NAME_PREFIX, 4],
+                            b"_SB_PCI0ISA_PRQA",
+                            b"IRQ_",
+                            &[RETURN_OP],
+                            b"RES_",
+                        ]
+                        .concat(),
+                    ),
+                ]
+                .concat()),
+            ]
+            .concat()),
+        ]
+        .concat();
+        let mut aml = Interpreter::new(TestHandler::default());
+        aml.load_table(&table(b"DSDT", &code)).unwrap();
+        let isa = PciAddress {
+            segment: 0,
+            bus: 0,
+            device: 1,
+            function: 0,
+        };
+        aml.handler_mut().pci.insert((isa, 0x60), 11);
+
+        let routes = aml.pci_routing(&"\\_SB.PCI0".parse().unwrap()).unwrap();
+        let link = RouteSource::Link {
+            device: "\\_SB.LNKA".parse().unwrap(),
+            index: 0,
+        };
+        assert_eq!(
+            routes,
+            [
+                PciRoute {
+                    device: 1,
+                    pin: 0,
+                    source: link,
+                },
+                PciRoute {
+                    device: 2,
+                    pin: 1,
+                    source: RouteSource::Gsi(17),
+                },
+            ]
+        );
+
+        let irq = aml.route_irq(&routes[0]).unwrap();
+        assert_eq!(
+            irq,
+            PciIrq {
+                gsi: 11,
+                edge_triggered: false,
+                active_low: false,
+            }
+        );
+        assert!(aml.route_irq(&routes[1]).unwrap().active_low);
+    }
+}
@@ -0,0 +1,251 @@
+//! Resource descriptors, the buffers `_CRS` and `_PRS` return
+
+use alloc::vec::Vec;
+
+use crate::AmlError;
+
+/// Small descriptor that ends a resource template
+pub(crate) const END_TAG: u8 = 0x79;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    Irq(Irq),
+    Dma {
+        channels: u8,
+        flags: u8,
+    },
+    Io {
+        min: u16,
+        max: u16,
+        alignment: u8,
+        length: u8,
+    },
+    FixedIo {
+        base: u16,
+        length: u8,
+    },
+    Memory32 {
+        writable: bool,
+        min: u32,
+        max: u32,
+        alignment: u32,
+        length: u32,
+    },
+    FixedMemory32 {
+        writable: bool,
+        base: u32,
+        length: u32,
+    },
+    Address(Address),
+    /// A descriptor that is not parsed, by its type
+    Other(u8),
+}
+
+/// Interrupts from an irq or extended interrupt descriptor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Irq {
+    pub interrupts: Vec<u32>,
+    pub edge_triggered: bool,
+    pub active_low: bool,
+    pub shared: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressKind {
+    Memory,
+    Io,
+    BusNumber,
+    Other(u8),
+}
+
+/// A word, dword or qword address space descriptor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub kind: AddressKind,
+    pub granularity: u64,
+    pub min: u64,
+    pub max: u64,
+    pub translation: u64,
+    pub length: u64,
+}
+
+/// Parse a resource template up to its end tag
+pub fn parse(buffer: &[u8]) -> Result<Vec<Resource>, AmlError> {
+    let mut resources = Vec::new();
+    let mut rest = buffer;
+    while let Some(&tag) = rest.first() {
+        let (resource, length) = match tag & 0x80 {
+            0 => {
+                let length = 1 + (tag & 0b111) as usize;
+                let data = rest.get(1..length).ok_or(AmlError::InvalidResource)?;
+                match (tag >> 3) & 0xF {
+                    0xF => return Ok(resources),
+                    kind => (small(kind, data)?, length),
+                }
+            }
+            _ => {
+                let header = rest.get(1..3).ok_or(AmlError::InvalidResource)?;
+                let length = 3 + u16::from_le_bytes([header[0], header[1]]) as usize;
+                let data = rest.get(3..length).ok_or(AmlError::InvalidResource)?;
+                (large(tag & 0x7F, data)?, length)
+            }
+        };
+        resources.push(resource);
+        rest = &rest[length..];
+    }
+    // a template without an end tag
+    Err(AmlError::InvalidResource)
+}
+
+fn small(kind: u8, data: &[u8]) -> Result<Resource, AmlError> {
+    let resource = match kind {
+        0x4 => {
+            let mask = u16::from_le_bytes([byte(data, 0)?, byte(data, 1)?]);
+            // without the information byte it is edge triggered and active high
+            let info = data.get(2).copied().unwrap_or(1);
+            Resource::Irq(Irq {
+                interrupts: (0..16).filter(|irq| mask & (1 << irq) != 0).collect(),
+                edge_triggered: info & (1 << 0) != 0,
+                active_low: info & (1 << 3) != 0,
+                shared: info & (1 << 4) != 0,
+            })
+        }
+        0x5 => Resource::Dma {
+            channels: byte(data, 0)?,
+            flags: byte(data, 1)?,
+        },
+        0x8 => Resource::Io {
+            min: le(data, 1, 2)? as u16,
+            max: le(data, 3, 2)? as u16,
+            alignment: byte(data, 5)?,
+            length: byte(data, 6)?,
+        },
+        0x9 => Resource::FixedIo {
+            base: le(data, 0, 2)? as u16,
+            length: byte(data, 2)?,
+        },
+        kind => Resource::Other(kind),
+    };
+    Ok(resource)
+}
+
+fn large(kind: u8, data: &[u8]) -> Result<Resource, AmlError> {
+    let resource = match kind {
+        0x05 => Resource::Memory32 {
+            writable: byte(data, 0)? & 1 != 0,
+            min: le(data, 1, 4)? as u32,
+            max: le(data, 5, 4)? as u32,
+            alignment: le(data, 9, 4)? as u32,
+            length: le(data, 13, 4)? as u32,
+        },
+        0x06 => Resource::FixedMemory32 {
+            writable: byte(data, 0)? & 1 != 0,
+            base: le(data, 1, 4)? as u32,
+            length: le(data, 5, 4)? as u32,
+        },
+        0x07 => address(data, 4)?,
+        0x08 => address(data, 2)?,
+        0x0A => address(data, 8)?,
+        0x09 => {
+            let flags = byte(data, 0)?;
+            let count = byte(data, 1)? as usize;
+            let interrupts = (0..count)
+                .map(|i| le(data, 2 + i * 4, 4).map(|irq| irq as u32))
+                .collect::<Result<_, _>>()?;
+            Resource::Irq(Irq {
+                interrupts,
+                edge_triggered: flags & (1 << 1) != 0,
+                active_low: flags & (1 << 2) != 0,
+                shared: flags & (1 << 3) != 0,
+            })
+        }
+        kind => Resource::Other(0x80 | kind),
+    };
+    Ok(resource)
+}
+
+/// An address space descriptor with `size` byte fields
+fn address(data: &[u8], size: usize) -> Result<Resource, AmlError> {
+    let kind = match byte(data, 0)? {
+        0 => AddressKind::Memory,
+        1 => AddressKind::Io,
+        2 => AddressKind::BusNumber,
+        kind => AddressKind::Other(kind),
+    };
+    // the resource type is followed by the general and type specific flags
+    let field = |i: usize| le(data, 3 + i * size, size);
+    Ok(Resource::Address(Address {
+        kind,
+        granularity: field(0)?,
+        min: field(1)?,
+        max: field(2)?,
+        translation: field(3)?,
+        length: field(4)?,
+    }))
+}
+
+fn byte(data: &[u8], offset: usize) -> Result<u8, AmlError> {
+    data.get(offset).copied().ok_or(AmlError::InvalidResource)
+}
+
+/// A little endian integer of `size` bytes
+fn le(data: &[u8], offset: usize, size: usize) -> Result<u64, AmlError> {
+    let bytes = data
+        .get(offset..offset + size)
+        .ok_or(AmlError::InvalidResource)?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | *byte as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qemu_templates() {
+        let template = [
+            // IRQNoFlags () { 1 }
+            &[0x22, 0x02, 0x00][..],
+            // IO (Decode16, 0x60, 0x60, 1, 1)
+            &[0x47, 0x01, 0x60, 0x00, 0x60, 0x00, 0x01, 0x01],
+            // Interrupt (ResourceConsumer, Level, ActiveHigh, Shared) { 11 }
+            &[0x89, 0x06, 0x00, 0x09, 0x01, 0x0B, 0x00, 0x00, 0x00],
+            // WordBusNumber (..., 0, 0, 0xFF, 0, 0x100)
+            &[0x88, 0x0D, 0x00, 0x02, 0x0C, 0x00],
+            &[0x00, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x01],
+            &[END_TAG, 0x00],
+        ]
+        .concat();
+        let resources = parse(&template).unwrap();
+        assert_eq!(resources.len(), 4);
+        assert_eq!(
+            resources[0],
+            Resource::Irq(Irq {
+                interrupts: vec![1],
+                edge_triggered: true,
+                active_low: false,
+                shared: false,
+            })
+        );
+        assert_eq!(
+            resources[1],
+            Resource::Io {
+                min: 0x60,
+                max: 0x60,
+                alignment: 1,
+                length: 1,
+            }
+        );
+        assert!(
+            matches!(&resources[2], Resource::Irq(irq) if irq.interrupts == [11] && irq.shared)
+        );
+        assert!(matches!(
+            &resources[3],
+            Resource::Address(address) if address.kind == AddressKind::BusNumber && address.length == 0x100
+        ));
+
+        assert_eq!(parse(&template[..3]), Err(AmlError::InvalidResource));
+    }
+}
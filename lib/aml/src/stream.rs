@@ -0,0 +1,195 @@
+use alloc::vec::Vec;
+
+use crate::name::{is_lead_char, is_name_char, AmlName, NameSeg};
+use crate::opcode::*;
+use crate::AmlError;
+
+/// Reads aml code front to back
+pub(crate) struct Stream<'a> {
+    code: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Stream<'a> {
+    pub fn new(code: &'a [u8]) -> Self {
+        Self { code, pos: 0 }
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn seek(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn peek(&self) -> Result<u8, AmlError> {
+        self.code
+            .get(self.pos)
+            .copied()
+            .ok_or(AmlError::UnexpectedEnd)
+    }
+
+    /// The byte `offset` bytes after the current one
+    pub fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.code.get(self.pos + offset).copied()
+    }
+
+    pub fn byte(&mut self) -> Result<u8, AmlError> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    pub fn bytes(&mut self, count: usize) -> Result<&'a [u8], AmlError> {
+        let end = self.pos.checked_add(count).ok_or(AmlError::UnexpectedEnd)?;
+        let bytes = self
+            .code
+            .get(self.pos..end)
+            .ok_or(AmlError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Code from the current position up to `end`, which is skipped
+    pub fn take_until(&mut self, end: usize) -> Result<&'a [u8], AmlError> {
+        let bytes = self
+            .code
+            .get(self.pos..end)
+            .ok_or(AmlError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// A little endian integer of `size` bytes
+    pub fn integer(&mut self, size: usize) -> Result<u64, AmlError> {
+        let bytes = self.bytes(size)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, byte| value << 8 | *byte as u64))
+    }
+
+    /// The value of a package length, the top two bits of the first byte say how many
+    /// more bytes follow
+    pub fn pkg_length_value(&mut self) -> Result<u64, AmlError> {
+        let lead = self.byte()?;
+        let count = lead >> 6;
+        if count == 0 {
+            return Ok((lead & 0x3F) as u64);
+        }
+        let rest = self.integer(count as usize)?;
+        Ok((lead & 0x0F) as u64 | rest << 4)
+    }
+
+    /// A package length, returns where the package ends
+    pub fn pkg_length(&mut self) -> Result<usize, AmlError> {
+        let start = self.pos;
+        let end = start + self.pkg_length_value()? as usize;
+        if end > self.code.len() || end < self.pos {
+            return Err(AmlError::UnexpectedEnd);
+        }
+        Ok(end)
+    }
+
+    /// Does a name string start here, the null name is not counted since it looks like zero
+    pub fn at_name(&self) -> bool {
+        matches!(
+            self.peek(),
+            Ok(ROOT_CHAR | PARENT_PREFIX | DUAL_NAME_PREFIX | MULTI_NAME_PREFIX)
+        ) || matches!(self.peek(), Ok(byte) if is_lead_char(byte))
+    }
+
+    pub fn name_seg(&mut self) -> Result<NameSeg, AmlError> {
+        let bytes = self.bytes(4)?;
+        if !is_lead_char(bytes[0]) || !bytes.iter().all(|byte| is_name_char(*byte)) {
+            return Err(AmlError::InvalidName);
+        }
+        Ok(NameSeg([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn name_string(&mut self) -> Result<AmlName, AmlError> {
+        let mut absolute = false;
+        let mut parents = 0u8;
+        match self.peek()? {
+            ROOT_CHAR => {
+                self.pos += 1;
+                absolute = true;
+            }
+            PARENT_PREFIX => {
+                while self.peek()? == PARENT_PREFIX {
+                    self.pos += 1;
+                    parents = parents.checked_add(1).ok_or(AmlError::InvalidName)?;
+                }
+            }
+            _ => {}
+        }
+
+        let count = match self.peek()? {
+            ZERO_OP => {
+                self.pos += 1;
+                0
+            }
+            DUAL_NAME_PREFIX => {
+                self.pos += 1;
+                2
+            }
+            MULTI_NAME_PREFIX => {
+                self.pos += 1;
+                self.byte()?
+            }
+            _ => 1,
+        };
+        let segments = (0..count)
+            .map(|_| self.name_seg())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AmlName::new(absolute, parents, segments))
+    }
+
+    /// A string constant after its prefix, up to the null byte
+    pub fn string(&mut self) -> Result<&'a str, AmlError> {
+        let rest = self.code.get(self.pos..).ok_or(AmlError::UnexpectedEnd)?;
+        let length = rest
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or(AmlError::UnexpectedEnd)?;
+        let string = core::str::from_utf8(&rest[..length]).map_err(|_| AmlError::TypeMismatch)?;
+        self.pos += length + 1;
+        Ok(string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn package_lengths() {
+        let mut stream = Stream::new(&[0x3F]);
+        assert_eq!(stream.pkg_length_value(), Ok(0x3F));
+        // 0x4 | 0x12 << 4 with one more byte
+        let mut stream = Stream::new(&[0x44, 0x12]);
+        assert_eq!(stream.pkg_length_value(), Ok(0x124));
+        let mut stream = Stream::new(&[0x81, 0x02, 0x03]);
+        assert_eq!(stream.pkg_length_value(), Ok(0x3021));
+        let mut stream = Stream::new(&[0x45, 0x00]);
+        assert_eq!(stream.pkg_length(), Err(AmlError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn name_strings() {
+        let mut stream = Stream::new(b"\\/\x03_SB_PCI0_PRT^^LNKA\x2eABCDEFGH");
+        assert_eq!(
+            stream.name_string().unwrap().to_string(),
+            "\\_SB_.PCI0._PRT"
+        );
+        assert_eq!(stream.name_string().unwrap().to_string(), "^^LNKA");
+        assert_eq!(stream.name_string().unwrap().to_string(), "ABCD.EFGH");
+        assert!(Stream::new(b"\\\x00").name_string().unwrap().is_root());
+    }
+}
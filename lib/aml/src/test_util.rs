@@ -0,0 +1,130 @@
+//! Helpers to assemble small tables and a handler that records what the code touched
+
+use std::collections::HashMap;
+use std::vec::Vec;
+
+use crate::opcode::*;
+use crate::{AmlValue, Handler, Interpreter, PciAddress};
+
+/// `body` after its package length
+pub fn pkg(body: &[u8]) -> Vec<u8> {
+    let mut bytes = match body.len() + 1 {
+        length if length < 0x40 => vec![length as u8],
+        _ => {
+            let length = body.len() + 2;
+            assert!(length < 0x1000);
+            vec![0x40 | (length & 0xF) as u8, (length >> 4) as u8]
+        }
+    };
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+/// A table with a header and `code`
+pub fn table(signature: &[u8; 4], code: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0; 36];
+    bytes[0..4].copy_from_slice(signature);
+    bytes[4..8].copy_from_slice(&(36 + code.len() as u32).to_le_bytes());
+    bytes[8] = 2;
+    bytes.extend_from_slice(code);
+    bytes
+}
+
+pub fn method(name: &[u8; 4], flags: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![METHOD_OP];
+    bytes.extend(pkg(&[&name[..], &[flags], body].concat()));
+    bytes
+}
+
+pub fn string(string: &str) -> Vec<u8> {
+    [&[STRING_PREFIX][..], string.as_bytes(), &[0]].concat()
+}
+
+pub fn region(name: &[u8; 4], space: u8, offset: u32, length: u8) -> Vec<u8> {
+    let offset = offset.to_le_bytes();
+    [
+        &[EXT_PREFIX, OP_REGION_OP][..],
+        name,
+        &[space, DWORD_PREFIX],
+        &offset,
+        &[BYTE_PREFIX, length],
+    ]
+    .concat()
+}
+
+pub fn field(region: &[u8; 4], flags: u8, elements: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![EXT_PREFIX, FIELD_OP];
+    bytes.extend(pkg(&[&region[..], &[flags], elements].concat()));
+    bytes
+}
+
+/// Evaluate `path`, panicking on errors
+pub fn eval(aml: &mut Interpreter<TestHandler>, path: &str, args: Vec<AmlValue>) -> AmlValue {
+    aml.evaluate(&path.parse().unwrap(), args).unwrap()
+}
+
+/// Memory, io ports and pci config space as bytes, unset bytes read as zero
+#[derive(Default)]
+pub struct TestHandler {
+    pub memory: HashMap<u64, u8>,
+    pub io: HashMap<u16, u8>,
+    pub io_writes: Vec<(u16, u64)>,
+    pub pci: HashMap<(PciAddress, u16), u8>,
+}
+
+fn read<K: Copy + Eq + std::hash::Hash>(
+    bytes: &HashMap<K, u8>,
+    key: impl Fn(u64) -> K,
+    width: u8,
+) -> u64 {
+    (0..width as u64 / 8).rev().fold(0, |value, i| {
+        value << 8 | *bytes.get(&key(i)).unwrap_or(&0) as u64
+    })
+}
+
+fn write<K: Copy + Eq + std::hash::Hash>(
+    bytes: &mut HashMap<K, u8>,
+    key: impl Fn(u64) -> K,
+    width: u8,
+    value: u64,
+) {
+    for i in 0..width as u64 / 8 {
+        bytes.insert(key(i), (value >> (i * 8)) as u8);
+    }
+}
+
+impl Handler for TestHandler {
+    fn read_memory(&mut self, address: u64, width: u8) -> u64 {
+        read(&self.memory, |i| address + i, width)
+    }
+
+    fn write_memory(&mut self, address: u64, width: u8, value: u64) {
+        write(&mut self.memory, |i| address + i, width, value)
+    }
+
+    fn read_io(&mut self, port: u16, width: u8) -> u64 {
+        read(&self.io, |i| port + i as u16, width)
+    }
+
+    fn write_io(&mut self, port: u16, width: u8, value: u64) {
+        self.io_writes.push((port, value));
+        write(&mut self.io, |i| port + i as u16, width, value)
+    }
+
+    fn read_pci(&mut self, address: PciAddress, offset: u16, width: u8) -> u64 {
+        read(&self.pci, |i| (address, offset + i as u16), width)
+    }
+
+    fn write_pci(&mut self, address: PciAddress, offset: u16, width: u8, value: u64) {
+        write(
+            &mut self.pci,
+            |i| (address, offset + i as u16),
+            width,
+            value,
+        )
+    }
+
+    fn stall(&mut self, _micros: u64) {}
+
+    fn sleep(&mut self, _millis: u64) {}
+}
@@ -0,0 +1,293 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::name::AmlName;
+use crate::AmlError;
+
+/// Address space of an operation region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    EmbeddedControl,
+    SmBus,
+    Cmos,
+    PciBarTarget,
+    Other(u8),
+}
+
+impl From<u8> for RegionSpace {
+    fn from(space: u8) -> Self {
+        match space {
+            0 => Self::SystemMemory,
+            1 => Self::SystemIo,
+            2 => Self::PciConfig,
+            3 => Self::EmbeddedControl,
+            4 => Self::SmBus,
+            5 => Self::Cmos,
+            6 => Self::PciBarTarget,
+            space => Self::Other(space),
+        }
+    }
+}
+
+/// What happens to the bits of an access unit outside the field when writing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateRule {
+    Preserve,
+    WriteAsOnes,
+    WriteAsZeros,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldFlags(pub(crate) u8);
+
+impl FieldFlags {
+    /// Bits read or written at once
+    pub fn access_width(&self) -> u8 {
+        match self.0 & 0xF {
+            2 => 16,
+            3 => 32,
+            4 => 64,
+            // any, byte and buffer access
+            _ => 8,
+        }
+    }
+
+    /// Must the global lock be held, this interpreter runs on one cpu so it never is
+    pub fn lock(&self) -> bool {
+        self.0 & (1 << 4) != 0
+    }
+
+    pub fn update_rule(&self) -> UpdateRule {
+        match (self.0 >> 5) & 0b11 {
+            1 => UpdateRule::WriteAsOnes,
+            2 => UpdateRule::WriteAsZeros,
+            _ => UpdateRule::Preserve,
+        }
+    }
+
+    /// The same flags with the access type of an access field
+    pub(crate) fn with_access_type(self, access_type: u8) -> Self {
+        Self(self.0 & !0xF | access_type & 0xF)
+    }
+}
+
+#[derive(Clone)]
+pub enum MethodCode {
+    Aml(Arc<[u8]>),
+    /// A method the interpreter provides, like `_OSI`
+    Native(fn(&[AmlValue]) -> Result<AmlValue, AmlError>),
+}
+
+impl fmt::Debug for MethodCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MethodCode::Aml(code) => write!(f, "Aml({} bytes)", code.len()),
+            MethodCode::Native(_) => f.write_str("Native"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Method {
+    pub arg_count: u8,
+    pub serialized: bool,
+    pub code: MethodCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpRegion {
+    pub space: RegionSpace,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Where the bits of a field are
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldKind {
+    /// Part of an operation region
+    Normal { region: AmlName },
+    /// Accessed by writing the offset to the index field and then using the data field
+    Index { index: AmlName, data: AmlName },
+    /// Part of an operation region once `value` is written to the bank field
+    Bank {
+        region: AmlName,
+        bank: AmlName,
+        value: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldUnit {
+    pub kind: FieldKind,
+    pub flags: FieldFlags,
+    pub bit_offset: u64,
+    pub bit_length: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Reference {
+    /// A named object
+    Name(AmlName),
+    /// An element of a buffer, package or string, or a local or argument
+    Value(Box<AmlValue>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum AmlValue {
+    #[default]
+    Uninitialized,
+    Integer(u64),
+    String(String),
+    Buffer(Vec<u8>),
+    Package(Vec<AmlValue>),
+    /// A name in a package, looked up from `scope` when it is used
+    Name {
+        scope: AmlName,
+        path: AmlName,
+    },
+    Reference(Reference),
+    Method(Method),
+    OpRegion(OpRegion),
+    Field(FieldUnit),
+    /// Bits of the named buffer
+    BufferField {
+        buffer: AmlName,
+        bit_offset: u64,
+        bit_length: u64,
+    },
+    /// A scope with no object of its own, like `\_GPE`
+    Scope,
+    Device,
+    Processor {
+        id: u8,
+        block_address: u32,
+        block_length: u8,
+    },
+    PowerResource {
+        system_level: u8,
+        resource_order: u16,
+    },
+    ThermalZone,
+    Mutex {
+        sync_level: u8,
+    },
+    Event,
+    /// Another name for the object at the path
+    Alias(AmlName),
+}
+
+impl AmlValue {
+    /// The code the object type operator returns
+    pub fn type_code(&self) -> u64 {
+        match self {
+            AmlValue::Uninitialized | AmlValue::Scope | AmlValue::Alias(_) => 0,
+            AmlValue::Integer(_) => 1,
+            AmlValue::String(_) => 2,
+            AmlValue::Buffer(_) => 3,
+            AmlValue::Package(_) => 4,
+            AmlValue::Field(_) => 5,
+            AmlValue::Device => 6,
+            AmlValue::Event => 7,
+            AmlValue::Method(_) => 8,
+            AmlValue::Mutex { .. } => 9,
+            AmlValue::OpRegion(_) => 10,
+            AmlValue::PowerResource { .. } => 11,
+            AmlValue::Processor { .. } => 12,
+            AmlValue::ThermalZone => 13,
+            AmlValue::BufferField { .. } => 14,
+            AmlValue::Name { .. } | AmlValue::Reference(_) => 20,
+        }
+    }
+
+    /// Implicit conversion to an integer, strings are read as hex
+    pub fn as_integer(&self) -> Result<u64, AmlError> {
+        match self {
+            AmlValue::Integer(value) => Ok(*value),
+            AmlValue::Buffer(bytes) => Ok(bytes
+                .iter()
+                .take(8)
+                .rev()
+                .fold(0, |value, byte| value << 8 | *byte as u64)),
+            AmlValue::String(string) => {
+                let string = string.trim_start_matches("0x").trim_start_matches("0X");
+                let digits = string
+                    .find(|c: char| !c.is_ascii_hexdigit())
+                    .unwrap_or(string.len());
+                Ok(u64::from_str_radix(&string[..digits.min(16)], 16).unwrap_or(0))
+            }
+            AmlValue::Reference(Reference::Value(value)) => value.as_integer(),
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    /// Implicit conversion to a buffer, integers are `width` bits long
+    pub fn as_buffer(&self, width: u8) -> Result<Vec<u8>, AmlError> {
+        match self {
+            AmlValue::Buffer(bytes) => Ok(bytes.clone()),
+            AmlValue::Integer(value) => Ok(value.to_le_bytes()[..width as usize / 8].to_vec()),
+            AmlValue::String(string) => {
+                let mut bytes = string.as_bytes().to_vec();
+                bytes.push(0);
+                Ok(bytes)
+            }
+            AmlValue::Reference(Reference::Value(value)) => value.as_buffer(width),
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    /// Implicit conversion to a string, integers and buffers become hex
+    pub fn as_string(&self) -> Result<String, AmlError> {
+        match self {
+            AmlValue::String(string) => Ok(string.clone()),
+            AmlValue::Integer(value) => Ok(alloc::format!("{:X}", value)),
+            AmlValue::Buffer(bytes) => {
+                let hex: Vec<String> = bytes.iter().map(|b| alloc::format!("{:02X}", b)).collect();
+                Ok(hex.join(" "))
+            }
+            AmlValue::Reference(Reference::Value(value)) => value.as_string(),
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+
+    pub fn as_package(&self) -> Result<&[AmlValue], AmlError> {
+        match self {
+            AmlValue::Package(elements) => Ok(elements),
+            AmlValue::Reference(Reference::Value(value)) => value.as_package(),
+            _ => Err(AmlError::TypeMismatch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(AmlValue::Buffer(vec![0x34, 0x12]).as_integer(), Ok(0x1234));
+        assert_eq!(AmlValue::String("0x1F".to_string()).as_integer(), Ok(0x1F));
+        assert_eq!(AmlValue::String("ab cd".to_string()).as_integer(), Ok(0xAB));
+        assert_eq!(
+            AmlValue::Integer(0x1234).as_buffer(32),
+            Ok(vec![0x34, 0x12, 0, 0])
+        );
+        assert_eq!(AmlValue::Integer(255).as_string(), Ok("FF".to_string()));
+        assert_eq!(AmlValue::Device.as_integer(), Err(AmlError::TypeMismatch));
+    }
+
+    #[test]
+    fn field_flags() {
+        // dword access, write as zeros
+        let flags = FieldFlags(0x43);
+        assert_eq!(flags.access_width(), 32);
+        assert_eq!(flags.update_rule(), UpdateRule::WriteAsZeros);
+        assert_eq!(flags.with_access_type(1).access_width(), 8);
+    }
+}
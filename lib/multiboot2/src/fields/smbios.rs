@@ -9,7 +9,27 @@ pub struct SMBIOSTables {
     major: u8,
     minor: u8,
     _reserved: [u8; 6],
-    smbios_tables: [u8; 0], // FIXME: allow for data
+    smbios_tables: [u8; 0],
+}
+
+impl SMBIOSTables {
+    /// Size of the tag before the copied tables
+    const HEADER_SIZE: usize = 16;
+
+    pub fn major(&self) -> u8 {
+        self.major
+    }
+
+    pub fn minor(&self) -> u8 {
+        self.minor
+    }
+
+    /// The copy of the smbios entry point
+    pub fn tables(&self) -> &[u8] {
+        let length = (self.tag.size() as usize).saturating_sub(Self::HEADER_SIZE);
+        let start = unsafe { (self as *const Self as *const u8).add(Self::HEADER_SIZE) };
+        unsafe { core::slice::from_raw_parts(start, length) }
+    }
 }
 
 impl Debug for SMBIOSTables {
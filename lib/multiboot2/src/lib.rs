@@ -97,6 +97,12 @@ impl MultibootInfo {
             .map(|tag| unsafe { &*(tag as *const Tag as *const RsdpV2Tag) })
     }
 
+    /// Search for the smbios tables
+    pub fn smbios(&self) -> Option<&SMBIOSTables> {
+        self.get_tag(TagType::SMBIOSTables)
+            .map(|tag| unsafe { &*(tag as *const Tag as *const SMBIOSTables) })
+    }
+
     fn get(&self) -> &MultibootTable {
         unsafe { &*self.inner }
     }
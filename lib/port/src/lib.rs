@@ -4,6 +4,6 @@
 #[macro_use]
 extern crate std;
 
-pub use port::{Port, PortReadOnly, PortWriteOnly};
+pub use port::{Port, PortRead, PortReadOnly, PortWrite, PortWriteOnly};
 
 mod port;
@@ -48,6 +48,15 @@ impl Uart {
     /// # Safety
     /// The correct things to write according to osdev wiki
     pub unsafe fn init(&mut self) {
+        if !self.try_init() {
+            panic!("Serial communication is faulty");
+        }
+    }
+
+    /// Same as [`Uart::init`] but returns false instead of panicking if there is no working uart
+    /// # Safety
+    /// The port must not belong to another device
+    pub unsafe fn try_init(&mut self) -> bool {
         self.int_en.write(0x00); // Disable all interrupts
         self.line_ctrl.write(0x80); // Enable DLAB (set baud rate divisor)
         self.data.write(0x03); // Set divisor to 3 (lo byte) 38400 baud
@@ -60,12 +69,13 @@ impl Uart {
 
         // Check if serial is faulty (i.e: not same byte as sent)
         if self.data.read() != 0xAE {
-            panic!("Serial communication is faulty");
+            return false;
         }
 
         // If serial is not faulty set it in normal operation mode
         // (not-loopback with IRQs enabled and OUT#1 and OUT#2 bits enabled)
         self.modem_ctrl.write(0x0F);
+        true
     }
 
     pub fn disable(&mut self) {
@@ -80,6 +90,36 @@ impl Uart {
         }
     }
 
+    /// Send a raw byte, without any of the terminal handling of [`fmt::Write`]
+    pub fn send(&mut self, byte: u8) {
+        unsafe {
+            while self.line_sts.read() & 0x20 == 0x0 {
+                core::hint::spin_loop();
+            }
+            self.data.write(byte);
+        }
+    }
+
+    /// Wait for a byte to be received
+    pub fn receive(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_receive() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// A received byte, if one is waiting
+    pub fn try_receive(&mut self) -> Option<u8> {
+        unsafe {
+            if self.line_sts.read() & 0x01 == 0x0 {
+                return None;
+            }
+            Some(self.data.read())
+        }
+    }
+
     fn write_byte(&mut self, byte: u8) {
         if self.column >= 80 {
             unsafe { self.data.write(b'\n') };
@@ -94,12 +134,7 @@ impl Uart {
             self.column = self.column.saturating_sub(2);
         }
 
-        unsafe {
-            while self.line_sts.read() & 0x20 == 0x0 {
-                core::hint::spin_loop();
-            }
-            self.data.write(byte);
-        }
+        self.send(byte);
         self.column += 1;
     }
 
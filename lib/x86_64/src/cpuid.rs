@@ -0,0 +1,99 @@
+use bitflags::bitflags;
+use core::arch::asm;
+
+/// The registers returned by the cpuid instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Query the cpu with `leaf` and `sub_leaf`
+pub fn cpuid(leaf: u32, sub_leaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    // llvm reserves rbx, so save it around the instruction
+    unsafe {
+        asm!(
+            "mov {tmp:r}, rbx",
+            "cpuid",
+            "xchg {tmp:r}, rbx",
+            tmp = out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") sub_leaf => ecx,
+            out("edx") edx,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+/// Highest supported extended leaf (0x8000_0000 and up)
+pub fn max_extended_leaf() -> u32 {
+    cpuid(0x8000_0000, 0).eax
+}
+
+bitflags! {
+    /// Feature flags from leaf 1, ecx
+    pub struct FeaturesEcx: u32 {
+        const SSE3 = 1;
+        const PCLMULQDQ = 1 << 1;
+        const MONITOR = 1 << 3;
+        const SSSE3 = 1 << 9;
+        const FMA = 1 << 12;
+        const CMPXCHG16B = 1 << 13;
+        const PCID = 1 << 17;
+        const SSE4_1 = 1 << 19;
+        const SSE4_2 = 1 << 20;
+        const X2APIC = 1 << 21;
+        const POPCNT = 1 << 23;
+        const TSC_DEADLINE = 1 << 24;
+        const AES = 1 << 25;
+        const XSAVE = 1 << 26;
+        const OSXSAVE = 1 << 27;
+        const AVX = 1 << 28;
+        const F16C = 1 << 29;
+        const RDRAND = 1 << 30;
+        const HYPERVISOR = 1 << 31;
+    }
+}
+
+bitflags! {
+    /// Feature flags from leaf 1, edx
+    pub struct FeaturesEdx: u32 {
+        const FPU = 1;
+        const VME = 1 << 1;
+        const DE = 1 << 2;
+        const PSE = 1 << 3;
+        const TSC = 1 << 4;
+        const MSR = 1 << 5;
+        const PAE = 1 << 6;
+        const MCE = 1 << 7;
+        const CX8 = 1 << 8;
+        const APIC = 1 << 9;
+        const SEP = 1 << 11;
+        const MTRR = 1 << 12;
+        const PGE = 1 << 13;
+        const MCA = 1 << 14;
+        const CMOV = 1 << 15;
+        const PAT = 1 << 16;
+        const PSE36 = 1 << 17;
+        const CLFSH = 1 << 19;
+        const ACPI = 1 << 22;
+        const MMX = 1 << 23;
+        const FXSR = 1 << 24;
+        const SSE = 1 << 25;
+        const SSE2 = 1 << 26;
+        const HTT = 1 << 28;
+    }
+}
+
+/// The feature flags of leaf 1
+pub fn features() -> (FeaturesEcx, FeaturesEdx) {
+    let result = cpuid(1, 0);
+    (
+        FeaturesEcx::from_bits_truncate(result.ecx),
+        FeaturesEdx::from_bits_truncate(result.edx),
+    )
+}
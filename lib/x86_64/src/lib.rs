@@ -4,6 +4,8 @@ pub mod tables;
 pub mod paging;
 pub mod registers;
 pub mod consts;
+pub mod cpuid;
+pub mod tsc;
 mod address;
 
 pub use address::{PhysicalAddress, VirtualAddress};
@@ -19,6 +19,36 @@ bitflags! {
     }
 }
 
+impl Cr0 {
+    pub fn read() -> Self {
+        let value: u64;
+        unsafe { asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        Self::from_bits_truncate(value)
+    }
+
+    /// # Safety
+    /// Clearing paging or protection will crash the kernel
+    pub unsafe fn write(flags: Self) {
+        // keep the reserved bits as they were
+        let old: u64;
+        asm!("mov {}, cr0", out(reg) old, options(nomem, nostack, preserves_flags));
+        let value = (old & !Self::all().bits()) | flags.bits();
+        asm!("mov cr0, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+
+    /// Read, modify, then write the flags back
+    /// # Safety
+    /// See [`Cr0::write`]
+    pub unsafe fn update<F>(f: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        let mut flags = Self::read();
+        f(&mut flags);
+        Self::write(flags);
+    }
+}
+
 #[derive(Debug)]
 pub struct Cr2;
 
@@ -84,8 +114,9 @@ bitflags! {
         const UMIP = 1 << 11;
         const VMXE = 1 << 13;
         const SMXE = 1 << 14;
+        const FSGSBASE = 1 << 16;
         const PCIDE = 1 << 17;
-        const OXXSAVE = 1 << 18;
+        const OSXSAVE = 1 << 18;
         const SMEP = 1 << 20;
         const SMAP = 1 << 21;
         const PKE = 1 << 22;
@@ -100,4 +131,26 @@ impl Cr4 {
         unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags)) };
         Self::from_bits_truncate(value)
     }
+
+    /// # Safety
+    /// Enabling a feature the cpu does not support will #GP
+    pub unsafe fn write(flags: Self) {
+        // keep the reserved bits as they were
+        let old: u64;
+        asm!("mov {}, cr4", out(reg) old, options(nomem, nostack, preserves_flags));
+        let value = (old & !Self::all().bits()) | flags.bits();
+        asm!("mov cr4, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+
+    /// Read, modify, then write the flags back
+    /// # Safety
+    /// See [`Cr4::write`]
+    pub unsafe fn update<F>(f: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        let mut flags = Self::read();
+        f(&mut flags);
+        Self::write(flags);
+    }
 }
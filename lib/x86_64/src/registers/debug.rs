@@ -0,0 +1,257 @@
+use bit_field::BitField;
+use bitflags::bitflags;
+use core::arch::asm;
+
+use crate::VirtualAddress;
+
+/// One of the four breakpoint address registers, DR0-DR3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DebugAddressRegister {
+    Dr0 = 0,
+    Dr1 = 1,
+    Dr2 = 2,
+    Dr3 = 3,
+}
+
+impl DebugAddressRegister {
+    pub const ALL: [Self; 4] = [Self::Dr0, Self::Dr1, Self::Dr2, Self::Dr3];
+
+    pub fn read(self) -> VirtualAddress {
+        let value: u64;
+        unsafe {
+            match self {
+                Self::Dr0 => {
+                    asm!("mov {}, dr0", out(reg) value, options(nomem, nostack, preserves_flags))
+                }
+                Self::Dr1 => {
+                    asm!("mov {}, dr1", out(reg) value, options(nomem, nostack, preserves_flags))
+                }
+                Self::Dr2 => {
+                    asm!("mov {}, dr2", out(reg) value, options(nomem, nostack, preserves_flags))
+                }
+                Self::Dr3 => {
+                    asm!("mov {}, dr3", out(reg) value, options(nomem, nostack, preserves_flags))
+                }
+            }
+        }
+        VirtualAddress::new(value)
+    }
+
+    /// # Safety
+    /// If the breakpoint is enabled in dr7 the cpu will trap on this address
+    pub unsafe fn write(self, address: VirtualAddress) {
+        let value = u64::from(address);
+        match self {
+            Self::Dr0 => {
+                asm!("mov dr0, {}", in(reg) value, options(nomem, nostack, preserves_flags))
+            }
+            Self::Dr1 => {
+                asm!("mov dr1, {}", in(reg) value, options(nomem, nostack, preserves_flags))
+            }
+            Self::Dr2 => {
+                asm!("mov dr2, {}", in(reg) value, options(nomem, nostack, preserves_flags))
+            }
+            Self::Dr3 => {
+                asm!("mov dr3, {}", in(reg) value, options(nomem, nostack, preserves_flags))
+            }
+        }
+    }
+}
+
+bitflags! {
+    /// DR6, the status of the last debug exception
+    pub struct Dr6: u64 {
+        const TRAP0 = 1; // B0, breakpoint 0 condition met
+        const TRAP1 = 1 << 1;
+        const TRAP2 = 1 << 2;
+        const TRAP3 = 1 << 3;
+        const BUS_LOCK = 1 << 11; // cleared (not set) when detected
+        const ACCESS_DETECTED = 1 << 13; // BD, next instruction accesses a debug register
+        const STEP = 1 << 14; // BS, single step through the trap flag
+        const SWITCH = 1 << 15; // BT, task switch
+        const RTM = 1 << 16; // cleared (not set) when in a transaction
+    }
+}
+
+impl Dr6 {
+    /// The value of dr6 with no conditions detected, reserved bits are 1
+    const CLEAR: u64 = 0xFFFF_0FF0 | Self::BUS_LOCK.bits | Self::RTM.bits;
+
+    pub fn read() -> Self {
+        let value: u64;
+        unsafe { asm!("mov {}, dr6", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        // bus lock and rtm are active low, flip them so a set flag means detected
+        Self::from_bits_truncate(value ^ (Self::BUS_LOCK.bits | Self::RTM.bits))
+    }
+
+    /// The cpu never clears dr6 so this must be called after handling a #DB
+    pub fn clear() {
+        unsafe {
+            asm!("mov dr6, {}", in(reg) Self::CLEAR, options(nomem, nostack, preserves_flags))
+        };
+    }
+
+    /// The breakpoint address registers whose condition was met
+    pub fn triggered(&self) -> impl Iterator<Item = DebugAddressRegister> + '_ {
+        DebugAddressRegister::ALL
+            .into_iter()
+            .filter(|dr| self.bits.get_bit(*dr as usize))
+    }
+}
+
+/// When a breakpoint triggers, the R/W bits of dr7
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BreakpointCondition {
+    InstructionExecution = 0b00,
+    DataWrites = 0b01,
+    IoReadsWrites = 0b10, // requires CR4.DE
+    DataReadsWrites = 0b11,
+}
+
+impl From<u64> for BreakpointCondition {
+    fn from(value: u64) -> Self {
+        match value {
+            0b00 => Self::InstructionExecution,
+            0b01 => Self::DataWrites,
+            0b10 => Self::IoReadsWrites,
+            0b11 => Self::DataReadsWrites,
+            i => panic!("{} is not a valid breakpoint condition", i),
+        }
+    }
+}
+
+/// How many bytes a breakpoint covers, the LEN bits of dr7
+/// The address must be aligned to the size, execution breakpoints must use one byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BreakpointSize {
+    Length1B = 0b00,
+    Length2B = 0b01,
+    Length8B = 0b10,
+    Length4B = 0b11,
+}
+
+impl BreakpointSize {
+    /// The size for a length in bytes
+    pub fn new(size: usize) -> Option<Self> {
+        match size {
+            1 => Some(Self::Length1B),
+            2 => Some(Self::Length2B),
+            4 => Some(Self::Length4B),
+            8 => Some(Self::Length8B),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::Length1B => 1,
+            Self::Length2B => 2,
+            Self::Length4B => 4,
+            Self::Length8B => 8,
+        }
+    }
+}
+
+impl From<u64> for BreakpointSize {
+    fn from(value: u64) -> Self {
+        match value {
+            0b00 => Self::Length1B,
+            0b01 => Self::Length2B,
+            0b10 => Self::Length8B,
+            0b11 => Self::Length4B,
+            i => panic!("{} is not a valid breakpoint size", i),
+        }
+    }
+}
+
+bitflags! {
+    pub struct Dr7Flags: u64 {
+        const LOCAL_BREAKPOINT_0_ENABLE = 1;
+        const GLOBAL_BREAKPOINT_0_ENABLE = 1 << 1;
+        const LOCAL_BREAKPOINT_1_ENABLE = 1 << 2;
+        const GLOBAL_BREAKPOINT_1_ENABLE = 1 << 3;
+        const LOCAL_BREAKPOINT_2_ENABLE = 1 << 4;
+        const GLOBAL_BREAKPOINT_2_ENABLE = 1 << 5;
+        const LOCAL_BREAKPOINT_3_ENABLE = 1 << 6;
+        const GLOBAL_BREAKPOINT_3_ENABLE = 1 << 7;
+        const LOCAL_EXACT_BREAKPOINT_ENABLE = 1 << 8;
+        const GLOBAL_EXACT_BREAKPOINT_ENABLE = 1 << 9;
+        const RESTRICTED_TRANSACTIONAL_MEMORY = 1 << 11;
+        const GENERAL_DETECT_ENABLE = 1 << 13;
+    }
+}
+
+impl Dr7Flags {
+    pub fn local_enable(dr: DebugAddressRegister) -> Self {
+        Self::from_bits_truncate(1 << (dr as u64 * 2))
+    }
+
+    pub fn global_enable(dr: DebugAddressRegister) -> Self {
+        Self::from_bits_truncate(1 << (dr as u64 * 2 + 1))
+    }
+}
+
+/// DR7, enables the breakpoints and selects their condition and size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Dr7(u64);
+
+impl Dr7 {
+    /// Bit 10 is reserved and always 1
+    pub const fn new() -> Self {
+        Self(1 << 10)
+    }
+
+    pub fn read() -> Self {
+        let value: u64;
+        unsafe { asm!("mov {}, dr7", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        Self(value)
+    }
+
+    /// # Safety
+    /// Enabled breakpoints trap into the #DB handler
+    pub unsafe fn write(self) {
+        asm!("mov dr7, {}", in(reg) self.0, options(nomem, nostack, preserves_flags));
+    }
+
+    pub fn flags(&self) -> Dr7Flags {
+        Dr7Flags::from_bits_truncate(self.0)
+    }
+
+    pub fn insert_flags(&mut self, flags: Dr7Flags) {
+        self.0 |= flags.bits();
+    }
+
+    pub fn remove_flags(&mut self, flags: Dr7Flags) {
+        self.0 &= !flags.bits();
+    }
+
+    pub fn condition(&self, dr: DebugAddressRegister) -> BreakpointCondition {
+        let start = 16 + dr as usize * 4;
+        self.0.get_bits(start..start + 2).into()
+    }
+
+    pub fn set_condition(&mut self, dr: DebugAddressRegister, condition: BreakpointCondition) {
+        let start = 16 + dr as usize * 4;
+        self.0.set_bits(start..start + 2, condition as u64);
+    }
+
+    pub fn size(&self, dr: DebugAddressRegister) -> BreakpointSize {
+        let start = 18 + dr as usize * 4;
+        self.0.get_bits(start..start + 2).into()
+    }
+
+    pub fn set_size(&mut self, dr: DebugAddressRegister, size: BreakpointSize) {
+        let start = 18 + dr as usize * 4;
+        self.0.set_bits(start..start + 2, size as u64);
+    }
+}
+
+impl Default for Dr7 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
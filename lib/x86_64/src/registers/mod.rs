@@ -1 +1,4 @@
 pub mod control;
+pub mod debug;
+pub mod model_specific;
+pub mod xcontrol;
@@ -0,0 +1,359 @@
+use crate::{PhysicalAddress, VirtualAddress};
+use bit_field::BitField;
+use bitflags::bitflags;
+use core::arch::asm;
+
+/// A model specific register, accessed with `rdmsr`/`wrmsr`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Msr(u32);
+
+impl Msr {
+    pub const fn new(register: u32) -> Self {
+        Self(register)
+    }
+
+    pub const fn register(&self) -> u32 {
+        self.0
+    }
+
+    /// Read the 64 bits of the msr
+    /// # Safety
+    /// The msr must exist on this cpu, else we will #GP
+    pub unsafe fn read(&self) -> u64 {
+        let (high, low): (u32, u32);
+        asm!(
+            "rdmsr",
+            in("ecx") self.0,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags),
+        );
+        ((high as u64) << 32) | (low as u64)
+    }
+
+    /// Write the 64 bits of the msr
+    /// # Safety
+    /// The msr must exist on this cpu and the value must be valid for it,
+    /// writing an msr can change the behavior of the whole cpu
+    pub unsafe fn write(&mut self, value: u64) {
+        let low = value as u32;
+        let high = (value >> 32) as u32;
+        asm!(
+            "wrmsr",
+            in("ecx") self.0,
+            in("eax") low,
+            in("edx") high,
+            options(nostack, preserves_flags),
+        );
+    }
+}
+
+bitflags! {
+    pub struct EferFlags: u64 {
+        const SYSTEM_CALL_EXTENSIONS = 1; // SCE, enables syscall/sysret
+        const LONG_MODE_ENABLE = 1 << 8; // LME
+        const LONG_MODE_ACTIVE = 1 << 10; // LMA, read only
+        const NO_EXECUTE_ENABLE = 1 << 11; // NXE
+        const SECURE_VIRTUAL_MACHINE_ENABLE = 1 << 12; // SVME
+        const LONG_MODE_SEGMENT_LIMIT_ENABLE = 1 << 13; // LMSLE
+        const FAST_FXSAVE_FXRSTOR = 1 << 14; // FFXSR
+        const TRANSLATION_CACHE_EXTENSION = 1 << 15; // TCE
+    }
+}
+
+/// IA32_EFER, Extended Feature Enable Register
+#[derive(Debug)]
+pub struct Efer;
+
+impl Efer {
+    const MSR: Msr = Msr::new(0xC000_0080);
+
+    pub fn read() -> EferFlags {
+        EferFlags::from_bits_truncate(unsafe { Self::MSR.read() })
+    }
+
+    /// # Safety
+    /// Clearing long mode or enabling unsupported features will crash the cpu
+    pub unsafe fn write(flags: EferFlags) {
+        // keep the reserved bits as they were
+        let reserved = Self::MSR.read() & !EferFlags::all().bits();
+        let mut msr = Self::MSR;
+        msr.write(reserved | flags.bits());
+    }
+
+    /// Read, modify, then write the flags back
+    /// # Safety
+    /// See [`Efer::write`]
+    pub unsafe fn update<F>(f: F)
+    where
+        F: FnOnce(&mut EferFlags),
+    {
+        let mut flags = Self::read();
+        f(&mut flags);
+        Self::write(flags);
+    }
+}
+
+bitflags! {
+    pub struct ApicBaseFlags: u64 {
+        const BSP = 1 << 8; // this is the bootstrap processor, read only
+        const X2APIC_ENABLE = 1 << 10; // EXTD
+        const XAPIC_ENABLE = 1 << 11; // EN
+    }
+}
+
+/// IA32_APIC_BASE, physical base of the local apic and its mode
+#[derive(Debug)]
+pub struct ApicBase;
+
+impl ApicBase {
+    const MSR: Msr = Msr::new(0x1B);
+
+    pub fn read() -> (PhysicalAddress, ApicBaseFlags) {
+        let value = unsafe { Self::MSR.read() };
+        let address = PhysicalAddress::new(value & 0x000f_ffff_ffff_f000);
+        (address, ApicBaseFlags::from_bits_truncate(value))
+    }
+
+    /// # Safety
+    /// Moving or disabling the apic affects all interrupt delivery to this cpu
+    pub unsafe fn write(address: PhysicalAddress, flags: ApicBaseFlags) {
+        let mut msr = Self::MSR;
+        msr.write(u64::from(address) | flags.bits());
+    }
+}
+
+/// IA32_FS_BASE, base address of the fs segment
+#[derive(Debug)]
+pub struct FsBase;
+
+impl FsBase {
+    const MSR: Msr = Msr::new(0xC000_0100);
+
+    pub fn read() -> VirtualAddress {
+        VirtualAddress::new(unsafe { Self::MSR.read() })
+    }
+
+    /// # Safety
+    /// Code using fs relative addressing will be pointed to the new base
+    pub unsafe fn write(address: VirtualAddress) {
+        let mut msr = Self::MSR;
+        msr.write(address.into());
+    }
+}
+
+/// IA32_GS_BASE, base address of the gs segment
+#[derive(Debug)]
+pub struct GsBase;
+
+impl GsBase {
+    const MSR: Msr = Msr::new(0xC000_0101);
+
+    pub fn read() -> VirtualAddress {
+        VirtualAddress::new(unsafe { Self::MSR.read() })
+    }
+
+    /// # Safety
+    /// Code using gs relative addressing will be pointed to the new base
+    pub unsafe fn write(address: VirtualAddress) {
+        let mut msr = Self::MSR;
+        msr.write(address.into());
+    }
+}
+
+/// IA32_KERNEL_GS_BASE, the value swapped into gs base by `swapgs`
+#[derive(Debug)]
+pub struct KernelGsBase;
+
+impl KernelGsBase {
+    const MSR: Msr = Msr::new(0xC000_0102);
+
+    pub fn read() -> VirtualAddress {
+        VirtualAddress::new(unsafe { Self::MSR.read() })
+    }
+
+    /// # Safety
+    /// The next `swapgs` will load this as the gs base
+    pub unsafe fn write(address: VirtualAddress) {
+        let mut msr = Self::MSR;
+        msr.write(address.into());
+    }
+}
+
+/// IA32_STAR, the segment bases used by syscall and sysret
+#[derive(Debug)]
+pub struct Star;
+
+impl Star {
+    const MSR: Msr = Msr::new(0xC000_0081);
+
+    /// Returns the raw (sysret, syscall) selector bases
+    pub fn read() -> (u16, u16) {
+        let value = unsafe { Self::MSR.read() };
+        (value.get_bits(48..64) as u16, value.get_bits(32..48) as u16)
+    }
+
+    /// `syscall` loads cs from `syscall_base` and ss from `syscall_base + 8`.
+    /// `sysret` (64 bit) loads cs from `sysret_base + 16` and ss from `sysret_base + 8`,
+    /// with the rpl of both forced to 3.
+    /// # Safety
+    /// The selectors must point to valid gdt entries laid out as above
+    pub unsafe fn write(sysret_base: u16, syscall_base: u16) {
+        let mut value = 0u64;
+        value.set_bits(32..48, syscall_base.into());
+        value.set_bits(48..64, sysret_base.into());
+        let mut msr = Self::MSR;
+        msr.write(value);
+    }
+}
+
+/// IA32_LSTAR, the rip loaded by `syscall` in long mode
+#[derive(Debug)]
+pub struct LStar;
+
+impl LStar {
+    const MSR: Msr = Msr::new(0xC000_0082);
+
+    pub fn read() -> VirtualAddress {
+        VirtualAddress::new(unsafe { Self::MSR.read() })
+    }
+
+    /// # Safety
+    /// Must point to a valid syscall entry point
+    pub unsafe fn write(address: VirtualAddress) {
+        let mut msr = Self::MSR;
+        msr.write(address.into());
+    }
+}
+
+/// IA32_FMASK, rflags bits that are cleared by `syscall`
+#[derive(Debug)]
+pub struct SFMask;
+
+impl SFMask {
+    const MSR: Msr = Msr::new(0xC000_0084);
+
+    pub fn read() -> u64 {
+        unsafe { Self::MSR.read() }
+    }
+
+    /// # Safety
+    /// Leaving IF unmasked means the entry can be interrupted before it switches stacks
+    pub unsafe fn write(mask: u64) {
+        let mut msr = Self::MSR;
+        msr.write(mask);
+    }
+}
+
+/// IA32_TSC_AUX, the value `rdtscp` returns next to the counter.
+/// Only exists if `rdtscp` is supported.
+#[derive(Debug)]
+pub struct TscAux;
+
+impl TscAux {
+    const MSR: Msr = Msr::new(0xC000_0103);
+
+    pub fn read() -> u32 {
+        unsafe { Self::MSR.read() as u32 }
+    }
+
+    /// # Safety
+    /// The msr only exists if `rdtscp` is supported
+    pub unsafe fn write(value: u32) {
+        let mut msr = Self::MSR;
+        msr.write(value.into());
+    }
+}
+
+/// IA32_TSC_DEADLINE, the local apic timer interrupts when the time stamp counter
+/// reaches this in tsc-deadline mode. Writing 0 disarms it.
+#[derive(Debug)]
+pub struct TscDeadline;
+
+impl TscDeadline {
+    const MSR: Msr = Msr::new(0x6E0);
+
+    pub fn read() -> u64 {
+        unsafe { Self::MSR.read() }
+    }
+
+    /// # Safety
+    /// The msr only exists if cpuid reports tsc-deadline support
+    pub unsafe fn write(deadline: u64) {
+        let mut msr = Self::MSR;
+        msr.write(deadline);
+    }
+}
+
+/// Memory types that can be placed in a pat entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PatMemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    Uncached = 7, // UC-, can be overridden by the mtrrs
+}
+
+/// An encoding the pat reserves, 2 and 3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatMemoryTypeInvalid(pub u8);
+
+impl TryFrom<u8> for PatMemoryType {
+    type Error = PatMemoryTypeInvalid;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Uncacheable),
+            1 => Ok(Self::WriteCombining),
+            4 => Ok(Self::WriteThrough),
+            5 => Ok(Self::WriteProtected),
+            6 => Ok(Self::WriteBack),
+            7 => Ok(Self::Uncached),
+            i => Err(PatMemoryTypeInvalid(i)),
+        }
+    }
+}
+
+/// IA32_PAT, the page attribute table
+#[derive(Debug)]
+pub struct Pat;
+
+impl Pat {
+    const MSR: Msr = Msr::new(0x277);
+
+    /// The table the cpu comes out of reset with
+    pub const DEFAULT: [PatMemoryType; 8] = [
+        PatMemoryType::WriteBack,
+        PatMemoryType::WriteThrough,
+        PatMemoryType::Uncached,
+        PatMemoryType::Uncacheable,
+        PatMemoryType::WriteBack,
+        PatMemoryType::WriteThrough,
+        PatMemoryType::Uncached,
+        PatMemoryType::Uncacheable,
+    ];
+
+    /// The table as it is, firmware can leave reserved encodings in entries
+    pub fn read() -> [Result<PatMemoryType, PatMemoryTypeInvalid>; 8] {
+        let value = unsafe { Self::MSR.read() };
+        let mut table = [Ok(PatMemoryType::Uncacheable); 8];
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = PatMemoryType::try_from(value.get_bits(i * 8..i * 8 + 3) as u8);
+        }
+        table
+    }
+
+    /// # Safety
+    /// Changing memory types of mapped pages requires flushing the tlb and caches
+    pub unsafe fn write(table: [PatMemoryType; 8]) {
+        let mut value = 0u64;
+        for (i, entry) in table.iter().enumerate() {
+            value.set_bits(i * 8..i * 8 + 8, *entry as u64);
+        }
+        let mut msr = Self::MSR;
+        msr.write(value);
+    }
+}
@@ -0,0 +1,47 @@
+use bitflags::bitflags;
+use core::arch::asm;
+
+bitflags! {
+    /// State components that xsave/xrstor manage
+    pub struct XCr0: u64 {
+        const X87 = 1;
+        const SSE = 1 << 1;
+        const AVX = 1 << 2;
+        const BNDREG = 1 << 3;
+        const BNDCSR = 1 << 4;
+        const OPMASK = 1 << 5;
+        const ZMM_HI256 = 1 << 6;
+        const HI16_ZMM = 1 << 7;
+        const PKRU = 1 << 9;
+    }
+}
+
+impl XCr0 {
+    /// # Safety
+    /// CR4.OSXSAVE must be set, else this will #UD
+    pub unsafe fn read() -> Self {
+        let (high, low): (u32, u32);
+        asm!(
+            "xgetbv",
+            in("ecx") 0,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags),
+        );
+        Self::from_bits_truncate(((high as u64) << 32) | (low as u64))
+    }
+
+    /// # Safety
+    /// CR4.OSXSAVE must be set, x87 must always be set and every
+    /// component must be supported by the cpu, else this will #GP
+    pub unsafe fn write(flags: Self) {
+        let value = flags.bits();
+        asm!(
+            "xsetbv",
+            in("ecx") 0,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+}
@@ -1,7 +1,11 @@
 use core::fmt::Debug;
+use core::mem::offset_of;
 use core::ptr::addr_of;
 use core::slice;
 
+use super::hpet::Hpet;
+use super::mcfg::Mcfg;
+use super::rsdp::{RsdpV1, RsdpV2};
 use crate::PhysicalAddress;
 
 #[derive(Clone, Copy)]
@@ -35,11 +39,25 @@ impl AcpiSdtHeader {
         self.length
     }
 
+    /// The table after the header
+    pub fn data(&self) -> &[u8] {
+        let header_size = core::mem::size_of::<Self>();
+        let start = unsafe { (self as *const Self as *const u8).add(header_size) };
+        let length = (self.length as usize).saturating_sub(header_size);
+        unsafe { slice::from_raw_parts(start, length) }
+    }
+
+    /// The whole table, header included
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) }
+    }
+
     /// Validation using the checksum
     pub fn is_valid(&self) -> bool {
-        let bytes =
-            unsafe { slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) };
-        bytes.iter().fold(0u8, |acc, val| acc.wrapping_add(*val)) == 0
+        self.bytes()
+            .iter()
+            .fold(0u8, |acc, val| acc.wrapping_add(*val))
+            == 0
     }
 }
 
@@ -72,6 +90,10 @@ pub struct Rsdt {
 }
 
 impl Rsdt {
+    pub fn header(&self) -> &AcpiSdtHeader {
+        &self.header
+    }
+
     pub fn total_entries(&self) -> usize {
         (self.header.length as usize - core::mem::size_of::<AcpiSdtHeader>()) / 4
     }
@@ -94,41 +116,208 @@ impl Debug for Rsdt {
     }
 }
 
+/// The rsdt with 64 bit pointers, from acpi 2.0
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct Xsdt {
+    header: AcpiSdtHeader,
+    pointers: [u64; 0],
+}
+
+impl Xsdt {
+    pub fn header(&self) -> &AcpiSdtHeader {
+        &self.header
+    }
+
+    pub fn total_entries(&self) -> usize {
+        (self.header.length as usize - core::mem::size_of::<AcpiSdtHeader>()) / 8
+    }
+
+    pub fn entry(&self, index: usize) -> PhysicalAddress {
+        let num_entries = self.total_entries();
+        assert!(index < num_entries);
+
+        let mut ptr = addr_of!(self.pointers) as *const u64;
+        ptr = unsafe { ptr.add(index) };
+        PhysicalAddress::new(unsafe { ptr.read_unaligned() })
+    }
+}
+
+impl Debug for Xsdt {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("XSDT")
+            .field("header", &self.header)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The table listing all others
+#[derive(Debug, Clone, Copy)]
+pub enum RootTable {
+    Rsdt(&'static Rsdt),
+    Xsdt(&'static Xsdt),
+}
+
+impl RootTable {
+    pub fn header(&self) -> &AcpiSdtHeader {
+        match self {
+            RootTable::Rsdt(rsdt) => rsdt.header(),
+            RootTable::Xsdt(xsdt) => xsdt.header(),
+        }
+    }
+
+    pub fn total_entries(&self) -> usize {
+        match self {
+            RootTable::Rsdt(rsdt) => rsdt.total_entries(),
+            RootTable::Xsdt(xsdt) => xsdt.total_entries(),
+        }
+    }
+
+    pub fn entry(&self, index: usize) -> PhysicalAddress {
+        match self {
+            RootTable::Rsdt(rsdt) => rsdt.entry(index),
+            RootTable::Xsdt(xsdt) => xsdt.entry(index),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The bootloader did not pass an rsdp
+    MissingRsdp,
+    InvalidRsdp,
+    /// The rsdt or xsdt checksum is wrong
+    InvalidRootTable,
+}
+
+/// Most tables the registry holds, later ones are dropped
+pub const MAX_TABLES: usize = 64;
+
+/// Signatures of the tables [`Acpi`] picks out
+const KNOWN_SIGNATURES: [&str; 4] = ["FACP", "APIC", "HPET", "MCFG"];
+
 #[derive(Debug)]
 pub struct Acpi {
-    pub rsdt: &'static Rsdt,
+    pub root: Option<RootTable>,
     pub fadt: Option<&'static Fadt>,
     pub madt_ptr: Option<PhysicalAddress>,
-    //pub hpet: Option<&'static HPET>,
-    //pub WAET: Option<&'static WAET>,
+    pub hpet: Option<&'static Hpet>,
+    pub mcfg: Option<&'static Mcfg>,
+    /// Every table with a valid checksum
+    tables: [Option<&'static AcpiSdtHeader>; MAX_TABLES],
+    /// Tables left out for a bad checksum or length
+    invalid: usize,
 }
 
 impl Acpi {
-    pub fn new(rsdt_ptr: PhysicalAddress) -> Self {
+    /// No tables, for when there is no usable rsdp
+    pub const fn empty() -> Self {
         Self {
-            rsdt: unsafe { &*rsdt_ptr.as_ptr::<Rsdt>() },
+            root: None,
             fadt: None,
             madt_ptr: None,
+            hpet: None,
+            mcfg: None,
+            tables: [None; MAX_TABLES],
+            invalid: 0,
+        }
+    }
+
+    pub fn new(root: RootTable) -> Result<Self, AcpiError> {
+        if !root.header().is_valid() {
+            return Err(AcpiError::InvalidRootTable);
+        }
+        let mut acpi = Self::empty();
+        acpi.root = Some(root);
+        acpi.init();
+        Ok(acpi)
+    }
+
+    pub fn from_rsdp_v1(rsdp: &RsdpV1) -> Result<Self, AcpiError> {
+        if !rsdp.is_valid() {
+            return Err(AcpiError::InvalidRsdp);
+        }
+        Self::new(RootTable::Rsdt(unsafe { &*rsdp.rsdt_address().as_ptr() }))
+    }
+
+    /// Uses the xsdt, or the rsdt for acpi 1.0 firmware
+    pub fn from_rsdp_v2(rsdp: &RsdpV2) -> Result<Self, AcpiError> {
+        if !rsdp.is_valid() {
+            return Err(AcpiError::InvalidRsdp);
         }
+        let root = match rsdp.revision() >= 2 && !rsdp.xsdt_address().is_null() {
+            true => RootTable::Xsdt(unsafe { &*rsdp.xsdt_address().as_ptr() }),
+            false => RootTable::Rsdt(unsafe { &*rsdp.rsdt_address().as_ptr() }),
+        };
+        Self::new(root)
     }
 
-    pub fn init(&mut self) {
-        let entry_count = self.rsdt.total_entries();
-        for i in 0..entry_count {
-            // TODO parse all tables
-            let ptr = self.rsdt.entry(i).as_ptr::<AcpiSdtHeader>();
-            let header = unsafe { *ptr };
-            //kdbg!(header);
-            let signature = header.signature();
-            match signature {
-                "FACP" => self.fadt = Some(unsafe { &*self.rsdt.entry(i).as_ptr::<Fadt>() }),
-                "APIC" => self.madt_ptr = Some(self.rsdt.entry(i)),
-                "HPET" => {}
-                "WAET" => {}
-                _ => todo!(),
+    fn init(&mut self) {
+        let Some(root) = self.root else { return };
+        let mut count = 0;
+        for i in 0..root.total_entries() {
+            let address = root.entry(i);
+            let header = unsafe { &*address.as_ptr::<AcpiSdtHeader>() };
+            let length = header.length() as usize;
+            if length < core::mem::size_of::<AcpiSdtHeader>() || !header.is_valid() {
+                self.invalid += 1;
+                continue;
+            }
+            if count == MAX_TABLES {
+                self.invalid += 1;
+                continue;
+            }
+            self.tables[count] = Some(header);
+            count += 1;
+
+            match header.signature() {
+                "FACP" => self.fadt = Some(unsafe { &*address.as_ptr::<Fadt>() }),
+                "APIC" => self.madt_ptr = Some(address),
+                "HPET" => self.hpet = Some(unsafe { &*address.as_ptr::<Hpet>() }),
+                "MCFG" => self.mcfg = Some(unsafe { &*address.as_ptr::<Mcfg>() }),
+                _ => {}
             }
         }
     }
+
+    /// Every table with a valid checksum, in the order of the root table
+    pub fn tables(&self) -> impl Iterator<Item = &'static AcpiSdtHeader> + '_ {
+        self.tables.iter().map_while(|table| *table)
+    }
+
+    /// The first table with `signature`
+    pub fn find(&self, signature: &str) -> Option<&'static AcpiSdtHeader> {
+        self.find_all(signature).next()
+    }
+
+    /// Every table with `signature`, there can be more than one ssdt
+    pub fn find_all<'a>(
+        &'a self,
+        signature: &'a str,
+    ) -> impl Iterator<Item = &'static AcpiSdtHeader> + 'a {
+        self.tables()
+            .filter(move |table| table.signature() == signature)
+    }
+
+    /// Tables without a field in [`Acpi`], look them up with [`Acpi::find`]
+    pub fn unknown_tables(&self) -> impl Iterator<Item = &'static AcpiSdtHeader> + '_ {
+        self.tables()
+            .filter(|table| !KNOWN_SIGNATURES.contains(&table.signature()))
+    }
+
+    /// The dsdt the fadt points to, if its checksum is valid
+    pub fn dsdt(&self) -> Option<&'static AcpiSdtHeader> {
+        let dsdt = unsafe { &*self.fadt?.dsdt().as_ptr::<AcpiSdtHeader>() };
+        match dsdt.signature() == "DSDT" && dsdt.is_valid() {
+            true => Some(dsdt),
+            false => None,
+        }
+    }
+
+    /// How many tables were left out for a bad checksum or length
+    pub fn invalid_tables(&self) -> usize {
+        self.invalid
+    }
 }
 
 //spec version 1.0 - 5.2.5 https://uefi.org/sites/default/files/resources/ACPI_1.pdf
@@ -177,14 +366,137 @@ pub struct Fadt {
     reserved_2: u16,
     reserved_3: u8,
     flags: u32,
+
+    // from acpi 2.0, check the length before using these
+    reset_register: GenericAddressStructure,
+    reset_value: u8,
+    arm_boot_arch: u16,
+    minor_version: u8,
+    x_firmware_ctrl: u64,
+    x_dsdt: u64,
+    x_pm1a_event_block: GenericAddressStructure,
+    x_pm1b_event_block: GenericAddressStructure,
+    x_pm1a_control_block: GenericAddressStructure,
+    x_pm1b_control_block: GenericAddressStructure,
+}
+
+/// Address space ids of a [`GenericAddressStructure`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// The fadt flag saying the reset register is there
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+impl Fadt {
+    /// Does the table go up to the end of the field at `offset` with `size`
+    fn has(&self, offset: usize, size: usize) -> bool {
+        self.header.length as usize >= offset + size
+    }
+
+    /// The differentiated system description table
+    pub fn dsdt(&self) -> PhysicalAddress {
+        let x_dsdt = self.x_dsdt;
+        match self.has(offset_of!(Fadt, x_dsdt), 8) && x_dsdt != 0 {
+            true => PhysicalAddress::new(x_dsdt),
+            false => PhysicalAddress::new(self.dsdt.into()),
+        }
+    }
+
+    /// Port that switches between legacy and acpi mode
+    pub fn smi_command_port(&self) -> Option<u16> {
+        match self.smi_command_port {
+            0 => None,
+            port => Some(port as u16),
+        }
+    }
+
+    /// Written to the smi command port to enter acpi mode
+    pub fn acpi_enable(&self) -> u8 {
+        self.acpi_enable
+    }
+
+    /// Port of the pm1a control register, every fadt has one
+    pub fn pm1a_control_block(&self) -> Option<u16> {
+        let x_block = self.x_pm1a_control_block;
+        Self::io_block(self.pm1a_control_block, x_block, self.has_x_blocks())
+    }
+
+    /// Port of the pm1b control register, if the chipset splits it
+    pub fn pm1b_control_block(&self) -> Option<u16> {
+        let x_block = self.x_pm1b_control_block;
+        Self::io_block(self.pm1b_control_block, x_block, self.has_x_blocks())
+    }
+
+    fn has_x_blocks(&self) -> bool {
+        self.has(offset_of!(Fadt, x_pm1b_control_block), 12)
+    }
+
+    /// A register block in io space, the 64 bit one is only used if the legacy one is unset
+    fn io_block(block: u32, x_block: GenericAddressStructure, has_x: bool) -> Option<u16> {
+        match block {
+            0 if has_x
+                && x_block.address_space() == AddressSpace::SystemIo
+                && x_block.address() != 0 =>
+            {
+                Some(x_block.address() as u16)
+            }
+            0 => None,
+            port => Some(port as u16),
+        }
+    }
+
+    /// The register to write and the value that resets the machine
+    pub fn reset_register(&self) -> Option<(GenericAddressStructure, u8)> {
+        let supported = self.flags & FADT_RESET_REG_SUP != 0;
+        if !supported || !self.has(offset_of!(Fadt, reset_value), 1) {
+            return None;
+        }
+        Some((self.reset_register, self.reset_value))
+    }
+
+    /// Cmos register holding the century of the rtc, if there is one
+    pub fn century_register(&self) -> Option<u8> {
+        match self.century {
+            0 => None,
+            register => Some(register),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
-struct GenericAddressStructure {
+pub struct GenericAddressStructure {
     address_space: u8,
     bit_width: u8,
     bit_offset: u8,
     access_size: u8,
     address: u64,
 }
+
+impl GenericAddressStructure {
+    pub fn address_space(&self) -> AddressSpace {
+        match self.address_space {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            id => AddressSpace::Other(id),
+        }
+    }
+
+    pub fn bit_width(&self) -> u8 {
+        self.bit_width
+    }
+
+    pub fn bit_offset(&self) -> u8 {
+        self.bit_offset
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+}
@@ -0,0 +1,63 @@
+use bit_field::BitField;
+
+use super::acpi::{AcpiSdtHeader, GenericAddressStructure};
+
+// IA-PC HPET specification 1.0a - 3.2.4
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Hpet {
+    header: AcpiSdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddressStructure,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+impl Hpet {
+    pub fn header(&self) -> &AcpiSdtHeader {
+        &self.header
+    }
+
+    /// Where the registers are, should be in system memory
+    pub fn base_address(&self) -> GenericAddressStructure {
+        self.base_address
+    }
+
+    /// Sequence number of the timer block, for systems with more than one
+    pub fn number(&self) -> u8 {
+        self.hpet_number
+    }
+
+    /// Smallest number of main counter ticks a periodic timer can use without losing interrupts
+    pub fn minimum_tick(&self) -> u16 {
+        self.minimum_tick
+    }
+
+    pub fn hardware_revision(&self) -> u8 {
+        self.block_id().get_bits(0..8) as u8
+    }
+
+    /// Number of comparators in the first timer block
+    pub fn comparators(&self) -> u8 {
+        self.block_id().get_bits(8..13) as u8 + 1
+    }
+
+    pub fn counter_is_64bit(&self) -> bool {
+        self.block_id().get_bit(13)
+    }
+
+    /// Can the first two comparators replace the pit and rtc interrupts
+    pub fn legacy_replacement(&self) -> bool {
+        self.block_id().get_bit(15)
+    }
+
+    pub fn pci_vendor_id(&self) -> u16 {
+        self.block_id().get_bits(16..32) as u16
+    }
+
+    /// Copy of the id, the table is packed
+    fn block_id(&self) -> u32 {
+        self.event_timer_block_id
+    }
+}
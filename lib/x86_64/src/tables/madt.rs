@@ -2,16 +2,24 @@ use super::acpi::AcpiSdtHeader;
 use crate::PhysicalAddress;
 use derive_more::From;
 
+/// Most processors we keep track of, x2apic systems can have more than 255
+pub const MAX_PROCESSORS: usize = 1024;
+pub const MAX_IOAPICS: usize = 16;
+/// Overrides and nmi sources are only listed for isa irqs, so there are at most 16
+pub const MAX_ISA_ENTRIES: usize = 16;
+
 pub struct MultiAPIC {
     start_address: PhysicalAddress,
     madt: &'static Madt,
     index: u32,
     init: bool,
     // these are what we want
-    apic_ids: [Option<u8>; 256],
-    num_cores: u8,
+    apic_ids: [Option<u32>; MAX_PROCESSORS],
+    num_cores: u32,
     lapic_addr: PhysicalAddress,
-    ioapic_addr: PhysicalAddress,
+    ioapics: [Option<IoapicEntry>; MAX_IOAPICS],
+    overrides: [Option<IoapicIntSrcOverrideEntry>; MAX_ISA_ENTRIES],
+    nmi_sources: [Option<IoapicNonMaskIntSrcEntry>; MAX_ISA_ENTRIES],
 }
 
 impl MultiAPIC {
@@ -21,10 +29,12 @@ impl MultiAPIC {
             madt: unsafe { &*address.as_ptr::<Madt>() },
             index: 0,
             init: false,
-            apic_ids: [None; 256],
+            apic_ids: [None; MAX_PROCESSORS],
             num_cores: 0,
             lapic_addr: PhysicalAddress::new(0),
-            ioapic_addr: PhysicalAddress::new(0),
+            ioapics: [None; MAX_IOAPICS],
+            overrides: [None; MAX_ISA_ENTRIES],
+            nmi_sources: [None; MAX_ISA_ENTRIES],
         }
     }
 
@@ -51,21 +61,34 @@ impl MultiAPIC {
             match header.r#type {
                 MadtEntryType::Lapic => {
                     let lapic_entry = unsafe { *item.as_ptr::<LapicEntry>() };
-                    self.apic_ids[self.num_cores as usize] = Some(lapic_entry.acpi_id);
-                    self.num_cores += 1;
+                    self.add_processor(lapic_entry.acpi_id.into());
                 }
                 MadtEntryType::Ioapic => {
                     let ioapic_entry = unsafe { *item.as_ptr::<IoapicEntry>() };
-                    self.ioapic_addr = ioapic_entry.address();
+                    push(&mut self.ioapics, ioapic_entry);
+                }
+                MadtEntryType::IoapicIntSrcOverride => {
+                    let entry = unsafe { *item.as_ptr::<IoapicIntSrcOverrideEntry>() };
+                    push(&mut self.overrides, entry);
+                }
+                MadtEntryType::IoapicNonMaskIntSrc => {
+                    let entry = unsafe { *item.as_ptr::<IoapicNonMaskIntSrcEntry>() };
+                    push(&mut self.nmi_sources, entry);
                 }
-                MadtEntryType::IoapicIntSrcOverride => {}
-                MadtEntryType::IoapicNonMaskIntSrc => {}
                 MadtEntryType::LapicNonMaskInts => {}
                 MadtEntryType::LapicAddrOveride => {
                     let lapic_overide = unsafe { *item.as_ptr::<LapicAddrOverrideEntry>() };
                     self.lapic_addr = lapic_overide.address();
                 }
-                MadtEntryType::ProcLocalx2Apic => {}
+                MadtEntryType::ProcLocalx2Apic => {
+                    let x2apic_entry = unsafe { *item.as_ptr::<ProcLocalx2ApicEntry>() };
+                    // ids below 255 are usually also listed as a normal lapic entry
+                    if x2apic_entry.is_enabled()
+                        && !self.apic_ids().contains(&Some(x2apic_entry.id()))
+                    {
+                        self.add_processor(x2apic_entry.id());
+                    }
+                }
             }
 
             self.index += item_size;
@@ -73,14 +96,21 @@ impl MultiAPIC {
 
         self.init = true
     }
+
+    fn add_processor(&mut self, apic_id: u32) {
+        if let Some(slot) = self.apic_ids.get_mut(self.num_cores as usize) {
+            *slot = Some(apic_id);
+            self.num_cores += 1;
+        }
+    }
 }
 
 impl MultiAPIC {
-    pub fn apic_ids(&self) -> &[Option<u8>] {
-        &self.apic_ids
+    pub fn apic_ids(&self) -> &[Option<u32>] {
+        &self.apic_ids[..self.num_cores as usize]
     }
 
-    pub fn num_cores(&self) -> u8 {
+    pub fn num_cores(&self) -> u32 {
         self.num_cores
     }
 
@@ -88,8 +118,26 @@ impl MultiAPIC {
         self.lapic_addr
     }
 
-    pub fn ioapic_addr(&self) -> PhysicalAddress {
-        self.ioapic_addr
+    pub fn ioapics(&self) -> impl Iterator<Item = &IoapicEntry> {
+        self.ioapics.iter().flatten()
+    }
+
+    /// Isa irqs that are not identity mapped to a global system interrupt,
+    /// or have a different polarity or trigger mode than the bus
+    pub fn overrides(&self) -> impl Iterator<Item = &IoapicIntSrcOverrideEntry> {
+        self.overrides.iter().flatten()
+    }
+
+    /// Global system interrupts that should be delivered as nmis
+    pub fn nmi_sources(&self) -> impl Iterator<Item = &IoapicNonMaskIntSrcEntry> {
+        self.nmi_sources.iter().flatten()
+    }
+}
+
+/// Put `entry` in the first free slot, extra entries are dropped
+fn push<T>(slots: &mut [Option<T>], entry: T) {
+    if let Some(slot) = slots.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(entry);
     }
 }
 
@@ -146,9 +194,18 @@ pub struct IoapicEntry {
 }
 
 impl IoapicEntry {
+    pub fn id(&self) -> u8 {
+        self.io_apic_id
+    }
+
     pub fn address(&self) -> PhysicalAddress {
         self.io_apic_address.into()
     }
+
+    /// First global system interrupt handled by this io apic
+    pub fn gsi_base(&self) -> u32 {
+        self.global_system_interupts_base
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -161,6 +218,21 @@ pub struct IoapicIntSrcOverrideEntry {
     flags: u16,
 }
 
+impl IoapicIntSrcOverrideEntry {
+    /// The isa irq
+    pub fn source(&self) -> u8 {
+        self.irq_source
+    }
+
+    pub fn gsi(&self) -> u32 {
+        self.global_system_interrupt
+    }
+
+    pub fn flags(&self) -> IntiFlags {
+        IntiFlags(self.flags)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct IoapicNonMaskIntSrcEntry {
@@ -171,6 +243,52 @@ pub struct IoapicNonMaskIntSrcEntry {
     global_system_interrupt: u32,
 }
 
+impl IoapicNonMaskIntSrcEntry {
+    pub fn gsi(&self) -> u32 {
+        self.global_system_interrupt
+    }
+
+    pub fn flags(&self) -> IntiFlags {
+        IntiFlags(self.flags)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ConformsToBus,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    ConformsToBus,
+    Edge,
+    Level,
+}
+
+/// Polarity and trigger mode of an interrupt input, the mps inti flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntiFlags(pub u16);
+
+impl IntiFlags {
+    pub fn polarity(&self) -> Polarity {
+        match self.0 & 0b11 {
+            0b01 => Polarity::ActiveHigh,
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::ConformsToBus,
+        }
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        match (self.0 >> 2) & 0b11 {
+            0b01 => TriggerMode::Edge,
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::ConformsToBus,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct LapicNonMaskIntsEntry {
@@ -203,3 +321,13 @@ pub struct ProcLocalx2ApicEntry {
     flags: u32,
     acpi_id: u32,
 }
+
+impl ProcLocalx2ApicEntry {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.flags & 1 == 1
+    }
+}
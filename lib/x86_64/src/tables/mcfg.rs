@@ -0,0 +1,66 @@
+use core::mem::size_of;
+
+use super::acpi::AcpiSdtHeader;
+use crate::PhysicalAddress;
+
+// PCI Firmware Specification 3.0 - 4.1.2
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Mcfg {
+    header: AcpiSdtHeader,
+    _reserved: u64,
+}
+
+impl Mcfg {
+    pub fn header(&self) -> &AcpiSdtHeader {
+        &self.header
+    }
+
+    /// The config space regions, one per segment and bus range
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> + '_ {
+        let start = unsafe { (self as *const Self).add(1) as *const McfgEntry };
+        let count = (self.header.length() as usize).saturating_sub(size_of::<Self>())
+            / size_of::<McfgEntry>();
+        (0..count).map(move |i| unsafe { start.add(i).read_unaligned() })
+    }
+}
+
+/// Enhanced config space for a range of buses, every function gets 4KiB
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct McfgEntry {
+    base_address: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    _reserved: u32,
+}
+
+impl McfgEntry {
+    /// Where bus 0 of the segment would be, even if `start_bus` is higher
+    pub fn base_address(&self) -> PhysicalAddress {
+        PhysicalAddress::new(self.base_address)
+    }
+
+    pub fn segment(&self) -> u16 {
+        self.segment
+    }
+
+    pub fn start_bus(&self) -> u8 {
+        self.start_bus
+    }
+
+    pub fn end_bus(&self) -> u8 {
+        self.end_bus
+    }
+
+    /// Config space of a function, if its bus is in this region
+    pub fn address(&self, bus: u8, device: u8, function: u8) -> Option<PhysicalAddress> {
+        if !(self.start_bus..=self.end_bus).contains(&bus) {
+            return None;
+        }
+        let offset =
+            (bus as u64) << 20 | (device as u64 & 0x1F) << 15 | (function as u64 & 0x7) << 12;
+        Some(PhysicalAddress::new(self.base_address + offset))
+    }
+}
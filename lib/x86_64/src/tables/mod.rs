@@ -1,4 +1,7 @@
 pub mod acpi;
+pub mod hpet;
 pub mod madt;
+pub mod mcfg;
 pub mod multiproc;
 pub mod rsdp;
+pub mod smbios;
@@ -63,11 +63,17 @@ impl RsdpV2 {
         PhysicalAddress::new(self.xsdt_address)
     }
 
-    /// Validation using the checksum
+    /// 0 for acpi 1.0, the xsdt is only there from revision 2
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    /// Validation using the checksum of the version 1 part and the extended checksum
     pub fn is_valid(&self) -> bool {
         let bytes =
             unsafe { slice::from_raw_parts(self as *const _ as *const u8, mem::size_of::<Self>()) };
-        bytes.iter().fold(0u8, |acc, val| acc.wrapping_add(*val)) == 0
+        let sum = |bytes: &[u8]| bytes.iter().fold(0u8, |acc, val| acc.wrapping_add(*val));
+        sum(&bytes[..mem::size_of::<RsdpV1>()]) == 0 && sum(bytes) == 0
     }
 }
 
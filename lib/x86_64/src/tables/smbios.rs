@@ -0,0 +1,604 @@
+//! System management bios, the firmware's description of the machine.
+//!
+//! An entry point (`_SM_` for 2.x, `_SM3_` for 3.x) points to a table of structures. Each
+//! structure has a formatted area followed by a set of strings, the formatted area refers to
+//! them by a one based index and zero means no string.
+
+use crate::PhysicalAddress;
+use core::{mem, slice, str};
+
+/// Where legacy bios puts the entry point, on a 16 byte boundary
+const LEGACY_REGION: (u64, usize) = (0xF0000, 0x10000);
+
+const BIOS_INFO: u8 = 0;
+const SYSTEM_INFO: u8 = 1;
+const BASEBOARD_INFO: u8 = 2;
+const PROCESSOR_INFO: u8 = 4;
+const CACHE_INFO: u8 = 7;
+const MEMORY_DEVICE: u8 = 17;
+const MEMORY_ARRAY_MAPPED_ADDRESS: u8 = 19;
+const END_OF_TABLE: u8 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmbiosError {
+    /// No entry point anchor
+    NotFound,
+    /// An entry point with a bad checksum or length
+    InvalidEntryPoint,
+}
+
+/// The 2.x entry point
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct EntryPoint32 {
+    anchor: [u8; 4],
+    checksum: u8,
+    length: u8,
+    major: u8,
+    minor: u8,
+    max_structure_size: u16,
+    revision: u8,
+    _formatted: [u8; 5],
+    intermediate_anchor: [u8; 5],
+    intermediate_checksum: u8,
+    table_length: u16,
+    table_address: u32,
+    structure_count: u16,
+    bcd_revision: u8,
+}
+
+impl EntryPoint32 {
+    /// Validation using both checksums
+    pub fn is_valid(&self) -> bool {
+        let length = self.length as usize;
+        if &self.anchor != b"_SM_" || !(0x1E..=mem::size_of::<Self>()).contains(&length) {
+            return false;
+        }
+        let size = mem::size_of::<Self>();
+        let bytes = unsafe { slice::from_raw_parts(self as *const _ as *const u8, size) };
+        // the intermediate checksum covers the `_DMI_` part, which firmware with a length
+        // of 0x1E still has
+        &self.intermediate_anchor == b"_DMI_"
+            && checksum(&bytes[..length])
+            && checksum(&bytes[0x10..size])
+    }
+}
+
+/// The 3.x entry point, the table can be above 4GiB
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct EntryPoint64 {
+    anchor: [u8; 5],
+    checksum: u8,
+    length: u8,
+    major: u8,
+    minor: u8,
+    docrev: u8,
+    revision: u8,
+    _reserved: u8,
+    max_table_size: u32,
+    table_address: u64,
+}
+
+impl EntryPoint64 {
+    /// Validation using the checksum
+    pub fn is_valid(&self) -> bool {
+        let length = self.length as usize;
+        if &self.anchor != b"_SM3_" || length != mem::size_of::<Self>() {
+            return false;
+        }
+        let bytes = unsafe { slice::from_raw_parts(self as *const _ as *const u8, length) };
+        checksum(bytes)
+    }
+}
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |acc, val| acc.wrapping_add(*val)) == 0
+}
+
+/// The structure table and the version of the specification it follows
+#[derive(Debug, Clone, Copy)]
+pub struct Smbios {
+    major: u8,
+    minor: u8,
+    table: &'static [u8],
+    /// Only 2.x entry points count the structures
+    count: Option<u16>,
+}
+
+impl Smbios {
+    /// Parse an entry point of either version, like the copy in the multiboot smbios tag
+    pub fn from_entry_point(bytes: &[u8]) -> Result<Self, SmbiosError> {
+        if bytes.starts_with(b"_SM3_") && bytes.len() >= mem::size_of::<EntryPoint64>() {
+            let entry = unsafe { (bytes.as_ptr() as *const EntryPoint64).read_unaligned() };
+            if !entry.is_valid() {
+                return Err(SmbiosError::InvalidEntryPoint);
+            }
+            let address = PhysicalAddress::new(entry.table_address);
+            return Ok(Self {
+                major: entry.major,
+                minor: entry.minor,
+                table: unsafe { physical_slice(address, entry.max_table_size as usize) },
+                count: None,
+            });
+        }
+        if bytes.starts_with(b"_SM_") && bytes.len() >= 0x1F {
+            let mut copy = [0u8; mem::size_of::<EntryPoint32>()];
+            let length = copy.len().min(bytes.len());
+            copy[..length].copy_from_slice(&bytes[..length]);
+            let entry = unsafe { (copy.as_ptr() as *const EntryPoint32).read_unaligned() };
+            if !entry.is_valid() {
+                return Err(SmbiosError::InvalidEntryPoint);
+            }
+            let address = PhysicalAddress::new(entry.table_address.into());
+            return Ok(Self {
+                major: entry.major,
+                minor: entry.minor,
+                table: unsafe { physical_slice(address, entry.table_length as usize) },
+                count: Some(entry.structure_count),
+            });
+        }
+        Err(SmbiosError::NotFound)
+    }
+
+    /// Search the legacy bios area for an entry point, preferring the 3.x one
+    pub fn find_legacy() -> Result<Self, SmbiosError> {
+        let (start, length) = LEGACY_REGION;
+        let region = unsafe { physical_slice(PhysicalAddress::new(start), length) };
+        let mut found = Err(SmbiosError::NotFound);
+        for offset in (0..length).step_by(16) {
+            let candidate = &region[offset..];
+            if !candidate.starts_with(b"_SM") {
+                continue;
+            }
+            match Self::from_entry_point(candidate) {
+                Ok(smbios) if smbios.count.is_none() => return Ok(smbios),
+                Ok(smbios) => found = Ok(smbios),
+                Err(SmbiosError::InvalidEntryPoint) if found.is_err() => {
+                    found = Err(SmbiosError::InvalidEntryPoint)
+                }
+                Err(_) => {}
+            }
+        }
+        found
+    }
+
+    /// A structure table that is already in memory
+    pub fn from_table(major: u8, minor: u8, table: &'static [u8]) -> Self {
+        Self {
+            major,
+            minor,
+            table,
+            count: None,
+        }
+    }
+
+    /// Major and minor version
+    pub fn version(&self) -> (u8, u8) {
+        (self.major, self.minor)
+    }
+
+    pub fn structures(&self) -> Structures<'static> {
+        Structures {
+            table: self.table,
+            remaining: self.count,
+        }
+    }
+
+    /// Every structure of type `kind`
+    pub fn find(&self, kind: u8) -> impl Iterator<Item = Structure<'static>> {
+        self.structures()
+            .filter(move |structure| structure.kind == kind)
+    }
+
+    pub fn bios(&self) -> Option<BiosInfo<'static>> {
+        self.find(BIOS_INFO).find_map(|s| BiosInfo::new(&s))
+    }
+
+    pub fn system(&self) -> Option<SystemInfo<'static>> {
+        self.find(SYSTEM_INFO).find_map(|s| SystemInfo::new(&s))
+    }
+
+    pub fn baseboard(&self) -> Option<BaseboardInfo<'static>> {
+        self.find(BASEBOARD_INFO)
+            .find_map(|s| BaseboardInfo::new(&s))
+    }
+
+    pub fn processors(&self) -> impl Iterator<Item = ProcessorInfo<'static>> {
+        self.find(PROCESSOR_INFO)
+            .filter_map(|s| ProcessorInfo::new(&s))
+    }
+
+    pub fn caches(&self) -> impl Iterator<Item = CacheInfo<'static>> {
+        self.find(CACHE_INFO).filter_map(|s| CacheInfo::new(&s))
+    }
+
+    pub fn memory_devices(&self) -> impl Iterator<Item = MemoryDevice<'static>> {
+        self.find(MEMORY_DEVICE)
+            .filter_map(|s| MemoryDevice::new(&s))
+    }
+
+    /// The physical address ranges the memory arrays are mapped at
+    pub fn memory_ranges(&self) -> impl Iterator<Item = MemoryRange> {
+        self.find(MEMORY_ARRAY_MAPPED_ADDRESS)
+            .filter_map(|s| MemoryRange::new(&s))
+    }
+
+    /// Bytes of memory in every populated memory device
+    pub fn installed_memory(&self) -> u64 {
+        self.memory_devices().filter_map(|device| device.size).sum()
+    }
+}
+
+unsafe fn physical_slice(address: PhysicalAddress, length: usize) -> &'static [u8] {
+    slice::from_raw_parts(address.as_ptr::<u8>(), length)
+}
+
+/// A structure, its formatted area and its strings
+#[derive(Debug, Clone, Copy)]
+pub struct Structure<'a> {
+    pub kind: u8,
+    pub handle: u16,
+    formatted: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    /// The formatted area, header included
+    pub fn data(&self) -> &'a [u8] {
+        self.formatted
+    }
+
+    pub fn byte(&self, offset: usize) -> Option<u8> {
+        self.formatted.get(offset).copied()
+    }
+
+    pub fn word(&self, offset: usize) -> Option<u16> {
+        let bytes = self.formatted.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    pub fn dword(&self, offset: usize) -> Option<u32> {
+        let bytes = self.formatted.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    pub fn qword(&self, offset: usize) -> Option<u64> {
+        let bytes = self.formatted.get(offset..offset + 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    /// String `index` of the string set, one based
+    pub fn string(&self, index: u8) -> Option<&'a str> {
+        if index == 0 {
+            return None;
+        }
+        let string = self
+            .strings
+            .split(|byte| *byte == 0)
+            .take_while(|string| !string.is_empty())
+            .nth(index as usize - 1)?;
+        str::from_utf8(string).ok()
+    }
+
+    /// The string the byte at `offset` refers to
+    pub fn string_at(&self, offset: usize) -> Option<&'a str> {
+        self.string(self.byte(offset)?)
+    }
+}
+
+/// Walks the structure table up to the end of table structure
+#[derive(Debug, Clone)]
+pub struct Structures<'a> {
+    table: &'a [u8],
+    remaining: Option<u16>,
+}
+
+impl<'a> Structures<'a> {
+    pub fn new(table: &'a [u8]) -> Self {
+        Self {
+            table,
+            remaining: None,
+        }
+    }
+}
+
+impl<'a> Iterator for Structures<'a> {
+    type Item = Structure<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == Some(0) || self.table.len() < 4 {
+            return None;
+        }
+        let kind = self.table[0];
+        let length = self.table[1] as usize;
+        if kind == END_OF_TABLE || length < 4 || length > self.table.len() {
+            return None;
+        }
+        let handle = u16::from_le_bytes([self.table[2], self.table[3]]);
+        let (formatted, rest) = self.table.split_at(length);
+
+        // the string set ends with two nulls, an empty one is just the two nulls
+        let end = rest.windows(2).position(|pair| pair == [0, 0])? + 2;
+        self.table = &rest[end..];
+        self.remaining = self.remaining.map(|count| count - 1);
+        Some(Structure {
+            kind,
+            handle,
+            formatted,
+            strings: &rest[..end],
+        })
+    }
+}
+
+/// Type 0
+#[derive(Debug, Clone, Copy)]
+pub struct BiosInfo<'a> {
+    pub vendor: Option<&'a str>,
+    pub version: Option<&'a str>,
+    pub release_date: Option<&'a str>,
+    /// Size of the bios rom in KiB
+    pub rom_size: u64,
+    /// Major and minor release of the bios, when it says
+    pub release: Option<(u8, u8)>,
+}
+
+impl<'a> BiosInfo<'a> {
+    pub fn new(structure: &Structure<'a>) -> Option<Self> {
+        if structure.kind != BIOS_INFO {
+            return None;
+        }
+        let rom_size = match structure.byte(0x09)? {
+            // 16MiB or more is in the extended size, bits 14 and 15 are the unit
+            0xFF => match structure.word(0x18) {
+                Some(size) if size >> 14 == 1 => (size as u64 & 0x3FFF) * 1024 * 1024,
+                Some(size) => (size as u64 & 0x3FFF) * 1024,
+                None => 16 * 1024,
+            },
+            size => (size as u64 + 1) * 64,
+        };
+        let release = match (structure.byte(0x14), structure.byte(0x15)) {
+            (Some(0xFF), _) | (None, _) | (_, None) => None,
+            (Some(major), Some(minor)) => Some((major, minor)),
+        };
+        Some(Self {
+            vendor: structure.string_at(0x04),
+            version: structure.string_at(0x05),
+            release_date: structure.string_at(0x08),
+            rom_size,
+            release,
+        })
+    }
+}
+
+/// Type 1, what the machine is
+#[derive(Debug, Clone, Copy)]
+pub struct SystemInfo<'a> {
+    pub manufacturer: Option<&'a str>,
+    pub product: Option<&'a str>,
+    pub version: Option<&'a str>,
+    pub serial: Option<&'a str>,
+    pub uuid: Option<[u8; 16]>,
+    pub sku: Option<&'a str>,
+    pub family: Option<&'a str>,
+}
+
+impl<'a> SystemInfo<'a> {
+    pub fn new(structure: &Structure<'a>) -> Option<Self> {
+        if structure.kind != SYSTEM_INFO {
+            return None;
+        }
+        let uuid = structure
+            .data()
+            .get(0x08..0x18)
+            .and_then(|uuid| uuid.try_into().ok());
+        Some(Self {
+            manufacturer: structure.string_at(0x04),
+            product: structure.string_at(0x05),
+            version: structure.string_at(0x06),
+            serial: structure.string_at(0x07),
+            // all zeros is not set and all ones is not settable
+            uuid: uuid.filter(|uuid: &[u8; 16]| uuid != &[0; 16] && uuid != &[0xFF; 16]),
+            sku: structure.string_at(0x19),
+            family: structure.string_at(0x1A),
+        })
+    }
+}
+
+/// Type 2, the motherboard
+#[derive(Debug, Clone, Copy)]
+pub struct BaseboardInfo<'a> {
+    pub manufacturer: Option<&'a str>,
+    pub product: Option<&'a str>,
+    pub version: Option<&'a str>,
+    pub serial: Option<&'a str>,
+    pub asset_tag: Option<&'a str>,
+}
+
+impl<'a> BaseboardInfo<'a> {
+    pub fn new(structure: &Structure<'a>) -> Option<Self> {
+        if structure.kind != BASEBOARD_INFO {
+            return None;
+        }
+        Some(Self {
+            manufacturer: structure.string_at(0x04),
+            product: structure.string_at(0x05),
+            version: structure.string_at(0x06),
+            serial: structure.string_at(0x07),
+            asset_tag: structure.string_at(0x08),
+        })
+    }
+}
+
+/// Type 4, a processor socket
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessorInfo<'a> {
+    pub socket: Option<&'a str>,
+    pub manufacturer: Option<&'a str>,
+    pub version: Option<&'a str>,
+    /// Cpuid leaf 1 eax and edx
+    pub id: u64,
+    /// Speeds in MHz, zero when unknown
+    pub max_speed: u16,
+    pub current_speed: u16,
+    pub populated: bool,
+    /// Counts are zero when unknown
+    pub cores: u16,
+    pub threads: u16,
+    /// Handles of the cache structures
+    pub l1_cache: Option<u16>,
+    pub l2_cache: Option<u16>,
+    pub l3_cache: Option<u16>,
+}
+
+impl<'a> ProcessorInfo<'a> {
+    pub fn new(structure: &Structure<'a>) -> Option<Self> {
+        if structure.kind != PROCESSOR_INFO {
+            return None;
+        }
+        // 0xFF in the byte counts means the word count from 3.0 has it
+        let count = |byte: usize, word: usize| match structure.byte(byte) {
+            Some(0xFF) => structure.word(word).unwrap_or(0xFF),
+            count => count.unwrap_or(0) as u16,
+        };
+        let cache = |offset: usize| structure.word(offset).filter(|handle| *handle != 0xFFFF);
+        Some(Self {
+            socket: structure.string_at(0x04),
+            manufacturer: structure.string_at(0x07),
+            version: structure.string_at(0x10),
+            id: structure.qword(0x08).unwrap_or(0),
+            max_speed: structure.word(0x14).unwrap_or(0),
+            current_speed: structure.word(0x16).unwrap_or(0),
+            populated: structure.byte(0x18).unwrap_or(0) & (1 << 6) != 0,
+            cores: count(0x23, 0x2A),
+            threads: count(0x25, 0x2E),
+            l1_cache: cache(0x1A),
+            l2_cache: cache(0x1C),
+            l3_cache: cache(0x1E),
+        })
+    }
+}
+
+/// Type 7
+#[derive(Debug, Clone, Copy)]
+pub struct CacheInfo<'a> {
+    pub handle: u16,
+    pub designation: Option<&'a str>,
+    /// One for l1
+    pub level: u8,
+    pub enabled: bool,
+    /// Installed size in bytes
+    pub size: u64,
+    pub max_size: u64,
+}
+
+impl<'a> CacheInfo<'a> {
+    pub fn new(structure: &Structure<'a>) -> Option<Self> {
+        if structure.kind != CACHE_INFO {
+            return None;
+        }
+        let configuration = structure.word(0x05)?;
+        // the 2.1 sizes are words, 3.1 added dwords for caches of 2GiB and more
+        let size = |word: usize, dword: usize| {
+            let size = structure.word(word).unwrap_or(0) as u64;
+            let granularity = if size & (1 << 15) != 0 { 64 } else { 1 };
+            match structure.dword(dword) {
+                Some(size) if size & 0x7FFF_FFFF > 0x7FFF => {
+                    let granularity = if size & (1 << 31) != 0 { 64 } else { 1 };
+                    (size as u64 & 0x7FFF_FFFF) * granularity * 1024
+                }
+                _ => (size & 0x7FFF) * granularity * 1024,
+            }
+        };
+        Some(Self {
+            handle: structure.handle,
+            designation: structure.string_at(0x04),
+            level: (configuration & 0b111) as u8 + 1,
+            enabled: configuration & (1 << 7) != 0,
+            size: size(0x09, 0x17),
+            max_size: size(0x07, 0x13),
+        })
+    }
+}
+
+/// Type 17, a memory module slot
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryDevice<'a> {
+    pub locator: Option<&'a str>,
+    pub bank_locator: Option<&'a str>,
+    /// Bytes, none if the slot is empty or the size is unknown
+    pub size: Option<u64>,
+    pub form_factor: u8,
+    pub memory_type: u8,
+    /// Mega transfers a second, zero when unknown
+    pub speed: u16,
+    pub manufacturer: Option<&'a str>,
+    pub serial: Option<&'a str>,
+    pub part_number: Option<&'a str>,
+}
+
+impl<'a> MemoryDevice<'a> {
+    pub fn new(structure: &Structure<'a>) -> Option<Self> {
+        if structure.kind != MEMORY_DEVICE {
+            return None;
+        }
+        let size = match structure.word(0x0C)? {
+            0 | 0xFFFF => None,
+            // 32GiB or more is in the extended size, in MiB
+            0x7FFF => structure
+                .dword(0x1C)
+                .map(|size| (size as u64 & 0x7FFF_FFFF) << 20),
+            // bit 15 says the unit is KiB instead of MiB
+            size if size & (1 << 15) != 0 => Some((size as u64 & 0x7FFF) << 10),
+            size => Some((size as u64) << 20),
+        };
+        Some(Self {
+            locator: structure.string_at(0x10),
+            bank_locator: structure.string_at(0x11),
+            size,
+            form_factor: structure.byte(0x0E).unwrap_or(0),
+            memory_type: structure.byte(0x12).unwrap_or(0),
+            speed: structure.word(0x15).unwrap_or(0),
+            manufacturer: structure.string_at(0x17),
+            serial: structure.string_at(0x18),
+            part_number: structure.string_at(0x1A),
+        })
+    }
+}
+
+/// Type 19, physical addresses a memory array is mapped at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRange {
+    pub start: PhysicalAddress,
+    /// Inclusive
+    pub end: PhysicalAddress,
+    /// Handle of the memory array
+    pub array: u16,
+}
+
+impl MemoryRange {
+    pub fn new(structure: &Structure) -> Option<Self> {
+        if structure.kind != MEMORY_ARRAY_MAPPED_ADDRESS {
+            return None;
+        }
+        // the starting and ending addresses are in KiB, all ones moves them to the
+        // extended addresses in bytes
+        let (start, end) = match structure.dword(0x04)? {
+            0xFFFF_FFFF => (structure.qword(0x0F)?, structure.qword(0x17)?),
+            start => (
+                (start as u64) << 10,
+                ((structure.dword(0x08)? as u64) << 10) | 0x3FF,
+            ),
+        };
+        Some(Self {
+            start: PhysicalAddress::new(start),
+            end: PhysicalAddress::new(end),
+            array: structure.word(0x0C)?,
+        })
+    }
+
+    /// Bytes in the range
+    pub fn size(&self) -> u64 {
+        u64::from(self.end) - u64::from(self.start) + 1
+    }
+}
@@ -0,0 +1,69 @@
+use core::arch::asm;
+
+use crate::cpuid::{cpuid, max_extended_leaf};
+
+/// Read the time stamp counter
+pub fn rdtsc() -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags))
+    };
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Read the time stamp counter after every earlier instruction finished,
+/// `rdtsc` alone can be executed before the code it is meant to time
+pub fn rdtsc_ordered() -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!(
+            "lfence",
+            "rdtsc",
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags),
+        )
+    };
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Read the time stamp counter and IA32_TSC_AUX (usually the cpu number) atomically,
+/// waits for earlier instructions like `rdtsc_ordered`
+pub fn rdtscp() -> (u64, u32) {
+    let (high, low, aux): (u32, u32, u32);
+    unsafe {
+        asm!(
+            "rdtscp",
+            out("eax") low,
+            out("edx") high,
+            out("ecx") aux,
+            options(nomem, nostack, preserves_flags),
+        )
+    };
+    (((high as u64) << 32) | (low as u64), aux)
+}
+
+/// Is `rdtscp` supported
+pub fn has_rdtscp() -> bool {
+    max_extended_leaf() >= 0x8000_0001 && cpuid(0x8000_0001, 0).edx & (1 << 27) != 0
+}
+
+/// Does the counter run at a constant rate in every power state,
+/// only then it can be used to measure time
+pub fn is_invariant() -> bool {
+    max_extended_leaf() >= 0x8000_0007 && cpuid(0x8000_0007, 0).edx & (1 << 8) != 0
+}
+
+/// Frequency in Hz from the core crystal clock leaf, if the cpu reports it
+pub fn cpuid_frequency() -> Option<u64> {
+    if cpuid(0, 0).eax < 0x15 {
+        return None;
+    }
+
+    // tsc hz = crystal hz * ebx / eax
+    let leaf = cpuid(0x15, 0);
+    if leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0 {
+        return None;
+    }
+    Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64)
+}
@@ -7,6 +7,13 @@ pub const HEAP_SIZE: u64 = 2 * SIZE_1MIB; // 2 MiB
 // KHEAP
 pub const KHEAP_START: u64 = HEAP_START + HEAP_SIZE;
 
+// USER
+pub const USER_START: u64 = 0x4000_0000_0000; // first address of user space, p4 entry 128
+pub const USER_END: u64 = 0x8000_0000_0000; // end of the lower canonical half, the kernel is above
+
+// CPU's
+pub const MAX_CPUS: usize = x86_64::tables::madt::MAX_PROCESSORS; // every cpu the madt can list
+
 // IRQ's
 pub const IRQ_0: u8 = 32;
 
@@ -0,0 +1,300 @@
+//! Registers of the host bus adapter and its ports, and the structures they read from memory.
+//!
+//! Serial ATA AHCI 1.3.1 - 3 and 4
+
+use bit_field::BitField;
+use bitflags::bitflags;
+
+/// Generic host control
+pub const CAP: usize = 0x00;
+pub const GHC: usize = 0x04;
+pub const IS: usize = 0x08;
+pub const PI: usize = 0x0C;
+pub const VS: usize = 0x10;
+
+/// Port registers, relative to the port
+pub const PX_CLB: usize = 0x00;
+pub const PX_CLBU: usize = 0x04;
+pub const PX_FB: usize = 0x08;
+pub const PX_FBU: usize = 0x0C;
+pub const PX_IS: usize = 0x10;
+pub const PX_IE: usize = 0x14;
+pub const PX_CMD: usize = 0x18;
+pub const PX_TFD: usize = 0x20;
+pub const PX_SIG: usize = 0x24;
+pub const PX_SSTS: usize = 0x28;
+pub const PX_SERR: usize = 0x30;
+pub const PX_SACT: usize = 0x34;
+pub const PX_CI: usize = 0x38;
+
+/// Where the ports start and how far apart they are
+const PORTS: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+
+/// The signature of a sata disk, atapi and port multipliers have others
+pub const SATA_SIGNATURE: u32 = 0x0000_0101;
+
+bitflags! {
+    pub struct Capabilities: u32 {
+        const STAGGERED_SPIN_UP = 1 << 27;
+        const NATIVE_COMMAND_QUEUING = 1 << 30;
+        const ADDRESSING_64BIT = 1 << 31;
+    }
+}
+
+bitflags! {
+    pub struct GlobalControl: u32 {
+        const RESET = 1 << 0;
+        const INTERRUPT_ENABLE = 1 << 1;
+        const AHCI_ENABLE = 1 << 31;
+    }
+}
+
+bitflags! {
+    pub struct PortCommand: u32 {
+        const START = 1 << 0;
+        const SPIN_UP = 1 << 1;
+        const POWER_ON = 1 << 2;
+        const FIS_RECEIVE_ENABLE = 1 << 4;
+        const FIS_RECEIVE_RUNNING = 1 << 14;
+        const COMMAND_LIST_RUNNING = 1 << 15;
+    }
+}
+
+bitflags! {
+    pub struct PortInterrupt: u32 {
+        const D2H_REGISTER_FIS = 1 << 0;
+        const PIO_SETUP_FIS = 1 << 1;
+        const DMA_SETUP_FIS = 1 << 2;
+        const SET_DEVICE_BITS = 1 << 3;
+        const INTERFACE_FATAL = 1 << 27;
+        const HOST_BUS_DATA = 1 << 28;
+        const HOST_BUS_FATAL = 1 << 29;
+        const TASK_FILE_ERROR = 1 << 30;
+    }
+}
+
+/// Task file status bits
+pub const STATUS_ERROR: u8 = 1 << 0;
+pub const STATUS_DRQ: u8 = 1 << 3;
+pub const STATUS_BUSY: u8 = 1 << 7;
+
+/// The memory mapped registers of a host bus adapter
+#[derive(Debug, Clone, Copy)]
+pub struct Hba {
+    base: *mut u32,
+}
+
+// the registers are only reached through the mapping of physical memory
+unsafe impl Send for Hba {}
+unsafe impl Sync for Hba {}
+
+impl Hba {
+    /// # Safety
+    /// `base` must point at the registers of a host bus adapter
+    pub unsafe fn new(base: *mut u32) -> Self {
+        Self { base }
+    }
+
+    /// Where the registers are mapped
+    pub fn address(&self) -> usize {
+        self.base as usize
+    }
+
+    pub fn read(&self, offset: usize) -> u32 {
+        unsafe { self.base.add(offset / 4).read_volatile() }
+    }
+
+    pub fn write(&self, offset: usize, value: u32) {
+        unsafe { self.base.add(offset / 4).write_volatile(value) }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_bits_truncate(self.read(CAP))
+    }
+
+    /// Number of command slots in each port
+    pub fn command_slots(&self) -> u32 {
+        self.read(CAP).get_bits(8..13) + 1
+    }
+
+    pub fn control(&self) -> GlobalControl {
+        GlobalControl::from_bits_truncate(self.read(GHC))
+    }
+
+    pub fn set_control(&self, control: GlobalControl) {
+        self.write(GHC, control.bits())
+    }
+
+    /// Bit set for each port that exists
+    pub fn ports_implemented(&self) -> u32 {
+        self.read(PI)
+    }
+
+    /// Major and minor version
+    pub fn version(&self) -> (u16, u16) {
+        let version = self.read(VS);
+        ((version >> 16) as u16, version as u16)
+    }
+
+    pub fn port(&self, index: usize) -> HbaPort {
+        HbaPort {
+            hba: *self,
+            offset: PORTS + index * PORT_SIZE,
+        }
+    }
+}
+
+/// The registers of one port
+#[derive(Debug, Clone, Copy)]
+pub struct HbaPort {
+    hba: Hba,
+    offset: usize,
+}
+
+impl HbaPort {
+    pub fn read(&self, register: usize) -> u32 {
+        self.hba.read(self.offset + register)
+    }
+
+    pub fn write(&self, register: usize, value: u32) {
+        self.hba.write(self.offset + register, value)
+    }
+
+    pub fn command(&self) -> PortCommand {
+        PortCommand::from_bits_truncate(self.read(PX_CMD))
+    }
+
+    pub fn set_command(&self, command: PortCommand) {
+        // keep the bits we have no names for
+        let other = self.read(PX_CMD) & !PortCommand::all().bits();
+        self.write(PX_CMD, other | command.bits())
+    }
+
+    /// A device is there and the phy is talking to it
+    pub fn is_present(&self) -> bool {
+        let status = self.read(PX_SSTS);
+        status.get_bits(0..4) == 3 && status.get_bits(8..12) == 1
+    }
+
+    pub fn signature(&self) -> u32 {
+        self.read(PX_SIG)
+    }
+
+    /// The status and error registers of the task file
+    pub fn task_file(&self) -> (u8, u8) {
+        let tfd = self.read(PX_TFD);
+        (tfd as u8, (tfd >> 8) as u8)
+    }
+
+    /// Write one to clear interrupt and error bits
+    pub fn clear_errors(&self) {
+        self.write(PX_SERR, u32::MAX);
+        self.write(PX_IS, u32::MAX);
+    }
+}
+
+/// An entry of the command list
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct CommandHeader {
+    /// Fis length in dwords, write, prefetchable and the number of prdt entries
+    pub flags: u32,
+    /// Bytes transferred, written by the hba
+    pub transferred: u32,
+    pub table: u64,
+    _reserved: [u32; 4],
+}
+
+impl CommandHeader {
+    pub fn new(table: u64, fis_dwords: u32, write: bool, prdt_entries: u16) -> Self {
+        let mut flags = fis_dwords;
+        flags.set_bit(6, write);
+        flags.set_bits(16..32, prdt_entries as u32);
+        Self {
+            flags,
+            transferred: 0,
+            table,
+            _reserved: [0; 4],
+        }
+    }
+}
+
+/// A region of memory to transfer, at most 4MiB
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct PrdtEntry {
+    pub address: u64,
+    _reserved: u32,
+    /// Byte count minus one
+    pub count: u32,
+}
+
+impl PrdtEntry {
+    pub fn new(address: u64, bytes: u32) -> Self {
+        Self {
+            address,
+            _reserved: 0,
+            count: bytes - 1,
+        }
+    }
+}
+
+/// Where the command fis goes in a command table, the prdt follows at 0x80
+pub const PRDT_OFFSET: usize = 0x80;
+
+/// A host to device register fis, in dwords
+pub const H2D_FIS_DWORDS: u32 = 5;
+
+/// Build a host to device register fis carrying an ata command
+pub fn h2d_fis(command: u8, features: u16, lba: u64, count: u16, device: u8) -> [u8; 20] {
+    let lba = lba.to_le_bytes();
+    let features = features.to_le_bytes();
+    let count = count.to_le_bytes();
+    [
+        0x27, // register fis, host to device
+        0x80, // this is a command, not a control update
+        command,
+        features[0],
+        lba[0],
+        lba[1],
+        lba[2],
+        device,
+        lba[3],
+        lba[4],
+        lba[5],
+        features[1],
+        count[0],
+        count[1],
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+
+    #[test_case]
+    fn layouts() {
+        assert_eq!(size_of::<CommandHeader>(), 32);
+        assert_eq!(size_of::<PrdtEntry>(), 16);
+
+        let header = CommandHeader::new(0x1000, H2D_FIS_DWORDS, true, 3);
+        assert_eq!(header.flags, 0x0003_0045);
+    }
+
+    #[test_case]
+    fn fis() {
+        // read fpdma queued of 8 sectors at 0x123456789A with tag 5
+        let fis = h2d_fis(0x60, 8, 0x12_3456_789A, 5 << 3, 0x40);
+        assert_eq!(fis[..4], [0x27, 0x80, 0x60, 8]);
+        assert_eq!(fis[4..8], [0x9A, 0x78, 0x56, 0x40]);
+        assert_eq!(fis[8..14], [0x34, 0x12, 0, 0, 5 << 3, 0]);
+    }
+}
@@ -0,0 +1,558 @@
+//! Sata disks behind an ahci host bus adapter.
+//!
+//! Each port with a disk gets a command list, a received fis area and a command table per
+//! slot in dma memory, and is registered as a block device. Reads and writes use native
+//! command queuing when the disk and the adapter both have it. Completion is signalled with
+//! msi, adapters without it are polled.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering};
+use spin::RwLock;
+
+use crate::interrupts::{self, trap::TrapFrame, vector};
+use crate::memory::dma::{physical_ranges, DmaBuffer};
+use crate::pci::device::Bar;
+use crate::pci::msi::Msi;
+use crate::pci::{DeviceMatch, PciDevice, PciDriver};
+use crate::time::tsc;
+
+use super::block::{self, BlockDevice, DiskError};
+use hba::*;
+
+mod hba;
+
+/// Mass storage, sata, ahci 1.0
+pub static DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    matches: &[DeviceMatch::Class {
+        class: 0x01,
+        subclass: 0x06,
+        prog_if: Some(0x01),
+    }],
+    probe,
+};
+
+/// Most adapters the interrupt handler looks at
+const MAX_CONTROLLERS: usize = 4;
+/// Register addresses of the adapters with interrupts, zero for a free entry
+static CONTROLLERS: [AtomicUsize; MAX_CONTROLLERS] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+/// Adapters probed so far, for naming the disks
+static PROBED: AtomicUsize = AtomicUsize::new(0);
+
+const COMMAND_TIMEOUT: u64 = 5_000_000_000;
+const RESET_TIMEOUT: u64 = 1_000_000_000;
+const PORT_TIMEOUT: u64 = 500_000_000;
+
+const PAGE_SIZE: usize = 4096;
+/// Data regions per command
+const PRDT_ENTRIES: usize = 16;
+const TABLE_SIZE: usize = PRDT_OFFSET + PRDT_ENTRIES * 16;
+/// A buffer this long touches at most `PRDT_ENTRIES` pages, however it is aligned
+const MAX_TRANSFER: usize = (PRDT_ENTRIES - 1) * PAGE_SIZE;
+
+const ATA_IDENTIFY: u8 = 0xEC;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_WRITE_FPDMA_QUEUED: u8 = 0x61;
+/// Device register, the address is an lba
+const DEVICE_LBA: u8 = 1 << 6;
+
+fn probe(device: &PciDevice) -> bool {
+    let Some(Bar::Memory { address, .. }) = device.bar(5) else {
+        return false;
+    };
+    device.enable();
+    let hba = unsafe { Hba::new(address.as_mut_ptr()) };
+    let controller = PROBED.fetch_add(1, Ordering::SeqCst);
+
+    if let Err(error) = reset(hba) {
+        crate::kprintln!("AHCI: {} reset failed, {:?}", device.address, error);
+        return false;
+    }
+    let interrupts = enable_interrupts(device, hba);
+    let (major, minor) = hba.version();
+    crate::kprintln!(
+        "AHCI: {} version {}.{:x}, {} slots{}",
+        device.address,
+        major,
+        minor >> 8,
+        hba.command_slots(),
+        if interrupts { ", msi" } else { ", polled" }
+    );
+
+    let ports = hba.ports_implemented();
+    for index in (0..32).filter(|index| ports & (1 << index) != 0) {
+        let name = format!("ahci{}p{}", controller, index);
+        match AhciDisk::new(hba, index, interrupts, name) {
+            Ok(Some(disk)) => {
+                crate::kprintln!(
+                    "AHCI: {} {}, {} MiB{}",
+                    disk.name,
+                    disk.model,
+                    (disk.sectors * disk.sector_size as u64) >> 20,
+                    if disk.queued { ", ncq" } else { "" }
+                );
+                if let Err(error) = block::register(Arc::new(disk)) {
+                    crate::kprintln!("AHCI: port {} not added, {:?}", index, error);
+                }
+            }
+            Ok(None) => {}
+            Err(error) => crate::kprintln!("AHCI: port {} failed, {:?}", index, error),
+        }
+    }
+    true
+}
+
+/// Spin until `done` or `nanos` pass
+fn wait_for(nanos: u64, mut done: impl FnMut() -> bool) -> Result<(), DiskError> {
+    let deadline = tsc::cycles() + tsc::nanos_to_cycles(nanos);
+    while !done() {
+        if tsc::cycles() > deadline {
+            return Err(DiskError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Reset the adapter and put it in ahci mode
+fn reset(hba: Hba) -> Result<(), DiskError> {
+    hba.set_control(GlobalControl::AHCI_ENABLE);
+    hba.set_control(GlobalControl::AHCI_ENABLE | GlobalControl::RESET);
+    wait_for(RESET_TIMEOUT, || {
+        !hba.control().contains(GlobalControl::RESET)
+    })?;
+    // the reset can clear the enable bit
+    hba.set_control(GlobalControl::AHCI_ENABLE);
+    Ok(())
+}
+
+/// Route the adapter's interrupt to an allocated vector, false if it has to be polled
+fn enable_interrupts(device: &PciDevice, hba: Hba) -> bool {
+    let Some(slot) = CONTROLLERS.iter().find(|slot| {
+        slot.compare_exchange(0, hba.address(), Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }) else {
+        return false;
+    };
+    let Some(vector) = vector::allocate(interrupt) else {
+        slot.store(0, Ordering::SeqCst);
+        return false;
+    };
+    if Msi::new(device).and_then(|msi| msi.enable(vector)).is_err() {
+        vector::free(vector);
+        slot.store(0, Ordering::SeqCst);
+        return false;
+    }
+    hba.write(IS, u32::MAX);
+    hba.set_control(GlobalControl::AHCI_ENABLE | GlobalControl::INTERRUPT_ENABLE);
+    true
+}
+
+/// Acknowledge every port that raised an interrupt, the waiters look at the registers
+fn interrupt(_frame: &mut TrapFrame) {
+    for controller in CONTROLLERS.iter() {
+        let base = controller.load(Ordering::SeqCst);
+        if base == 0 {
+            continue;
+        }
+        let hba = unsafe { Hba::new(base as *mut u32) };
+        let pending = hba.read(IS);
+        for index in (0..32).filter(|index| pending & (1 << index) != 0) {
+            let port = hba.port(index);
+            port.write(PX_IS, port.read(PX_IS));
+        }
+        hba.write(IS, pending);
+    }
+}
+
+/// A sata disk on a port
+pub struct AhciDisk {
+    name: String,
+    model: String,
+    port: HbaPort,
+    sectors: u64,
+    sector_size: usize,
+    /// Reads and writes are queued commands
+    queued: bool,
+    /// Slots we use, fewer than the adapter has if the queue of the disk is shorter
+    slots: u32,
+    busy: AtomicU32,
+    /// Slots whose command was lost to an error another slot caused
+    failed: AtomicU32,
+    interrupts: bool,
+    /// Held shared for reads and writes, commands that can not be queued need the port alone
+    exclusive: RwLock<()>,
+    commands: DmaBuffer,
+    tables: DmaBuffer,
+    _received: DmaBuffer,
+}
+
+impl AhciDisk {
+    /// Start port `index`, none if there is no sata disk on it
+    fn new(
+        hba: Hba,
+        index: usize,
+        interrupts: bool,
+        name: String,
+    ) -> Result<Option<Self>, DiskError> {
+        let port = hba.port(index);
+        let slots = hba.command_slots();
+        let commands = DmaBuffer::new(32 * 32, 1024).ok_or(DiskError::OutOfMemory)?;
+        let received = DmaBuffer::new(256, 256).ok_or(DiskError::OutOfMemory)?;
+        let tables =
+            DmaBuffer::new(slots as usize * TABLE_SIZE, 128).ok_or(DiskError::OutOfMemory)?;
+
+        stop(port)?;
+        let (list, fis) = (
+            u64::from(commands.physical()),
+            u64::from(received.physical()),
+        );
+        port.write(PX_CLB, list as u32);
+        port.write(PX_CLBU, (list >> 32) as u32);
+        port.write(PX_FB, fis as u32);
+        port.write(PX_FBU, (fis >> 32) as u32);
+        port.set_command(port.command() | PortCommand::FIS_RECEIVE_ENABLE);
+        if hba.capabilities().contains(Capabilities::STAGGERED_SPIN_UP) {
+            port.set_command(port.command() | PortCommand::SPIN_UP | PortCommand::POWER_ON);
+        }
+
+        // a port without a disk never comes up
+        if wait_for(PORT_TIMEOUT, || port.is_present()).is_err()
+            || port.signature() != SATA_SIGNATURE
+        {
+            stop(port)?;
+            return Ok(None);
+        }
+        port.clear_errors();
+        wait_for(COMMAND_TIMEOUT, || {
+            port.task_file().0 & (STATUS_BUSY | STATUS_DRQ) == 0
+        })?;
+        if interrupts {
+            let enabled = PortInterrupt::D2H_REGISTER_FIS
+                | PortInterrupt::SET_DEVICE_BITS
+                | PortInterrupt::INTERFACE_FATAL
+                | PortInterrupt::HOST_BUS_DATA
+                | PortInterrupt::HOST_BUS_FATAL
+                | PortInterrupt::TASK_FILE_ERROR;
+            port.write(PX_IE, enabled.bits());
+        }
+        port.set_command(port.command() | PortCommand::START);
+
+        let mut disk = Self {
+            name,
+            model: String::new(),
+            port,
+            sectors: 0,
+            sector_size: 512,
+            queued: false,
+            slots,
+            busy: AtomicU32::new(0),
+            failed: AtomicU32::new(0),
+            interrupts,
+            exclusive: RwLock::new(()),
+            commands,
+            tables,
+            _received: received,
+        };
+        disk.identify(hba.capabilities())?;
+        Ok(Some(disk))
+    }
+
+    /// Ask the disk its size and features
+    fn identify(&mut self, capabilities: Capabilities) -> Result<(), DiskError> {
+        let identify = DmaBuffer::new(512, 2).ok_or(DiskError::OutOfMemory)?;
+        let fis = |_| h2d_fis(ATA_IDENTIFY, 0, 0, 0, 0);
+        self.issue(fis, identify.as_mut_ptr(), 512, false, false)?;
+
+        let words = unsafe { core::slice::from_raw_parts(identify.as_ptr::<u16>(), 256) };
+        let model: String = words[27..47]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .map(char::from)
+            .collect();
+        self.model = String::from(model.trim());
+
+        let lba48 = words[83] & (1 << 10) != 0;
+        self.sectors = match lba48 {
+            true => (0..4).fold(0, |sectors, i| {
+                sectors | (words[100 + i] as u64) << (16 * i)
+            }),
+            false => words[60] as u64 | (words[61] as u64) << 16,
+        };
+        // word 106 is valid with bit 14 set and 15 clear, bit 12 says there is a size
+        if words[106] & 0xD000 == 0x5000 {
+            let size = words[117] as usize | (words[118] as usize) << 16;
+            self.sector_size = size * 2;
+        }
+        if words[76] & (1 << 8) != 0 && capabilities.contains(Capabilities::NATIVE_COMMAND_QUEUING)
+        {
+            self.queued = true;
+            self.slots = self.slots.min((words[75] & 0x1F) as u32 + 1);
+        }
+        Ok(())
+    }
+
+    /// Claim a free command slot, spinning until one is
+    fn take_slot(&self) -> u32 {
+        loop {
+            let busy = self.busy.load(Ordering::SeqCst);
+            let slot = (!busy).trailing_zeros();
+            if slot >= self.slots {
+                core::hint::spin_loop();
+                continue;
+            }
+            let taken = busy | 1 << slot;
+            if self
+                .busy
+                .compare_exchange(busy, taken, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return slot;
+            }
+        }
+    }
+
+    /// Run the command `fis` builds for a slot, with `len` bytes at `data`, and wait for it
+    fn issue(
+        &self,
+        fis: impl Fn(u32) -> [u8; 20],
+        data: *mut u8,
+        len: usize,
+        write: bool,
+        queued: bool,
+    ) -> Result<(), DiskError> {
+        if !(data as usize).is_multiple_of(2) {
+            return Err(DiskError::Unaligned);
+        }
+        let slot = self.take_slot();
+        let table = unsafe {
+            self.tables
+                .as_mut_ptr::<u8>()
+                .add(slot as usize * TABLE_SIZE)
+        };
+        let table_address = u64::from(self.tables.physical()) + (slot as usize * TABLE_SIZE) as u64;
+
+        let command = fis(slot);
+        let entries = unsafe {
+            core::ptr::write_bytes(table, 0, PRDT_OFFSET);
+            core::ptr::copy_nonoverlapping(command.as_ptr(), table, command.len());
+            let prdt = table.add(PRDT_OFFSET) as *mut PrdtEntry;
+            fill_prdt(
+                core::slice::from_raw_parts_mut(prdt, PRDT_ENTRIES),
+                data,
+                len,
+            )
+        };
+        let header = CommandHeader::new(table_address, H2D_FIS_DWORDS, write, entries as u16);
+        unsafe {
+            let headers = self.commands.as_mut_ptr::<CommandHeader>();
+            headers.add(slot as usize).write_volatile(header);
+        }
+        // the table has to be in memory before the adapter is told about it
+        fence(Ordering::SeqCst);
+
+        if queued {
+            self.port.write(PX_SACT, 1 << slot);
+        }
+        self.port.write(PX_CI, 1 << slot);
+        let result = self.wait(slot, queued);
+        self.busy.fetch_and(!(1 << slot), Ordering::SeqCst);
+        result
+    }
+
+    /// Wait for the command in `slot`, sleeping until an interrupt if there is one
+    fn wait(&self, slot: u32, queued: bool) -> Result<(), DiskError> {
+        let bit = 1 << slot;
+        let register = if queued { PX_SACT } else { PX_CI };
+        let deadline = tsc::cycles() + tsc::nanos_to_cycles(COMMAND_TIMEOUT);
+        loop {
+            let sleep = self.interrupts && interrupts::interrupts_enabled();
+            if sleep {
+                interrupts::disable_interrupts();
+            }
+
+            let (status, error) = self.port.task_file();
+            let result = if self.failed.fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+                Some(Err(DiskError::Device { status, error }))
+            } else if status & STATUS_ERROR != 0 {
+                self.recover(bit);
+                Some(Err(DiskError::Device { status, error }))
+            } else if self.port.read(register) & bit == 0 {
+                Some(Ok(()))
+            } else if tsc::cycles() > deadline {
+                self.recover(bit);
+                Some(Err(DiskError::Timeout))
+            } else {
+                None
+            };
+
+            match (result, sleep) {
+                (Some(result), true) => {
+                    interrupts::enable_interrupts();
+                    return result;
+                }
+                (Some(result), false) => return result,
+                // enabling and halting at once, so the interrupt can not slip in between
+                (None, true) => interrupts::enable_interrupts_hlt(),
+                (None, false) => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Restart the port after an error, the other outstanding commands fail too
+    fn recover(&self, bit: u32) {
+        let outstanding = self.port.read(PX_CI) | self.port.read(PX_SACT);
+        self.failed.fetch_or(outstanding & !bit, Ordering::SeqCst);
+        let _ = stop_engine(self.port);
+        self.port.clear_errors();
+        let _ = wait_for(PORT_TIMEOUT, || {
+            self.port.task_file().0 & (STATUS_BUSY | STATUS_DRQ) == 0
+        });
+        self.port
+            .set_command(self.port.command() | PortCommand::START);
+    }
+
+    /// Read or write whole sectors, split in commands the prdt can describe
+    fn transfer(&self, lba: u64, data: *mut u8, len: usize, write: bool) -> Result<(), DiskError> {
+        self.check_range(lba, len)?;
+        let _shared = self.exclusive.read();
+        let chunk = MAX_TRANSFER - MAX_TRANSFER % self.sector_size;
+        let mut done = 0;
+        while done < len {
+            let bytes = chunk.min(len - done);
+            let lba = lba + (done / self.sector_size) as u64;
+            let count = (bytes / self.sector_size) as u16;
+            let fis = |slot: u32| match (self.queued, write) {
+                (true, false) => h2d_fis(
+                    ATA_READ_FPDMA_QUEUED,
+                    count,
+                    lba,
+                    (slot << 3) as u16,
+                    DEVICE_LBA,
+                ),
+                (true, true) => h2d_fis(
+                    ATA_WRITE_FPDMA_QUEUED,
+                    count,
+                    lba,
+                    (slot << 3) as u16,
+                    DEVICE_LBA,
+                ),
+                (false, false) => h2d_fis(ATA_READ_DMA_EXT, 0, lba, count, DEVICE_LBA),
+                (false, true) => h2d_fis(ATA_WRITE_DMA_EXT, 0, lba, count, DEVICE_LBA),
+            };
+            let data = unsafe { data.add(done) };
+            self.issue(fis, data, bytes, write, self.queued)?;
+            done += bytes;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), DiskError> {
+        self.transfer(lba, buf.as_mut_ptr(), buf.len(), false)
+    }
+
+    fn write(&self, lba: u64, buf: &[u8]) -> Result<(), DiskError> {
+        // the adapter only reads from the buffer
+        self.transfer(lba, buf.as_ptr() as *mut u8, buf.len(), true)
+    }
+
+    fn flush(&self) -> Result<(), DiskError> {
+        let _alone = self.exclusive.write();
+        let fis = |_| h2d_fis(ATA_FLUSH_CACHE_EXT, 0, 0, 0, DEVICE_LBA);
+        self.issue(fis, core::ptr::null_mut(), 0, false, false)
+    }
+}
+
+/// Describe `len` bytes at `data` in `prdt`, returns how many entries it took
+fn fill_prdt(prdt: &mut [PrdtEntry], data: *mut u8, len: usize) -> usize {
+    let mut entries = 0;
+    for (physical, bytes) in physical_ranges(data, len) {
+        prdt[entries] = PrdtEntry::new(u64::from(physical), bytes as u32);
+        entries += 1;
+    }
+    entries
+}
+
+/// Stop the port processing commands
+fn stop_engine(port: HbaPort) -> Result<(), DiskError> {
+    port.set_command(port.command() - PortCommand::START);
+    wait_for(PORT_TIMEOUT, || {
+        !port.command().contains(PortCommand::COMMAND_LIST_RUNNING)
+    })
+}
+
+/// Stop the port processing commands and receiving fises, before its memory is changed
+fn stop(port: HbaPort) -> Result<(), DiskError> {
+    stop_engine(port)?;
+    port.set_command(port.command() - PortCommand::FIS_RECEIVE_ENABLE);
+    wait_for(PORT_TIMEOUT, || {
+        !port.command().contains(PortCommand::FIS_RECEIVE_RUNNING)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn prdt_merges_contiguous_pages() {
+        let buffer = DmaBuffer::new(3 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let mut prdt = [PrdtEntry::default(); PRDT_ENTRIES];
+        let data = unsafe { buffer.as_mut_ptr::<u8>().add(512) };
+        assert_eq!(fill_prdt(&mut prdt, data, 2 * PAGE_SIZE), 1);
+        assert_eq!(prdt[0].address, u64::from(buffer.physical()) + 512);
+        assert_eq!(prdt[0].count as usize, 2 * PAGE_SIZE - 1);
+    }
+
+    /// Whatever disks qemu has on an ahci adapter read back what was written
+    #[test_case]
+    fn disks() {
+        for (_, disk) in block::devices() {
+            if !disk.name().starts_with("ahci") {
+                continue;
+            }
+            let size = disk.sector_size();
+            let last = disk.sectors() - 1;
+            let mut saved = vec![0; size];
+            disk.read(last, &mut saved).unwrap();
+
+            let pattern: alloc::vec::Vec<u8> = (0..size).map(|i| i as u8).collect();
+            disk.write(last, &pattern).unwrap();
+            disk.flush().unwrap();
+            let mut back = vec![0; size];
+            disk.read(last, &mut back).unwrap();
+            assert_eq!(back, pattern);
+
+            disk.write(last, &saved).unwrap();
+            assert_eq!(
+                disk.read(disk.sectors(), &mut back),
+                Err(DiskError::OutOfRange)
+            );
+        }
+    }
+}
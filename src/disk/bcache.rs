@@ -1,6 +1,8 @@
 use core::cell::RefCell;
 
+use super::block::{self, BlockDevice, DiskError};
 use super::buf::Buffer;
+use crate::consts::BSIZE;
 use alloc::sync::Arc;
 
 //static mut BUFFERS: StaticVec<RefCell<Buffer>, 30> = StaticVec::new();
@@ -56,18 +58,45 @@ impl BufferCache {
         panic!("are we forgeting to flush pages?")
     }
 
-    pub fn read(&mut self, device: u32, block_no: u32) -> Arc<RefCell<Buffer>> {
+    /// The buffer of a block, read from the disk if it is not cached.
+    /// On an error the buffer stays invalid, so the next read tries again
+    pub fn read(&mut self, device: u32, block_no: u32) -> Result<Arc<RefCell<Buffer>>, DiskError> {
         let buf = unsafe { self.get(device, block_no) };
         if !buf.borrow().is_valid() {
-            super::ide::add_ide_queue(buf.clone());
+            match block::get(device) {
+                Some(disk) => {
+                    let mut buffer = buf.borrow_mut();
+                    let lba = first_sector(&*disk, block_no);
+                    disk.read(lba, buffer.data_mut())?;
+                    buffer.set_valid(true);
+                }
+                None => super::ide::add_ide_queue(buf.clone()),
+            }
         }
-        buf
+        Ok(buf)
     }
 
-    pub fn write(buf: Arc<RefCell<Buffer>>) {
+    /// Write a buffer to its disk. On an error it stays dirty, so it is not evicted
+    /// before it is on the disk
+    pub fn write(buf: Arc<RefCell<Buffer>>) -> Result<(), DiskError> {
         let buf = buf;
 
         buf.borrow_mut().set_dirty(true);
-        super::ide::add_ide_queue(buf);
+        let device = buf.borrow().device();
+        match block::get(device) {
+            Some(disk) => {
+                let mut buffer = buf.borrow_mut();
+                let lba = first_sector(&*disk, buffer.block_no());
+                disk.write(lba, buffer.data())?;
+                buffer.set_dirty(false);
+            }
+            None => super::ide::add_ide_queue(buf),
+        }
+        Ok(())
     }
 }
+
+/// The sector a block starts at, [`block::register`] makes sure a block is whole sectors
+fn first_sector(disk: &dyn BlockDevice, block_no: u32) -> u64 {
+    block_no as u64 * (BSIZE / disk.sector_size()) as u64
+}
@@ -0,0 +1,186 @@
+//! Disks by device number, for the buffer cache and file systems.
+//!
+//! Device 0 is unused and 1 is the legacy ide disk, which has its own queue, so registered
+//! devices start at [`FIRST_DEVICE`].
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::consts::BSIZE;
+
+/// Number of the first registered device
+pub const FIRST_DEVICE: u32 = 2;
+
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskError {
+    /// Past the last sector
+    OutOfRange,
+    /// A buffer that is not a whole number of sectors
+    Unaligned,
+    /// The drive failed the command, with its status and error registers
+    Device { status: u8, error: u8 },
+    /// No answer in time
+    Timeout,
+    /// No memory for the buffers the device needs
+    OutOfMemory,
+    /// The device does not take writes
+    ReadOnly,
+    /// Sectors the cache blocks are not a whole number of
+    SectorSize,
+}
+
+/// A disk addressed by sector, safe to share between tasks
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Bytes per sector
+    fn sector_size(&self) -> usize {
+        512
+    }
+
+    /// Number of sectors
+    fn sectors(&self) -> u64;
+
+    /// Read sectors starting at `lba`, the length of `buf` says how many
+    fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), DiskError>;
+
+    /// Write sectors starting at `lba`, the length of `buf` says how many
+    fn write(&self, lba: u64, buf: &[u8]) -> Result<(), DiskError>;
+
+    /// Make sure written sectors are on the media
+    fn flush(&self) -> Result<(), DiskError> {
+        Ok(())
+    }
+
+    /// Number of sectors in `len` bytes starting at `lba`, if they are all on the disk
+    fn check_range(&self, lba: u64, len: usize) -> Result<u64, DiskError> {
+        if !len.is_multiple_of(self.sector_size()) {
+            return Err(DiskError::Unaligned);
+        }
+        let count = (len / self.sector_size()) as u64;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.sectors() => Ok(count),
+            _ => Err(DiskError::OutOfRange),
+        }
+    }
+}
+
+/// Add a disk, returns its device number. The buffer cache reads whole blocks, so they
+/// have to be a whole number of sectors
+pub fn register(device: Arc<dyn BlockDevice>) -> Result<u32, DiskError> {
+    if !BSIZE.is_multiple_of(device.sector_size()) {
+        return Err(DiskError::SectorSize);
+    }
+    let mut devices = DEVICES.lock();
+    devices.push(device);
+    Ok(FIRST_DEVICE + devices.len() as u32 - 1)
+}
+
+pub fn get(number: u32) -> Option<Arc<dyn BlockDevice>> {
+    let index = number.checked_sub(FIRST_DEVICE)?;
+    DEVICES.lock().get(index as usize).cloned()
+}
+
+/// Every registered disk with its device number
+pub fn devices() -> Vec<(u32, Arc<dyn BlockDevice>)> {
+    DEVICES
+        .lock()
+        .iter()
+        .enumerate()
+        .map(|(i, device)| (FIRST_DEVICE + i as u32, device.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// A disk in memory
+    struct RamDisk {
+        bytes: Mutex<Vec<u8>>,
+        sector_size: usize,
+    }
+
+    impl RamDisk {
+        fn new(bytes: Vec<u8>, sector_size: usize) -> Self {
+            Self {
+                bytes: Mutex::new(bytes),
+                sector_size,
+            }
+        }
+    }
+
+    impl BlockDevice for RamDisk {
+        fn name(&self) -> &str {
+            "ram"
+        }
+
+        fn sector_size(&self) -> usize {
+            self.sector_size
+        }
+
+        fn sectors(&self) -> u64 {
+            (self.bytes.lock().len() / self.sector_size()) as u64
+        }
+
+        fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), DiskError> {
+            self.check_range(lba, buf.len())?;
+            let start = lba as usize * self.sector_size();
+            buf.copy_from_slice(&self.bytes.lock()[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&self, lba: u64, buf: &[u8]) -> Result<(), DiskError> {
+            self.check_range(lba, buf.len())?;
+            let start = lba as usize * self.sector_size();
+            self.bytes.lock()[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    fn ranges() {
+        let disk = RamDisk::new(vec![0; 4 * 512], 512);
+        assert_eq!(disk.check_range(0, 4 * 512), Ok(4));
+        assert_eq!(disk.check_range(1, 4 * 512), Err(DiskError::OutOfRange));
+        assert_eq!(disk.check_range(0, 100), Err(DiskError::Unaligned));
+        assert_eq!(disk.check_range(u64::MAX, 512), Err(DiskError::OutOfRange));
+    }
+
+    #[test_case]
+    fn buffer_cache() {
+        let mut bytes = vec![0; 4 * BSIZE];
+        bytes[BSIZE..2 * BSIZE].fill(0xAB);
+        let number = register(Arc::new(RamDisk::new(bytes, 512))).unwrap();
+        assert_eq!(get(number).unwrap().name(), "ram");
+
+        let buffer = super::super::BUFFERS.lock().read(number, 1).unwrap();
+        assert!(buffer.borrow().is_valid());
+        assert!(buffer.borrow().data().iter().all(|byte| *byte == 0xAB));
+
+        buffer.borrow_mut().data_mut()[0] = 0xCD;
+        super::super::bcache::BufferCache::write(buffer).unwrap();
+        let mut sector = [0; 512];
+        get(number).unwrap().read(2, &mut sector).unwrap();
+        assert_eq!(sector[0], 0xCD);
+    }
+
+    /// Sectors larger than a block would all map to sector 0
+    #[test_case]
+    fn sector_sizes() {
+        let disk = RamDisk::new(vec![0; 2 * 4096], 4096);
+        assert_eq!(register(Arc::new(disk)), Err(DiskError::SectorSize));
+    }
+
+    /// A failed read is returned instead of panicking or handing out the stale buffer
+    #[test_case]
+    fn buffer_cache_errors() {
+        let number = register(Arc::new(RamDisk::new(vec![0; BSIZE], 512))).unwrap();
+        let result = super::super::BUFFERS.lock().read(number, 5);
+        assert_eq!(result.err(), Some(DiskError::OutOfRange));
+    }
+}
@@ -10,18 +10,93 @@ use super::buf::Buffer;
 use crate::consts::{BSIZE, SSIZE};
 use crate::disk::DiskIo;
 
-struct IdeDevice {
-    _reserved: u8,     // 0 (Empty) or 1 (This Drive really exists).
+/// The drive the buffer cache queue reads and writes, the image is the primary slave
+const QUEUE_DRIVE: u8 = 1;
+
+/// Sectors lba28 can address
+const LBA28_SECTORS: u64 = 0x1000_0000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IdeDevice {
     channel: u8,       // 0 (Primary Channel) or 1 (Secondary Channel).
     drive: u8,         // 0 (Master Drive) or 1 (Slave Drive).
     r#type: u16,       // 0: ATA, 1:ATAPI.
     signature: u16,    // Drive Signature
     capabilities: u16, // Features.
     command_sets: u32, // Command Sets Supported.
-    size: u32,         // Size in Sectors.
+    size: u64,         // Size in Sectors.
+    lba48: bool,       // Addresses past 128 GiB.
     model: [u8; 41],   // Model in cstring.
 }
 
+impl IdeDevice {
+    /// Read the fields out of the 256 words identify returned
+    fn parse(channel: u8, drive: u8, r#type: u16, words: &[u16; 256]) -> Self {
+        // the offsets are in bytes
+        let index = |offset: Identify| offset.bits() as usize / 2;
+        let word = |offset: Identify| words[index(offset)];
+        let dword = |offset: Identify| {
+            words[index(offset)] as u32 | (words[index(offset) + 1] as u32) << 16
+        };
+
+        // bit 26 of the command sets is lba48
+        let command_sets = dword(Identify::COMMANDSETS);
+        let lba48 = command_sets & (1 << 26) != 0;
+        let size = match lba48 {
+            true => (0..3).fold(0, |size, i| {
+                size | (words[index(Identify::MAX_LBA_EXT) + i] as u64) << (16 * i)
+            }),
+            false => dword(Identify::MAX_LBA) as u64,
+        };
+
+        // the model is space padded, with the bytes of each word swapped
+        let mut model = [0; 41];
+        let start = index(Identify::MODEL);
+        for (i, word) in words[start..start + 20].iter().enumerate() {
+            model[2 * i..2 * i + 2].copy_from_slice(&word.to_be_bytes());
+        }
+
+        Self {
+            channel,
+            drive,
+            r#type,
+            signature: word(Identify::DEVICETYPE),
+            capabilities: word(Identify::CAPABILITIES),
+            command_sets,
+            size,
+            lba48,
+            model,
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        let end = self.model.iter().position(|byte| *byte == 0).unwrap_or(40);
+        core::str::from_utf8(&self.model[..end])
+            .unwrap_or("")
+            .trim_end()
+    }
+
+    pub fn is_atapi(&self) -> bool {
+        self.r#type == 1
+    }
+}
+
+impl core::fmt::Display for IdeDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let channel = ["primary", "secondary"][self.channel as usize];
+        let drive = ["master", "slave"][self.drive as usize];
+        let kind = if self.is_atapi() { "ATAPI" } else { "ATA" };
+        write!(f, "{channel} {drive} {kind} {}", self.model())?;
+        if !self.is_atapi() {
+            write!(f, ", {} MiB", (self.size * SSIZE as u64) >> 20)?;
+        }
+        if self.lba48 {
+            write!(f, ", lba48")?;
+        }
+        Ok(())
+    }
+}
+
 bitflags! {
     struct Command: u8 {
         const READ_PIO        = 0x20;
@@ -105,6 +180,7 @@ pub struct Ata {
     command: PortWriteOnly<u8>,         // index 7
     alternate_status: PortReadOnly<u8>, // index control 2
     control: PortWriteOnly<u8>,         // index control 2
+    drives: [Option<IdeDevice>; 2],     // master and slave
 }
 
 impl Ata {
@@ -124,6 +200,7 @@ impl Ata {
             // control port
             alternate_status: PortReadOnly::new(control_port + 2),
             control: PortWriteOnly::new(control_port + 2),
+            drives: [None, None],
         }
     }
 
@@ -135,25 +212,60 @@ impl Ata {
         Self::new(0x170, 0x376, false)
     }
 
-    pub fn init(&mut self) -> bool {
+    /// Identify both drives on the channel
+    pub fn init(&mut self) {
         use crate::consts::IRQ;
 
-        unsafe { (*crate::io::IO_APIC.as_mut_ptr()).enable(IRQ::Ide, 0) };
-        self.poll(false).unwrap();
+        if self.primary {
+            crate::io::IO_APIC.lock().enable(IRQ::Ide, 0);
+        }
+        for drive in 0..2 {
+            self.drives[drive as usize] = self.identify(drive);
+        }
+    }
 
-        let mut have_disk_1 = false;
+    pub fn drive(&self, drive: u8) -> Option<&IdeDevice> {
+        self.drives[drive as usize].as_ref()
+    }
 
-        unsafe { self.drive_head.write(0xe0 | (1 << 4)) };
-        for _ in 0..1000 {
-            if unsafe { self.status.read() } != 0 {
-                have_disk_1 = true;
+    /// Ask `drive` what it is with IDENTIFY, or IDENTIFY PACKET if it turns out to be
+    /// ATAPI. None if there is nothing there
+    fn identify(&mut self, drive: u8) -> Option<IdeDevice> {
+        let mut select = DriveHead::RESERVED_1 | DriveHead::RESERVED_2;
+        select.set(DriveHead::SLAVE, drive == 1);
+        unsafe {
+            self.drive_head.write(select.bits());
+            // a channel without drives floats high
+            if self.status.read() == 0xFF {
+                return None;
             }
+            self.sector_count.write(0);
+            self.sector_number.write(0);
+            self.cylinder_low.write(0);
+            self.cylinder_high.write(0);
+            self.command.write(Command::IDENTIFY.bits());
+        }
+        self.poll(false).ok()?;
+        if unsafe { self.status.read() } == 0 {
+            return None;
         }
 
-        // switch to disk 0
-        // unsafe { self.drive_head.write(0xe0 | (0 << 4)) };
+        // ATAPI drives abort IDENTIFY and leave their signature in the lba registers
+        let mut r#type = 0;
+        let signature = unsafe { (self.cylinder_low.read(), self.cylinder_high.read()) };
+        if self.status().contains(Status::ERR) || signature != (0, 0) {
+            match signature {
+                (0x14, 0xEB) | (0x69, 0x96) => r#type = 1,
+                _ => return None,
+            }
+            unsafe { self.command.write(Command::IDENTIFY_PACKET.bits()) };
+        }
+        self.poll(true).ok()?;
 
-        have_disk_1
+        let mut words = [0; 256];
+        unsafe { self.data.reads(&mut words) };
+        let channel = if self.primary { 0 } else { 1 };
+        Some(IdeDevice::parse(channel, drive, r#type, &words))
     }
 
     fn error(&self) -> Errors {
@@ -195,12 +307,16 @@ impl Ata {
         Ok(())
     }
 
-    fn setup_access(&mut self, direction: u8, lba: u32, num_sects: u8) -> Result<(), u64> {
+    fn setup_access(
+        &mut self,
+        drive: u8,
+        direction: u8,
+        lba: u64,
+        num_sects: u8,
+    ) -> Result<(), u64> {
         let lba_mode: u8; // 0: CHS, 1: LBA28, 2: LBA48
-        let mut lba_io: [u8; 6] = [0; 6];
-        let slavebit = 1; // 0 master, 1 slave
+        let lba_io = lba.to_le_bytes();
         let head: u8;
-        //let num_sects = 1;
 
         // disable iqs
         //self.disable_irq();
@@ -209,19 +325,17 @@ impl Ata {
         }
 
         // select lba mode
-        if lba >= 0x10000000 {
-            // TODO: support lba48
-            panic!("TODO support lba48")
+        if lba + num_sects as u64 > LBA28_SECTORS {
+            // the drive has to understand the ext commands
+            if !self.drive(drive).is_some_and(|device| device.lba48) {
+                return Err(4);
+            }
+            lba_mode = 2;
+            head = 0; // lba48 has no address bits in the head
         } else {
             // lba28
             lba_mode = 1;
-            lba_io[0] = (lba & 0x00000FF) as u8;
-            lba_io[1] = ((lba & 0x000FF00) >> 8) as u8;
-            lba_io[2] = ((lba & 0x0FF0000) >> 16) as u8;
-            lba_io[3] = 0; // These Registers are not used here.
-            lba_io[4] = 0; // These Registers are not used here.
-            lba_io[5] = 0; // These Registers are not used here.
-            head = ((lba & 0xF000000) >> 24) as u8;
+            head = lba_io[3] & 0x0F;
         }
         // TODO: support chs
 
@@ -231,6 +345,7 @@ impl Ata {
         while self.status().contains(Status::BSY) {}
 
         // select drive
+        let slavebit = drive; // 0 master, 1 slave
         if lba_mode == 0 {
             // drive and chs
             unsafe { self.drive_head.write(0xA0 | (slavebit << 4) | head) };
@@ -239,13 +354,13 @@ impl Ata {
             unsafe { self.drive_head.write(0xE0 | (slavebit << 4) | head) };
         }
 
-        // write params
+        // write params, lba48 takes the high bytes first through the same registers
         unsafe {
             if lba_mode == 2 {
                 self.sector_count.write(0);
-                // self.write(ATA_REG_LBA3, lba_io[3]);
-                // self.write(ATA_REG_LBA4, lba_io[4]);
-                // self.write(ATA_REG_LBA5, lba_io[5]);
+                self.sector_number.write(lba_io[3]);
+                self.cylinder_low.write(lba_io[4]);
+                self.cylinder_high.write(lba_io[5]);
             }
             self.sector_count.write(num_sects);
             self.sector_number.write(lba_io[0]);
@@ -322,7 +437,8 @@ impl DiskIo<BSIZE> for Ata {
     fn read(&mut self, lba: u32, buf: &mut [u8; BSIZE]) {
         let buf = unsafe { core::mem::transmute::<&mut [u8; BSIZE], &mut [u16; BSIZE / 2]>(buf) };
         let num_sects = BSIZE / SSIZE;
-        self.setup_access(0, lba, num_sects as u8).unwrap();
+        self.setup_access(QUEUE_DRIVE, 0, lba as u64, num_sects as u8)
+            .unwrap();
         self._read(buf, num_sects).unwrap();
     }
 
@@ -330,7 +446,8 @@ impl DiskIo<BSIZE> for Ata {
         let buf = unsafe { core::mem::transmute::<&[u8; BSIZE], &[u16; BSIZE / 2]>(buf) };
         let num_sects = BSIZE / SSIZE;
 
-        self.setup_access(1, lba, num_sects as u8).unwrap();
+        self.setup_access(QUEUE_DRIVE, 1, lba as u64, num_sects as u8)
+            .unwrap();
         self._write(buf, num_sects).unwrap();
     }
 }
@@ -396,3 +513,34 @@ pub fn read_write() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Identify data of a 2 TiB disk, past what lba28 reaches
+    #[test_case]
+    fn identify_data() {
+        let mut words = [0; 256];
+        for (i, pair) in b"QEMU HARDDISK                           "
+            .chunks(2)
+            .enumerate()
+        {
+            words[27 + i] = u16::from_be_bytes([pair[0], pair[1]]);
+        }
+        words[60] = 0xFFFF;
+        words[61] = 0x0FFF;
+        words[83] = 1 << 10;
+        words[102] = 0x1;
+
+        let device = IdeDevice::parse(0, 1, 0, &words);
+        assert_eq!(device.model(), "QEMU HARDDISK");
+        assert!(device.lba48);
+        assert_eq!(device.size, 1 << 32);
+
+        words[83] = 0;
+        let device = IdeDevice::parse(0, 1, 0, &words);
+        assert!(!device.lba48);
+        assert_eq!(device.size, LBA28_SECTORS - 1);
+    }
+}
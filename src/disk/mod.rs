@@ -5,26 +5,57 @@ use spin::Mutex;
 
 pub use ide::interrupt_handler;
 
+pub mod ahci;
 mod bcache;
+pub mod block;
 mod buf;
 mod ide;
+pub mod virtio;
 
 static HAVE_DISK_1: AtomicBool = AtomicBool::new(false);
 static IDE: Mutex<Ata> = Mutex::new(Ata::new_primary());
+static IDE_SECONDARY: Mutex<Ata> = Mutex::new(Ata::new_secondary());
 static BUFFERS: Mutex<BufferCache> = Mutex::new(BufferCache::new());
 
 pub fn ide_init() {
-    let disk_1 = IDE.lock().init();
-    HAVE_DISK_1.store(true, core::sync::atomic::Ordering::Relaxed);
+    let disk_1 = {
+        let mut primary = IDE.lock();
+        let mut secondary = IDE_SECONDARY.lock();
+        primary.init();
+        secondary.init();
+        for ata in [&primary, &secondary] {
+            for device in (0..2).filter_map(|drive| ata.drive(drive)) {
+                crate::kprintln!("IDE: {device}");
+            }
+        }
+        primary.drive(1).is_some_and(|device| !device.is_atapi())
+    };
+    HAVE_DISK_1.store(disk_1, core::sync::atomic::Ordering::Relaxed);
     ide::ide_queue_init();
 
     crate::kprintln!("Disk 1 exists: {disk_1}");
 }
 
+/// Hand ahci adapters to their driver, each disk becomes a block device
+pub fn ahci_init() {
+    crate::pci::register_driver(&ahci::DRIVER);
+}
+
+/// Hand virtio block functions to their driver, each becomes a block device
+pub fn virtio_init() {
+    crate::pci::register_driver(&virtio::DRIVER);
+}
+
 pub fn ide_test() {
+    // the image can be on virtio instead
+    if !HAVE_DISK_1.load(core::sync::atomic::Ordering::Relaxed) {
+        return;
+    }
     let mut bufs = BUFFERS.lock();
     for i in 0..40 {
-        let _data = bufs.read(1, i);
+        if let Err(error) = bufs.read(1, i) {
+            crate::kprintln!("IDE: reading block {} failed, {:?}", i, error);
+        }
     }
     //*data.borrow_mut().data_mut() = [0x00u8; 1024];
     //bcache::BufferCache::write(data);
@@ -0,0 +1,303 @@
+//! Virtio block devices, the paravirtual disks of `-drive if=virtio`.
+//!
+//! A request is a chain of a header the device reads, the data, and a status byte it
+//! writes. Completion is signalled with msi-x, functions without it are polled.
+//!
+//! Virtio 1.1 - 5.2
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::interrupts::{self, trap::TrapFrame, vector};
+use crate::memory::dma::{physical_ranges, DmaBuffer};
+use crate::pci::msi::MsiX;
+use crate::pci::{DeviceMatch, PciDevice, PciDriver};
+use crate::virtio::transport::NO_VECTOR;
+use crate::virtio::{self, Segment, Transport, VirtioError, Virtqueue};
+
+use super::block::{self, BlockDevice, DiskError};
+
+/// Transitional and modern block devices
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &[
+        DeviceMatch::Id {
+            vendor: virtio::VENDOR_ID,
+            device: 0x1001,
+        },
+        DeviceMatch::Id {
+            vendor: virtio::VENDOR_ID,
+            device: 0x1042,
+        },
+    ],
+    probe,
+};
+
+/// Disks probed so far, for naming them
+static PROBED: AtomicUsize = AtomicUsize::new(0);
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+/// Config offsets
+const CONFIG_CAPACITY: u16 = 0x00;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const S_OK: u8 = 0;
+
+/// Capacity and requests count in 512 byte sectors, whatever the block size
+const SECTOR_SIZE: usize = 512;
+const PAGE_SIZE: usize = 4096;
+/// Longest queue we ask a modern device for
+const QUEUE_SIZE: u16 = 128;
+/// The header and status, and a page of data that takes two descriptors when unaligned
+const MIN_QUEUE_SIZE: u16 = 4;
+const MAX_TRANSFER: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    _reserved: u32,
+    sector: u64,
+}
+
+fn probe(device: &PciDevice) -> bool {
+    device.enable();
+    let name = format!("virtio{}", PROBED.fetch_add(1, Ordering::SeqCst));
+    match VirtioDisk::new(device, name) {
+        Ok(disk) => {
+            crate::kprintln!(
+                "VIRTIO: {} {} {} MiB{}{}",
+                device.address,
+                disk.name,
+                (disk.sectors * SECTOR_SIZE as u64) >> 20,
+                if disk.read_only { ", read only" } else { "" },
+                if disk.interrupts {
+                    ", msi-x"
+                } else {
+                    ", polled"
+                }
+            );
+            match block::register(Arc::new(disk)) {
+                Ok(_) => true,
+                Err(error) => {
+                    crate::kprintln!("VIRTIO: {} not added, {:?}", device.address, error);
+                    false
+                }
+            }
+        }
+        Err(error) => {
+            crate::kprintln!("VIRTIO: {} failed, {:?}", device.address, error);
+            false
+        }
+    }
+}
+
+/// The waiters look at their queues, msi-x needs no acknowledging
+fn interrupt(_frame: &mut TrapFrame) {}
+
+/// A virtio block device with a single request queue
+pub struct VirtioDisk {
+    name: String,
+    transport: Transport,
+    queue: Virtqueue,
+    sectors: u64,
+    read_only: bool,
+    /// The device caches writes until it is flushed
+    flush: bool,
+    interrupts: bool,
+}
+
+impl VirtioDisk {
+    fn new(device: &PciDevice, name: String) -> Result<Self, VirtioError> {
+        let transport = Transport::new(device)?;
+        let features = transport.negotiate(F_RO | F_FLUSH)?;
+        let size = match transport.is_legacy() {
+            // legacy queues can not be resized
+            true => transport.queue_size(0),
+            false => transport.queue_size(0).min(QUEUE_SIZE),
+        };
+        if size < MIN_QUEUE_SIZE {
+            return Err(VirtioError::QueueTooSmall(size));
+        }
+
+        // msi-x has to be on before a legacy device is told its vectors
+        let vector = MsiX::new(device).ok().and_then(|msi_x| {
+            let vector = vector::allocate(interrupt)?;
+            msi_x.enable();
+            match msi_x.enable_vector(0, vector) {
+                Ok(()) => Some(vector),
+                Err(_) => {
+                    msi_x.disable();
+                    vector::free(vector);
+                    None
+                }
+            }
+        });
+        transport.set_config_vector(NO_VECTOR);
+
+        let mut queue = Virtqueue::new(0, size, transport.is_legacy())?;
+        let queue_vector = if vector.is_some() { 0 } else { NO_VECTOR };
+        transport.setup_queue(&mut queue, queue_vector)?;
+        transport.driver_ok();
+
+        Ok(Self {
+            name,
+            sectors: transport.read_config_u64(CONFIG_CAPACITY),
+            read_only: features & F_RO != 0,
+            flush: features & F_FLUSH != 0,
+            interrupts: vector.is_some(),
+            transport,
+            queue,
+        })
+    }
+
+    /// Send a request with `len` bytes at `data` and wait for its status
+    fn request(&self, kind: u32, sector: u64, data: *mut u8, len: usize) -> Result<(), DiskError> {
+        // the header, and the status byte after it
+        let request = DmaBuffer::new(32, 16).ok_or(DiskError::OutOfMemory)?;
+        let header = RequestHeader {
+            kind,
+            _reserved: 0,
+            sector,
+        };
+        unsafe {
+            request.as_mut_ptr::<RequestHeader>().write_volatile(header);
+            request.as_mut_ptr::<u8>().add(16).write_volatile(0xFF);
+        }
+
+        let mut segments = Vec::new();
+        segments.push(Segment {
+            address: request.physical(),
+            len: 16,
+            writable: false,
+        });
+        for (address, bytes) in physical_ranges(data, len) {
+            segments.push(Segment {
+                address,
+                len: bytes as u32,
+                writable: kind == T_IN,
+            });
+        }
+        segments.push(Segment {
+            address: request.physical() + 16u64,
+            len: 1,
+            writable: true,
+        });
+
+        let head = loop {
+            match self.queue.submit(&segments) {
+                Some(head) => break head,
+                None => core::hint::spin_loop(),
+            }
+        };
+        self.transport.notify(&self.queue);
+        self.wait(head);
+
+        match unsafe { request.as_ptr::<u8>().add(16).read_volatile() } {
+            S_OK => Ok(()),
+            status => Err(DiskError::Device { status, error: 0 }),
+        }
+    }
+
+    /// Wait for the chain at `head`, sleeping until an interrupt if there is one. The device
+    /// always answers, so there is no timeout
+    fn wait(&self, head: u16) {
+        loop {
+            let sleep = self.interrupts && interrupts::interrupts_enabled();
+            if sleep {
+                interrupts::disable_interrupts();
+            }
+            let done = self.queue.poll(head).is_some();
+            match (done, sleep) {
+                (true, true) => return interrupts::enable_interrupts(),
+                (true, false) => return,
+                // enabling and halting at once, so the interrupt can not slip in between
+                (false, true) => interrupts::enable_interrupts_hlt(),
+                (false, false) => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Read or write whole sectors, split in requests that fit the queue
+    fn transfer(&self, lba: u64, data: *mut u8, len: usize, kind: u32) -> Result<(), DiskError> {
+        self.check_range(lba, len)?;
+        // the header and status take two descriptors, an unaligned buffer one page more,
+        // `new` makes sure that leaves at least one page
+        let pages = self.queue.size() as usize - 3;
+        let chunk = MAX_TRANSFER.min(pages * PAGE_SIZE);
+        let mut done = 0;
+        while done < len {
+            let bytes = chunk.min(len - done);
+            let sector = lba + (done / SECTOR_SIZE) as u64;
+            self.request(kind, sector, unsafe { data.add(done) }, bytes)?;
+            done += bytes;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), DiskError> {
+        self.transfer(lba, buf.as_mut_ptr(), buf.len(), T_IN)
+    }
+
+    fn write(&self, lba: u64, buf: &[u8]) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        // the device only reads from the buffer
+        self.transfer(lba, buf.as_ptr() as *mut u8, buf.len(), T_OUT)
+    }
+
+    fn flush(&self) -> Result<(), DiskError> {
+        match self.flush {
+            true => self.request(T_FLUSH, 0, core::ptr::null_mut(), 0),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Whatever disks qemu has on virtio read back what was written, past one request
+    #[test_case]
+    fn disks() {
+        for (_, disk) in block::devices() {
+            if !disk.name().starts_with("virtio") || disk.sectors() < 256 {
+                continue;
+            }
+            let len = MAX_TRANSFER + 2 * SECTOR_SIZE;
+            let lba = disk.sectors() - (len / SECTOR_SIZE) as u64;
+            let mut saved = vec![0; len];
+            disk.read(lba, &mut saved).unwrap();
+
+            let pattern: Vec<u8> = (0..len).map(|i| (i / 7) as u8).collect();
+            if disk.write(lba, &pattern) == Err(DiskError::ReadOnly) {
+                continue;
+            }
+            disk.flush().unwrap();
+            let mut back = vec![0; len];
+            disk.read(lba, &mut back).unwrap();
+            assert_eq!(back, pattern);
+            disk.write(lba, &saved).unwrap();
+        }
+    }
+}
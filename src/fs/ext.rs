@@ -6,6 +6,7 @@ use staticvec::StaticVec;
 
 use crate::consts::BSIZE;
 use crate::disk::bcache;
+use crate::disk::block::DiskError;
 
 const BLOCK_SIZE: usize = 1024;
 const INODE_SIZE: usize = 256;
@@ -13,7 +14,13 @@ const INODE_SIZE: usize = 256;
 pub fn ext_test() {
     use crate::kdbg;
 
-    let ext = Ext::new(1);
+    let ext = match Ext::new(1) {
+        Ok(ext) => ext,
+        Err(error) => {
+            crate::kprintln!("EXT: reading the file system failed, {:?}", error);
+            return;
+        }
+    };
     //kdbg!(ext.block_groups[0].block_bitmap(1));
     //kdbg!(ext.block_groups[0].inode_bitmap(1));
     let i = ext.read_inode(2);
@@ -27,14 +34,14 @@ pub struct Ext {
 }
 
 impl Ext {
-    pub fn new(device: u32) -> Self {
-        let superblock = Superblock::read(device);
+    pub fn new(device: u32) -> Result<Self, DiskError> {
+        let superblock = Superblock::read(device)?;
         let mut block_groups = StaticVec::new();
 
         // we are starting at 1-2 empty, 2-3 superblock
         // TODO: this is stupid and slow
         for i in 0..superblock.block_groups() {
-            block_groups.push(BlockGroupDescriptor::new(device, i as u8))
+            block_groups.push(BlockGroupDescriptor::new(device, i as u8)?)
         }
 
         // assert we have a valid ext2 superblock
@@ -51,19 +58,19 @@ impl Ext {
         //crate::kdbg!(superblock);
         //crate::kdbg!(&block_groups);
 
-        Self {
+        Ok(Self {
             device,
             superblock,
             block_groups,
-        }
+        })
     }
 
-    fn read_root_dir(&self) {
+    fn read_root_dir(&self) -> Result<Minode, DiskError> {
         const ROOT_INODE: usize = 2;
-        self.read_inode(2);
+        self.read_inode(2)
     }
 
-    fn read_inode(&self, inode: u32) -> Minode {
+    fn read_inode(&self, inode: u32) -> Result<Minode, DiskError> {
         use super::BUFFERS;
         let block_no = self.block_containing_inode(inode);
 
@@ -75,7 +82,7 @@ impl Ext {
         let disk_index = (inode - 1) % 4;
 
         let mut bufs = BUFFERS.lock();
-        let buf = bufs.read(self.device, disk_block);
+        let buf = bufs.read(self.device, disk_block)?;
         let b = buf.borrow();
 
         let mut inode_raw: [u8; 256] = [0; 256];
@@ -84,16 +91,16 @@ impl Ext {
         }
 
         let node = unsafe { core::intrinsics::transmute::<[u8; 256], Inode>(inode_raw) };
-        node.into_minode(self.device, inode)
+        Ok(node.into_minode(self.device, inode))
     }
 
     /// zero a block
-    fn block_zero(&self, block_no: u32) {
+    fn block_zero(&self, block_no: u32) -> Result<(), DiskError> {
         use super::BUFFERS;
         let mut bufs = BUFFERS.lock();
-        let b = bufs.read(self.device, block_no);
+        let b = bufs.read(self.device, block_no)?;
         *b.borrow_mut().data_mut() = [0; BSIZE];
-        bcache::BufferCache::write(b);
+        bcache::BufferCache::write(b)
     }
 
     fn block_group_containing_inode(&self, inode: u32) -> &BlockGroupDescriptor {
@@ -170,12 +177,12 @@ struct Superblock {
 
 impl Superblock {
     /// Read the superblock from the disk
-    pub fn read(device: u32) -> Self {
+    pub fn read(device: u32) -> Result<Self, DiskError> {
         use core::mem::transmute;
         let mut b = super::BUFFERS.lock();
         // the superblock will in block 1
-        let data = b.read(device, 1).borrow().data().clone();
-        unsafe { transmute::<[u8; 1024], Superblock>(data) }
+        let data = b.read(device, 1)?.borrow().data().clone();
+        Ok(unsafe { transmute::<[u8; 1024], Superblock>(data) })
     }
 
     // check magic ext2 value
@@ -288,7 +295,7 @@ struct BlockGroupDescriptor {
 }
 
 impl BlockGroupDescriptor {
-    pub fn new(device: u32, index: u8) -> Self {
+    pub fn new(device: u32, index: u8) -> Result<Self, DiskError> {
         use core::mem::transmute;
 
         let size = core::mem::size_of::<Self>();
@@ -297,7 +304,8 @@ impl BlockGroupDescriptor {
 
         let mut b = super::BUFFERS.lock();
         // the group descriptor will in block 4
-        let block = b.read(device, 2).borrow();
+        let buffer = b.read(device, 2)?;
+        let block = buffer.borrow();
 
         let mut bgd_raw: [u8; 32] = [0; 32];
         let data = block.data();
@@ -305,19 +313,19 @@ impl BlockGroupDescriptor {
             bgd_raw[i] = data[i + (size * index as usize)];
         }
 
-        unsafe { transmute::<[u8; 32], Self>(bgd_raw) }
+        Ok(unsafe { transmute::<[u8; 32], Self>(bgd_raw) })
     }
 
-    pub fn block_bitmap(&self, device: u32) -> Bitmap<BSIZE> {
+    pub fn block_bitmap(&self, device: u32) -> Result<Bitmap<BSIZE>, DiskError> {
         let mut b = super::BUFFERS.lock();
-        let block = b.read(device, self.block_bitmap);
-        unsafe { transmute_copy(block.borrow().data()) }
+        let block = b.read(device, self.block_bitmap)?;
+        Ok(unsafe { transmute_copy(block.borrow().data()) })
     }
 
-    pub fn inode_bitmap(&self, device: u32) -> Bitmap<BSIZE> {
+    pub fn inode_bitmap(&self, device: u32) -> Result<Bitmap<BSIZE>, DiskError> {
         let mut b = super::BUFFERS.lock();
-        let block = b.read(device, self.inode_bitmap);
-        unsafe { transmute_copy(block.borrow().data()) }
+        let block = b.read(device, self.inode_bitmap)?;
+        Ok(unsafe { transmute_copy(block.borrow().data()) })
     }
 }
 
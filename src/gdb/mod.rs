@@ -0,0 +1,472 @@
+//! A gdb remote serial protocol stub on COM2, attach with
+//! `target remote localhost:1234` after `./x.py run --gdb`
+//!
+//! While gdb has control the other cpus are parked in their nmi handler. An exception on
+//! one of them at the same time as the stopped cpu is not reported to gdb, it takes the
+//! normal handler instead, and gdb only sees the registers of the cpu that stopped.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use serial::Uart;
+use spin::Mutex;
+use x86_64::registers::control::Cr0;
+use x86_64::registers::debug::Dr6;
+
+use crate::interrupts::trap::TrapFrame;
+use crate::io::LAPIC;
+use crate::paging::is_mapped;
+use crate::proc::cpu::Rflags;
+use crate::proc::percpu;
+use packet::{parse_hex, parse_hex_le, Connection, Response, PACKET_SIZE};
+
+mod packet;
+
+const COM2: u16 = 0x2F8;
+const MAX_BREAKPOINTS: usize = 64;
+const INT3: u8 = 0xCC;
+
+// unix signal numbers gdb expects in stop replies
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// Number of registers in the amd64 `g` packet that we report,
+/// rax..r15, rip, eflags, cs, ss, ds, es, fs, gs
+const NUM_REGISTERS: usize = 24;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Set while gdb has control, the other cpus spin in [`park`] until it is cleared
+static HALTED: AtomicBool = AtomicBool::new(false);
+static GDB: Mutex<Option<Gdb>> = Mutex::new(None);
+
+/// A software breakpoint, the int3 replaced `original`
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    original: u8,
+}
+
+/// What to do after a command
+enum Action {
+    Reply,
+    Resume,
+}
+
+struct Gdb {
+    connection: Connection,
+    packet: Box<[u8]>,
+    response: Response,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    stepping: bool,
+    signal: u8,
+}
+
+/// Look for a uart on COM2 and start handling exceptions there, returns false if there is none
+pub fn init() -> bool {
+    let mut uart = Uart::new(COM2);
+    if !unsafe { uart.try_init() } {
+        return false;
+    }
+
+    *GDB.lock() = Some(Gdb {
+        connection: Connection::new(uart),
+        packet: vec![0; PACKET_SIZE].into_boxed_slice(),
+        response: Response::new(),
+        breakpoints: [None; MAX_BREAKPOINTS],
+        stepping: false,
+        signal: SIGTRAP,
+    });
+    ENABLED.store(true, Ordering::SeqCst);
+
+    crate::kprintln!("GDB stub waiting on COM2");
+    true
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Stop and hand control to gdb, does nothing if the stub is not running
+pub fn breakpoint() {
+    if is_enabled() {
+        unsafe { asm!("int3", options(nomem, nostack)) };
+    }
+}
+
+/// Called by the exception handlers that go through a trap stub,
+/// returns false if gdb is not attached and the normal handler should run
+pub fn handle_exception(frame: &mut TrapFrame) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+
+    // a fault while we are talking to gdb, let the normal handler report it
+    let mut gdb = match GDB.try_lock() {
+        Some(gdb) => gdb,
+        None => return false,
+    };
+    let gdb = match gdb.as_mut() {
+        Some(gdb) => gdb,
+        None => return false,
+    };
+
+    if frame.vector == 1 {
+        // only our single steps, watchpoints are left to their own callback
+        if !gdb.stepping || !Dr6::read().contains(Dr6::STEP) {
+            return false;
+        }
+        Dr6::clear();
+    }
+
+    gdb.stepping = false;
+    frame.set_flags(frame.flags() - Rflags::TF);
+    gdb.signal = signal(frame.vector);
+
+    stop_others();
+    gdb.stop_reply();
+    gdb.run(frame);
+    HALTED.store(false, Ordering::SeqCst);
+    true
+}
+
+/// Send the other cpus into [`park`] so they stay put while gdb looks at memory
+fn stop_others() {
+    HALTED.store(true, Ordering::SeqCst);
+    if percpu::count() > 1 {
+        unsafe { (*LAPIC.as_mut_ptr()).send_nmi_to_others() };
+    }
+}
+
+/// Called by the nmi handler, waits while gdb has control and returns true if it did
+pub fn park() -> bool {
+    if !HALTED.load(Ordering::SeqCst) {
+        return false;
+    }
+    while HALTED.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// The signal an exception is reported as
+fn signal(vector: u64) -> u8 {
+    match vector {
+        0 => SIGFPE,
+        6 => SIGILL,
+        13 | 14 => SIGSEGV,
+        _ => SIGTRAP,
+    }
+}
+
+impl Gdb {
+    /// Serve commands until gdb continues or steps
+    fn run(&mut self, frame: &mut TrapFrame) {
+        loop {
+            let mut packet = core::mem::take(&mut self.packet);
+            let len = self.connection.receive(&mut packet);
+            self.response.clear();
+            let action = self.command(&packet[..len], frame);
+            self.packet = packet;
+
+            match action {
+                Action::Reply => self.connection.send(self.response.as_bytes()),
+                Action::Resume => return,
+            }
+        }
+    }
+
+    fn stop_reply(&mut self) {
+        self.response.clear();
+        self.response.push(b'S');
+        self.response.push_hex(self.signal);
+        self.connection.send(self.response.as_bytes());
+    }
+
+    fn command(&mut self, packet: &[u8], frame: &mut TrapFrame) -> Action {
+        let (command, args) = match packet.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Action::Reply,
+        };
+
+        match command {
+            b'?' => {
+                self.response.push(b'S');
+                self.response.push_hex(self.signal);
+            }
+            b'g' => {
+                for n in 0..NUM_REGISTERS {
+                    let (value, size) = register(frame, n);
+                    self.response.push_hex_le(value, size);
+                }
+            }
+            b'G' => self.write_registers(args, frame),
+            b'p' => match parse_hex(args) {
+                Some(n) if (n as usize) < NUM_REGISTERS => {
+                    let (value, size) = register(frame, n as usize);
+                    self.response.push_hex_le(value, size);
+                }
+                _ => self.response.push_str("E01"),
+            },
+            b'P' => {
+                let parsed = split(args, b'=')
+                    .and_then(|(n, value)| Some((parse_hex(n)? as usize, parse_hex_le(value)?)));
+                match parsed {
+                    Some((n, value)) if n < NUM_REGISTERS => {
+                        set_register(frame, n, value);
+                        self.response.push_str("OK");
+                    }
+                    _ => self.response.push_str("E01"),
+                }
+            }
+            b'm' => self.read_memory(args),
+            b'M' => self.write_memory(args),
+            b'c' => {
+                if let Some(address) = parse_hex(args) {
+                    frame.rip = address;
+                }
+                return Action::Resume;
+            }
+            b's' => {
+                if let Some(address) = parse_hex(args) {
+                    frame.rip = address;
+                }
+                self.stepping = true;
+                frame.set_flags(frame.flags() | Rflags::TF);
+                return Action::Resume;
+            }
+            b'Z' | b'z' => self.breakpoint_command(command == b'Z', args),
+            b'D' => {
+                self.remove_all_breakpoints();
+                self.response.push_str("OK");
+                self.connection.send(self.response.as_bytes());
+                return Action::Resume;
+            }
+            b'k' => {
+                self.remove_all_breakpoints();
+                return Action::Resume;
+            }
+            b'H' => self.response.push_str("OK"), // there is only one thread
+            b'q' if args.starts_with(b"Supported") => {
+                self.response.push_str("PacketSize=1000");
+            }
+            b'q' if args == b"Attached" => self.response.push(b'1'),
+            // anything else is unsupported, which is an empty reply
+            _ => {}
+        }
+        Action::Reply
+    }
+
+    fn write_registers(&mut self, args: &[u8], frame: &mut TrapFrame) {
+        let mut rest = args;
+        for n in 0..NUM_REGISTERS {
+            let size = register(frame, n).1;
+            if rest.len() / 2 < size {
+                break;
+            }
+            let (value, tail) = rest.split_at(size * 2);
+            rest = tail;
+
+            // registers gdb does not know are sent as 'x'
+            if let Some(value) = parse_hex_le(value) {
+                set_register(frame, n, value);
+            }
+        }
+        self.response.push_str("OK");
+    }
+
+    /// `m addr,length`
+    fn read_memory(&mut self, args: &[u8]) {
+        let (address, len) = match parse_range(args) {
+            Some(range) => range,
+            None => return self.response.push_str("E01"),
+        };
+        let len = len.min(self.response.remaining() / 2);
+        if !is_mapped(address, len) {
+            return self.response.push_str("E14");
+        }
+
+        for i in 0..len as u64 {
+            let byte = unsafe { ((address + i) as *const u8).read_volatile() };
+            self.response.push_hex(byte);
+        }
+    }
+
+    /// `M addr,length:XX...`
+    fn write_memory(&mut self, args: &[u8]) {
+        let parsed = split(args, b':').and_then(|(range, data)| Some((parse_range(range)?, data)));
+        let ((address, len), data) = match parsed {
+            Some(parsed) if parsed.0 .1.checked_mul(2) == Some(parsed.1.len()) => parsed,
+            _ => return self.response.push_str("E01"),
+        };
+        if !is_mapped(address, len) {
+            return self.response.push_str("E14");
+        }
+
+        for (i, pair) in data.chunks(2).enumerate() {
+            match parse_hex(pair) {
+                Some(byte) => unsafe { poke(address + i as u64, byte as u8) },
+                None => return self.response.push_str("E01"),
+            }
+        }
+        self.response.push_str("OK");
+    }
+
+    /// `Z0,addr,kind` and `z0,addr,kind`, only software breakpoints are supported
+    fn breakpoint_command(&mut self, insert: bool, args: &[u8]) {
+        let mut fields = args.split(|byte| *byte == b',');
+        let kind = fields.next();
+        let address = fields.next().and_then(parse_hex);
+        let address = match (kind, address) {
+            (Some(b"0"), Some(address)) => address,
+            _ => return, // unsupported, gdb will fall back to writing int3 itself
+        };
+
+        let done = if insert {
+            self.insert_breakpoint(address)
+        } else {
+            self.remove_breakpoint(address)
+        };
+        self.response.push_str(if done { "OK" } else { "E01" });
+    }
+
+    fn insert_breakpoint(&mut self, address: u64) -> bool {
+        if self
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|b| b.address == address)
+        {
+            return true;
+        }
+        let slot = match self.breakpoints.iter_mut().find(|b| b.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        if !is_mapped(address, 1) {
+            return false;
+        }
+
+        let original = unsafe { (address as *const u8).read_volatile() };
+        unsafe { poke(address, INT3) };
+        *slot = Some(Breakpoint { address, original });
+        true
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) -> bool {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(breakpoint) = slot {
+                if breakpoint.address == address {
+                    unsafe { poke(address, breakpoint.original) };
+                    *slot = None;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(breakpoint) = slot.take() {
+                unsafe { poke(breakpoint.address, breakpoint.original) };
+            }
+        }
+        self.stepping = false;
+    }
+}
+
+/// Register `n` in the amd64 `g` packet order, and its size in bytes
+fn register(frame: &TrapFrame, n: usize) -> (u64, usize) {
+    match n {
+        0 => (frame.rax, 8),
+        1 => (frame.rbx, 8),
+        2 => (frame.rcx, 8),
+        3 => (frame.rdx, 8),
+        4 => (frame.rsi, 8),
+        5 => (frame.rdi, 8),
+        6 => (frame.rbp, 8),
+        7 => (frame.rsp, 8),
+        8 => (frame.r8, 8),
+        9 => (frame.r9, 8),
+        10 => (frame.r10, 8),
+        11 => (frame.r11, 8),
+        12 => (frame.r12, 8),
+        13 => (frame.r13, 8),
+        14 => (frame.r14, 8),
+        15 => (frame.r15, 8),
+        16 => (frame.rip, 8),
+        17 => (frame.rflags, 4),
+        18 => (frame.cs, 4),
+        19 => (frame.ss, 4),
+        // the kernel runs with null data segments
+        _ => (0, 4),
+    }
+}
+
+/// Change a register, segments can not be changed
+fn set_register(frame: &mut TrapFrame, n: usize, value: u64) {
+    match n {
+        0 => frame.rax = value,
+        1 => frame.rbx = value,
+        2 => frame.rcx = value,
+        3 => frame.rdx = value,
+        4 => frame.rsi = value,
+        5 => frame.rdi = value,
+        6 => frame.rbp = value,
+        7 => frame.rsp = value,
+        8 => frame.r8 = value,
+        9 => frame.r9 = value,
+        10 => frame.r10 = value,
+        11 => frame.r11 = value,
+        12 => frame.r12 = value,
+        13 => frame.r13 = value,
+        14 => frame.r14 = value,
+        15 => frame.r15 = value,
+        16 => frame.rip = value,
+        17 => frame.rflags = value,
+        _ => {}
+    }
+}
+
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().position(|byte| *byte == separator)?;
+    Some((&bytes[..index], &bytes[index + 1..]))
+}
+
+/// `addr,length`
+fn parse_range(args: &[u8]) -> Option<(u64, usize)> {
+    let (address, len) = split(args, b',')?;
+    Some((parse_hex(address)?, parse_hex(len)? as usize))
+}
+
+/// Write a byte even if the page is read only (like kernel code)
+/// # Safety
+/// `address` must be mapped
+unsafe fn poke(address: u64, byte: u8) {
+    let write_protect = Cr0::read().contains(Cr0::WRITE_PROTECT);
+    Cr0::update(|flags| flags.remove(Cr0::WRITE_PROTECT));
+    (address as *mut u8).write_volatile(byte);
+    if write_protect {
+        Cr0::update(|flags| flags.insert(Cr0::WRITE_PROTECT));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn register_order() {
+        let mut frame: TrapFrame = unsafe { core::mem::zeroed() };
+        frame.rsp = 0x1000;
+        frame.rip = 0x2000;
+        assert_eq!(register(&frame, 7), (0x1000, 8));
+        assert_eq!(register(&frame, 16), (0x2000, 8));
+
+        set_register(&mut frame, 0, 42);
+        assert_eq!(frame.rax, 42);
+    }
+}
@@ -0,0 +1,182 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use serial::Uart;
+
+/// Largest packet we accept or send, reported to gdb in `qSupported`
+pub const PACKET_SIZE: usize = 0x1000;
+
+/// The framing of the remote serial protocol, `$<payload>#<checksum>` acked with `+`/`-`
+pub struct Connection {
+    uart: Uart,
+}
+
+impl Connection {
+    pub fn new(uart: Uart) -> Self {
+        Self { uart }
+    }
+
+    /// Wait for a packet with a valid checksum and return the length of its payload
+    pub fn receive(&mut self, buf: &mut [u8]) -> usize {
+        loop {
+            // skip acks and interrupts (0x03) until the start of a packet
+            while self.uart.receive() != b'$' {}
+
+            let mut len = 0;
+            let mut sum = 0u8;
+            loop {
+                let byte = self.uart.receive();
+                if byte == b'#' {
+                    break;
+                }
+                if len < buf.len() {
+                    buf[len] = byte;
+                    len += 1;
+                }
+                sum = sum.wrapping_add(byte);
+            }
+
+            let high = hex_value(self.uart.receive());
+            let low = hex_value(self.uart.receive());
+            match (high, low) {
+                (Some(high), Some(low)) if high << 4 | low == sum => {
+                    self.uart.send(b'+');
+                    return len;
+                }
+                _ => self.uart.send(b'-'),
+            }
+        }
+    }
+
+    /// Send a packet, resending until gdb acks it
+    pub fn send(&mut self, payload: &[u8]) {
+        let sum = payload
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        loop {
+            self.uart.send(b'$');
+            for byte in payload {
+                self.uart.send(*byte);
+            }
+            self.uart.send(b'#');
+            self.uart.send(HEX[(sum >> 4) as usize]);
+            self.uart.send(HEX[(sum & 0xF) as usize]);
+
+            if self.uart.receive() != b'-' {
+                return;
+            }
+        }
+    }
+}
+
+/// An outgoing payload, allocated once so nothing allocates while the kernel is stopped
+pub struct Response {
+    buf: Box<[u8]>,
+    len: usize,
+}
+
+impl Response {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![0; PACKET_SIZE].into_boxed_slice(),
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Bytes that can still be pushed
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.len
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+    }
+
+    /// Push a byte as two hex digits
+    pub fn push_hex(&mut self, byte: u8) {
+        self.push(HEX[(byte >> 4) as usize]);
+        self.push(HEX[(byte & 0xF) as usize]);
+    }
+
+    /// Push the low `size` bytes of `value` in target (little endian) order
+    pub fn push_hex_le(&mut self, value: u64, size: usize) {
+        for byte in value.to_le_bytes().iter().take(size) {
+            self.push_hex(*byte);
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+pub fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a big endian hex number, like the addresses and lengths in commands
+pub fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |value, digit| {
+        Some(value << 4 | hex_value(*digit)? as u64)
+    })
+}
+
+/// Parse hex bytes in target (little endian) order, like register values
+pub fn parse_hex_le(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut value = 0u64;
+    for (i, pair) in digits.chunks(2).enumerate() {
+        value |= parse_hex(pair)? << (i * 8);
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn hex_parsing() {
+        assert_eq!(parse_hex(b"ffff8000"), Some(0xffff_8000));
+        assert_eq!(parse_hex(b"x1"), None);
+        assert_eq!(parse_hex_le(b"34120000"), Some(0x1234));
+        assert_eq!(parse_hex_le(b"123"), None);
+    }
+
+    #[test_case]
+    fn response_hex() {
+        let mut response = Response::new();
+        response.push_hex_le(0x1234, 4);
+        assert_eq!(response.as_bytes(), b"34120000");
+    }
+}
@@ -67,6 +67,12 @@ impl SelectorError {
     }
 }
 
+impl From<u64> for SelectorError {
+    fn from(error_code: u64) -> Self {
+        Self(error_code)
+    }
+}
+
 impl Debug for SelectorError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut s = f.debug_struct("SelectorError");
@@ -19,12 +19,12 @@ impl GlobalDescriptorTable {
         Self(gdt, 1)
     }
 
+    /// Add an entry, the selector requests the same privilege level as the descriptor
     pub const fn push(&mut self, entry: Entry) -> SegmentSelector {
         let index = self.1;
         self.0[self.1 as usize] = entry;
         self.1 += 1;
-        // TODO support more then just ring0
-        SegmentSelector::new(index as u16, PrivilegeLevel::Ring0)
+        SegmentSelector::new(index as u16, entry.privilege_level())
     }
 
     fn pointer(&self) -> DescriptorTablePointer {
@@ -154,6 +154,17 @@ impl Entry {
         (low, high)
     }
 
+    /// The descriptor privilege level, system entries are always ring 0
+    pub const fn privilege_level(&self) -> PrivilegeLevel {
+        let flags = self.flags;
+        match (flags.bits() >> 5) & 0b11 {
+            0 => PrivilegeLevel::Ring0,
+            1 => PrivilegeLevel::Ring1,
+            2 => PrivilegeLevel::Ring2,
+            _ => PrivilegeLevel::Ring3,
+        }
+    }
+
     pub fn get_limit(&self) -> VirtualAddress {
         let flags = self.flags;
 
@@ -294,4 +305,15 @@ mod tests {
         let data_pl_three: u64 = unsafe{ transmute(Entry::new(0, Flags::DATA_PL_THREE)) };
         assert_eq!(data_pl_three, 0x00cff3000000ffff);
     }
+
+    /// Selectors of user segments must request ring 3
+    #[test_case]
+    fn push_privilege_level() {
+        let mut gdt = GlobalDescriptorTable::new();
+        let kernel = gdt.push(Entry::new(0, Flags::CODE_PL_ZERO));
+        let user = gdt.push(Entry::new(0, Flags::DATA_PL_THREE));
+        assert_eq!(kernel.get_privilege_level(), PrivilegeLevel::Ring0);
+        assert_eq!(user.get_privilege_level(), PrivilegeLevel::Ring3);
+        assert_eq!(user.bits(), 0x13);
+    }
 }
@@ -128,6 +128,13 @@ impl<T> Entry<T> {
         self.selector = SegmentSelector::code_segment();
     }
 
+    /// Set a present entry with a handler that is not a rust function, like a trap stub
+    /// # Safety
+    /// The address must be the start of code that returns with `iretq`
+    pub unsafe fn set_raw_handler(&mut self, handler: u64) {
+        self.set_handler_addr(handler);
+    }
+
     /// Create a non present entry
     fn empty() -> Self {
         Self {
@@ -230,8 +237,10 @@ pub enum InterruptIndex {
     Timer = 0,
     Keyboard = 1,
     Com1 = 4,
+    Rtc = 8,
     Ide = 14,
     Error = 19,
+    Hpet = 20,
     Spurious = 31,
 }
 
@@ -241,8 +250,10 @@ impl From<usize> for InterruptIndex {
             0 => Self::Timer,
             1 => Self::Keyboard,
             4 => Self::Com1,
+            8 => Self::Rtc,
             14 => Self::Ide,
             19 => Self::Error,
+            20 => Self::Hpet,
             31 => Self::Spurious,
             _ => unreachable!(),
         }
@@ -262,47 +273,71 @@ impl From<u8> for InterruptIndex {
 }
 
 pub mod handlers {
+    use super::InterruptIndex;
     use crate::interrupts::errors::{ExceptionStackFrame, PageFaultErrorCode, SelectorError};
-    use crate::interrupts::halt_loop;
+    use crate::interrupts::trap::TrapFrame;
+    use crate::interrupts::{halt_loop, stats};
     use crate::kprintln;
 
     /// 1
-    pub extern "x86-interrupt" fn divide_by_zero(stack_frame: ExceptionStackFrame) {
-        kprintln!("EXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
+    pub fn divide_by_zero(frame: &mut TrapFrame) {
+        if crate::gdb::handle_exception(frame) {
+            return;
+        }
+        kprintln!("EXCEPTION: DIVIDE BY ZERO\n{:#?}", frame);
     }
 
-    /// 2
-    pub extern "x86-interrupt" fn debug(stack_frame: ExceptionStackFrame) {
-        kprintln!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+    /// 2, goes through a trap stub so watchpoint callbacks get the registers
+    pub fn debug(frame: &mut TrapFrame) {
+        if crate::gdb::handle_exception(frame) {
+            return;
+        }
+        if crate::interrupts::watchpoint::debug_exception(frame) {
+            return;
+        }
+        kprintln!("EXCEPTION: DEBUG\n{:#?}", frame);
     }
 
     /// 3
     pub extern "x86-interrupt" fn non_maskable_interrupt(stack_frame: ExceptionStackFrame) {
+        if crate::gdb::park() {
+            return;
+        }
         kprintln!("EXCEPTION: NON MASKABLE INTERRUPT\n{:#?}", stack_frame);
     }
 
     /// 4
-    pub extern "x86-interrupt" fn breakpoint(stack_frame: ExceptionStackFrame) {
-        kprintln!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    pub fn breakpoint(frame: &mut TrapFrame) {
+        if crate::gdb::handle_exception(frame) {
+            return;
+        }
+        kprintln!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
     }
 
     /// 5
-    pub extern "x86-interrupt" fn overflow(stack_frame: ExceptionStackFrame) {
-        kprintln!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
+    pub fn overflow(frame: &mut TrapFrame) {
+        kprintln!("EXCEPTION: OVERFLOW\n{:#?}", frame);
     }
 
     /// 6
-    pub extern "x86-interrupt" fn bound_range_exceeded(stack_frame: ExceptionStackFrame) {
-        kprintln!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
+    pub fn bound_range_exceeded(frame: &mut TrapFrame) {
+        kprintln!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", frame);
     }
 
     /// 7
-    pub extern "x86-interrupt" fn invalid_opcode(stack_frame: ExceptionStackFrame) {
-        kprintln!("EXCEPTION: INVALID_OPCODE\n{:#?}", stack_frame);
+    pub fn invalid_opcode(frame: &mut TrapFrame) {
+        if crate::gdb::handle_exception(frame) {
+            return;
+        }
+        kprintln!("EXCEPTION: INVALID_OPCODE\n{:#?}", frame);
     }
 
     /// 8
     pub extern "x86-interrupt" fn device_not_available(stack_frame: ExceptionStackFrame) {
+        // lazy fpu switching, load the state of the running task
+        if crate::proc::fpu::device_not_available() {
+            return;
+        }
         kprintln!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
     }
 
@@ -324,69 +359,61 @@ pub mod handlers {
     }
 
     /// 11
-    pub extern "x86-interrupt" fn segment_not_present(
-        stack_frame: ExceptionStackFrame,
-        error_code: SelectorError,
-    ) {
+    pub fn segment_not_present(frame: &mut TrapFrame) {
         kprintln!(
             "EXCEPTION: SEGMENT_NOT_PRESENT\n{:#?}\n{:#?}",
-            stack_frame,
-            error_code
+            frame,
+            SelectorError::from(frame.error_code)
         );
     }
 
     /// 12
-    pub extern "x86-interrupt" fn stack_segment_fault(
-        stack_frame: ExceptionStackFrame,
-        error_code: u64,
-    ) {
+    pub fn stack_segment_fault(frame: &mut TrapFrame) {
         kprintln!(
             "EXCEPTION: STACK SEGMENT FAULT\n{:#?}\nError Code: {}",
-            stack_frame,
-            error_code
+            frame,
+            frame.error_code
         );
     }
 
     /// 13
-    pub extern "x86-interrupt" fn general_protection_fault(
-        stack_frame: ExceptionStackFrame,
-        error_code: u64,
-    ) {
+    pub fn general_protection_fault(frame: &mut TrapFrame) {
+        if crate::gdb::handle_exception(frame) {
+            return;
+        }
         kprintln!(
             "EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}\nError Code: {}",
-            stack_frame,
-            error_code
+            frame,
+            frame.error_code
         );
     }
 
     /// 14
-    pub extern "x86-interrupt" fn page_fault(
-        stack_frame: ExceptionStackFrame,
-        error_code: PageFaultErrorCode,
-    ) {
+    pub fn page_fault(frame: &mut TrapFrame) {
         use x86_64::registers::control::Cr2;
 
+        if crate::gdb::handle_exception(frame) {
+            return;
+        }
+        let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
         kprintln!("EXCEPTION: PAGE FAULT");
         kprintln!("Accessed Address: {:?}", Cr2::read());
         kprintln!("Error Code: {:?}", error_code);
-        kprintln!("{:#?}", stack_frame);
+        kprintln!("{:#?}", frame);
         halt_loop();
     }
 
     /// 15
-    pub extern "x86-interrupt" fn x87_floating_point(stack_frame: ExceptionStackFrame) {
-        kprintln!("EXCEPTION: x87 FLOATING POINT\n{:#?}", stack_frame);
+    pub fn x87_floating_point(frame: &mut TrapFrame) {
+        kprintln!("EXCEPTION: x87 FLOATING POINT\n{:#?}", frame);
     }
 
     /// 16
-    pub extern "x86-interrupt" fn alignment_check(
-        stack_frame: ExceptionStackFrame,
-        error_code: u64,
-    ) {
+    pub fn alignment_check(frame: &mut TrapFrame) {
         kprintln!(
-            "EXCEPTION: x87 FLOATING POINT\n{:#?}\nError Code: {}",
-            stack_frame,
-            error_code
+            "EXCEPTION: ALIGNMENT CHECK\n{:#?}\nError Code: {}",
+            frame,
+            frame.error_code
         );
     }
 
@@ -396,8 +423,8 @@ pub mod handlers {
     }
 
     /// 18
-    pub extern "x86-interrupt" fn simd_floating_point(stack_frame: ExceptionStackFrame) {
-        kprintln!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
+    pub fn simd_floating_point(frame: &mut TrapFrame) {
+        kprintln!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", frame);
     }
 
     /// 19
@@ -419,15 +446,24 @@ pub mod handlers {
 
     // Interrupt handler
 
+    /// Count the interrupt and time the handler until the guard is dropped
+    fn irq_stats(index: InterruptIndex) -> stats::HandlerTimer {
+        stats::enter(crate::consts::IRQ_0 + index as u8)
+    }
+
     /// Timer Interrupt
-    pub extern "x86-interrupt" fn timer(_stack_frame: ExceptionStackFrame) {
+    pub fn timer(_frame: &mut TrapFrame) {
+        let _stats = irq_stats(InterruptIndex::Timer);
+        crate::time::tick();
         lapic_eoi();
     }
 
     /// Keyboard Interrupt
-    pub extern "x86-interrupt" fn keyboard(_stack_frame: ExceptionStackFrame) {
+    pub fn keyboard(_frame: &mut TrapFrame) {
         use crate::io::keyboard::Keyboard;
 
+        let _stats = irq_stats(InterruptIndex::Keyboard);
+
         let keyboard = Keyboard::new();
         if let Some(key) = keyboard.get_scancode() {
             crate::io::keyboard::add_scancode(key);
@@ -436,12 +472,43 @@ pub mod handlers {
         lapic_eoi();
     }
 
+    /// Real time clock Interrupt
+    pub fn rtc(_frame: &mut TrapFrame) {
+        let _stats = irq_stats(InterruptIndex::Rtc);
+        crate::io::rtc::interrupt();
+        lapic_eoi();
+    }
+
     /// Ide Interrupt
-    pub extern "x86-interrupt" fn ide(_stack_frame: ExceptionStackFrame) {
+    pub fn ide(_frame: &mut TrapFrame) {
+        let _stats = irq_stats(InterruptIndex::Ide);
         crate::disk::interrupt_handler();
         lapic_eoi();
     }
 
+    /// Hpet comparator Interrupt
+    pub fn hpet(_frame: &mut TrapFrame) {
+        let _stats = irq_stats(InterruptIndex::Hpet);
+        crate::io::hpet::interrupt();
+        lapic_eoi();
+    }
+
+    /// Local apic error Interrupt
+    pub fn lapic_error(_frame: &mut TrapFrame) {
+        let _stats = irq_stats(InterruptIndex::Error);
+        stats::record_lapic_error();
+
+        let status = unsafe { (*crate::io::LAPIC.as_mut_ptr()).update_error_status() };
+        kprintln!("LAPIC ERROR: {:?}", status);
+        lapic_eoi();
+    }
+
+    /// Spurious Interrupt, these are not real interrupts so they must not be acknowledged
+    pub fn spurious(_frame: &mut TrapFrame) {
+        let _stats = irq_stats(InterruptIndex::Spurious);
+        stats::record_spurious();
+    }
+
     /// Send a end of interrupt to the local apic
     pub fn lapic_eoi() {
         unsafe { (*crate::io::LAPIC.as_mut_ptr()).end_of_interrupt() };
@@ -12,13 +12,24 @@ use crate::kprintln;
 pub mod errors;
 pub mod gdt;
 pub mod idt;
+pub mod stats;
+pub mod trap;
 pub mod tss;
+pub mod vector;
+pub mod watchpoint;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+// The gdt layout is fixed, syscall and sysret expect kernel code, kernel data,
+// then user data followed by user code
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+
 pub struct Selectors {
     pub kernel_code_segment: SegmentSelector,
     pub kernel_data_segment: SegmentSelector,
+    pub user_data_segment: SegmentSelector,
+    pub user_code_segment: SegmentSelector,
     pub tss_segment: SegmentSelector,
 }
 
@@ -71,6 +82,11 @@ impl SegmentSelector {
         self.0 >> 3
     }
 
+    /// The raw value loaded into a segment register
+    pub const fn bits(&self) -> u16 {
+        self.0
+    }
+
     // gets the code segment index
     fn code_segment() -> Self {
         let segment: u16;
@@ -100,18 +116,46 @@ impl fmt::Debug for SegmentSelector {
 }
 
 pub static IDT: Lazy<idt::InterruptDescriptorTable> = Lazy::new(|| {
+    use crate::consts::IRQ_0;
     use idt::handlers::*;
-    use idt::InterruptIndex;
 
     let mut idt = idt::InterruptDescriptorTable::new();
-    idt.divide_by_zero.set_handler(divide_by_zero);
-    idt.debug.set_handler(debug);
+    // these go through trap stubs so the debugger can see and change all registers
+    unsafe {
+        idt.divide_by_zero.set_raw_handler(trap::stub(0));
+        idt.debug.set_raw_handler(trap::stub(1));
+        idt.breakpoint.set_raw_handler(trap::stub(3));
+        idt.invalid_opcode.set_raw_handler(trap::stub(6));
+        idt.general_protection_fault.set_raw_handler(trap::stub(13));
+        idt.page_fault.set_raw_handler(trap::stub(14));
+
+        // faults user code can cause, so they can be sent to the process
+        idt.overflow.set_raw_handler(trap::stub(4));
+        idt.bound_range_exceeded.set_raw_handler(trap::stub(5));
+        idt.segment_not_present.set_raw_handler(trap::stub(11));
+        idt.stack_segment_fault.set_raw_handler(trap::stub(12));
+        idt.x87_floating_point.set_raw_handler(trap::stub(16));
+        idt.alignment_check.set_raw_handler(trap::stub(17));
+        idt.simd_floating_point.set_raw_handler(trap::stub(19));
+
+        // int 0x80 system calls, callable from ring 3
+        let syscall = &mut idt.interrupts[(crate::syscall::SYSCALL_VECTOR - IRQ_0) as usize];
+        syscall.set_raw_handler(trap::stub(crate::syscall::SYSCALL_VECTOR));
+        syscall.options.insert(idt::Options::PRIVILEGE_THREE);
+
+        // vectors handed out to devices at run time
+        for vector in vector::ALLOCATABLE {
+            idt.interrupts[(vector - IRQ_0) as usize].set_raw_handler(trap::stub(vector));
+        }
+
+        // interrupt handlers
+        for index in IRQ_HANDLERS.map(|(index, _)| index) {
+            let vector = IRQ_0 + index as u8;
+            idt.interrupts[index as usize].set_raw_handler(trap::stub(vector));
+        }
+    }
     idt.non_maskable_interrupt
         .set_handler(non_maskable_interrupt);
-    idt.breakpoint.set_handler(breakpoint);
-    idt.overflow.set_handler(overflow);
-    idt.bound_range_exceeded.set_handler(bound_range_exceeded);
-    idt.invalid_opcode.set_handler(invalid_opcode);
     idt.device_not_available.set_handler(device_not_available);
 
     // double fault handler
@@ -121,27 +165,18 @@ pub static IDT: Lazy<idt::InterruptDescriptorTable> = Lazy::new(|| {
         .set_stack_index(DOUBLE_FAULT_IST_INDEX);
 
     idt.invalid_tss.set_handler(invalid_tss);
-    idt.segment_not_present.set_handler(segment_not_present);
-    idt.stack_segment_fault.set_handler(stack_segment_fault);
-    idt.general_protection_fault
-        .set_handler(general_protection_fault);
-    idt.page_fault.set_handler(page_fault);
-    idt.x87_floating_point.set_handler(x87_floating_point);
-    idt.alignment_check.set_handler(alignment_check);
     idt.machine_check.set_handler(machine_check);
-    idt.simd_floating_point.set_handler(simd_floating_point);
     idt.virtualization.set_handler(virtualization);
     idt.security_exception.set_handler(security_exception);
 
-    // interrupt handlers
-    idt.interrupts[InterruptIndex::Timer as usize].set_handler(timer);
-    idt.interrupts[InterruptIndex::Keyboard as usize].set_handler(keyboard);
-    idt.interrupts[InterruptIndex::Ide as usize].set_handler(ide);
-
     idt
 });
 
-pub static GDT: Lazy<(gdt::GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
+/// The tables the bootstrap processor starts with, every cpu switches to its own in
+/// [`load_cpu_tables`]. The layout is the same, so the selectors hold for all of them
+pub static GDT: Lazy<(gdt::GlobalDescriptorTable, Selectors)> = Lazy::new(|| build_gdt(&BOOT_TSS));
+
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
     use gdt::{Entry, Flags};
 
     let mut gdt = GlobalDescriptorTable::new();
@@ -149,11 +184,13 @@ pub static GDT: Lazy<(gdt::GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
     // initialized to be empty and zero should be null anyway
     let kernel_code_segment = gdt.push(Entry::new(0, Flags::CODE_PL_ZERO));
     let kernel_data_segment = gdt.push(Entry::new(0, Flags::DATA_PL_ZERO));
-    gdt.push(Entry::new(0, Flags::CODE_PL_THREE));
-    gdt.push(Entry::new(0, Flags::DATA_PL_THREE));
+    let user_data_segment = gdt.push(Entry::new(0, Flags::DATA_PL_THREE));
+    let user_code_segment = gdt.push(Entry::new(0, Flags::CODE_PL_THREE));
+    assert_eq!(user_data_segment.bits(), USER_DATA_SELECTOR.bits());
+    assert_eq!(user_code_segment.bits(), USER_CODE_SELECTOR.bits());
 
     // tss
-    let (tss_segment_1, tss_segment_2) = Entry::tss(&TSS);
+    let (tss_segment_1, tss_segment_2) = Entry::tss(tss);
     let tss_segment = gdt.push(tss_segment_1);
     gdt.push(tss_segment_2);
 
@@ -162,12 +199,14 @@ pub static GDT: Lazy<(gdt::GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
         Selectors {
             kernel_code_segment,
             kernel_data_segment,
+            user_data_segment,
+            user_code_segment,
             tss_segment,
         },
     )
-});
+}
 
-static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
+static BOOT_TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     let mut tss = TaskStateSegment::zero();
 
     tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
@@ -180,6 +219,41 @@ static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     tss
 });
 
+/// Switch this cpu to its own gdt and tss, the tss holds the stack used when entering
+/// the kernel from ring 3 so it can not be shared. `double_fault_stack` is the top of
+/// the stack for double faults, none keeps the one of the boot tss
+/// # Safety
+/// Once per cpu, after [`init`], with tables no other cpu uses
+pub unsafe fn load_cpu_tables(
+    gdt: &'static mut GlobalDescriptorTable,
+    tss: &'static mut TaskStateSegment,
+    double_fault_stack: Option<u64>,
+) {
+    let index = DOUBLE_FAULT_IST_INDEX as usize;
+    *tss = TaskStateSegment::zero();
+    tss.interrupt_stack_table[index] =
+        double_fault_stack.unwrap_or(BOOT_TSS.interrupt_stack_table[index]);
+
+    let (table, selectors) = build_gdt(tss);
+    *gdt = table;
+    let gdt: &'static GlobalDescriptorTable = gdt;
+    gdt.load();
+    gdt::load_tss(selectors.tss_segment);
+}
+
+/// Set the stack this cpu switches to when an interrupt or system call arrives from ring 3
+/// # Safety
+/// `top` must be the top of a mapped stack that nothing else is using
+pub unsafe fn set_kernel_stack(top: u64) {
+    (*crate::proc::percpu::current_tss()).privileged_stack_table[0] = top;
+    crate::proc::percpu::set_kernel_stack(top);
+}
+
+/// The stack used when entering the kernel from ring 3 on this cpu
+pub fn kernel_stack() -> u64 {
+    crate::proc::percpu::current().tss().privileged_stack_table[0]
+}
+
 pub fn disable_interrupts() {
     unsafe { asm!("cli", options(nomem, nostack)) };
 }
@@ -207,8 +281,19 @@ pub fn halt_loop() -> ! {
     }
 }
 
+/// Load an empty idt and raise an exception, the double and triple fault reset the cpu
+pub fn triple_fault() -> ! {
+    let ptr = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        asm!("lidt [{}]", in(reg) &ptr, options(readonly, nostack, preserves_flags));
+        asm!("int3", options(nomem, nostack));
+    }
+    halt_loop()
+}
+
 pub fn init() {
     GDT.0.load();
+    register_traps();
     IDT.load();
 
     unsafe {
@@ -219,6 +304,44 @@ pub fn init() {
     kprintln!("IDT & GDT initialized");
 }
 
+/// Handlers for the vectors that use trap stubs in the idt
+fn register_traps() {
+    use idt::handlers::*;
+
+    trap::register(0, divide_by_zero);
+    trap::register(1, debug);
+    trap::register(3, breakpoint);
+    trap::register(6, invalid_opcode);
+    trap::register(13, general_protection_fault);
+    trap::register(14, page_fault);
+    trap::register(4, overflow);
+    trap::register(5, bound_range_exceeded);
+    trap::register(11, segment_not_present);
+    trap::register(12, stack_segment_fault);
+    trap::register(16, x87_floating_point);
+    trap::register(17, alignment_check);
+    trap::register(19, simd_floating_point);
+    trap::register(crate::syscall::SYSCALL_VECTOR, crate::syscall::dispatch);
+    for (index, handler) in IRQ_HANDLERS {
+        trap::register(crate::consts::IRQ_0 + index as u8, handler);
+    }
+}
+
+/// The fixed interrupts, they go through trap stubs as they can interrupt ring 3
+const IRQ_HANDLERS: [(idt::InterruptIndex, trap::TrapHandler); 7] = {
+    use idt::handlers::*;
+    use idt::InterruptIndex;
+    [
+        (InterruptIndex::Timer, timer),
+        (InterruptIndex::Keyboard, keyboard),
+        (InterruptIndex::Rtc, rtc),
+        (InterruptIndex::Ide, ide),
+        (InterruptIndex::Error, lapic_error),
+        (InterruptIndex::Hpet, hpet),
+        (InterruptIndex::Spurious, spurious),
+    ]
+};
+
 // Run a chunk of code without interrupts enabled
 pub fn without_interrupts<F, R>(f: F) -> R
 where
@@ -259,4 +382,14 @@ mod tests {
         }
         uart_enable();
     }
+
+    /// The tss and the per cpu block have to agree on the stack used from ring 3
+    #[test_case]
+    fn kernel_stack_update() {
+        let old = super::kernel_stack();
+        unsafe { super::set_kernel_stack(old - 16) };
+        assert_eq!(super::kernel_stack(), old - 16);
+        assert_eq!(crate::proc::percpu::current().kernel_stack(), old - 16);
+        unsafe { super::set_kernel_stack(old) };
+    }
 }
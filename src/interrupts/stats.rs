@@ -0,0 +1,313 @@
+use alloc::alloc::{alloc_zeroed, Layout};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use x86_64::tsc::rdtsc;
+
+use crate::consts::MAX_CPUS;
+
+const VECTORS: usize = 256;
+
+/// Number of duration buckets, bucket `i` holds handlers that took
+/// `[2^(i + BUCKET_SHIFT), 2^(i + BUCKET_SHIFT + 1))` cycles,
+/// the first and last buckets also take everything below and above
+pub const BUCKETS: usize = 16;
+const BUCKET_SHIFT: u32 = 8;
+
+/// Counters and handler durations for a single cpu
+struct CpuStats {
+    counts: [AtomicU64; VECTORS],
+    timing: [VectorTiming; VECTORS],
+    spurious: AtomicU64,
+    lapic_errors: AtomicU64,
+}
+
+impl CpuStats {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY_TIMING: VectorTiming = VectorTiming::new();
+        Self {
+            counts: [ZERO; VECTORS],
+            timing: [EMPTY_TIMING; VECTORS],
+            spurious: ZERO,
+            lapic_errors: ZERO,
+        }
+    }
+}
+
+/// Handler durations of a single vector on one cpu
+struct VectorTiming {
+    histogram: [AtomicU64; BUCKETS],
+    total_cycles: AtomicU64,
+    max_cycles: AtomicU64,
+}
+
+impl VectorTiming {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            histogram: [ZERO; BUCKETS],
+            total_cycles: ZERO,
+            max_cycles: ZERO,
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_CPU: CpuStats = CpuStats::new();
+
+/// Counters of the bootstrap processor, it takes interrupts before there is a heap
+static BSP: CpuStats = EMPTY_CPU;
+/// Counters of the other cpus by per cpu index, allocated as they come up
+static APS: [AtomicPtr<CpuStats>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+/// Allocate the counters of the cpu with per cpu `index`, for all but the bootstrap processor
+pub fn init_cpu(index: usize) {
+    assert_ne!(index, 0, "the bootstrap processor has static counters");
+    // too large for the stack of a starting cpu, and zero is a valid value for the atomics
+    let stats = unsafe { alloc_zeroed(Layout::new::<CpuStats>()) } as *mut CpuStats;
+    assert!(
+        !stats.is_null(),
+        "out of memory for the interrupt stats of cpu {}",
+        index
+    );
+    APS[index].store(stats, Ordering::Release);
+}
+
+/// Counters of the cpu with per cpu `index`, if it is up
+fn cpu(index: usize) -> Option<&'static CpuStats> {
+    match index {
+        0 => Some(&BSP),
+        _ => unsafe { APS.get(index)?.load(Ordering::Acquire).as_ref() },
+    }
+}
+
+/// Counters of the running cpu
+fn current() -> &'static CpuStats {
+    cpu(crate::proc::percpu::current_index()).expect("cpu has no interrupt counters")
+}
+
+/// The histogram bucket for a handler that took `cycles`
+fn bucket(cycles: u64) -> usize {
+    let log2 = 63 - cycles.max(1).leading_zeros();
+    (log2.saturating_sub(BUCKET_SHIFT) as usize).min(BUCKETS - 1)
+}
+
+/// Count an interrupt and time its handler, the duration is recorded when this is dropped
+pub struct HandlerTimer {
+    vector: u8,
+    start: u64,
+}
+
+/// Call at the start of an interrupt handler for `vector`
+pub fn enter(vector: u8) -> HandlerTimer {
+    current().counts[vector as usize].fetch_add(1, Ordering::Relaxed);
+    HandlerTimer {
+        vector,
+        start: rdtsc(),
+    }
+}
+
+impl Drop for HandlerTimer {
+    fn drop(&mut self) {
+        let cycles = rdtsc().wrapping_sub(self.start);
+        let timing = &current().timing[self.vector as usize];
+        timing.histogram[bucket(cycles)].fetch_add(1, Ordering::Relaxed);
+        timing.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+        timing.max_cycles.fetch_max(cycles, Ordering::Relaxed);
+    }
+}
+
+/// Record a spurious interrupt on this cpu
+pub fn record_spurious() {
+    current().spurious.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record a local apic error interrupt on this cpu
+pub fn record_lapic_error() {
+    current().lapic_errors.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts and handler durations of a single vector
+#[derive(Debug, Clone)]
+pub struct VectorStats {
+    pub vector: u8,
+    /// By per cpu index
+    pub per_cpu: Vec<u64>,
+    /// The durations are summed over every cpu
+    pub histogram: [u64; BUCKETS],
+    pub total_cycles: u64,
+    pub max_cycles: u64,
+}
+
+impl VectorStats {
+    pub fn count(&self) -> u64 {
+        self.per_cpu.iter().sum()
+    }
+
+    /// Mean handler duration in cycles
+    pub fn mean_cycles(&self) -> u64 {
+        let timed: u64 = self.histogram.iter().sum();
+        self.total_cycles.checked_div(timed).unwrap_or(0)
+    }
+
+    /// Mean handler duration in nanoseconds
+    pub fn mean_nanos(&self) -> u64 {
+        crate::time::tsc::cycles_to_nanos(self.mean_cycles())
+    }
+
+    pub fn max_nanos(&self) -> u64 {
+        crate::time::tsc::cycles_to_nanos(self.max_cycles)
+    }
+}
+
+/// A point in time copy of all the interrupt statistics
+#[derive(Debug, Clone)]
+pub struct InterruptStats {
+    /// Only the vectors that have fired at least once
+    pub vectors: Vec<VectorStats>,
+    pub spurious: Vec<u64>,
+    pub lapic_errors: Vec<u64>,
+}
+
+/// Take a snapshot of the counters, other cpus may keep counting while this runs
+pub fn snapshot() -> InterruptStats {
+    let cpus: Vec<Option<&CpuStats>> = (0..crate::proc::percpu::count()).map(cpu).collect();
+    let load = |counter: fn(&CpuStats) -> &AtomicU64| -> Vec<u64> {
+        cpus.iter()
+            .map(|cpu| cpu.map_or(0, |cpu| counter(cpu).load(Ordering::Relaxed)))
+            .collect()
+    };
+
+    let mut vectors = Vec::new();
+    for vector in 0..VECTORS {
+        let per_cpu: Vec<u64> = cpus
+            .iter()
+            .map(|cpu| cpu.map_or(0, |cpu| cpu.counts[vector].load(Ordering::Relaxed)))
+            .collect();
+        if per_cpu.iter().all(|&count| count == 0) {
+            continue;
+        }
+
+        let mut stats = VectorStats {
+            vector: vector as u8,
+            per_cpu,
+            histogram: [0; BUCKETS],
+            total_cycles: 0,
+            max_cycles: 0,
+        };
+        for timing in cpus.iter().flatten().map(|cpu| &cpu.timing[vector]) {
+            for (count, bucket) in stats.histogram.iter_mut().zip(timing.histogram.iter()) {
+                *count += bucket.load(Ordering::Relaxed);
+            }
+            stats.total_cycles += timing.total_cycles.load(Ordering::Relaxed);
+            let max = timing.max_cycles.load(Ordering::Relaxed);
+            stats.max_cycles = stats.max_cycles.max(max);
+        }
+        vectors.push(stats);
+    }
+
+    InterruptStats {
+        vectors,
+        spurious: load(|cpu| &cpu.spurious),
+        lapic_errors: load(|cpu| &cpu.lapic_errors),
+    }
+}
+
+/// Set every counter back to zero
+pub fn reset() {
+    let cpus = (0..crate::proc::percpu::count()).filter_map(cpu);
+    for cpu in cpus {
+        for count in cpu.counts.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+        for timing in cpu.timing.iter() {
+            for bucket in timing.histogram.iter() {
+                bucket.store(0, Ordering::Relaxed);
+            }
+            timing.total_cycles.store(0, Ordering::Relaxed);
+            timing.max_cycles.store(0, Ordering::Relaxed);
+        }
+        cpu.spurious.store(0, Ordering::Relaxed);
+        cpu.lapic_errors.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Formats like /proc/interrupts, one row per vector and one column per cpu
+impl fmt::Display for InterruptStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cpus = self.spurious.len();
+
+        write!(f, "     ")?;
+        for cpu in 0..cpus {
+            write!(f, " {:>7}{:<3}", "CPU", cpu)?;
+        }
+        writeln!(f, " {:>12} {:>12}", "mean cyc", "max cyc")?;
+
+        for stats in self.vectors.iter() {
+            write!(f, "{:>4}:", stats.vector)?;
+            for count in stats.per_cpu.iter() {
+                write!(f, " {:>10}", count)?;
+            }
+            write!(f, " {:>12} {:>12}", stats.mean_cycles(), stats.max_cycles)?;
+            if let Some(name) = vector_name(stats.vector) {
+                write!(f, "  {}", name)?;
+            }
+            writeln!(f)?;
+        }
+
+        for (name, counts) in [("SPU", &self.spurious), ("ERR", &self.lapic_errors)] {
+            write!(f, "{:>4}:", name)?;
+            for count in counts.iter() {
+                write!(f, " {:>10}", count)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn vector_name(vector: u8) -> Option<&'static str> {
+    use super::idt::InterruptIndex;
+
+    let irq = vector.checked_sub(crate::consts::IRQ_0)?;
+    let name = match irq {
+        i if i == InterruptIndex::Timer as u8 => "timer",
+        i if i == InterruptIndex::Keyboard as u8 => "keyboard",
+        i if i == InterruptIndex::Com1 as u8 => "com1",
+        i if i == InterruptIndex::Rtc as u8 => "rtc",
+        i if i == InterruptIndex::Ide as u8 => "ide",
+        i if i == InterruptIndex::Error as u8 => "lapic error",
+        i if i == InterruptIndex::Hpet as u8 => "hpet",
+        i if i == InterruptIndex::Spurious as u8 => "spurious",
+        _ => return None,
+    };
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn bucket_bounds() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1 << BUCKET_SHIFT), 0);
+        assert_eq!(bucket(1 << (BUCKET_SHIFT + 1)), 1);
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    /// Counters are kept by per cpu index, one column per cpu that is up
+    #[test_case]
+    fn counts_by_cpu_index() {
+        let before = snapshot();
+        record_spurious();
+        let after = snapshot();
+        assert_eq!(after.spurious.len(), crate::proc::percpu::count());
+        assert_eq!(after.spurious[0], before.spurious[0] + 1);
+    }
+}
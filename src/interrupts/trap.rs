@@ -0,0 +1,302 @@
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{stats, vector};
+use crate::proc::cpu::Rflags;
+
+/// A handler that gets (and may change) the full register state of the interrupted code
+pub type TrapHandler = fn(&mut TrapFrame);
+
+/// Every register of the interrupted code, in the order the trap stubs push them
+#[derive(Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64, // zero for vectors without an error code
+    // pushed by the cpu
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    pub fn flags(&self) -> Rflags {
+        Rflags::from_bits_truncate(self.rflags)
+    }
+
+    pub fn set_flags(&mut self, flags: Rflags) {
+        self.rflags = flags.bits();
+    }
+
+    /// The trap interrupted code running in ring 3
+    pub fn is_user(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
+impl fmt::Debug for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("TrapFrame");
+        s.field("vector", &self.vector);
+        s.field("error_code", &format_args!("{:#x}", self.error_code));
+        s.field("rip", &format_args!("{:#x}", self.rip));
+        s.field("cs", &format_args!("{:#x}", self.cs));
+        s.field("rflags", &self.flags());
+        s.field("rsp", &format_args!("{:#x}", self.rsp));
+        s.field("ss", &format_args!("{:#x}", self.ss));
+        s.field("rax", &format_args!("{:#x}", self.rax));
+        s.field("rbx", &format_args!("{:#x}", self.rbx));
+        s.field("rcx", &format_args!("{:#x}", self.rcx));
+        s.field("rdx", &format_args!("{:#x}", self.rdx));
+        s.field("rsi", &format_args!("{:#x}", self.rsi));
+        s.field("rdi", &format_args!("{:#x}", self.rdi));
+        s.field("rbp", &format_args!("{:#x}", self.rbp));
+        s.field("r8", &format_args!("{:#x}", self.r8));
+        s.field("r9", &format_args!("{:#x}", self.r9));
+        s.field("r10", &format_args!("{:#x}", self.r10));
+        s.field("r11", &format_args!("{:#x}", self.r11));
+        s.field("r12", &format_args!("{:#x}", self.r12));
+        s.field("r13", &format_args!("{:#x}", self.r13));
+        s.field("r14", &format_args!("{:#x}", self.r14));
+        s.field("r15", &format_args!("{:#x}", self.r15));
+        s.finish()
+    }
+}
+
+/// Handlers for the vectors that go through a trap stub, stored as fn addresses
+/// so they can be swapped without taking a lock in the handler
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static HANDLERS: [AtomicUsize; 256] = [NO_HANDLER; 256];
+
+/// Set the handler called by the trap stub of `vector`
+pub fn register(vector: u8, handler: TrapHandler) {
+    HANDLERS[vector as usize].store(handler as usize, Ordering::SeqCst);
+}
+
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    // exceptions in ring 3 belong to the process that caused them, not the kernel
+    if frame.is_user() && frame.vector < 32 {
+        crate::proc::user::fault(frame);
+    }
+
+    // allocated vectors are device interrupts, they are counted and ended here
+    let vector = frame.vector as u8;
+    let device = vector::is_allocatable(vector);
+    let _stats = device.then(|| stats::enter(vector));
+
+    let handler = HANDLERS[frame.vector as usize].load(Ordering::SeqCst);
+    if handler == 0 {
+        crate::kprintln!("UNHANDLED TRAP\n{:#?}", frame);
+    } else {
+        let handler: TrapHandler = unsafe { core::mem::transmute(handler) };
+        handler(frame);
+    }
+
+    if device {
+        super::idt::handlers::lapic_eoi();
+    }
+}
+
+// Saves the registers into a TrapFrame on the stack, the stub already pushed the
+// error code and vector. The cpu aligns rsp to 16 before pushing its frame,
+// so after the 2 + 15 pushes it is aligned again for the call.
+// Traps from ring 3 swap in the kernel gs base on entry and swap it back out on return.
+core::arch::global_asm!(
+    ".pushsection .text",
+    ".global __trap_common",
+    "__trap_common:",
+    // coming from user mode, switch to the kernel gs base
+    "test qword ptr [rsp + 24], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call {dispatch}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "add rsp, 16", // vector and error code
+    "test qword ptr [rsp + 8], 3",
+    "jz 3f",
+    "swapgs",
+    "3:",
+    "iretq",
+    ".popsection",
+    dispatch = sym trap_dispatch,
+);
+
+/// Entry point for a vector where the cpu does not push an error code
+macro_rules! trap_stub {
+    ($name:ident, $vector:literal) => {
+        core::arch::global_asm!(
+            ".pushsection .text",
+            concat!(".global ", stringify!($name)),
+            concat!(stringify!($name), ":"),
+            "push 0",
+            concat!("push ", $vector),
+            "jmp __trap_common",
+            ".popsection",
+        );
+        extern "C" {
+            fn $name();
+        }
+    };
+}
+
+/// Entry point for a vector where the cpu pushes an error code
+macro_rules! trap_stub_error_code {
+    ($name:ident, $vector:literal) => {
+        core::arch::global_asm!(
+            ".pushsection .text",
+            concat!(".global ", stringify!($name)),
+            concat!(stringify!($name), ":"),
+            concat!("push ", $vector),
+            "jmp __trap_common",
+            ".popsection",
+        );
+        extern "C" {
+            fn $name();
+        }
+    };
+}
+
+trap_stub!(__trap_stub_divide_by_zero, 0);
+trap_stub!(__trap_stub_debug, 1);
+trap_stub!(__trap_stub_breakpoint, 3);
+trap_stub!(__trap_stub_overflow, 4);
+trap_stub!(__trap_stub_bound_range_exceeded, 5);
+trap_stub!(__trap_stub_invalid_opcode, 6);
+trap_stub_error_code!(__trap_stub_segment_not_present, 11);
+trap_stub_error_code!(__trap_stub_stack_segment_fault, 12);
+trap_stub_error_code!(__trap_stub_general_protection_fault, 13);
+trap_stub_error_code!(__trap_stub_page_fault, 14);
+trap_stub!(__trap_stub_x87_floating_point, 16);
+trap_stub_error_code!(__trap_stub_alignment_check, 17);
+trap_stub!(__trap_stub_simd_floating_point, 19);
+trap_stub!(__trap_stub_syscall, 0x80);
+// legacy and local apic interrupts, they can arrive in ring 3 and need the gs swap too
+trap_stub!(__trap_stub_timer, 32);
+trap_stub!(__trap_stub_keyboard, 33);
+trap_stub!(__trap_stub_rtc, 40);
+trap_stub!(__trap_stub_ide, 46);
+trap_stub!(__trap_stub_lapic_error, 51);
+trap_stub!(__trap_stub_hpet, 52);
+trap_stub!(__trap_stub_spurious, 63);
+
+/// Size of each stub for the allocatable vectors, they are laid out in order
+const ALLOCATABLE_STUB_SIZE: u64 = 16;
+
+// One stub per allocatable vector, padded so the stub of a vector can be found by its index
+core::arch::global_asm!(
+    ".pushsection .text",
+    ".global __trap_stubs_allocatable",
+    ".balign 16",
+    "__trap_stubs_allocatable:",
+    ".set vector, {start}",
+    ".rept {count}",
+    ".balign 16",
+    "push 0",
+    "push vector",
+    "jmp __trap_common",
+    ".set vector, vector + 1",
+    ".endr",
+    ".popsection",
+    start = const vector::ALLOCATABLE.start,
+    count = const vector::ALLOCATABLE.end - vector::ALLOCATABLE.start,
+);
+
+extern "C" {
+    fn __trap_stubs_allocatable();
+}
+
+/// Address of the trap stub for `vector`, to be placed in the idt
+pub fn stub(vector: u8) -> u64 {
+    if vector::is_allocatable(vector) {
+        let first: unsafe extern "C" fn() = __trap_stubs_allocatable;
+        let index = (vector - vector::ALLOCATABLE.start) as u64;
+        return first as usize as u64 + index * ALLOCATABLE_STUB_SIZE;
+    }
+    let stub: unsafe extern "C" fn() = match vector {
+        0 => __trap_stub_divide_by_zero,
+        1 => __trap_stub_debug,
+        3 => __trap_stub_breakpoint,
+        4 => __trap_stub_overflow,
+        5 => __trap_stub_bound_range_exceeded,
+        6 => __trap_stub_invalid_opcode,
+        11 => __trap_stub_segment_not_present,
+        12 => __trap_stub_stack_segment_fault,
+        13 => __trap_stub_general_protection_fault,
+        14 => __trap_stub_page_fault,
+        16 => __trap_stub_x87_floating_point,
+        17 => __trap_stub_alignment_check,
+        19 => __trap_stub_simd_floating_point,
+        0x80 => __trap_stub_syscall,
+        32 => __trap_stub_timer,
+        33 => __trap_stub_keyboard,
+        40 => __trap_stub_rtc,
+        46 => __trap_stub_ide,
+        51 => __trap_stub_lapic_error,
+        52 => __trap_stub_hpet,
+        63 => __trap_stub_spurious,
+        i => panic!("vector {} has no trap stub", i),
+    };
+    stub as usize as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+
+    /// Make sure the frame matches what the stubs push
+    #[test_case]
+    fn frame_size() {
+        assert_eq!(size_of::<TrapFrame>(), 8 * 22);
+    }
+}
@@ -0,0 +1,85 @@
+//! Vectors handed out at run time, for devices with message signalled interrupts.
+//!
+//! Each one goes through a trap stub, the dispatcher counts it and sends the end of
+//! interrupt after the handler returns.
+
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::trap::{self, TrapFrame, TrapHandler};
+
+/// Between the legacy irqs and the system call vector
+pub const ALLOCATABLE: Range<u8> = 0x40..0x80;
+
+/// A bit per allocatable vector, set when it is in use
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+
+pub fn is_allocatable(vector: u8) -> bool {
+    ALLOCATABLE.contains(&vector)
+}
+
+/// Take a free vector and call `handler` when it fires, none if all of them are in use
+pub fn allocate(handler: TrapHandler) -> Option<u8> {
+    let mut allocated = ALLOCATED.load(Ordering::SeqCst);
+    loop {
+        let index = (!allocated).trailing_zeros();
+        if index as usize >= ALLOCATABLE.len() {
+            return None;
+        }
+        match ALLOCATED.compare_exchange(
+            allocated,
+            allocated | 1 << index,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => {
+                let vector = ALLOCATABLE.start + index as u8;
+                trap::register(vector, handler);
+                return Some(vector);
+            }
+            Err(current) => allocated = current,
+        }
+    }
+}
+
+/// Give back a vector, the device must not send it anymore
+pub fn free(vector: u8) {
+    assert!(
+        is_allocatable(vector),
+        "vector {} was not allocated",
+        vector
+    );
+    trap::register(vector, unhandled);
+    let index = vector - ALLOCATABLE.start;
+    ALLOCATED.fetch_and(!(1 << index), Ordering::SeqCst);
+}
+
+/// Left in place of a freed handler, an interrupt that was already in flight is dropped
+fn unhandled(_frame: &mut TrapFrame) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn allocate_and_free() {
+        let first = allocate(unhandled).unwrap();
+        let second = allocate(unhandled).unwrap();
+        assert!(is_allocatable(first) && is_allocatable(second));
+        assert_ne!(first, second);
+
+        free(first);
+        assert_eq!(allocate(unhandled), Some(first));
+        free(first);
+        free(second);
+    }
+
+    /// Every stub pushes a zero error code and its vector
+    #[test_case]
+    fn stub_layout() {
+        for vector in ALLOCATABLE {
+            let stub = unsafe { core::slice::from_raw_parts(trap::stub(vector) as *const u8, 4) };
+            assert_eq!(stub, [0x6A, 0x00, 0x6A, vector]);
+        }
+    }
+}
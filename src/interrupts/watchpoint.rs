@@ -0,0 +1,280 @@
+use spin::{Lazy, Mutex};
+use x86_64::registers::debug::{
+    BreakpointCondition, BreakpointSize, DebugAddressRegister, Dr6, Dr7, Dr7Flags,
+};
+use x86_64::VirtualAddress;
+
+use super::trap::TrapFrame;
+use super::{vector, without_interrupts};
+use crate::io::LAPIC;
+use crate::proc::cpu::Rflags;
+use crate::proc::percpu;
+
+/// What kind of access triggers a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Execute,
+    Write,
+    ReadWrite,
+}
+
+impl From<WatchKind> for BreakpointCondition {
+    fn from(kind: WatchKind) -> Self {
+        match kind {
+            WatchKind::Execute => Self::InstructionExecution,
+            WatchKind::Write => Self::DataWrites,
+            WatchKind::ReadWrite => Self::DataReadsWrites,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchpointError {
+    /// All four debug address registers are in use
+    NoFreeSlot,
+    /// Length must be 1, 2, 4 or 8 bytes (only 1 for execute)
+    InvalidLength(usize),
+    /// The address must be aligned to the length
+    Unaligned(VirtualAddress),
+}
+
+/// A hardware watchpoint held in one of DR0-DR3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    register: DebugAddressRegister,
+    address: VirtualAddress,
+    kind: WatchKind,
+    size: BreakpointSize,
+}
+
+impl Watchpoint {
+    pub fn address(&self) -> VirtualAddress {
+        self.address
+    }
+
+    pub fn kind(&self) -> WatchKind {
+        self.kind
+    }
+
+    pub fn size(&self) -> usize {
+        self.size.bytes()
+    }
+}
+
+/// What caused a debug exception
+#[derive(Debug, Clone, Copy)]
+pub struct DebugEvent {
+    pub status: Dr6,
+    /// The watchpoints whose condition was met, indexed by debug register
+    pub watchpoints: [Option<Watchpoint>; 4],
+}
+
+impl DebugEvent {
+    pub fn single_step(&self) -> bool {
+        self.status.contains(Dr6::STEP)
+    }
+
+    pub fn triggered(&self) -> impl Iterator<Item = &Watchpoint> {
+        self.watchpoints.iter().flatten()
+    }
+}
+
+/// Called from the #DB handler, the frame may be changed before we resume
+pub type DebugCallback = fn(&DebugEvent, &mut TrapFrame);
+
+static WATCHPOINTS: Mutex<[Option<Watchpoint>; 4]> = Mutex::new([None; 4]);
+static CALLBACK: Mutex<Option<DebugCallback>> = Mutex::new(None);
+/// Tells the other cpus to load the watchpoints again after they changed
+static RELOAD_VECTOR: Lazy<Option<u8>> = Lazy::new(|| vector::allocate(reload));
+
+/// Watch `len` bytes at `address`, takes effect on this cpu immediately and on the others
+/// once they take the reload interrupt
+pub fn set(
+    address: VirtualAddress,
+    kind: WatchKind,
+    len: usize,
+) -> Result<Watchpoint, WatchpointError> {
+    let size = match BreakpointSize::new(len) {
+        Some(size) if kind != WatchKind::Execute || len == 1 => size,
+        _ => return Err(WatchpointError::InvalidLength(len)),
+    };
+    if u64::from(address) % len as u64 != 0 {
+        return Err(WatchpointError::Unaligned(address));
+    }
+
+    let watchpoint = update(|watchpoints| {
+        let slot = watchpoints
+            .iter()
+            .position(Option::is_none)
+            .ok_or(WatchpointError::NoFreeSlot)?;
+
+        let watchpoint = Watchpoint {
+            register: DebugAddressRegister::ALL[slot],
+            address,
+            kind,
+            size,
+        };
+        watchpoints[slot] = Some(watchpoint);
+        Ok(watchpoint)
+    })?;
+    reload_others();
+    Ok(watchpoint)
+}
+
+/// Remove a watchpoint returned by [`set`]
+pub fn clear(watchpoint: Watchpoint) {
+    update(|watchpoints| {
+        let slot = &mut watchpoints[watchpoint.register as usize];
+        if *slot == Some(watchpoint) {
+            *slot = None;
+        }
+    });
+    reload_others();
+}
+
+/// Remove all watchpoints
+pub fn clear_all() {
+    update(|watchpoints| *watchpoints = [None; 4]);
+    reload_others();
+}
+
+/// Currently set watchpoints
+pub fn watchpoints() -> [Option<Watchpoint>; 4] {
+    without_interrupts(|| *WATCHPOINTS.lock())
+}
+
+/// Set the function called when a watchpoint triggers or a single step completes
+pub fn set_callback(callback: DebugCallback) {
+    *CALLBACK.lock() = Some(callback);
+}
+
+pub fn remove_callback() {
+    *CALLBACK.lock() = None;
+}
+
+/// Write the watchpoints into this cpu's debug registers
+fn load(watchpoints: &[Option<Watchpoint>; 4]) {
+    let mut dr7 = Dr7::new();
+    for watchpoint in watchpoints.iter().flatten() {
+        let register = watchpoint.register;
+        unsafe { register.write(watchpoint.address) };
+        dr7.set_condition(register, watchpoint.kind.into());
+        dr7.set_size(register, watchpoint.size);
+        dr7.insert_flags(Dr7Flags::global_enable(register));
+    }
+    if watchpoints.iter().any(Option::is_some) {
+        dr7.insert_flags(Dr7Flags::GLOBAL_EXACT_BREAKPOINT_ENABLE);
+    }
+    unsafe { dr7.write() };
+}
+
+/// Change the watchpoints and load them on this cpu.
+///
+/// The reload interrupt takes the lock, so it is only held with interrupts disabled
+fn update<R>(f: impl FnOnce(&mut [Option<Watchpoint>; 4]) -> R) -> R {
+    without_interrupts(|| {
+        let mut watchpoints = WATCHPOINTS.lock();
+        let result = f(&mut watchpoints);
+        load(&watchpoints);
+        result
+    })
+}
+
+/// Interrupt the other cpus so they load the current watchpoints
+fn reload_others() {
+    if percpu::count() < 2 {
+        return;
+    }
+    match *RELOAD_VECTOR {
+        Some(vector) => unsafe { (*LAPIC.as_mut_ptr()).send_to_others(vector) },
+        None => crate::kprintln!("DEBUG: no free vector, watchpoints are not set on other cpus"),
+    }
+}
+
+fn reload(_frame: &mut TrapFrame) {
+    init_cpu();
+}
+
+/// Load the watchpoints on a cpu that was started after they were set
+pub fn init_cpu() {
+    without_interrupts(|| load(&WATCHPOINTS.lock()));
+}
+
+/// Handle a #DB, returns false if nothing here caused it
+pub fn debug_exception(frame: &mut TrapFrame) -> bool {
+    let status = Dr6::read();
+    Dr6::clear();
+
+    // the debug exception can not be masked, so never block on the lock here
+    let set = match WATCHPOINTS.try_lock() {
+        Some(watchpoints) => *watchpoints,
+        None => [None; 4],
+    };
+    let mut watchpoints = [None; 4];
+    for register in status.triggered() {
+        watchpoints[register as usize] = set[register as usize];
+    }
+
+    let event = DebugEvent {
+        status,
+        watchpoints,
+    };
+    if !event.single_step() && event.triggered().next().is_none() {
+        return false;
+    }
+
+    // execute breakpoints are faults, without RF we would trap on the same instruction again
+    if event.triggered().any(|w| w.kind == WatchKind::Execute) {
+        frame.set_flags(frame.flags() | Rflags::RF);
+    }
+
+    let callback = CALLBACK.try_lock().and_then(|callback| *callback);
+    match callback {
+        Some(callback) => callback(&event, frame),
+        None => crate::kprintln!("DEBUG: {:?} at {:#x}", event, frame.rip),
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    static HITS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_hits(event: &DebugEvent, _frame: &mut TrapFrame) {
+        HITS.fetch_add(event.triggered().count(), Ordering::SeqCst);
+    }
+
+    #[test_case]
+    fn write_watchpoint() {
+        static WATCHED: AtomicU64 = AtomicU64::new(0);
+        let address = VirtualAddress::new(WATCHED.as_ptr() as u64);
+
+        set_callback(count_hits);
+        let watchpoint = set(address, WatchKind::Write, 8).unwrap();
+        WATCHED.store(42, Ordering::SeqCst);
+        clear(watchpoint);
+        remove_callback();
+
+        assert_eq!(HITS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn invalid_length() {
+        let address = VirtualAddress::new(0x1000);
+        assert_eq!(
+            set(address, WatchKind::Write, 3),
+            Err(WatchpointError::InvalidLength(3))
+        );
+        assert_eq!(
+            set(address, WatchKind::Execute, 4),
+            Err(WatchpointError::InvalidLength(4))
+        );
+        assert_eq!(
+            set(address + 2u64, WatchKind::Write, 4),
+            Err(WatchpointError::Unaligned(address + 2u64))
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! The acpi namespace, built by running the aml code of the dsdt and every ssdt.
+//!
+//! Operation regions are backed by port io, physical memory through the kernel mapping and
+//! pci config space through [`PCI_CONFIG`].
+
+use aml::{AmlError, Handler, Interpreter, PciAddress};
+use port::Port;
+use spin::{Lazy, Mutex};
+use x86_64::PhysicalAddress;
+
+use crate::multiboot::ACPI_TABLE;
+use crate::pci::{PciAddress as ConfigAddress, PCI_CONFIG};
+use crate::time::tsc;
+
+/// The interpreter with every table loaded, none without a valid dsdt
+pub static AML: Lazy<Option<Mutex<Interpreter<KernelHandler>>>> = Lazy::new(|| match load() {
+    Ok(aml) => aml.map(Mutex::new),
+    Err(error) => {
+        crate::kprintln!("AML: could not load the tables: {:?}", error);
+        None
+    }
+});
+
+fn load() -> Result<Option<Interpreter<KernelHandler>>, AmlError> {
+    let Some(dsdt) = ACPI_TABLE.dsdt() else {
+        return Ok(None);
+    };
+    let mut interpreter = Interpreter::new(KernelHandler);
+    interpreter.load_table(dsdt.bytes())?;
+    for ssdt in ACPI_TABLE.find_all("SSDT") {
+        interpreter.load_table(ssdt.bytes())?;
+    }
+    Ok(Some(interpreter))
+}
+
+/// Load the tables, switch the firmware to io apic routing and run `_INI` of every device
+pub fn init() {
+    let Some(aml) = AML.as_ref() else {
+        crate::kprintln!("AML: no namespace");
+        return;
+    };
+    let mut aml = aml.lock();
+    let objects = aml.namespace().len();
+    let devices = aml.set_apic_mode().and_then(|_| aml.initialize_devices());
+    match devices {
+        Ok(devices) => {
+            crate::kprintln!("AML: {} objects, {} devices initialized", objects, devices)
+        }
+        Err(error) => crate::kprintln!("AML: initializing devices failed: {:?}", error),
+    }
+}
+
+/// Hardware access for the interpreter
+pub struct KernelHandler;
+
+impl KernelHandler {
+    fn config_address(address: PciAddress) -> ConfigAddress {
+        ConfigAddress::new(
+            address.segment,
+            address.bus,
+            address.device,
+            address.function,
+        )
+    }
+
+    fn spin(nanos: u64) {
+        let start = tsc::cycles();
+        let wait = tsc::nanos_to_cycles(nanos);
+        while tsc::cycles().wrapping_sub(start) < wait {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Handler for KernelHandler {
+    fn read_memory(&mut self, address: u64, width: u8) -> u64 {
+        let address = PhysicalAddress::new(address);
+        unsafe {
+            match width {
+                8 => address.as_ptr::<u8>().read_volatile() as u64,
+                16 => address.as_ptr::<u16>().read_volatile() as u64,
+                32 => address.as_ptr::<u32>().read_volatile() as u64,
+                _ => address.as_ptr::<u64>().read_volatile(),
+            }
+        }
+    }
+
+    fn write_memory(&mut self, address: u64, width: u8, value: u64) {
+        let address = PhysicalAddress::new(address);
+        unsafe {
+            match width {
+                8 => address.as_mut_ptr::<u8>().write_volatile(value as u8),
+                16 => address.as_mut_ptr::<u16>().write_volatile(value as u16),
+                32 => address.as_mut_ptr::<u32>().write_volatile(value as u32),
+                _ => address.as_mut_ptr::<u64>().write_volatile(value),
+            }
+        }
+    }
+
+    fn read_io(&mut self, port: u16, width: u8) -> u64 {
+        unsafe {
+            match width {
+                8 => Port::<u8>::new(port).read() as u64,
+                16 => Port::<u16>::new(port).read() as u64,
+                32 => Port::<u32>::new(port).read() as u64,
+                // there are no 64 bit ports
+                _ => {
+                    let low = Port::<u32>::new(port).read() as u64;
+                    low | (Port::<u32>::new(port + 4).read() as u64) << 32
+                }
+            }
+        }
+    }
+
+    fn write_io(&mut self, port: u16, width: u8, value: u64) {
+        unsafe {
+            match width {
+                8 => Port::<u8>::new(port).write(value as u8),
+                16 => Port::<u16>::new(port).write(value as u16),
+                32 => Port::<u32>::new(port).write(value as u32),
+                _ => {
+                    Port::<u32>::new(port).write(value as u32);
+                    Port::<u32>::new(port + 4).write((value >> 32) as u32);
+                }
+            }
+        }
+    }
+
+    fn read_pci(&mut self, address: PciAddress, offset: u16, width: u8) -> u64 {
+        if width == 64 {
+            let low = self.read_pci(address, offset, 32);
+            return low | self.read_pci(address, offset + 4, 32) << 32;
+        }
+        PCI_CONFIG.read(Self::config_address(address), offset, width) as u64
+    }
+
+    fn write_pci(&mut self, address: PciAddress, offset: u16, width: u8, value: u64) {
+        if width == 64 {
+            self.write_pci(address, offset, 32, value);
+            return self.write_pci(address, offset + 4, 32, value >> 32);
+        }
+        PCI_CONFIG.write(Self::config_address(address), offset, width, value as u32)
+    }
+
+    fn stall(&mut self, micros: u64) {
+        Self::spin(micros * 1_000);
+    }
+
+    /// Aml runs with the namespace locked, so this does not give up the cpu either
+    fn sleep(&mut self, millis: u64) {
+        Self::spin(millis * 1_000_000);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use aml::{AmlName, AmlValue};
+
+    fn name(path: &str) -> AmlName {
+        path.parse().unwrap()
+    }
+
+    #[test_case]
+    fn qemu_namespace() {
+        let mut aml = AML.as_ref().expect("the dsdt should load").lock();
+        // both the i440fx and q35 tables have these
+        assert_eq!(aml.sleep_type(5), Ok((0, 0)));
+        for path in ["\\_SB.PCI0", "\\_SB.PCI0._CRS", "\\_SB.PCI0._PRT"] {
+            assert!(aml.namespace().contains(&name(path)), "{} is missing", path);
+        }
+    }
+
+    #[test_case]
+    fn pci_interrupt_routing() {
+        let mut aml = AML.as_ref().unwrap().lock();
+        aml.set_apic_mode().unwrap();
+        let routes = aml.pci_routing(&name("\\_SB.PCI0")).unwrap();
+        assert!(!routes.is_empty());
+        for route in routes {
+            let irq = aml.route_irq(&route).unwrap();
+            // the firmware programmed every link
+            assert_ne!(irq.gsi, 0);
+        }
+    }
+
+    #[test_case]
+    fn device_status() {
+        let mut aml = AML.as_ref().unwrap().lock();
+        let devices: Vec<AmlName> = aml
+            .namespace()
+            .iter()
+            .filter(|(_, value)| matches!(value, AmlValue::Device))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for device in devices {
+            assert!(
+                aml.device_status(&device).is_ok(),
+                "_STA of {} failed",
+                device
+            );
+        }
+        assert!(aml.device_status(&name("\\_SB.PCI0")).unwrap().present());
+    }
+}
@@ -169,6 +169,37 @@ impl RtcDate {
         }
     }
 
+    /// Create a date, `None` if it does not exist. The weekday is worked out from the date.
+    pub fn new(
+        year: u16,
+        month: u16,
+        day: u16,
+        hour: u16,
+        minute: u16,
+        second: u16,
+    ) -> Option<Self> {
+        let valid = (1..=12).contains(&month)
+            && (1..=days_in_month(year, month)).contains(&day)
+            && hour < 24
+            && minute < 60
+            && second < 60;
+        if !valid {
+            return None;
+        }
+
+        let mut date = Self {
+            second: Second(second),
+            minute: Minute(minute),
+            hour: Hour(hour),
+            day: Day(day),
+            month: Month(month),
+            year: Year(year),
+            weekday: Weekday::Sunday,
+        };
+        date.weekday = weekday(date.days_since_epoch());
+        Some(date)
+    }
+
     /// Create a new date that reads from the cmos (this is somewhat expensive)
     pub fn cmos_read() -> Self {
         let mut cmos = Cmos::default();
@@ -184,7 +215,89 @@ impl RtcDate {
         }
     }
 
-    /// Convert from bsd encoded data
+    /// The date `seconds` after the unix epoch (1970-01-01 00:00:00 UTC)
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = (seconds / SECONDS_PER_DAY) as i64;
+        let time = (seconds % SECONDS_PER_DAY) as u16;
+
+        // Howard Hinnant's civil_from_days, with years starting in march
+        let days = days + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        Self {
+            second: Second(time % 60),
+            minute: Minute(time / 60 % 60),
+            hour: Hour(time / 3600),
+            day: Day(day as u16),
+            month: Month(month as u16),
+            year: Year(year as u16),
+            weekday: weekday(days - 719_468),
+        }
+    }
+
+    /// Seconds since the unix epoch, 0 for earlier dates
+    pub fn to_unix(&self) -> u64 {
+        let days = self.days_since_epoch();
+        let time = u16::from(self.hour) as i64 * 3600
+            + u16::from(self.minute) as i64 * 60
+            + u16::from(self.second) as i64;
+        (days * SECONDS_PER_DAY as i64 + time).max(0) as u64
+    }
+
+    /// Howard Hinnant's days_from_civil
+    fn days_since_epoch(&self) -> i64 {
+        let month = u16::from(self.month) as i64;
+        let day = u16::from(self.day) as i64;
+        let year = u16::from(self.year) as i64 - (month <= 2) as i64;
+
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    pub fn second(&self) -> Second {
+        self.second
+    }
+
+    pub fn minute(&self) -> Minute {
+        self.minute
+    }
+
+    pub fn hour(&self) -> Hour {
+        self.hour
+    }
+
+    pub fn day(&self) -> Day {
+        self.day
+    }
+
+    pub fn month(&self) -> Month {
+        self.month
+    }
+
+    pub fn year(&self) -> Year {
+        self.year
+    }
+
+    pub fn weekday(&self) -> Weekday {
+        self.weekday
+    }
+
+    /// Convert from bsd encoded data, the year stays two digits
     pub fn bsd_convert(&mut self) {
         self.second = Second(Self::convert(self.second.into()));
         self.minute = Minute(Self::convert(self.minute.into()));
@@ -192,8 +305,6 @@ impl RtcDate {
         self.day = Day(Self::convert(self.day.into()));
         self.month = Month(Self::convert(self.month.into()));
         self.year = Year(Self::convert(self.year.into()));
-
-        self.year += Year(2000);
     }
 
     /// Convert function
@@ -202,11 +313,58 @@ impl RtcDate {
     }
 }
 
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+fn is_leap_year(year: u16) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+fn days_in_month(year: u16, month: u16) -> u16 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Weekday of a day counted from the epoch, which was a thursday
+fn weekday(days_since_epoch: i64) -> Weekday {
+    Weekday::from((days_since_epoch + 4).rem_euclid(7) as u8 + 1)
+}
+
+/// Encode for the cmos, which stores either bcd or binary
+const fn encode(value: u16, bcd: bool) -> u8 {
+    match bcd {
+        true => (((value / 10) << 4) | (value % 10)) as u8,
+        false => value as u8,
+    }
+}
+
+/// Encode an hour for the cmos, which can also be in 12 hour mode with a pm bit
+fn encode_hour(hour: u16, bcd: bool, hour_12: bool) -> u8 {
+    match hour_12 {
+        true if hour >= 12 => encode((hour + 11) % 12 + 1, bcd) | HOUR_PM as u8,
+        true => encode((hour + 11) % 12 + 1, bcd),
+        false => encode(hour, bcd),
+    }
+}
+
+/// Cmos register of the century, from the fadt
+fn century_register() -> Option<u8> {
+    crate::multiboot::ACPI_TABLE
+        .fadt
+        .and_then(|fadt| fadt.century_register())
+}
+
 bitflags! {
     pub struct Time: u8 {
         const SECS = 0x00;
+        const SECS_ALARM = 0x01;
         const MINS = 0x02;
+        const MINS_ALARM = 0x03;
         const HOURS = 0x04;
+        const HOURS_ALARM = 0x05;
         const WEEKDAY = 0x06;
         const DAY = 0x07;
         const MONTH = 0x08;
@@ -215,10 +373,28 @@ bitflags! {
         // other
         const STATA = 0x0A;
         const STATB = 0x0b;
+        const STATC = 0x0C;
         const UIP = 1 << 7;
     }
 }
 
+bitflags! {
+    /// Rtc interrupts, the same bits enable them in status register b and flag them in c
+    pub struct RtcInterrupts: u8 {
+        const UPDATE_ENDED = 1 << 4;
+        const ALARM = 1 << 5;
+        const PERIODIC = 1 << 6;
+    }
+}
+
+/// Status register b bits
+const STATB_24_HOUR: u8 = 1 << 1;
+const STATB_BINARY: u8 = 1 << 2;
+/// Stops the clock from updating while it is set
+const STATB_SET: u8 = 1 << 7;
+/// Hour bit for pm in 12 hour mode
+const HOUR_PM: u16 = 1 << 7;
+
 pub struct Cmos {
     command: PortWriteOnly<u8>,
     data: Port<u8>,
@@ -238,49 +414,142 @@ impl Cmos {
     }
 
     pub fn read(&mut self, time: Time) -> u8 {
+        self.read_register(time.bits())
+    }
+
+    fn read_register(&mut self, register: u8) -> u8 {
         unsafe {
-            self.command.write(time.bits());
+            self.command.write(register);
             //super::micro_delay(200);
             self.data.read()
         }
     }
 
+    fn write_register(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.command.write(register);
+            self.data.write(value);
+        }
+    }
+
     pub fn is_updating(&mut self) -> bool {
-        self.read(Time::STATB) & Time::UIP.bits() != 0
+        self.read(Time::STATA) & Time::UIP.bits() != 0
     }
 
     pub fn is_bcd(&mut self) -> bool {
         let sb = self.read(Time::STATB);
-        (sb & (1 << 2)) == 0
+        (sb & STATB_BINARY) == 0
     }
 
+    pub fn is_24_hour(&mut self) -> bool {
+        self.read(Time::STATB) & STATB_24_HOUR != 0
+    }
+
+    /// Read the date, with the full year from the century register if there is one
     pub fn time(&mut self) -> RtcDate {
-        let mut t1;
+        let century_register = century_register();
+        let read = |cmos: &mut Self| {
+            let century = century_register.map(|register| cmos.read_register(register));
+            (RtcDate::cmos_read(), century)
+        };
 
-        loop {
-            t1 = RtcDate::cmos_read();
+        // read until two reads outside of an update agree
+        let (mut t1, mut century) = loop {
+            while self.is_updating() {}
+            let t1 = read(self);
             if self.is_updating() {
                 continue;
             }
-            let t2 = RtcDate::cmos_read();
-            if t1 == t2 {
-                break;
+            if t1 == read(self) {
+                break t1;
             }
-        }
+        };
 
-        if self.is_bcd() {
+        let bcd = self.is_bcd();
+        let hour_12 = !self.is_24_hour();
+        let pm = hour_12 && u16::from(t1.hour) & HOUR_PM != 0;
+        t1.hour = Hour(u16::from(t1.hour) & !HOUR_PM);
+
+        if bcd {
             t1.bsd_convert();
+            century = century.map(|century| RtcDate::convert(century.into()) as u8);
+        }
+        if hour_12 {
+            // 12 am is midnight and 12 pm noon
+            t1.hour = Hour(u16::from(t1.hour) % 12 + if pm { 12 } else { 0 });
         }
 
+        // without a century register assume this century
+        let century = century.map_or(20, u16::from);
+        t1.year = Year(century * 100 + u16::from(t1.year));
+        t1.weekday = weekday(t1.days_since_epoch());
         t1
     }
 
+    /// Write `date` to the clock, in the format it is using
+    pub fn set_time(&mut self, date: &RtcDate) {
+        let status = self.read(Time::STATB);
+        let bcd = status & STATB_BINARY == 0;
+        let hour_12 = status & STATB_24_HOUR == 0;
+
+        let year = u16::from(date.year);
+
+        // stop the clock so it does not update halfway through
+        self.write_register(Time::STATB.bits(), status | STATB_SET);
+        self.write_register(Time::SECS.bits(), encode(date.second.into(), bcd));
+        self.write_register(Time::MINS.bits(), encode(date.minute.into(), bcd));
+        let hour = encode_hour(date.hour.into(), bcd, hour_12);
+        self.write_register(Time::HOURS.bits(), hour);
+        self.write_register(Time::WEEKDAY.bits(), date.weekday as u8);
+        self.write_register(Time::DAY.bits(), encode(date.day.into(), bcd));
+        self.write_register(Time::MONTH.bits(), encode(date.month.into(), bcd));
+        self.write_register(Time::YEAR.bits(), encode(year % 100, bcd));
+        if let Some(register) = century_register() {
+            self.write_register(register, encode(year / 100, bcd));
+        }
+        self.write_register(Time::STATB.bits(), status & !STATB_SET);
+    }
+
+    pub fn enabled_interrupts(&mut self) -> RtcInterrupts {
+        RtcInterrupts::from_bits_truncate(self.read(Time::STATB))
+    }
+
+    pub fn enable_interrupts(&mut self, interrupts: RtcInterrupts) {
+        let status = self.read(Time::STATB);
+        self.write_register(Time::STATB.bits(), status | interrupts.bits());
+    }
+
+    pub fn disable_interrupts(&mut self, interrupts: RtcInterrupts) {
+        let status = self.read(Time::STATB);
+        self.write_register(Time::STATB.bits(), status & !interrupts.bits());
+    }
+
+    /// Set the rate select of status register a, the periodic interrupt
+    /// runs at `32768 >> (rate - 1)` Hz. Rates below 3 do not work.
+    pub fn set_periodic_rate(&mut self, rate: u8) {
+        assert!((3..=15).contains(&rate), "invalid rtc rate {}", rate);
+        let status = self.read(Time::STATA);
+        self.write_register(Time::STATA.bits(), (status & 0xF0) | rate);
+    }
+
+    /// Set the time of day the alarm interrupt fires
+    pub fn set_alarm(&mut self, hour: u16, minute: u16, second: u16) {
+        let bcd = self.is_bcd();
+        let hour = encode_hour(hour, bcd, !self.is_24_hour());
+        self.write_register(Time::SECS_ALARM.bits(), encode(second, bcd));
+        self.write_register(Time::MINS_ALARM.bits(), encode(minute, bcd));
+        self.write_register(Time::HOURS_ALARM.bits(), hour);
+    }
+
+    /// Read status register c, which acknowledges the interrupt.
+    /// The rtc does not interrupt again until this is read.
+    pub fn acknowledge(&mut self) -> RtcInterrupts {
+        RtcInterrupts::from_bits_truncate(self.read(Time::STATC))
+    }
+
     /// Set the cmos reset value to "warm start with far just"
     pub fn warm_reset(&mut self) {
-        unsafe {
-            self.command.write(0x0F);
-            self.data.write(0x0A);
-        }
+        self.write_register(0x0F, 0x0A);
     }
 }
 
@@ -289,3 +558,48 @@ impl Default for Cmos {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn unix_conversion() {
+        let epoch = RtcDate::new(1970, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(epoch.to_unix(), 0);
+        assert_eq!(epoch.weekday(), Weekday::Thursday);
+        assert_eq!(RtcDate::from_unix(0), epoch);
+
+        let leap_day = RtcDate::new(2000, 2, 29, 12, 30, 15).unwrap();
+        assert_eq!(leap_day.to_unix(), 951_827_415);
+        assert_eq!(leap_day.weekday(), Weekday::Tuesday);
+        assert_eq!(RtcDate::from_unix(951_827_415), leap_day);
+
+        let last = RtcDate::new(2099, 12, 31, 23, 59, 59).unwrap();
+        assert_eq!(RtcDate::from_unix(last.to_unix()), last);
+    }
+
+    #[test_case]
+    fn invalid_dates() {
+        assert_eq!(RtcDate::new(2023, 2, 29, 0, 0, 0), None);
+        assert_eq!(RtcDate::new(2023, 13, 1, 0, 0, 0), None);
+        assert_eq!(RtcDate::new(2023, 4, 31, 0, 0, 0), None);
+        assert_eq!(RtcDate::new(2023, 1, 1, 24, 0, 0), None);
+        assert_eq!(RtcDate::new(1900, 2, 28, 0, 0, 0).unwrap().to_unix(), 0);
+    }
+
+    #[test_case]
+    fn read_full_year() {
+        let date = Cmos::new().time();
+        assert!(u16::from(date.year()) >= 2000);
+        assert!(RtcDate::new(
+            date.year().into(),
+            date.month().into(),
+            date.day().into(),
+            date.hour().into(),
+            date.minute().into(),
+            date.second().into()
+        )
+        .is_some());
+    }
+}
@@ -0,0 +1,369 @@
+//! High precision event timer.
+//!
+//! A main counter running at a fixed frequency (at least 10 MHz) and a few comparators
+//! that interrupt when the counter reaches them. The comparators are routed through the
+//! io apic, the legacy replacement mode that steals the pit and rtc irqs is not used.
+
+use bit_field::BitField;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::tables::acpi::AddressSpace;
+use x86_64::PhysicalAddress;
+
+use super::ioapic::{DeliveryMode, Polarity, RedirectionEntry, RouteError, TriggerMode};
+
+/// Longest period the specification allows, in femtoseconds
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+// register offsets
+const CAPABILITIES: usize = 0x000;
+const CONFIG: usize = 0x010;
+const INTERRUPT_STATUS: usize = 0x020;
+const MAIN_COUNTER: usize = 0x0F0;
+
+/// Register offset of a comparator's configuration, the comparator value follows
+const fn timer_config(timer: u8) -> usize {
+    0x100 + 0x20 * timer as usize
+}
+
+const fn timer_comparator(timer: u8) -> usize {
+    timer_config(timer) + 0x08
+}
+
+// general configuration bits
+const ENABLE: usize = 0;
+const LEGACY_REPLACEMENT: usize = 1;
+
+// comparator configuration bits
+const LEVEL_TRIGGERED: usize = 1;
+const INTERRUPT_ENABLE: usize = 2;
+const PERIODIC: usize = 3;
+const PERIODIC_CAPABLE: usize = 4;
+const SIZE_64: usize = 5;
+const VALUE_SET: usize = 6;
+const FORCE_32: usize = 8;
+const ROUTE: core::ops::Range<usize> = 9..14;
+const FSB_CAPABLE: usize = 15;
+const ROUTE_CAPABILITIES: core::ops::Range<usize> = 32..64;
+
+/// Comparator interrupts seen, see [`interrupt`]
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// There are not that many comparators
+    NoSuchTimer(u8),
+    /// Only some comparators can run periodically
+    PeriodicUnsupported(u8),
+    /// None of the io apic inputs the comparator can use are free
+    NoRoute(u8),
+    Route(RouteError),
+}
+
+impl From<RouteError> for HpetError {
+    fn from(error: RouteError) -> Self {
+        Self::Route(error)
+    }
+}
+
+/// What a comparator can do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerCapabilities {
+    pub periodic: bool,
+    pub size_64: bool,
+    /// Can deliver interrupts as front side bus messages
+    pub fsb: bool,
+    /// Bitmap of the io apic inputs it can be routed to
+    pub routes: u32,
+}
+
+/// The first timer block, the registers are only accessed with volatile reads and writes
+pub struct Hpet {
+    registers: *mut u64,
+    /// Main counter period in femtoseconds
+    period: u64,
+    timers: u8,
+    counter_64: bool,
+    minimum_tick: u16,
+}
+
+// the registers are memory mapped io, not memory rust knows about
+unsafe impl Send for Hpet {}
+unsafe impl Sync for Hpet {}
+
+impl Hpet {
+    /// # Safety
+    /// `address` must point to the registers of a hpet
+    pub unsafe fn new(address: PhysicalAddress, minimum_tick: u16) -> Self {
+        let mut hpet = Self {
+            registers: address.as_mut_ptr(),
+            period: 0,
+            timers: 0,
+            counter_64: false,
+            minimum_tick,
+        };
+        let capabilities = hpet.read(CAPABILITIES);
+        hpet.period = capabilities.get_bits(32..64);
+        hpet.timers = capabilities.get_bits(8..13) as u8 + 1;
+        hpet.counter_64 = capabilities.get_bit(13);
+        hpet
+    }
+
+    /// The hpet from the acpi tables, if there is a usable one
+    pub fn from_acpi() -> Option<Self> {
+        let table = crate::multiboot::ACPI_TABLE.hpet?;
+        let base = table.base_address();
+        if base.address_space() != AddressSpace::SystemMemory {
+            return None;
+        }
+
+        let hpet = unsafe { Self::new(PhysicalAddress::new(base.address()), table.minimum_tick()) };
+        if hpet.period == 0 || hpet.period > MAX_PERIOD_FS {
+            return None;
+        }
+        Some(hpet)
+    }
+
+    /// Stop every comparator and start the main counter from zero
+    pub fn init(&self) {
+        let mut config = self.read(CONFIG);
+        config.set_bit(ENABLE, false);
+        config.set_bit(LEGACY_REPLACEMENT, false);
+        self.write(CONFIG, config);
+
+        for timer in 0..self.timers {
+            let _ = self.stop_timer(timer);
+        }
+        self.write(MAIN_COUNTER, 0);
+        self.write(INTERRUPT_STATUS, self.read(INTERRUPT_STATUS));
+
+        config.set_bit(ENABLE, true);
+        self.write(CONFIG, config);
+    }
+
+    /// Main counter period in femtoseconds
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+
+    /// Main counter frequency in Hz
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period
+    }
+
+    /// Number of comparators
+    pub fn timers(&self) -> u8 {
+        self.timers
+    }
+
+    /// Does the main counter have 64 bits, a 32 bit one wraps every few minutes
+    pub fn is_64bit(&self) -> bool {
+        self.counter_64
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    /// Main counter in nanoseconds
+    pub fn nanos(&self) -> u64 {
+        (self.counter() as u128 * self.period as u128 / FEMTOS_PER_NANO as u128) as u64
+    }
+
+    /// Main counter ticks in `duration`, rounded up
+    pub fn ticks(&self, duration: Duration) -> u64 {
+        let femtos = duration.as_nanos() * FEMTOS_PER_NANO as u128;
+        let ticks = femtos.div_ceil(self.period as u128);
+        u64::try_from(ticks).unwrap_or(u64::MAX)
+    }
+
+    pub fn timer_capabilities(&self, timer: u8) -> Result<TimerCapabilities, HpetError> {
+        let config = self.read(self.config_register(timer)?);
+        Ok(TimerCapabilities {
+            periodic: config.get_bit(PERIODIC_CAPABLE),
+            size_64: config.get_bit(SIZE_64),
+            fsb: config.get_bit(FSB_CAPABLE),
+            routes: config.get_bits(ROUTE_CAPABILITIES) as u32,
+        })
+    }
+
+    /// Send the interrupts of `timer` to `vector` on the cpu with `apic_id`, picks an io apic
+    /// input that is not used by an isa irq. Returns the global system interrupt it uses.
+    pub fn route_timer(&self, timer: u8, vector: u8, apic_id: u32) -> Result<u32, HpetError> {
+        let register = self.config_register(timer)?;
+        let routes = self.timer_capabilities(timer)?.routes;
+        let destination =
+            u8::try_from(apic_id).map_err(|_| RouteError::InvalidDestination(apic_id))?;
+
+        let mut ioapics = super::IO_APIC.lock();
+        let is_isa = |gsi| (0..16).any(|irq| ioapics.isa_route(irq).gsi == gsi);
+        let gsi = (0..32)
+            .rev()
+            .filter(|gsi| routes.get_bit(*gsi))
+            .map(|gsi| gsi as u32)
+            .find(|gsi| ioapics.handles(*gsi) && !is_isa(*gsi))
+            .ok_or(HpetError::NoRoute(timer))?;
+
+        let mut config = self.read(register);
+        config.set_bits(ROUTE, gsi as u64);
+        config.set_bit(LEVEL_TRIGGERED, false);
+        self.write(register, config);
+
+        // the comparator sends edges, whatever pci devices on the input normally use
+        let entry = RedirectionEntry {
+            vector,
+            delivery_mode: DeliveryMode::Fixed,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+            masked: false,
+            destination,
+        };
+        ioapics.set_entry(gsi, entry)?;
+        Ok(gsi)
+    }
+
+    /// Interrupt after `period`, and every `period` after that if periodic.
+    /// The timer has to be routed first.
+    pub fn start_timer(
+        &self,
+        timer: u8,
+        mode: TimerMode,
+        period: Duration,
+    ) -> Result<(), HpetError> {
+        let register = self.config_register(timer)?;
+        let capabilities = self.timer_capabilities(timer)?;
+        if mode == TimerMode::Periodic && !capabilities.periodic {
+            return Err(HpetError::PeriodicUnsupported(timer));
+        }
+
+        let mut ticks = self.ticks(period).max(1);
+        if mode == TimerMode::Periodic {
+            ticks = ticks.max(self.minimum_tick.into());
+        }
+
+        let mut config = self.read(register);
+        config.set_bit(INTERRUPT_ENABLE, false);
+        self.write(register, config);
+
+        // a 32 bit comparator only looks at the low half of the counter
+        config.set_bit(FORCE_32, !capabilities.size_64);
+        config.set_bit(PERIODIC, mode == TimerMode::Periodic);
+        config.set_bit(VALUE_SET, mode == TimerMode::Periodic);
+        self.write(register, config);
+
+        let deadline = self.counter().wrapping_add(ticks);
+        self.write(timer_comparator(timer), deadline);
+        if mode == TimerMode::Periodic {
+            // with the value set bit the second write is the period
+            self.write(timer_comparator(timer), ticks);
+        }
+
+        config.set_bit(INTERRUPT_ENABLE, true);
+        config.set_bit(VALUE_SET, false);
+        self.write(register, config);
+        Ok(())
+    }
+
+    pub fn stop_timer(&self, timer: u8) -> Result<(), HpetError> {
+        let register = self.config_register(timer)?;
+        let mut config = self.read(register);
+        config.set_bit(INTERRUPT_ENABLE, false);
+        config.set_bit(PERIODIC, false);
+        self.write(register, config);
+        Ok(())
+    }
+
+    fn config_register(&self, timer: u8) -> Result<usize, HpetError> {
+        match timer < self.timers {
+            true => Ok(timer_config(timer)),
+            false => Err(HpetError::NoSuchTimer(timer)),
+        }
+    }
+
+    fn read(&self, offset: usize) -> u64 {
+        unsafe { self.registers.add(offset / 8).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u64) {
+        unsafe { self.registers.add(offset / 8).write_volatile(value) }
+    }
+}
+
+/// Called by the hpet interrupt handler
+pub fn interrupt() {
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    // only level triggered comparators need this, clearing does not hurt the others
+    if let Some(hpet) = super::HPET.as_ref() {
+        hpet.write(INTERRUPT_STATUS, hpet.read(INTERRUPT_STATUS));
+    }
+}
+
+/// Comparator interrupts since boot
+pub fn interrupts() -> u64 {
+    INTERRUPTS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::{IRQ, IRQ_0};
+
+    fn hpet() -> &'static Hpet {
+        super::super::HPET.as_ref().expect("qemu has a hpet")
+    }
+
+    #[test_case]
+    fn counter_runs() {
+        let hpet = hpet();
+        assert!(hpet.frequency() >= 10_000_000);
+        let start = hpet.counter();
+        while hpet.counter() == start {}
+        assert!(hpet.nanos() > 0);
+    }
+
+    #[test_case]
+    fn comparator_interrupts() {
+        let hpet = hpet();
+        let apic_id = crate::io::LAPIC.id();
+        if apic_id > 0xff {
+            return;
+        }
+        assert_eq!(
+            hpet.stop_timer(hpet.timers()),
+            Err(HpetError::NoSuchTimer(hpet.timers()))
+        );
+
+        let gsi = hpet
+            .route_timer(0, IRQ_0 + IRQ::Hpet as u8, apic_id)
+            .unwrap();
+        let wait_for = |count: u64| {
+            let start = interrupts();
+            let deadline = crate::time::uptime() + Duration::from_millis(100);
+            while interrupts() < start + count && crate::time::uptime() < deadline {
+                crate::interrupts::halt();
+            }
+            interrupts() - start
+        };
+
+        crate::interrupts::enable_interrupts();
+        hpet.start_timer(0, TimerMode::OneShot, Duration::from_millis(1))
+            .unwrap();
+        let one_shot = wait_for(1);
+        hpet.start_timer(0, TimerMode::Periodic, Duration::from_millis(1))
+            .unwrap();
+        let periodic = wait_for(3);
+        hpet.stop_timer(0).unwrap();
+        crate::interrupts::disable_interrupts();
+        crate::io::IO_APIC.lock().mask(gsi).unwrap();
+
+        assert_eq!(one_shot, 1);
+        assert!(periodic >= 3);
+    }
+}
@@ -1,76 +1,415 @@
-use crate::consts::IRQ;
+use bit_field::BitField;
 use volatile::Volatile;
+use x86_64::tables::madt;
 
-pub struct IoApic(Volatile<&'static mut IoApicRegister>);
+use crate::consts::{IRQ, IRQ_0};
 
-impl IoApic {
-    unsafe fn new(ptr: *mut IoApicRegister) -> Self {
-        Self(Volatile::new(&mut *ptr))
+/// Number of isa irqs, the only ones the madt can override
+const ISA_IRQS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DeliveryMode {
+    Fixed = 0b000,
+    LowestPriority = 0b001,
+    Smi = 0b010,
+    Nmi = 0b100,
+    Init = 0b101,
+    ExtInt = 0b111,
+}
+
+impl DeliveryMode {
+    fn from_bits(bits: u64) -> Self {
+        match bits {
+            0b001 => Self::LowestPriority,
+            0b010 => Self::Smi,
+            0b100 => Self::Nmi,
+            0b101 => Self::Init,
+            0b111 => Self::ExtInt,
+            _ => Self::Fixed,
+        }
     }
+}
 
-    /// Initialize the IO Apic
-    pub fn init(&mut self) {
-        let maxintr = (self.read(0x1) >> 16) & 0xFF;
-        let _id = self.read(0x00) >> 24;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteError {
+    /// No io apic handles the global system interrupt
+    UnknownGsi(u32),
+    /// The io apic can only send to physical apic ids below 256
+    InvalidDestination(u32),
+}
+
+/// One entry of the redirection table, what an input pin turns into.
+/// The destination is always a physical apic id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    pub delivery_mode: DeliveryMode,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+    pub masked: bool,
+    pub destination: u8,
+}
 
-        // TODO make sure it is the right id
-        // TODO make this rusty
+impl RedirectionEntry {
+    /// A masked entry, what every pin is set to at init
+    pub const fn masked() -> Self {
+        Self {
+            vector: 0,
+            delivery_mode: DeliveryMode::Fixed,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+            masked: true,
+            destination: 0,
+        }
+    }
+}
 
-        // Mark all interrupts edge-triggered, active high, disabled,
-        // and not routed to any CPUs.
-        for i in 0..maxintr {
-            self.write(0x10 + 2 * i, 0x10000 | (32 + i));
-            self.write(0x10 + 2 * i + 1, 0);
+impl From<u64> for RedirectionEntry {
+    fn from(value: u64) -> Self {
+        Self {
+            vector: value.get_bits(0..8) as u8,
+            delivery_mode: DeliveryMode::from_bits(value.get_bits(8..11)),
+            polarity: match value.get_bit(13) {
+                false => Polarity::ActiveHigh,
+                true => Polarity::ActiveLow,
+            },
+            trigger_mode: match value.get_bit(15) {
+                false => TriggerMode::Edge,
+                true => TriggerMode::Level,
+            },
+            masked: value.get_bit(16),
+            destination: value.get_bits(56..64) as u8,
         }
     }
+}
 
-    pub fn enable(&mut self, irq: IRQ, cpu_num: u32) {
-        let irq = usize::from(irq) as u32;
-        // Mark interrupt edge-triggered, active high,
-        // enabled, and routed to the given cpunum,
-        // which happens to be that cpu's APIC ID.
-        self.write(0x10 + 2 * irq, 32 + irq);
-        self.write(0x10 + 2 * irq + 1, cpu_num << 24);
+impl From<RedirectionEntry> for u64 {
+    fn from(entry: RedirectionEntry) -> Self {
+        let mut value = 0u64;
+        value.set_bits(0..8, entry.vector.into());
+        value.set_bits(8..11, entry.delivery_mode as u64);
+        // bit 11 is the destination mode, 0 for physical
+        value.set_bit(13, entry.polarity == Polarity::ActiveLow);
+        value.set_bit(15, entry.trigger_mode == TriggerMode::Level);
+        value.set_bit(16, entry.masked);
+        value.set_bits(56..64, entry.destination.into());
+        value
+    }
+}
+
+/// A single io apic, handling the global system interrupts from `gsi_base`
+pub struct IoApic {
+    registers: Volatile<&'static mut IoApicRegister>,
+    id: u8,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn new(ptr: *mut IoApicRegister, gsi_base: u32) -> Self {
+        let mut ioapic = Self {
+            registers: Volatile::new(&mut *ptr),
+            id: 0,
+            gsi_base,
+            entries: 0,
+        };
+        ioapic.id = (ioapic.read(0x00) >> 24) as u8 & 0b1111;
+        ioapic.entries = ((ioapic.read(0x01) >> 16) & 0xFF) + 1;
+        ioapic
+    }
+
+    /// Mask every input
+    fn init(&mut self) {
+        for pin in 0..self.entries {
+            self.write_entry(pin, RedirectionEntry::masked());
+        }
     }
 
     /// IO apic id
-    pub fn id(&mut self) -> u8 {
-        // only want bytes 24 -27
-        (self.read(0) >> 24) as u8 & 0b00000111
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Does this io apic handle `gsi`
+    pub fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    pub fn read_entry(&mut self, pin: u32) -> RedirectionEntry {
+        let low = self.read(0x10 + 2 * pin) as u64;
+        let high = self.read(0x10 + 2 * pin + 1) as u64;
+        RedirectionEntry::from(high << 32 | low)
+    }
+
+    pub fn write_entry(&mut self, pin: u32, entry: RedirectionEntry) {
+        let value = u64::from(entry);
+        // mask first so the pin never fires with half an entry
+        self.write(0x10 + 2 * pin, value as u32 | 1 << 16);
+        self.write(0x10 + 2 * pin + 1, (value >> 32) as u32);
+        self.write(0x10 + 2 * pin, value as u32);
     }
 
     fn write(&mut self, register: u32, data: u32) {
-        self.0.map_mut(|apic| &mut apic.register).write(register);
-        self.0.map_mut(|apic| &mut apic.data).write(data);
+        self.registers
+            .map_mut(|apic| &mut apic.register)
+            .write(register);
+        self.registers.map_mut(|apic| &mut apic.data).write(data);
     }
 
     fn read(&mut self, register: u32) -> u32 {
-        self.0.map_mut(|apic| &mut apic.register).write(register);
-        self.0.map_mut(|apic| &mut apic.data).read()
+        self.registers
+            .map_mut(|apic| &mut apic.register)
+            .write(register);
+        self.registers.map_mut(|apic| &mut apic.data).read()
     }
 }
 
-impl Default for IoApic {
+/// Where an isa irq ends up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+/// All io apics and the global system interrupt routing from the madt.
+/// Fixed size like the madt, as the io apics are set up before the heap
+pub struct IoApics {
+    ioapics: [Option<IoApic>; madt::MAX_IOAPICS],
+    isa: [IsaRoute; ISA_IRQS],
+    nmi_sources: [Option<(u32, Polarity, TriggerMode)>; madt::MAX_ISA_ENTRIES],
+}
+
+impl IoApics {
+    /// Mask every input and set up the nmi sources
+    pub fn init(&mut self) {
+        for ioapic in self.ioapics.iter_mut().flatten() {
+            ioapic.init();
+        }
+
+        // without interrupt remapping the destination field only takes 8 bit apic ids
+        let apic_id = crate::io::LAPIC.id();
+        let Ok(destination) = u8::try_from(apic_id) else {
+            crate::kprintln!(
+                "IOAPIC: apic id {} can not take nmis, leaving them masked",
+                apic_id
+            );
+            return;
+        };
+        let nmi_sources = self.nmi_sources;
+        for (gsi, polarity, trigger_mode) in nmi_sources.into_iter().flatten() {
+            let entry = RedirectionEntry {
+                vector: 0,
+                delivery_mode: DeliveryMode::Nmi,
+                polarity,
+                trigger_mode,
+                masked: false,
+                destination,
+            };
+            let _ = self.set_entry(gsi, entry);
+        }
+    }
+
+    /// Does any io apic handle `gsi`
+    pub fn handles(&self, gsi: u32) -> bool {
+        self.ioapics
+            .iter()
+            .flatten()
+            .any(|ioapic| ioapic.handles(gsi))
+    }
+
+    /// The global system interrupt and flags of an isa irq, after the madt overrides
+    pub fn isa_route(&self, irq: u8) -> IsaRoute {
+        self.isa[irq as usize]
+    }
+
+    /// Polarity and trigger mode of `gsi`, isa defaults to edge and active high,
+    /// everything else is pci, level and active low
+    pub fn gsi_flags(&self, gsi: u32) -> (Polarity, TriggerMode) {
+        if let Some(route) = self.isa.iter().find(|route| route.gsi == gsi) {
+            return (route.polarity, route.trigger_mode);
+        }
+        let mut nmi_sources = self.nmi_sources.iter().flatten();
+        let nmi_source = nmi_sources.find(|source| source.0 == gsi);
+        if let Some(&(_, polarity, trigger_mode)) = nmi_source {
+            return (polarity, trigger_mode);
+        }
+        (Polarity::ActiveLow, TriggerMode::Level)
+    }
+
+    /// Send `gsi` to `vector` on the cpu with `apic_id`, with the polarity and trigger mode from the madt
+    pub fn route(&mut self, gsi: u32, vector: u8, apic_id: u32) -> Result<(), RouteError> {
+        let destination =
+            u8::try_from(apic_id).map_err(|_| RouteError::InvalidDestination(apic_id))?;
+        let (polarity, trigger_mode) = self.gsi_flags(gsi);
+        let entry = RedirectionEntry {
+            vector,
+            delivery_mode: DeliveryMode::Fixed,
+            polarity,
+            trigger_mode,
+            masked: false,
+            destination,
+        };
+        self.set_entry(gsi, entry)
+    }
+
+    /// Route an isa irq to its usual vector on the cpu with `apic_id`
+    pub fn enable(&mut self, irq: IRQ, apic_id: u32) {
+        let irq = irq as u8;
+        let gsi = self.isa[irq as usize].gsi;
+        if let Err(error) = self.route(gsi, IRQ_0 + irq, apic_id) {
+            panic!("could not route irq {}: {:?}", irq, error);
+        }
+    }
+
+    pub fn mask(&mut self, gsi: u32) -> Result<(), RouteError> {
+        let (ioapic, pin) = self.find(gsi)?;
+        let mut entry = ioapic.read_entry(pin);
+        entry.masked = true;
+        ioapic.write_entry(pin, entry);
+        Ok(())
+    }
+
+    pub fn entry(&mut self, gsi: u32) -> Result<RedirectionEntry, RouteError> {
+        let (ioapic, pin) = self.find(gsi)?;
+        Ok(ioapic.read_entry(pin))
+    }
+
+    pub fn set_entry(&mut self, gsi: u32, entry: RedirectionEntry) -> Result<(), RouteError> {
+        let (ioapic, pin) = self.find(gsi)?;
+        ioapic.write_entry(pin, entry);
+        Ok(())
+    }
+
+    /// The io apic handling `gsi` and its pin
+    fn find(&mut self, gsi: u32) -> Result<(&mut IoApic, u32), RouteError> {
+        self.ioapics
+            .iter_mut()
+            .flatten()
+            .find(|ioapic| ioapic.handles(gsi))
+            .map(|ioapic| {
+                let pin = gsi - ioapic.gsi_base;
+                (ioapic, pin)
+            })
+            .ok_or(RouteError::UnknownGsi(gsi))
+    }
+}
+
+/// Isa irqs conform to the bus when the flags do not say otherwise
+fn isa_flags(flags: madt::IntiFlags) -> (Polarity, TriggerMode) {
+    let polarity = match flags.polarity() {
+        madt::Polarity::ActiveLow => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    let trigger_mode = match flags.trigger_mode() {
+        madt::TriggerMode::Level => TriggerMode::Level,
+        _ => TriggerMode::Edge,
+    };
+    (polarity, trigger_mode)
+}
+
+impl Default for IoApics {
     fn default() -> Self {
         use crate::multiboot::MADT_TABLE;
-        let addr = MADT_TABLE.ioapic_addr();
-        unsafe { Self::new(addr.as_mut_ptr::<IoApicRegister>()) }
+
+        let mut ioapics = [const { None }; madt::MAX_IOAPICS];
+        for (ioapic, entry) in ioapics.iter_mut().zip(MADT_TABLE.ioapics()) {
+            let registers = entry.address().as_mut_ptr::<IoApicRegister>();
+            *ioapic = Some(unsafe { IoApic::new(registers, entry.gsi_base()) });
+        }
+
+        let mut isa = [IsaRoute {
+            gsi: 0,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        }; ISA_IRQS];
+        for (irq, route) in isa.iter_mut().enumerate() {
+            route.gsi = irq as u32;
+        }
+        for entry in MADT_TABLE.overrides() {
+            if let Some(route) = isa.get_mut(entry.source() as usize) {
+                let (polarity, trigger_mode) = isa_flags(entry.flags());
+                *route = IsaRoute {
+                    gsi: entry.gsi(),
+                    polarity,
+                    trigger_mode,
+                };
+            }
+        }
+
+        let mut nmi_sources = [None; madt::MAX_ISA_ENTRIES];
+        for (source, entry) in nmi_sources.iter_mut().zip(MADT_TABLE.nmi_sources()) {
+            let (polarity, trigger_mode) = isa_flags(entry.flags());
+            *source = Some((entry.gsi(), polarity, trigger_mode));
+        }
+
+        Self {
+            ioapics,
+            isa,
+            nmi_sources,
+        }
     }
 }
 
+#[repr(C)]
 struct IoApicRegister {
     register: u32,
     _reserved: [u32; 3],
     data: u32,
 }
 
-impl IoApicRegister {
-    fn set_register(&mut self, register: u32) {
-        self.register = register;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn redirection_entry_bits() {
+        let entry = RedirectionEntry {
+            vector: 0x31,
+            delivery_mode: DeliveryMode::LowestPriority,
+            polarity: Polarity::ActiveLow,
+            trigger_mode: TriggerMode::Level,
+            masked: true,
+            destination: 3,
+        };
+        let value = u64::from(entry);
+        assert_eq!(value, 0x0300_0000_0001_a131);
+        assert_eq!(RedirectionEntry::from(value), entry);
     }
 
-    fn write(&mut self, register: u32, data: u32) {
-        self.register = register;
-        self.data = data;
+    #[test_case]
+    fn route_and_mask() {
+        let mut ioapics = crate::io::IO_APIC.lock();
+        let gsi = ioapics.isa_route(IRQ::Com1 as u8).gsi;
+        let apic_id = crate::io::LAPIC.id();
+        if apic_id > 0xff {
+            return;
+        }
+
+        ioapics.route(gsi, 0x40, apic_id).unwrap();
+        let entry = ioapics.entry(gsi).unwrap();
+        assert_eq!(entry.vector, 0x40);
+        assert_eq!(entry.destination as u32, apic_id);
+        assert!(!entry.masked);
+
+        ioapics.mask(gsi).unwrap();
+        assert!(ioapics.entry(gsi).unwrap().masked);
+        assert_eq!(
+            ioapics.route(u32::MAX, 0x40, 0),
+            Err(RouteError::UnknownGsi(u32::MAX))
+        );
     }
 }
@@ -24,6 +24,9 @@ const KEY_PGDN: u8 = 0xE7;
 const KEY_INS: u8 = 0xE8;
 const KEY_DEL: u8 = 0xE9;
 
+/// Make code of delete on the keypad, the extended key sends it after an E0 prefix
+const DELETE_MAKE: u8 = 0x53;
+
 #[rustfmt::skip]
 const NORMAL_MAP: [u8; 256] = [
     NONE, 0x1B, b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'0', b'-', b'=', b'\x08', b'\t', // 0x10
@@ -228,6 +231,14 @@ impl Keyboard {
         MODIFER_STATE.fetch_or(shift_code, Ordering::Relaxed);
         MODIFER_STATE.fetch_xor(toggle_code, Ordering::Relaxed);
 
+        // ctrl + alt + delete, on the keypad or the extended key. Releases of either returned
+        // above, and the E0 prefix of the extended key set the top bit of the make code
+        let reboot = (KeyboardState::CTRL | KeyboardState::ALT).bits;
+        let delete = scancode & 0x7F == DELETE_MAKE;
+        if delete && MODIFER_STATE.load(Ordering::Relaxed) & reboot == reboot {
+            crate::power::reboot();
+        }
+
         let index = MODIFER_STATE.load(Ordering::Relaxed)
             & (KeyboardState::CTRL | KeyboardState::SHIFT).bits;
 
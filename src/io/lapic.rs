@@ -1,8 +1,26 @@
 use bitflags::bitflags;
 use core::ops::{Index, IndexMut};
 
+use x86_64::cpuid::{self, FeaturesEcx};
+use x86_64::registers::model_specific::{ApicBase, ApicBaseFlags, Msr, TscDeadline};
 use x86_64::{PhysicalAddress, VirtualAddress};
 
+/// The x2apic registers are msrs starting here, one for every 16 bytes of the mmio page
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Mask bit of the local vector table entries
+const LVT_MASKED: u32 = 0x10000;
+const TIMER_DIVIDE_BY_1: u32 = 0xb;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TimerMode {
+    OneShot = 0,
+    Periodic = 0x20000,
+    /// Interrupt when the time stamp counter reaches IA32_TSC_DEADLINE, the count is unused
+    TscDeadline = 0x40000,
+}
+
 bitflags! {
     struct InterruptCommand: u32 {
         const INIT     =  0x00000500;  // INIT/RESET
@@ -11,7 +29,9 @@ bitflags! {
         const ASSERT   =  0x00004000;  // Assert interrupt (vs deassert)
         const DEASSERT =  0x00000000;
         const LEVEL    =  0x00008000;  // Level triggered
+        const NMI      =  0x00000400;  // Non maskable interrupt, the vector is ignored
         const BCAST    =  0x00080000;  // Send to all APICs, including self.
+        const OTHERS   =  0x000C0000;  // Send to all APICs, excluding self.
         const BUSY     =  0x00001000;
         const FIXED    =  0x00000000;
     }
@@ -60,11 +80,68 @@ enum Register {
     TimerDivideConfiguration,
 }
 
-pub struct Lapic(&'static mut Registers);
+impl Register {
+    /// Offset of the register in the mmio page
+    const fn offset(self) -> u32 {
+        match self {
+            Register::Id => 0x20,
+            Register::Version => 0x30,
+            Register::TaskPriority => 0x80,
+            Register::ArbitrationPriority => 0x90,
+            Register::ProcessorPriority => 0xA0,
+            Register::EndOfInterrupt => 0xB0,
+            Register::RemoteRead => 0xC0,
+            Register::LogicalDestination => 0xD0,
+            Register::DestinationFormat => 0xE0,
+            Register::SpuriousInterruptVector => 0xF0,
+            Register::InService(i) => 0x100 + i as u32 * 0x10,
+            Register::TriggerMode(i) => 0x180 + i as u32 * 0x10,
+            Register::InterruptRequest(i) => 0x200 + i as u32 * 0x10,
+            Register::ErrorStatus => 0x280,
+            Register::LvtCorrectMachineCheck => 0x2F0,
+            Register::InterruptCommand(i) => 0x300 + i as u32 * 0x10,
+            Register::Timer => 0x320,
+            Register::ThermalSensor => 0x330,
+            Register::PerformanceMonitoring => 0x340,
+            Register::Lint(i) => 0x350 + i as u32 * 0x10,
+            Register::Error => 0x370,
+            Register::TimerInitialCount => 0x380,
+            Register::TimerCurrentCount => 0x390,
+            Register::TimerDivideConfiguration => 0x3E0,
+        }
+    }
+
+    /// The msr of the register in x2apic mode
+    const fn msr(self) -> Msr {
+        Msr::new(X2APIC_MSR_BASE + (self.offset() >> 4))
+    }
+}
+
+/// How the registers are reached
+enum Mode {
+    /// Memory mapped, with 8 bit apic ids
+    XApic(&'static mut Registers),
+    /// Through msrs, with 32 bit apic ids
+    X2Apic,
+}
+
+pub struct Lapic(Mode);
 
 impl Lapic {
     pub fn new(value: &'static mut Registers) -> Self {
-        Self(value)
+        Self(Mode::XApic(value))
+    }
+
+    /// Use the local apic of this cpu in x2apic mode, switching it over if needed
+    /// # Safety
+    /// The cpu must support x2apic
+    pub unsafe fn new_x2apic() -> Self {
+        enable_x2apic();
+        Self(Mode::X2Apic)
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        matches!(self.0, Mode::X2Apic)
     }
 
     pub fn init(&mut self) {
@@ -74,6 +151,11 @@ impl Lapic {
 
         let irq0 = crate::consts::IRQ_0;
 
+        // x2apic mode is per cpu, every cpu that shares this has to switch
+        if self.is_x2apic() {
+            unsafe { enable_x2apic() };
+        }
+
         // TODO don't hard code
 
         // Enable local APIC, set spurious interrupt vector
@@ -83,16 +165,11 @@ impl Lapic {
             0x100 | (irq0 + spurious_irq) as u32,
         );
 
-        // Timer counts down at bus frequency
-        let timer_irq = InterruptIndex::Timer as u8;
-        let x1 = 0xb;
-        let periodic = 0x20000;
-        self.write(Reg::TimerDivideConfiguration, x1);
-        self.write(Reg::Timer, periodic | (irq0 + timer_irq) as u32);
-        self.write(Reg::TimerInitialCount, 10_000_000);
+        // The timer stays off until it is calibrated
+        self.stop_timer();
 
         // Disable logical interrupt lines
-        let masked = 0x10000;
+        let masked = LVT_MASKED;
         self.write(Reg::Lint(0), masked);
         self.write(Reg::Lint(1), masked);
 
@@ -109,13 +186,11 @@ impl Lapic {
         // Ack outstanding interrupts
         self.end_of_interrupt();
 
-        // Send an init level de assert to synchronize arbitration id
-        self.write(Reg::InterruptCommand(1), 0);
-        self.write(
-            Reg::InterruptCommand(0),
-            ICR::BCAST.bits | ICR::INIT.bits | ICR::LEVEL.bits,
-        );
-        while self.read(Reg::InterruptCommand(0)) & ICR::DELIVS.bits != 0 {}
+        // Send an init level de assert to synchronize arbitration id,
+        // x2apic does not have arbitration ids (or this ipi)
+        if !self.is_x2apic() {
+            self.send_ipi(0, ICR::BCAST.bits | ICR::INIT.bits | ICR::LEVEL.bits);
+        }
 
         // Enable interrupt on the APIC (but not the processor)
         self.write(Reg::TaskPriority, 0);
@@ -123,33 +198,123 @@ impl Lapic {
 
     /// Write to a register
     fn write(&mut self, index: Register, value: u32) {
-        self.0[index].write(value);
-
-        // wait for write to finish, by reading
-        self.id();
+        match &mut self.0 {
+            Mode::XApic(registers) => {
+                registers[index].write(value);
+
+                // wait for write to finish, by reading
+                self.id();
+            }
+            Mode::X2Apic => unsafe { index.msr().write(value.into()) },
+        }
     }
 
     /// Read from a register
     fn read(&self, index: Register) -> u32 {
-        self.0[index].read()
+        match &self.0 {
+            Mode::XApic(registers) => registers[index].read(),
+            Mode::X2Apic => unsafe { index.msr().read() as u32 },
+        }
     }
 
     /// Get the Id of the lapic
-    pub fn id(&self) -> u8 {
-        (self.read(Register::Id) >> 24) as u8
+    pub fn id(&self) -> u32 {
+        match self.0 {
+            Mode::XApic(_) => self.read(Register::Id) >> 24,
+            Mode::X2Apic => self.read(Register::Id),
+        }
+    }
+
+    /// Send an interprocessor interrupt to `apic_id`, `command` is the low half of the icr
+    fn send_ipi(&mut self, apic_id: u32, command: u32) {
+        use InterruptCommand as ICR;
+
+        match self.0 {
+            Mode::XApic(_) => {
+                self.write(Register::InterruptCommand(1), apic_id << 24);
+                self.write(Register::InterruptCommand(0), command);
+                while self.read(Register::InterruptCommand(0)) & ICR::DELIVS.bits != 0 {}
+            }
+            // a single 64 bit register, with the whole destination in the high half
+            Mode::X2Apic => unsafe {
+                let value = (u64::from(apic_id) << 32) | u64::from(command);
+                Register::InterruptCommand(0).msr().write(value);
+            },
+        }
+    }
+
+    /// Interrupt every other cpu with `vector`
+    pub fn send_to_others(&mut self, vector: u8) {
+        use InterruptCommand as ICR;
+        self.send_ipi(0, ICR::OTHERS.bits | ICR::ASSERT.bits | u32::from(vector));
+    }
+
+    /// Send a non maskable interrupt to every other cpu
+    pub fn send_nmi_to_others(&mut self) {
+        use InterruptCommand as ICR;
+        self.send_ipi(0, ICR::OTHERS.bits | ICR::ASSERT.bits | ICR::NMI.bits);
+    }
+
+    /// Start the timer, it counts down from `initial_count` at the bus frequency
+    pub fn start_timer(&mut self, mode: TimerMode, initial_count: u32) {
+        let vector = crate::consts::IRQ_0 + crate::interrupts::idt::InterruptIndex::Timer as u8;
+        self.write(Register::TimerDivideConfiguration, TIMER_DIVIDE_BY_1);
+        self.write(Register::Timer, mode as u32 | vector as u32);
+        self.write(Register::TimerInitialCount, initial_count);
+    }
+
+    /// Interrupt once the time stamp counter reaches `deadline`
+    pub fn start_tsc_deadline(&mut self, deadline: u64) {
+        let vector = crate::consts::IRQ_0 + crate::interrupts::idt::InterruptIndex::Timer as u8;
+        let mode = TimerMode::TscDeadline as u32;
+        self.write(Register::Timer, mode | vector as u32);
+        // the lvt write has to land before the msr write arms the timer
+        unsafe {
+            core::arch::asm!("mfence", options(nostack, preserves_flags));
+            TscDeadline::write(deadline);
+        }
+    }
+
+    /// Count down from the largest count without raising an interrupt, for calibration
+    pub fn start_free_running(&mut self) {
+        self.write(Register::TimerDivideConfiguration, TIMER_DIVIDE_BY_1);
+        self.write(Register::Timer, LVT_MASKED);
+        self.write(Register::TimerInitialCount, u32::MAX);
+    }
+
+    /// Stop the timer and mask its interrupt
+    pub fn stop_timer(&mut self) {
+        self.write(Register::Timer, LVT_MASKED);
+        self.write(Register::TimerInitialCount, 0);
+    }
+
+    /// What is left of the current count down
+    pub fn timer_count(&self) -> u32 {
+        self.read(Register::TimerCurrentCount)
+    }
+
+    /// Is an interrupt for `vector` waiting to be delivered
+    pub fn is_pending(&self, vector: u8) -> bool {
+        self.read(Register::InterruptRequest(vector / 32)) & (1 << (vector % 32)) != 0
     }
 
     pub fn error_status(&self) -> ErrorStatus {
         ErrorStatus::from_bits_truncate(self.read(Register::ErrorStatus))
     }
 
+    /// Latch the errors seen since the last update, then read and clear them
+    pub fn update_error_status(&mut self) -> ErrorStatus {
+        self.write(Register::ErrorStatus, 0);
+        self.error_status()
+    }
+
     /// call when an interrupt has ended
     pub fn end_of_interrupt(&mut self) {
         self.write(Register::EndOfInterrupt, 0);
     }
 
     /// Start additional processors
-    pub fn start_ap(&mut self, apic_id: u8, addr: PhysicalAddress) {
+    pub fn start_ap(&mut self, apic_id: u32, addr: PhysicalAddress) {
         let addr: VirtualAddress = addr.into();
         // "The BSP must initialize CMOS shutdown code to 0AH
         // and the warm reset vector (DWORD based at 40:67) to point at
@@ -162,43 +327,65 @@ impl Lapic {
         unsafe { warm_reset_vector.write_unaligned(wrv_value) };
 
         use InterruptCommand as IC;
-        use Register as Reg;
 
         // universal startup algorithm
-        self.write(Reg::InterruptCommand(1), (apic_id as u32) << 24);
-        self.write(
-            Reg::InterruptCommand(0),
-            IC::INIT.bits | IC::LEVEL.bits | IC::ASSERT.bits,
-        );
+        self.send_ipi(apic_id, IC::INIT.bits | IC::LEVEL.bits | IC::ASSERT.bits);
         super::micro_delay(200);
         assert!(self.error_status().is_empty());
 
-        self.write(Reg::InterruptCommand(0), IC::INIT.bits | IC::LEVEL.bits);
+        if !self.is_x2apic() {
+            self.send_ipi(apic_id, IC::INIT.bits | IC::LEVEL.bits);
+        }
 
         super::micro_delay(100);
         assert!(self.error_status().is_empty());
         // send ipi twice!
         for _ in 0..2 {
-            self.write(Reg::InterruptCommand(1), (apic_id as u32) << 24);
-            self.write(
-                Reg::InterruptCommand(0),
-                IC::STARTUP.bits | (u64::from(addr) >> 12) as u32,
-            );
+            self.send_ipi(apic_id, IC::STARTUP.bits | (u64::from(addr) >> 12) as u32);
             super::micro_delay(200);
             assert!(self.error_status().is_empty());
         }
     }
 }
 
+/// Use x2apic when the cpu has it, else the memory mapped registers from the madt
 impl Default for Lapic {
     fn default() -> Self {
         use crate::multiboot::MADT_TABLE;
+
+        if x2apic_supported() {
+            return unsafe { Self::new_x2apic() };
+        }
+
         let pa = MADT_TABLE.lapic_addr();
         let ptr = unsafe { &mut *pa.as_mut_ptr::<Registers>() };
         Self::new(ptr)
     }
 }
 
+pub fn x2apic_supported() -> bool {
+    cpuid::features().0.contains(FeaturesEcx::X2APIC)
+}
+
+pub fn tsc_deadline_supported() -> bool {
+    cpuid::features().0.contains(FeaturesEcx::TSC_DEADLINE)
+}
+
+/// Switch the local apic of this cpu to x2apic mode, it has to go through xapic mode first
+/// # Safety
+/// The cpu must support x2apic, there is no way back without resetting the apic
+unsafe fn enable_x2apic() {
+    let (address, flags) = ApicBase::read();
+    if flags.contains(ApicBaseFlags::X2APIC_ENABLE) {
+        return;
+    }
+    ApicBase::write(address, flags | ApicBaseFlags::XAPIC_ENABLE);
+    ApicBase::write(
+        address,
+        flags | ApicBaseFlags::XAPIC_ENABLE | ApicBaseFlags::X2APIC_ENABLE,
+    );
+}
+
 // TODO we must write a custom debug that only reads from the readable registers, else we lock it up
 #[repr(C)]
 pub struct Registers {
@@ -217,7 +404,7 @@ pub struct Registers {
     in_service: [Reg; 8],            // read only
     trigger_mode: [Reg; 8],          // read only
     interrupt_request: [Reg; 8],     // read only
-    error_status: Reg,               // read only
+    error_status: Reg,               // read/write (write latches the errors)
     _reserved_3: [Reg; 6],           // none
     lvt_correct_machine_check: Reg,  // read/write
     interrupt_command: [Reg; 2],     // read/write
@@ -286,7 +473,7 @@ impl IndexMut<Register> for Registers {
             Register::InService(_) => panic!("In Service is read only"),
             Register::TriggerMode(_) => panic!("Trigger Mode is read only"),
             Register::InterruptRequest(_) => panic!("Interrupt Request is read only"),
-            Register::ErrorStatus => panic!("Error Status is read only"),
+            Register::ErrorStatus => &mut self.error_status,
             Register::LvtCorrectMachineCheck => &mut self.lvt_correct_machine_check,
             Register::InterruptCommand(i) if i < 2 => &mut self.interrupt_command[i as usize],
             Register::InterruptCommand(_) => panic!("Index out of bounds, must be < 2"),
@@ -350,4 +537,35 @@ mod test {
         use core::mem::size_of;
         assert_eq!(size_of::<Reg>(), 16)
     }
+
+    /// The mmio offsets have to match the struct, and map to the right msrs
+    #[test_case]
+    fn register_offsets() {
+        use core::mem::offset_of;
+        assert_eq!(Register::Id.offset() as usize, offset_of!(Registers, id));
+        assert_eq!(
+            Register::ErrorStatus.offset() as usize,
+            offset_of!(Registers, error_status)
+        );
+        assert_eq!(
+            Register::Lint(1).offset() as usize,
+            offset_of!(Registers, lvt_lint) + 16
+        );
+        assert_eq!(
+            Register::TimerDivideConfiguration.offset() as usize,
+            offset_of!(Registers, divide_configuration)
+        );
+
+        assert_eq!(Register::Id.msr().register(), 0x802);
+        assert_eq!(Register::EndOfInterrupt.msr().register(), 0x80B);
+        assert_eq!(Register::InterruptCommand(0).msr().register(), 0x830);
+        assert_eq!(Register::TimerDivideConfiguration.msr().register(), 0x83E);
+    }
+
+    #[test_case]
+    fn id_matches_mode() {
+        let lapic = &crate::io::LAPIC;
+        assert_eq!(lapic.is_x2apic(), x2apic_supported());
+        assert_eq!(lapic.id(), crate::proc::percpu::current().apic_id());
+    }
 }
@@ -1,16 +1,20 @@
+pub mod aml;
 pub mod cmos;
 pub mod console;
+pub mod hpet;
 pub mod ioapic;
 pub mod keyboard;
 pub mod lapic;
 pub mod pic;
 pub mod pit;
+pub mod rtc;
 pub mod vga;
 
 use cmos::Cmos;
 use core::fmt::{Arguments, Write};
 use core::sync::atomic::AtomicBool;
-use ioapic::IoApic;
+use hpet::Hpet;
+use ioapic::IoApics;
 use lapic::Lapic;
 use serial::Uart;
 use spin::{Lazy, Mutex};
@@ -43,9 +47,12 @@ static PICS: Mutex<Pics> = Mutex::new(Pics::new());
 /// must be initalized once
 pub static LAPIC: Lazy<Lapic> = Lazy::new(Lapic::default);
 
-/// Global IO APIC, not need to may be mut static since it is unique per cpu
+/// Every IO APIC in the system and the interrupt routing from the madt,
 /// must be initalized once
-pub static IO_APIC: Lazy<IoApic> = Lazy::new(IoApic::default);
+pub static IO_APIC: Lazy<Mutex<IoApics>> = Lazy::new(|| Mutex::new(IoApics::default()));
+
+/// The high precision event timer, if the acpi tables have one
+pub static HPET: Lazy<Option<Hpet>> = Lazy::new(Hpet::from_acpi);
 
 pub static mut CMOS: Cmos = Cmos::new();
 
@@ -83,6 +90,27 @@ pub fn lapic_init() {
     }
 }
 
+/// Start the hpet main counter, does nothing without a hpet
+pub fn hpet_init() {
+    // only init once (ok to be "expensive" since we only call once")
+    static ALREADY_INIT: AtomicBool = AtomicBool::new(false);
+    if ALREADY_INIT.fetch_or(true, core::sync::atomic::Ordering::SeqCst) {
+        panic!("hpet already init")
+    }
+
+    match HPET.as_ref() {
+        Some(hpet) => {
+            hpet.init();
+            crate::kprintln!(
+                "HPET has been initialized: {} Hz, {} comparators",
+                hpet.frequency(),
+                hpet.timers()
+            );
+        }
+        None => crate::kprintln!("No HPET found"),
+    }
+}
+
 pub fn uart_disable() {
     UART.lock().disable();
 }
@@ -101,17 +129,21 @@ pub fn ioapic_init() {
         panic!("ioapic already init")
     }
 
-    unsafe {
-        Lazy::<IoApic>::force(&IO_APIC);
-        (*IO_APIC.as_mut_ptr()).init();
-        (*IO_APIC.as_mut_ptr()).enable(IRQ::Keyboard, 0);
-    }
+    let mut ioapics = IO_APIC.lock();
+    ioapics.init();
+    ioapics.enable(IRQ::Keyboard, 0);
 
     crate::kprintln!("IOAPIC has been initialized");
 }
 
 pub fn current_time() -> RtcDate {
-    unsafe { CMOS.time() }
+    // the rtc interrupt handler also selects cmos registers
+    crate::interrupts::without_interrupts(|| unsafe { CMOS.time() })
+}
+
+/// Set the real time clock, use [`crate::time::wall::set`] to also move the wall clock
+pub fn set_current_time(date: &RtcDate) {
+    crate::interrupts::without_interrupts(|| unsafe { CMOS.set_time(date) });
 }
 
 /// TODO: make spin on real hardware
@@ -161,11 +193,15 @@ macro_rules! kpanicprint {
     ($($arg:tt)*) => ($crate::io::_print(format_args!($($arg)*), true));
 }
 
-/// Print line that writes to VGA and Uart
+/// Print line that writes to VGA and Uart, stamped with the time since boot
 #[macro_export]
 macro_rules! kprintln {
     () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::kprint!("{}\n", format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::kprint!(
+        "{} {}\n",
+        $crate::time::Timestamp::now(),
+        format_args!($($arg)*)
+    ));
 }
 
 /// Panic use only, Print line that writes to VGA and Uart
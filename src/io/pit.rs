@@ -1,4 +1,8 @@
 use port::{Port, PortWriteOnly};
+
+/// Input clock of the pit in Hz
+pub const FREQUENCY: u64 = 1_193_182;
+
 pub struct Pit {
     channel_0: Port<u8>,
     channel_1: Port<u8>,
@@ -17,10 +21,17 @@ impl Pit {
         }
     }
 
+    /// Sleep for about a millisecond, wait for it with `check_done`
     pub fn sleep(&mut self) {
+        self.start_countdown(0x4A9);
+    }
+
+    /// Count down `count` clocks of [`FREQUENCY`] on channel 0, wait for it with `check_done`
+    pub fn start_countdown(&mut self, count: u16) {
+        // channel 0, low then high byte, interrupt on terminal count
         unsafe { self.mode_command.write(0x30) };
-        unsafe { self.channel_0.write(0xA9) };
-        unsafe { self.channel_0.write(0x4) };
+        unsafe { self.channel_0.write(count as u8) };
+        unsafe { self.channel_0.write((count >> 8) as u8) };
     }
 
     pub fn check_done(&mut self) -> bool {
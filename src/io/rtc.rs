@@ -0,0 +1,243 @@
+//! Real time clock interrupts on irq 8.
+//!
+//! The clock can interrupt periodically, when its alarm time is reached and after every
+//! update of the time (once a second). Each of them can have one listener at a time,
+//! a stream or future that enables the interrupt while it exists.
+
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use futures_util::future::Future;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+use super::cmos::{Cmos, RtcDate, RtcInterrupts};
+use crate::interrupts::without_interrupts;
+
+/// Highest periodic interrupt frequency, the rtc can do 32768 Hz but not reliably
+pub const MAX_FREQUENCY: u32 = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// Something already listens to this interrupt
+    Busy,
+    /// The periodic interrupt runs at powers of two from 2 to [`MAX_FREQUENCY`] Hz
+    InvalidFrequency(u32),
+    /// Not a time of day
+    InvalidTime,
+}
+
+/// Interrupts of one kind and the task waiting for them
+struct Source {
+    interrupt: RtcInterrupts,
+    count: AtomicU64,
+    waker: AtomicWaker,
+    in_use: AtomicBool,
+}
+
+impl Source {
+    const fn new(interrupt: RtcInterrupts) -> Self {
+        Self {
+            interrupt,
+            count: AtomicU64::new(0),
+            waker: AtomicWaker::new(),
+            in_use: AtomicBool::new(false),
+        }
+    }
+
+    /// Take the source and enable its interrupt after `setup`
+    fn acquire(&'static self, setup: impl FnOnce(&mut Cmos)) -> Result<Listener, RtcError> {
+        if self.in_use.swap(true, Ordering::Acquire) {
+            return Err(RtcError::Busy);
+        }
+        let seen = self.count.load(Ordering::Acquire);
+        without_interrupts(|| {
+            let mut cmos = Cmos::new();
+            setup(&mut cmos);
+            cmos.enable_interrupts(self.interrupt);
+        });
+        Ok(Listener { source: self, seen })
+    }
+}
+
+static PERIODIC: Source = Source::new(RtcInterrupts::PERIODIC);
+static ALARM: Source = Source::new(RtcInterrupts::ALARM);
+static UPDATE_ENDED: Source = Source::new(RtcInterrupts::UPDATE_ENDED);
+
+/// Route irq 8 to this cpu, must be called once
+pub fn init() {
+    use crate::consts::IRQ;
+
+    // only init once (ok to be "expensive" since we only call once")
+    static ALREADY_INIT: AtomicBool = AtomicBool::new(false);
+    if ALREADY_INIT.fetch_or(true, Ordering::SeqCst) {
+        panic!("rtc already init")
+    }
+
+    without_interrupts(|| {
+        let mut cmos = Cmos::new();
+        cmos.disable_interrupts(RtcInterrupts::all());
+        // a flag left set from before boot would block every later interrupt
+        cmos.acknowledge();
+    });
+    super::IO_APIC.lock().enable(IRQ::Rtc, super::LAPIC.id());
+}
+
+/// Called by the rtc interrupt handler
+pub fn interrupt() {
+    let flags = Cmos::new().acknowledge();
+    for source in [&PERIODIC, &ALARM, &UPDATE_ENDED] {
+        if flags.contains(source.interrupt) {
+            source.count.fetch_add(1, Ordering::Release);
+            source.waker.wake();
+        }
+    }
+}
+
+/// Holds a source, disables its interrupt when dropped
+struct Listener {
+    source: &'static Source,
+    /// Interrupts already returned
+    seen: u64,
+}
+
+impl Listener {
+    /// Interrupts since the last call that returned some
+    fn poll_interrupts(&mut self, cx: &mut Context) -> Poll<u64> {
+        // register first so an interrupt right after the check still wakes us
+        self.source.waker.register(cx.waker());
+        let count = self.source.count.load(Ordering::Acquire);
+        if count == self.seen {
+            return Poll::Pending;
+        }
+        let new = count - self.seen;
+        self.seen = count;
+        Poll::Ready(new)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        without_interrupts(|| Cmos::new().disable_interrupts(self.source.interrupt));
+        self.source.in_use.store(false, Ordering::Release);
+    }
+}
+
+/// Periodic interrupts, yields how many happened since the last item
+pub struct Periodic(Listener);
+
+impl Stream for Periodic {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        self.0.poll_interrupts(cx).map(Some)
+    }
+}
+
+/// Interrupt `hz` times a second, a power of two from 2 to [`MAX_FREQUENCY`]
+pub fn periodic(hz: u32) -> Result<Periodic, RtcError> {
+    if !hz.is_power_of_two() || !(2..=MAX_FREQUENCY).contains(&hz) {
+        return Err(RtcError::InvalidFrequency(hz));
+    }
+    // hz = 32768 >> (rate - 1)
+    let rate = 16 - hz.trailing_zeros() as u8;
+    PERIODIC
+        .acquire(|cmos| cmos.set_periodic_rate(rate))
+        .map(Periodic)
+}
+
+/// Yields the date every time the clock has updated it
+pub struct UpdateEnded(Listener);
+
+impl Stream for UpdateEnded {
+    type Item = RtcDate;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<RtcDate>> {
+        self.0
+            .poll_interrupts(cx)
+            .map(|_| Some(without_interrupts(|| Cmos::new().time())))
+    }
+}
+
+pub fn update_ended() -> Result<UpdateEnded, RtcError> {
+    UPDATE_ENDED.acquire(|_| {}).map(UpdateEnded)
+}
+
+/// Completes when the clock reaches the alarm time
+pub struct Alarm(Listener);
+
+impl Future for Alarm {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.0.poll_interrupts(cx).map(|_| ())
+    }
+}
+
+/// Wait until the clock reads `hour:minute:second`, which can be up to a day
+pub fn alarm(hour: u16, minute: u16, second: u16) -> Result<Alarm, RtcError> {
+    if hour >= 24 || minute >= 60 || second >= 60 {
+        return Err(RtcError::InvalidTime);
+    }
+    ALARM
+        .acquire(|cmos| cmos.set_alarm(hour, minute, second))
+        .map(Alarm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::executor::Executor;
+    use crate::task::Task;
+    use crate::time::timer::{timeout, Elapsed};
+    use core::time::Duration;
+    use futures_util::StreamExt;
+
+    #[test_case]
+    fn periodic_interrupts() {
+        assert_eq!(periodic(3).err(), Some(RtcError::InvalidFrequency(3)));
+
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            let ticks = periodic(1024).unwrap();
+            assert_eq!(periodic(2).err(), Some(RtcError::Busy));
+
+            let count = ticks
+                .take(4)
+                .fold(0, |total, new| async move { total + new });
+            let count = timeout(Duration::from_millis(100), count).await;
+            assert!(count.unwrap() >= 4);
+        }));
+        executor.run_until_done();
+        crate::interrupts::disable_interrupts();
+
+        // dropping the stream gave it back
+        drop(periodic(2).unwrap());
+    }
+
+    #[test_case]
+    fn update_and_alarm() {
+        assert_eq!(alarm(24, 0, 0).err(), Some(RtcError::InvalidTime));
+
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            let mut updates = update_ended().unwrap();
+            let date = timeout(Duration::from_millis(1100), updates.next()).await;
+            let date = date.unwrap().unwrap();
+
+            // the update that woke us just happened, so the alarm is about two seconds away
+            let alarm_time = RtcDate::from_unix(date.to_unix() + 2);
+            let mut ring = alarm(
+                alarm_time.hour().into(),
+                alarm_time.minute().into(),
+                alarm_time.second().into(),
+            )
+            .unwrap();
+            let early = timeout(Duration::from_millis(500), &mut ring).await;
+            assert_eq!(early, Err(Elapsed));
+            timeout(Duration::from_secs(3), ring).await.unwrap();
+        }));
+        executor.run_until_done();
+        crate::interrupts::disable_interrupts();
+    }
+}
@@ -20,14 +20,21 @@ extern crate alloc;
 pub mod common;
 pub mod consts;
 pub mod disk;
+pub mod gdb;
 pub mod interrupts;
 pub mod io;
 pub mod memory;
 pub mod multiboot;
 pub mod paging;
+pub mod pci;
+pub mod power;
 pub mod proc;
 pub mod sections;
+pub mod smbios;
+pub mod syscall;
 pub mod task;
+pub mod time;
+pub mod virtio;
 
 /// Entry point for `cargo test`
 #[cfg(test)]
@@ -38,6 +45,21 @@ pub extern "C" fn kmain() -> ! {
     paging::map_all_physical_memory(SECTIONS[Section::PhysPageTable].start());
 
     interrupts::init();
+    io::lapic_init();
+    proc::percpu::init();
+    syscall::init();
+    io::pic_init();
+    io::hpet_init();
+    time::init();
+    io::ioapic_init();
+    io::rtc::init();
+    memory::heap::init();
+    proc::fpu::init();
+    io::aml::init();
+    smbios::init();
+    pci::init();
+    disk::ahci_init();
+    disk::virtio_init();
     test_main();
 
     interrupts::halt_loop();
@@ -20,14 +20,21 @@ extern crate alloc;
 mod common;
 mod consts;
 mod disk;
+mod gdb;
 mod interrupts;
 mod io;
 mod memory;
 mod multiboot;
 mod paging;
+mod pci;
+mod power;
 mod proc;
 mod sections;
+mod smbios;
+mod syscall;
 mod task;
+mod time;
+mod virtio;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
@@ -50,6 +57,7 @@ pub extern "C" fn kmain() -> ! {
 
     // Load GDT and IDT
     interrupts::init();
+    proc::cpu::init();
 
     // log that we are starting
     if let Some(name) = multiboot::MULTIBOOT_INFO.boot_loader_name() {
@@ -60,14 +68,38 @@ pub extern "C" fn kmain() -> ! {
 
     // enable the lapic
     io::lapic_init();
+    // per cpu data (in gs) and the syscall entry that depends on it
+    proc::percpu::init();
+    syscall::init();
     // Remap and disable the pic
     io::pic_init();
+    // start the hpet counter, if there is one
+    io::hpet_init();
+    // calibrate the lapic timer against the hpet or pit and start ticking
+    time::init();
 
     io::ioapic_init();
+    // rtc interrupts go through the io apic
+    io::rtc::init();
     // enable the heap
     memory::heap::init();
+    // enable fpu/sse, needs the heap for per task save areas
+    proc::fpu::init();
+    // build the acpi namespace, needs the heap
+    io::aml::init();
+    // log the machine model and memory layout
+    smbios::init();
+    // find the pci functions and hand them to drivers, needs the heap
+    pci::init();
+    // wait for a debugger on the second serial port, only when built with the gdb feature
+    if cfg!(feature = "gdb") && gdb::init() {
+        gdb::breakpoint();
+    }
     // enable ide driver
     disk::ide_init();
+    // start the sata and virtio disks, needs pci
+    disk::ahci_init();
+    disk::virtio_init();
 
     disk::ide_test();
     kprintln!("Current time: {}", io::current_time());
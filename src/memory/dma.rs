@@ -0,0 +1,140 @@
+//! Memory devices read and write on their own, taken from the heap.
+//!
+//! The heap is a single range of physical memory, so an allocation is physically contiguous
+//! and its physical address is found by walking the page tables once.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::slice;
+
+use x86_64::{PhysicalAddress, VirtualAddress};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// A zeroed, aligned and physically contiguous buffer
+pub struct DmaBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+    physical: PhysicalAddress,
+}
+
+// only the owner touches the memory, devices do not count
+unsafe impl Send for DmaBuffer {}
+unsafe impl Sync for DmaBuffer {}
+
+impl DmaBuffer {
+    /// None if the heap is out of memory
+    pub fn new(size: usize, align: usize) -> Option<Self> {
+        let layout = Layout::from_size_align(size, align).ok()?;
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return None;
+        }
+        let physical =
+            crate::paging::translate(VirtualAddress::new(ptr as u64)).expect("the heap is mapped");
+        Some(Self {
+            ptr,
+            layout,
+            physical,
+        })
+    }
+
+    pub fn physical(&self) -> PhysicalAddress {
+        self.physical
+    }
+
+    pub fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.ptr as *const T
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.ptr as *mut T
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// The physically contiguous pieces of `len` bytes at `data`, which can be anywhere mapped
+pub fn physical_ranges(data: *const u8, len: usize) -> PhysicalRanges {
+    PhysicalRanges {
+        address: data as u64,
+        end: data as u64 + len as u64,
+    }
+}
+
+pub struct PhysicalRanges {
+    address: u64,
+    end: u64,
+}
+
+impl PhysicalRanges {
+    fn translate(address: u64) -> u64 {
+        let physical = crate::paging::translate(VirtualAddress::new(address));
+        u64::from(physical.expect("dma buffers are mapped"))
+    }
+
+    /// Bytes from `address` to the end of its page or the buffer
+    fn page_bytes(&self, address: u64) -> u64 {
+        (PAGE_SIZE - address % PAGE_SIZE).min(self.end - address)
+    }
+}
+
+impl Iterator for PhysicalRanges {
+    type Item = (PhysicalAddress, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.address >= self.end {
+            return None;
+        }
+        let start = Self::translate(self.address);
+        let mut bytes = self.page_bytes(self.address);
+        // take the following pages while they follow in physical memory
+        while self.address + bytes < self.end
+            && Self::translate(self.address + bytes) == start + bytes
+        {
+            bytes += self.page_bytes(self.address + bytes);
+        }
+        self.address += bytes;
+        Some((PhysicalAddress::new(start), bytes as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn aligned_and_zeroed() {
+        let buffer = DmaBuffer::new(1024, 1024).unwrap();
+        assert_eq!(u64::from(buffer.physical()) % 1024, 0);
+        assert!(buffer.as_slice().iter().all(|byte| *byte == 0));
+    }
+
+    #[test_case]
+    fn contiguous_pages_merge() {
+        let buffer = DmaBuffer::new(3 * 4096, 4096).unwrap();
+        let data = unsafe { buffer.as_ptr::<u8>().add(512) };
+        let mut ranges = physical_ranges(data, 2 * 4096);
+        assert_eq!(ranges.next(), Some((buffer.physical() + 512u64, 2 * 4096)));
+        assert_eq!(ranges.next(), None);
+    }
+}
@@ -1,3 +1,4 @@
+pub mod dma;
 pub mod heap;
 
 /// A completly unsafe memory copy, just like c's memcpy
@@ -1,7 +1,7 @@
 use spin::Lazy;
 
 use multiboot2::{MultiBoot2Header, MultibootInfo};
-use x86_64::tables::acpi::Acpi;
+use x86_64::tables::acpi::{Acpi, AcpiError};
 use x86_64::tables::madt::MultiAPIC;
 
 /// Puts the multiboot2 header at section .multiboot allowing for booting for bootloaders such as grub2
@@ -20,23 +20,57 @@ pub static MULTIBOOT_INFO: Lazy<MultibootInfo> = Lazy::new(|| {
         .expect("There should be valid multiboot info table")
 });
 
+/// The acpi tables, empty if the bootloader did not pass a usable rsdp
 pub static ACPI_TABLE: Lazy<Acpi> = Lazy::new(|| {
-    let rsdp_addr = match MULTIBOOT_INFO.rsdp_v1() {
-        Some(rsdp) => match rsdp.table().is_valid() {
-            true => Some(rsdp.table().rsdt_address()),
-            false => panic!("RsdpV1 table is invalid"),
-        },
-        None => None,
+    // prefer the xsdt, its pointers can be above 4GiB
+    let v2 = MULTIBOOT_INFO
+        .rsdp_v2()
+        .map(|rsdp| Acpi::from_rsdp_v2(&rsdp.table()));
+    let v1 = || {
+        MULTIBOOT_INFO
+            .rsdp_v1()
+            .map(|rsdp| Acpi::from_rsdp_v1(&rsdp.table()))
+    };
+    let acpi = match v2 {
+        Some(Ok(acpi)) => Ok(acpi),
+        _ => v1().unwrap_or(Err(AcpiError::MissingRsdp)),
     };
 
-    let mut acpi = Acpi::new(rsdp_addr.unwrap());
-    acpi.init();
-    acpi
+    match acpi {
+        Ok(acpi) => {
+            for table in acpi.unknown_tables() {
+                crate::kprintln!("ACPI: unused table {}", table.signature());
+            }
+            if acpi.invalid_tables() > 0 {
+                crate::kprintln!("ACPI: skipped {} invalid tables", acpi.invalid_tables());
+            }
+            acpi
+        }
+        Err(err) => {
+            crate::kprintln!("ACPI: no tables, {:?}", err);
+            Acpi::empty()
+        }
+    }
 });
 
 pub static MADT_TABLE: Lazy<MultiAPIC> = Lazy::new(|| {
-    let madt_ptr = ACPI_TABLE.madt_ptr.unwrap();
+    let madt_ptr = ACPI_TABLE.madt_ptr.expect("There should be a MADT");
     let mut madt = MultiAPIC::new(madt_ptr);
     madt.init();
     madt
 });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn acpi_registry() {
+        assert!(ACPI_TABLE.root.is_some());
+        assert!(ACPI_TABLE.fadt.is_some());
+        let madt = ACPI_TABLE.find("APIC").unwrap();
+        assert_eq!(madt.signature(), "APIC");
+        assert!(ACPI_TABLE.tables().all(|table| table.is_valid()));
+        assert!(ACPI_TABLE.find("NONE").is_none());
+    }
+}
@@ -1,10 +1,11 @@
-use x86_64::PhysicalAddress;
+use x86_64::{PhysicalAddress, VirtualAddress};
 
-use crate::consts::SIZE_1MIB;
+use crate::consts::{SIZE_1KIB, SIZE_1MIB};
 use core::mem::size_of;
 use spin::{Lazy, Mutex};
 use x86_64::paging::allocator::Mapper;
-use x86_64::paging::page_table::{Level4, PageFlags, PageTable};
+use x86_64::paging::page_table::{HierarchicalLevel, Level4, PageFlags, PageTable, PageTableEntry};
+use x86_64::paging::tlb;
 
 pub static MAPPER: Lazy<Mutex<Mapper>> = Lazy::new(|| Mutex::new(unsafe { Mapper::new() }));
 
@@ -34,11 +35,190 @@ pub fn map_all_physical_memory(start_address: PhysicalAddress) {
     crate::kprintln!("All physical memory as been mapped");
 }
 
+/// Walk the active page tables to find where `address` is mapped.
+/// Does not take the `MAPPER` lock so it is safe to use from exception handlers.
+pub fn translate(address: VirtualAddress) -> Option<PhysicalAddress> {
+    const SIZE_2MIB: u64 = SIZE_1MIB * 2;
+    const SIZE_1GIB: u64 = SIZE_1MIB * 1024;
+
+    let offset = |size: u64| u64::from(address) & (size - 1);
+    let mapper = unsafe { Mapper::new() };
+
+    let p4 = mapper.p4();
+    let p3 = p4.next_table(address.p4_index().into())?;
+
+    let entry = &p3[address.p3_index()];
+    if !entry.is_present() {
+        return None;
+    } else if entry.is_huge() {
+        return Some(entry.address() + offset(SIZE_1GIB));
+    }
+    let p2 = p3.next_table(address.p3_index().into())?;
+
+    let entry = &p2[address.p2_index()];
+    if !entry.is_present() {
+        return None;
+    } else if entry.is_huge() {
+        return Some(entry.address() + offset(SIZE_2MIB));
+    }
+    let p1 = p2.next_table(address.p2_index().into())?;
+
+    let entry = &p1[address.p1_index()];
+    if !entry.is_present() {
+        return None;
+    }
+    Some(entry.address() + offset(SIZE_1KIB))
+}
+
+/// Make sure accessing `len` bytes at `address` will not page fault
+pub fn is_mapped(address: u64, len: usize) -> bool {
+    every_page(address, len, |page| translate(page).is_some())
+}
+
+/// Make sure user code may access `len` bytes at `address`, every page is mapped
+/// and marked user accessible at every level of the tables
+pub fn is_user_accessible(address: u64, len: usize) -> bool {
+    every_page(address, len, is_user_page)
+}
+
+/// Whether `check` holds for every page `len` bytes at `address` touch
+fn every_page(address: u64, len: usize, check: impl Fn(VirtualAddress) -> bool) -> bool {
+    let end = match address.checked_add(len as u64) {
+        Some(end) => end,
+        None => return false,
+    };
+    let mut page = address & !(SIZE_1KIB - 1);
+    while page < end {
+        match VirtualAddress::try_from(page) {
+            Ok(page) if check(page) => {}
+            _ => return false,
+        }
+        page += SIZE_1KIB;
+    }
+    true
+}
+
+/// Walk the active page tables like [`translate`], but only through user accessible entries
+fn is_user_page(address: VirtualAddress) -> bool {
+    let mapper = unsafe { Mapper::new() };
+    let user = |entry: &PageTableEntry| entry.is_present() && entry.is_user();
+
+    let p4 = mapper.p4();
+    if !user(&p4[address.p4_index()]) {
+        return false;
+    }
+    let Some(p3) = p4.next_table(address.p4_index().into()) else {
+        return false;
+    };
+
+    let entry = &p3[address.p3_index()];
+    if !user(entry) {
+        return false;
+    } else if entry.is_huge() {
+        return true;
+    }
+    let Some(p2) = p3.next_table(address.p3_index().into()) else {
+        return false;
+    };
+
+    let entry = &p2[address.p2_index()];
+    if !user(entry) {
+        return false;
+    } else if entry.is_huge() {
+        return true;
+    }
+    match p2.next_table(address.p2_index().into()) {
+        Some(p1) => user(&p1[address.p1_index()]),
+        None => false,
+    }
+}
+
+/// Map the 4KiB page at `page` to `frame`, creating missing tables on the heap.
+/// Tables on the way are made user accessible if the page is.
+pub fn map_page(page: VirtualAddress, frame: PhysicalAddress, flags: PageFlags) {
+    assert!(page.is_aligned(SIZE_1KIB), "{:?} is not page aligned", page);
+    let table_flags =
+        PageFlags::PRESENT | PageFlags::WRITEABLE | (flags & PageFlags::USER_ACCESSIBLE);
+
+    let mut mapper = MAPPER.lock();
+    let p3 = next_table_create(mapper.p4_mut(), page.p4_index().into(), table_flags);
+    let p2 = next_table_create(p3, page.p3_index().into(), table_flags);
+    let p1 = next_table_create(p2, page.p2_index().into(), table_flags);
+
+    p1[page.p1_index()].set_address(frame, flags | PageFlags::PRESENT);
+    tlb::flush(page);
+}
+
+/// Remove the mapping of a page made with [`map_page`], returns the frame it pointed to.
+/// The tables are kept around for the next mapping.
+pub fn unmap_page(page: VirtualAddress) -> Option<PhysicalAddress> {
+    let mut mapper = MAPPER.lock();
+    let p3 = mapper.p4_mut().next_table_mut(page.p4_index().into())?;
+    let p2 = p3.next_table_mut(page.p3_index().into())?;
+    let p1 = p2.next_table_mut(page.p2_index().into())?;
+
+    let entry = &mut p1[page.p1_index()];
+    let frame = entry.frame()?.address();
+    entry.set_unused();
+    tlb::flush(page);
+    Some(frame)
+}
+
+/// The table `index` points to, allocating an empty one if there is none
+fn next_table_create<L: HierarchicalLevel>(
+    table: &mut PageTable<L>,
+    index: usize,
+    flags: PageFlags,
+) -> &mut PageTable<L::NextLevel> {
+    use alloc::alloc::{alloc_zeroed, Layout};
+
+    let entry = &mut table[index];
+    if entry.is_unused() {
+        // the heap is identity mapped so the address is also the physical address
+        let layout = Layout::new::<PageTable<L::NextLevel>>();
+        let frame = unsafe { alloc_zeroed(layout) };
+        assert!(!frame.is_null(), "out of memory for page tables");
+        entry.set_address(PhysicalAddress::new(frame as u64), flags);
+    } else {
+        entry.set_flags(flags);
+    }
+    table
+        .next_table_mut(index)
+        .expect("page is inside a huge page")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::kprintln;
 
+    #[test_case]
+    fn translate_physical_map() {
+        let address = VirtualAddress::new(x86_64::KERNEL_OFFSET + 0x1234);
+        assert_eq!(translate(address), Some(PhysicalAddress::new(0x1234)));
+    }
+
+    #[test_case]
+    fn map_and_unmap() {
+        use alloc::boxed::Box;
+
+        #[repr(C, align(4096))]
+        struct Page([u64; 512]);
+
+        let mut frame = Box::new(Page([0; 512]));
+        frame.0[1] = 42;
+        let physical = PhysicalAddress::new(frame.0.as_ptr() as u64);
+        let page = VirtualAddress::new(crate::consts::USER_START + 0x20_0000);
+
+        map_page(page, physical, PageFlags::WRITEABLE);
+        assert_eq!(translate(page + 8u64), Some(physical + 8u64));
+        let value = unsafe { *(u64::from(page) as *const u64).add(1) };
+        assert_eq!(value, 42);
+
+        assert_eq!(unmap_page(page), Some(physical));
+        assert_eq!(translate(page), None);
+    }
+
     // #[test_case]
     pub fn debug_print_p4_table() {
         let m = MAPPER.lock();
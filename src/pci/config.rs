@@ -0,0 +1,187 @@
+//! Config space access, through the mcfg's memory mapped regions (ecam) when there are any
+//! and the legacy 0xCF8/0xCFC ports otherwise.
+//!
+//! The ports only reach the first 256 bytes of segment 0.
+
+use core::fmt;
+
+use port::Port;
+use spin::Mutex;
+use x86_64::tables::mcfg::McfgEntry;
+
+use crate::multiboot::ACPI_TABLE;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// Most mcfg regions we keep track of
+const MAX_REGIONS: usize = 16;
+
+/// A function on a pci bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+pub struct ConfigSpace {
+    regions: [Option<McfgEntry>; MAX_REGIONS],
+    /// The address and data ports are written in pairs
+    ports: Mutex<()>,
+}
+
+impl ConfigSpace {
+    /// Ports only
+    pub const fn legacy() -> Self {
+        Self {
+            regions: [None; MAX_REGIONS],
+            ports: Mutex::new(()),
+        }
+    }
+
+    /// The regions of the mcfg, if the acpi tables have one
+    pub fn from_acpi() -> Self {
+        let mut config = Self::legacy();
+        let Some(mcfg) = ACPI_TABLE.mcfg else {
+            return config;
+        };
+        for (slot, entry) in config.regions.iter_mut().zip(mcfg.entries()) {
+            *slot = Some(entry);
+        }
+        config
+    }
+
+    /// The mcfg regions, each one is a segment and a range of buses
+    pub fn regions(&self) -> impl Iterator<Item = McfgEntry> + '_ {
+        self.regions.iter().map_while(|region| *region)
+    }
+
+    /// Read `width` bits at `offset`, all ones if there is no way to reach it
+    pub fn read(&self, address: PciAddress, offset: u16, width: u8) -> u32 {
+        if let Some(region) = self.region(address) {
+            if offset >= 4096 {
+                return u32::MAX;
+            }
+            let register = region
+                .address(address.bus, address.device, address.function)
+                .unwrap()
+                + offset as u64;
+            return unsafe {
+                match width {
+                    8 => register.as_ptr::<u8>().read_volatile() as u32,
+                    16 => register.as_ptr::<u16>().read_volatile() as u32,
+                    _ => register.as_ptr::<u32>().read_volatile(),
+                }
+            };
+        }
+        if address.segment != 0 || offset >= 256 {
+            return u32::MAX;
+        }
+        let _ports = self.ports.lock();
+        let data = Self::select(address, offset);
+        unsafe {
+            match width {
+                8 => Port::<u8>::new(data).read() as u32,
+                16 => Port::<u16>::new(data).read() as u32,
+                _ => Port::<u32>::new(data).read(),
+            }
+        }
+    }
+
+    /// Write `width` bits at `offset`, ignored if there is no way to reach it
+    pub fn write(&self, address: PciAddress, offset: u16, width: u8, value: u32) {
+        if let Some(region) = self.region(address) {
+            if offset >= 4096 {
+                return;
+            }
+            let register = region
+                .address(address.bus, address.device, address.function)
+                .unwrap()
+                + offset as u64;
+            return unsafe {
+                match width {
+                    8 => register.as_mut_ptr::<u8>().write_volatile(value as u8),
+                    16 => register.as_mut_ptr::<u16>().write_volatile(value as u16),
+                    _ => register.as_mut_ptr::<u32>().write_volatile(value),
+                }
+            };
+        }
+        if address.segment != 0 || offset >= 256 {
+            return;
+        }
+        let _ports = self.ports.lock();
+        let data = Self::select(address, offset);
+        unsafe {
+            match width {
+                8 => Port::<u8>::new(data).write(value as u8),
+                16 => Port::<u16>::new(data).write(value as u16),
+                _ => Port::<u32>::new(data).write(value),
+            }
+        }
+    }
+
+    pub fn read_u8(&self, address: PciAddress, offset: u16) -> u8 {
+        self.read(address, offset, 8) as u8
+    }
+
+    pub fn read_u16(&self, address: PciAddress, offset: u16) -> u16 {
+        self.read(address, offset, 16) as u16
+    }
+
+    pub fn read_u32(&self, address: PciAddress, offset: u16) -> u32 {
+        self.read(address, offset, 32)
+    }
+
+    pub fn write_u8(&self, address: PciAddress, offset: u16, value: u8) {
+        self.write(address, offset, 8, value.into())
+    }
+
+    pub fn write_u16(&self, address: PciAddress, offset: u16, value: u16) {
+        self.write(address, offset, 16, value.into())
+    }
+
+    pub fn write_u32(&self, address: PciAddress, offset: u16, value: u32) {
+        self.write(address, offset, 32, value)
+    }
+
+    fn region(&self, address: PciAddress) -> Option<McfgEntry> {
+        self.regions().find(|region| {
+            region.segment() == address.segment
+                && (region.start_bus()..=region.end_bus()).contains(&address.bus)
+        })
+    }
+
+    /// Select the dword `offset` is in, returns the data port for `offset`
+    fn select(address: PciAddress, offset: u16) -> u16 {
+        let config = 1 << 31
+            | (address.bus as u32) << 16
+            | (address.device as u32 & 0x1F) << 11
+            | (address.function as u32 & 0x7) << 8
+            | (offset as u32 & 0xFC);
+        unsafe { Port::<u32>::new(CONFIG_ADDRESS).write(config) };
+        CONFIG_DATA + (offset & 0b11)
+    }
+}
@@ -0,0 +1,294 @@
+//! What the scan records for each function: its ids, class, bars and interrupt pin
+
+use bitflags::bitflags;
+use x86_64::PhysicalAddress;
+
+use super::config::PciAddress;
+use super::PCI_CONFIG;
+
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const REVISION: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0E;
+const BAR0: u16 = 0x10;
+const SECONDARY_BUS: u16 = 0x19;
+const CAPABILITIES: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3C;
+const INTERRUPT_PIN: u16 = 0x3D;
+
+/// Status bit saying there is a capability list
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+/// At most 48 capabilities fit after the header, more means the list loops
+const MAX_CAPABILITIES: usize = 48;
+
+bitflags! {
+    pub struct Command: u16 {
+        const IO_SPACE = 1 << 0;
+        const MEMORY_SPACE = 1 << 1;
+        const BUS_MASTER = 1 << 2;
+        const INTERRUPT_DISABLE = 1 << 10;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderType {
+    General,
+    PciBridge,
+    CardBusBridge,
+    Other(u8),
+}
+
+/// A base address register, `size` is in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: PhysicalAddress,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+/// An entry of the capability list, `offset` is where it is in config space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: HeaderType,
+    pub multifunction: bool,
+    /// Six for general devices and two for bridges, the upper half of a 64 bit bar is `None`
+    pub bars: [Option<Bar>; 6],
+    /// 1 is INTA, 0 is none
+    pub interrupt_pin: u8,
+    /// What the firmware put in the interrupt line, a pic irq
+    pub interrupt_line: u8,
+}
+
+impl PciDevice {
+    /// Read the function at `address`, none if nothing answers
+    pub fn new(address: PciAddress) -> Option<Self> {
+        let vendor_id = PCI_CONFIG.read_u16(address, VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let class = PCI_CONFIG.read_u32(address, REVISION);
+        let header = PCI_CONFIG.read_u8(address, HEADER_TYPE);
+        let header_type = match header & 0x7F {
+            0 => HeaderType::General,
+            1 => HeaderType::PciBridge,
+            2 => HeaderType::CardBusBridge,
+            other => HeaderType::Other(other),
+        };
+
+        let mut device = Self {
+            address,
+            vendor_id,
+            device_id: PCI_CONFIG.read_u16(address, DEVICE_ID),
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            multifunction: header & 0x80 != 0,
+            bars: [None; 6],
+            interrupt_pin: PCI_CONFIG.read_u8(address, INTERRUPT_PIN),
+            interrupt_line: PCI_CONFIG.read_u8(address, INTERRUPT_LINE),
+        };
+        device.probe_bars();
+        Some(device)
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        PCI_CONFIG.read_u8(self.address, offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        PCI_CONFIG.read_u16(self.address, offset)
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        PCI_CONFIG.read_u32(self.address, offset)
+    }
+
+    pub fn write_u8(&self, offset: u16, value: u8) {
+        PCI_CONFIG.write_u8(self.address, offset, value)
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        PCI_CONFIG.write_u16(self.address, offset, value)
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        PCI_CONFIG.write_u32(self.address, offset, value)
+    }
+
+    pub fn command(&self) -> Command {
+        Command::from_bits_truncate(self.read_u16(COMMAND))
+    }
+
+    pub fn set_command(&self, command: Command) {
+        // keep the bits we have no names for
+        let other = self.read_u16(COMMAND) & !Command::all().bits();
+        self.write_u16(COMMAND, other | command.bits());
+    }
+
+    /// Let the device decode its bars and do dma
+    pub fn enable(&self) {
+        self.set_command(
+            self.command() | Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER,
+        );
+    }
+
+    pub fn status(&self) -> u16 {
+        self.read_u16(STATUS)
+    }
+
+    /// The secondary bus of a pci to pci bridge
+    pub fn secondary_bus(&self) -> Option<u8> {
+        match self.header_type {
+            HeaderType::PciBridge => Some(self.read_u8(SECONDARY_BUS)),
+            _ => None,
+        }
+    }
+
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.bars.get(index).copied().flatten()
+    }
+
+    /// Walk the capability list
+    pub fn capabilities(&self) -> Capabilities<'_> {
+        let next = match self.status() & STATUS_CAPABILITIES != 0 {
+            true => self.read_u8(CAPABILITIES),
+            false => 0,
+        };
+        Capabilities {
+            device: self,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// The first capability with `id`
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().find(|capability| capability.id == id)
+    }
+
+    /// Size the bars by writing all ones and reading back which bits stuck
+    fn probe_bars(&mut self) {
+        let count = match self.header_type {
+            HeaderType::General => 6,
+            HeaderType::PciBridge => 2,
+            _ => 0,
+        };
+        // stop decoding while the bars hold garbage
+        let command = self.read_u16(COMMAND);
+        self.write_u16(
+            COMMAND,
+            command & !(Command::IO_SPACE | Command::MEMORY_SPACE).bits(),
+        );
+
+        let mut index = 0;
+        while index < count {
+            let offset = BAR0 + index as u16 * 4;
+            let (bar, used) = self.probe_bar(offset, index + 1 < count);
+            self.bars[index] = bar;
+            index += used;
+        }
+
+        self.write_u16(COMMAND, command);
+    }
+
+    /// The bar at `offset` and how many slots it takes
+    fn probe_bar(&self, offset: u16, has_upper: bool) -> (Option<Bar>, usize) {
+        let size_of = |offset: u16| {
+            let value = self.read_u32(offset);
+            self.write_u32(offset, u32::MAX);
+            let mask = self.read_u32(offset);
+            self.write_u32(offset, value);
+            (value, mask)
+        };
+
+        let (value, mask) = size_of(offset);
+        if value & 1 != 0 {
+            let mask = mask & !0b11 & 0xFFFF;
+            let bar = match mask {
+                0 => None,
+                mask => Some(Bar::Io {
+                    port: (value & !0b11) as u16,
+                    size: (!mask).wrapping_add(1) as u16,
+                }),
+            };
+            return (bar, 1);
+        }
+
+        let is_64bit = (value >> 1) & 0b11 == 0b10 && has_upper;
+        let prefetchable = value & (1 << 3) != 0;
+        let (address, mask, used) = match is_64bit {
+            true => {
+                let (upper, upper_mask) = size_of(offset + 4);
+                let address = (upper as u64) << 32 | (value & !0xF) as u64;
+                let mask = (upper_mask as u64) << 32 | (mask & !0xF) as u64;
+                (address, mask, 2)
+            }
+            false => {
+                // with the upper half all ones the size comes out right
+                let mask = match mask & !0xF {
+                    0 => 0,
+                    mask => 0xFFFF_FFFF_0000_0000 | mask as u64,
+                };
+                ((value & !0xF) as u64, mask, 1)
+            }
+        };
+        // a bar that keeps none of the ones is not implemented
+        let bar = match mask {
+            0 => None,
+            mask => Some(Bar::Memory {
+                address: PhysicalAddress::new(address),
+                size: (!mask).wrapping_add(1),
+                prefetchable,
+                is_64bit,
+            }),
+        };
+        (bar, used)
+    }
+}
+
+/// Iterator over the capability list of a device
+pub struct Capabilities<'a> {
+    device: &'a PciDevice,
+    next: u8,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Capabilities<'a> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the bottom two bits are reserved, anything in the header is the end of the list
+        let offset = (self.next & !0b11) as u16;
+        if offset < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let id = self.device.read_u8(offset);
+        self.next = self.device.read_u8(offset + 1);
+        Some(Capability { id, offset })
+    }
+}
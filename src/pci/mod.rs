@@ -0,0 +1,279 @@
+//! The pci buses, the functions found on them and the drivers that claim them.
+//!
+//! Every segment is scanned from its first bus, following pci to pci bridges to the buses
+//! behind them. Drivers register with the ids or class they handle and are probed with
+//! every function that matches and is not claimed yet, whether it was found before or after
+//! the driver registered.
+
+use alloc::vec::Vec;
+use spin::{Lazy, Mutex};
+
+pub use config::{ConfigSpace, PciAddress};
+pub use device::PciDevice;
+
+pub mod config;
+pub mod device;
+pub mod msi;
+
+/// Config space of every segment
+pub static PCI_CONFIG: Lazy<ConfigSpace> = Lazy::new(ConfigSpace::from_acpi);
+
+/// Every function the scan found, with the driver that claimed it
+static DEVICES: Mutex<Vec<(PciDevice, Option<&'static PciDriver>)>> = Mutex::new(Vec::new());
+static DRIVERS: Mutex<Vec<&'static PciDriver>> = Mutex::new(Vec::new());
+
+/// Which functions a driver handles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceMatch {
+    Id {
+        vendor: u16,
+        device: u16,
+    },
+    /// Any programming interface if `prog_if` is none
+    Class {
+        class: u8,
+        subclass: u8,
+        prog_if: Option<u8>,
+    },
+}
+
+impl DeviceMatch {
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            Self::Id { vendor, device: id } => device.vendor_id == vendor && device.device_id == id,
+            Self::Class {
+                class,
+                subclass,
+                prog_if,
+            } => {
+                device.class == class
+                    && device.subclass == subclass
+                    && prog_if.is_none_or(|prog_if| device.prog_if == prog_if)
+            }
+        }
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [DeviceMatch],
+    /// Set the function up, false leaves it for another driver
+    pub probe: fn(&PciDevice) -> bool,
+}
+
+/// Scan every bus and probe the drivers registered so far, needs the heap
+pub fn init() {
+    let mut found = Vec::new();
+    let regions: Vec<_> = PCI_CONFIG.regions().collect();
+    if regions.is_empty() {
+        scan_segment(0, 0, &mut found);
+    }
+    for region in regions {
+        scan_segment(region.segment(), region.start_bus(), &mut found);
+    }
+
+    for device in found.iter() {
+        crate::kprintln!(
+            "PCI: {} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if
+        );
+    }
+    crate::kprintln!("PCI: {} functions", found.len());
+
+    DEVICES
+        .lock()
+        .extend(found.into_iter().map(|device| (device, None)));
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers {
+        probe(driver);
+    }
+}
+
+/// Add a driver and probe it with the functions that match
+pub fn register_driver(driver: &'static PciDriver) {
+    DRIVERS.lock().push(driver);
+    probe(driver);
+}
+
+/// A copy of every function found
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|(device, _)| device.clone())
+        .collect()
+}
+
+/// The first function with a vendor and device id
+pub fn find(vendor: u16, device: u16) -> Option<PciDevice> {
+    let id = DeviceMatch::Id { vendor, device };
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(found, _)| id.matches(found))
+        .map(|(device, _)| device.clone())
+}
+
+/// The name of the driver that claimed the function at `address`
+pub fn driver_of(address: PciAddress) -> Option<&'static str> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(device, _)| device.address == address)
+        .and_then(|(_, driver)| driver.map(|driver| driver.name))
+}
+
+/// Offer `driver` the unclaimed functions it matches, without holding the lock in the probe
+fn probe(driver: &'static PciDriver) {
+    let candidates: Vec<PciDevice> = DEVICES
+        .lock()
+        .iter()
+        .filter(|(device, claimed)| {
+            claimed.is_none() && driver.matches.iter().any(|id| id.matches(device))
+        })
+        .map(|(device, _)| device.clone())
+        .collect();
+
+    for device in candidates {
+        if !(driver.probe)(&device) {
+            continue;
+        }
+        crate::kprintln!("PCI: {} claimed by {}", device.address, driver.name);
+        let mut devices = DEVICES.lock();
+        if let Some((_, claimed)) = devices
+            .iter_mut()
+            .find(|(d, _)| d.address == device.address)
+        {
+            *claimed = Some(driver);
+        }
+    }
+}
+
+fn scan_segment(segment: u16, start_bus: u8, found: &mut Vec<PciDevice>) {
+    let mut scanned = [false; 256];
+    let host = PciAddress::new(segment, start_bus, 0, 0);
+    match PciDevice::new(host) {
+        // every function of a multifunction host bridge is the host bridge of another bus
+        Some(bridge) if bridge.multifunction => {
+            for function in 0..8 {
+                let address = PciAddress::new(segment, start_bus, 0, function);
+                if PciDevice::new(address).is_some() {
+                    scan_bus(
+                        segment,
+                        start_bus.saturating_add(function),
+                        &mut scanned,
+                        found,
+                    );
+                }
+            }
+        }
+        _ => scan_bus(segment, start_bus, &mut scanned, found),
+    }
+}
+
+fn scan_bus(segment: u16, bus: u8, scanned: &mut [bool; 256], found: &mut Vec<PciDevice>) {
+    // a misprogrammed bridge could point back at a bus we already did
+    if scanned[bus as usize] {
+        return;
+    }
+    scanned[bus as usize] = true;
+
+    for slot in 0..32 {
+        let Some(device) = PciDevice::new(PciAddress::new(segment, bus, slot, 0)) else {
+            continue;
+        };
+        let functions = match device.multifunction {
+            true => 8,
+            false => 1,
+        };
+        scan_function(device, scanned, found);
+        for function in 1..functions {
+            if let Some(device) = PciDevice::new(PciAddress::new(segment, bus, slot, function)) {
+                scan_function(device, scanned, found);
+            }
+        }
+    }
+}
+
+fn scan_function(device: PciDevice, scanned: &mut [bool; 256], found: &mut Vec<PciDevice>) {
+    let secondary = device.secondary_bus();
+    let segment = device.address.segment;
+    found.push(device);
+    if let Some(bus) = secondary.filter(|bus| *bus != 0) {
+        scan_bus(segment, bus, scanned, found);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::device::Bar;
+    use super::*;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    /// Class 6 is bridges, subclass 0 is a host bridge
+    const HOST_BRIDGE: DeviceMatch = DeviceMatch::Class {
+        class: 0x06,
+        subclass: 0x00,
+        prog_if: None,
+    };
+
+    #[test_case]
+    fn scan() {
+        let devices = devices();
+        let host = devices
+            .iter()
+            .find(|device| device.address == PciAddress::new(0, 0, 0, 0))
+            .expect("there is always a host bridge");
+        assert!(HOST_BRIDGE.matches(host));
+        assert_eq!(host.vendor_id, 0x8086);
+
+        let addresses: Vec<_> = devices.iter().map(|device| device.address).collect();
+        for (i, address) in addresses.iter().enumerate() {
+            assert!(!addresses[i + 1..].contains(address), "{} twice", address);
+        }
+    }
+
+    #[test_case]
+    fn bars_and_capabilities() {
+        // the bochs display qemu uses by default, its framebuffer is 16MiB
+        let vga = find(0x1234, 0x1111).expect("qemu has a std vga");
+        match vga.bar(0) {
+            Some(Bar::Memory {
+                size, prefetchable, ..
+            }) => {
+                assert_eq!(size, 16 * 1024 * 1024);
+                assert!(prefetchable);
+            }
+            bar => panic!("unexpected bar {:?}", bar),
+        }
+        // sizing does not leave the bar changed
+        assert_eq!(PciDevice::new(vga.address).unwrap().bars, vga.bars);
+
+        for device in devices() {
+            for capability in device.capabilities() {
+                assert!(capability.offset >= 0x40);
+            }
+        }
+    }
+
+    #[test_case]
+    fn driver_probe() {
+        static PROBED: AtomicBool = AtomicBool::new(false);
+        static DRIVER: PciDriver = PciDriver {
+            name: "test host bridge",
+            matches: &[HOST_BRIDGE],
+            probe: |device| {
+                PROBED.store(true, Ordering::SeqCst);
+                device.address == PciAddress::new(0, 0, 0, 0)
+            },
+        };
+        register_driver(&DRIVER);
+        assert!(PROBED.load(Ordering::SeqCst));
+        assert_eq!(driver_of(PciAddress::new(0, 0, 0, 0)), Some(DRIVER.name));
+    }
+}
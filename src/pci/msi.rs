@@ -0,0 +1,347 @@
+//! Message signalled interrupts, the device writes a vector straight to a local apic.
+//!
+//! Without interrupt remapping the destination is the 8 bit apic id in the message address,
+//! so cpus with a larger x2apic id can not be targeted.
+
+use bit_field::BitField;
+use x86_64::PhysicalAddress;
+
+use super::device::{Bar, Command};
+use super::PciDevice;
+
+/// Capability ids
+pub const MSI_CAPABILITY: u8 = 0x05;
+pub const MSI_X_CAPABILITY: u8 = 0x11;
+
+/// Where the local apics take messages, the destination goes in bits 12 to 19
+const MESSAGE_ADDRESS: u64 = 0xFEE0_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The function does not have the capability
+    NotSupported,
+    /// The apic id does not fit in a message address
+    UnreachableApic,
+    /// An entry past the end of the table
+    InvalidIndex,
+    /// The msi-x table is not in a memory bar
+    InvalidBar,
+}
+
+/// The address and data a device writes to raise `vector` on the cpu with `apic_id`,
+/// fixed delivery and edge triggered
+pub fn message(vector: u8, apic_id: u32) -> Result<(u64, u32), MsiError> {
+    if apic_id > 0xFF {
+        return Err(MsiError::UnreachableApic);
+    }
+    Ok((MESSAGE_ADDRESS | (apic_id as u64) << 12, vector as u32))
+}
+
+/// The apic id of the running cpu, the default target
+fn this_cpu() -> u32 {
+    crate::io::LAPIC.id()
+}
+
+/// The msi capability, one or a power of two consecutive vectors
+pub struct Msi {
+    device: PciDevice,
+    offset: u16,
+}
+
+impl Msi {
+    pub fn new(device: &PciDevice) -> Result<Self, MsiError> {
+        let capability = device
+            .find_capability(MSI_CAPABILITY)
+            .ok_or(MsiError::NotSupported)?;
+        Ok(Self {
+            device: device.clone(),
+            offset: capability.offset,
+        })
+    }
+
+    fn control(&self) -> u16 {
+        self.device.read_u16(self.offset + 2)
+    }
+
+    fn set_control(&self, control: u16) {
+        self.device.write_u16(self.offset + 2, control)
+    }
+
+    /// How many vectors the function asks for
+    pub fn vectors(&self) -> u8 {
+        1 << self.control().get_bits(1..4)
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.control().get_bit(7)
+    }
+
+    pub fn per_vector_masking(&self) -> bool {
+        self.control().get_bit(8)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.control().get_bit(0)
+    }
+
+    /// Offset of the data register, it moves down for 64 bit addresses
+    fn data_offset(&self) -> u16 {
+        match self.is_64bit() {
+            true => self.offset + 12,
+            false => self.offset + 8,
+        }
+    }
+
+    fn mask_offset(&self) -> u16 {
+        self.data_offset() + 4
+    }
+
+    /// Send `vector` to the running cpu with a single message, and turn it on
+    pub fn enable(&self, vector: u8) -> Result<(), MsiError> {
+        self.configure(vector, this_cpu())?;
+        // one vector, and no more legacy interrupts
+        let mut control = self.control();
+        control.set_bits(4..7, 0);
+        control.set_bit(0, true);
+        self.set_control(control);
+        self.device
+            .set_command(self.device.command() | Command::INTERRUPT_DISABLE);
+        Ok(())
+    }
+
+    pub fn disable(&self) {
+        let mut control = self.control();
+        control.set_bit(0, false);
+        self.set_control(control);
+    }
+
+    /// Program the message for `vector` on the cpu with `apic_id`
+    pub fn configure(&self, vector: u8, apic_id: u32) -> Result<(), MsiError> {
+        let (address, data) = message(vector, apic_id)?;
+        self.device.write_u32(self.offset + 4, address as u32);
+        if self.is_64bit() {
+            self.device
+                .write_u32(self.offset + 8, (address >> 32) as u32);
+        }
+        self.device.write_u16(self.data_offset(), data as u16);
+        Ok(())
+    }
+
+    /// Move the interrupt to another cpu, keeping its vector
+    pub fn set_affinity(&self, apic_id: u32) -> Result<(), MsiError> {
+        let vector = self.device.read_u16(self.data_offset()) as u8;
+        self.configure(vector, apic_id)
+    }
+
+    /// Mask vector `index`, only if the function supports per vector masking
+    pub fn mask(&self, index: u8) -> Result<(), MsiError> {
+        self.set_mask(index, true)
+    }
+
+    pub fn unmask(&self, index: u8) -> Result<(), MsiError> {
+        self.set_mask(index, false)
+    }
+
+    fn set_mask(&self, index: u8, masked: bool) -> Result<(), MsiError> {
+        if !self.per_vector_masking() {
+            return Err(MsiError::NotSupported);
+        }
+        if index >= self.vectors() {
+            return Err(MsiError::InvalidIndex);
+        }
+        let mut mask = self.device.read_u32(self.mask_offset());
+        mask.set_bit(index as usize, masked);
+        self.device.write_u32(self.mask_offset(), mask);
+        Ok(())
+    }
+}
+
+/// An entry of the msi-x table
+#[derive(Debug)]
+#[repr(C)]
+struct TableEntry {
+    address_low: u32,
+    address_high: u32,
+    data: u32,
+    vector_control: u32,
+}
+
+/// The msi-x capability, a table of separately targeted and masked vectors in a bar
+pub struct MsiX {
+    device: PciDevice,
+    offset: u16,
+    table: *mut TableEntry,
+    pending: *const u64,
+    size: u16,
+}
+
+// the table is only reached through the mapping of physical memory
+unsafe impl Send for MsiX {}
+
+impl MsiX {
+    pub fn new(device: &PciDevice) -> Result<Self, MsiError> {
+        let capability = device
+            .find_capability(MSI_X_CAPABILITY)
+            .ok_or(MsiError::NotSupported)?;
+        let offset = capability.offset;
+        let control = device.read_u16(offset + 2);
+        // the low three bits pick the bar, the rest is the offset in it
+        let locate = |register: u16| -> Result<PhysicalAddress, MsiError> {
+            let value = device.read_u32(offset + register);
+            match device.bar(value.get_bits(0..3) as usize) {
+                Some(Bar::Memory { address, .. }) => Ok(address + (value & !0b111) as u64),
+                _ => Err(MsiError::InvalidBar),
+            }
+        };
+
+        Ok(Self {
+            device: device.clone(),
+            offset,
+            table: locate(4)?.as_mut_ptr(),
+            pending: locate(8)?.as_ptr(),
+            size: control.get_bits(0..11) + 1,
+        })
+    }
+
+    fn control(&self) -> u16 {
+        self.device.read_u16(self.offset + 2)
+    }
+
+    fn set_control(&self, control: u16) {
+        self.device.write_u16(self.offset + 2, control)
+    }
+
+    /// Number of entries in the table
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.control().get_bit(15)
+    }
+
+    /// Turn msi-x on with every entry masked, unmask them once they are configured
+    pub fn enable(&self) {
+        let mut control = self.control();
+        control.set_bit(14, true);
+        control.set_bit(15, true);
+        self.set_control(control);
+        for index in 0..self.size {
+            let _ = self.mask(index);
+        }
+        control.set_bit(14, false);
+        self.set_control(control);
+        self.device
+            .set_command(self.device.command() | Command::INTERRUPT_DISABLE);
+    }
+
+    pub fn disable(&self) {
+        let mut control = self.control();
+        control.set_bit(15, false);
+        self.set_control(control);
+    }
+
+    /// Mask every entry at once
+    pub fn set_function_mask(&self, masked: bool) {
+        let mut control = self.control();
+        control.set_bit(14, masked);
+        self.set_control(control);
+    }
+
+    fn entry(&self, index: u16) -> Result<*mut TableEntry, MsiError> {
+        match index < self.size {
+            true => Ok(unsafe { self.table.add(index as usize) }),
+            false => Err(MsiError::InvalidIndex),
+        }
+    }
+
+    /// Program entry `index` with `vector` on the cpu with `apic_id`, it stays masked
+    pub fn configure(&self, index: u16, vector: u8, apic_id: u32) -> Result<(), MsiError> {
+        let entry = self.entry(index)?;
+        let (address, data) = message(vector, apic_id)?;
+        unsafe {
+            let masked = self.is_masked(index)?;
+            self.mask(index)?;
+            core::ptr::write_volatile(&mut (*entry).address_low, address as u32);
+            core::ptr::write_volatile(&mut (*entry).address_high, (address >> 32) as u32);
+            core::ptr::write_volatile(&mut (*entry).data, data);
+            if !masked {
+                self.unmask(index)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Program entry `index` with `vector` on the running cpu and unmask it
+    pub fn enable_vector(&self, index: u16, vector: u8) -> Result<(), MsiError> {
+        self.configure(index, vector, this_cpu())?;
+        self.unmask(index)
+    }
+
+    /// Move entry `index` to another cpu, keeping its vector
+    pub fn set_affinity(&self, index: u16, apic_id: u32) -> Result<(), MsiError> {
+        let entry = self.entry(index)?;
+        let vector = unsafe { core::ptr::read_volatile(&(*entry).data) } as u8;
+        self.configure(index, vector, apic_id)
+    }
+
+    pub fn mask(&self, index: u16) -> Result<(), MsiError> {
+        self.set_mask(index, true)
+    }
+
+    pub fn unmask(&self, index: u16) -> Result<(), MsiError> {
+        self.set_mask(index, false)
+    }
+
+    pub fn is_masked(&self, index: u16) -> Result<bool, MsiError> {
+        let entry = self.entry(index)?;
+        Ok(unsafe { core::ptr::read_volatile(&(*entry).vector_control) }.get_bit(0))
+    }
+
+    fn set_mask(&self, index: u16, masked: bool) -> Result<(), MsiError> {
+        let entry = self.entry(index)?;
+        unsafe {
+            let control = &mut (*entry).vector_control;
+            let mut value = core::ptr::read_volatile(control);
+            value.set_bit(0, masked);
+            core::ptr::write_volatile(control, value);
+        }
+        Ok(())
+    }
+
+    /// Has entry `index` fired while it was masked
+    pub fn is_pending(&self, index: u16) -> Result<bool, MsiError> {
+        self.entry(index)?;
+        let bits = unsafe { self.pending.add(index as usize / 64).read_volatile() };
+        Ok(bits.get_bit(index as usize % 64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn messages() {
+        assert_eq!(message(0x41, 0), Ok((0xFEE0_0000, 0x41)));
+        assert_eq!(message(0x7F, 3), Ok((0xFEE0_3000, 0x7F)));
+        assert_eq!(message(0x40, 0x100), Err(MsiError::UnreachableApic));
+    }
+
+    /// Whatever qemu has with msi-x gets a readable table, masked until configured
+    #[test_case]
+    fn msi_x_tables() {
+        for device in super::super::devices() {
+            let Ok(msi_x) = MsiX::new(&device) else {
+                continue;
+            };
+            assert!(msi_x.size() > 0);
+            assert!(msi_x.entry(msi_x.size()).is_err());
+            if !msi_x.is_enabled() {
+                msi_x.enable();
+                assert!(msi_x.is_masked(0).unwrap());
+                msi_x.disable();
+            }
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Rebooting and powering off the machine.
+//!
+//! Reboot writes the fadt reset register, then pulses the reset line through the keyboard
+//! controller and triple faults if the machine is still running. Power off puts the
+//! machine into the S5 sleep state through the pm1 control registers, the sleep type
+//! comes from evaluating `\_S5_` in the acpi namespace.
+
+use core::convert::Infallible;
+use port::Port;
+use x86_64::tables::acpi::{AddressSpace, Fadt, GenericAddressStructure};
+use x86_64::PhysicalAddress;
+
+use crate::interrupts::disable_interrupts;
+use crate::io::aml::AML;
+use crate::multiboot::ACPI_TABLE;
+use crate::time::tsc;
+
+/// Set in pm1 control once the firmware handed power management to us
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+/// Keyboard controller status and command port
+const KBC_COMMAND: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_RESET: u8 = 0xFE;
+
+/// How long a reset or power off gets to take effect
+const SETTLE_MS: u64 = 500;
+
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// The acpi tables have no fadt
+    NoFadt,
+    /// There is no pm1a control register
+    NoPm1Control,
+    /// The dsdt has no `_S5_` package
+    NoS5,
+    /// The machine is still running after writing the sleep state
+    NoEffect,
+}
+
+/// Reset the machine
+pub fn reboot() -> ! {
+    disable_interrupts();
+
+    if let Some((register, value)) = ACPI_TABLE.fadt.and_then(|fadt| fadt.reset_register()) {
+        write_register(register, value);
+        settle();
+    }
+
+    // without a keyboard controller the status reads 0xFF, so the input never looks empty
+    let mut command: Port<u8> = Port::new(KBC_COMMAND);
+    let start = tsc::cycles();
+    let timeout = tsc::nanos_to_cycles(SETTLE_MS * 1_000_000);
+    let mut ready = false;
+    while !ready && tsc::cycles().wrapping_sub(start) < timeout {
+        ready = unsafe { command.read() } & KBC_INPUT_FULL == 0;
+    }
+    if ready {
+        unsafe { command.write(KBC_RESET) };
+        settle();
+    }
+
+    crate::interrupts::triple_fault()
+}
+
+/// Power the machine off, only returns if that failed
+pub fn shutdown() -> Result<Infallible, PowerError> {
+    let fadt = ACPI_TABLE.fadt.ok_or(PowerError::NoFadt)?;
+    let pm1a = fadt.pm1a_control_block().ok_or(PowerError::NoPm1Control)?;
+    let (sleep_type_a, sleep_type_b) = s5_sleep_type().ok_or(PowerError::NoS5)?;
+
+    disable_interrupts();
+    enable_acpi(fadt, pm1a);
+    enter_sleep_state(pm1a, sleep_type_a);
+    if let Some(pm1b) = fadt.pm1b_control_block() {
+        enter_sleep_state(pm1b, sleep_type_b);
+    }
+    settle();
+    Err(PowerError::NoEffect)
+}
+
+/// The pm1a and pm1b sleep types of S5, from the acpi namespace
+pub fn s5_sleep_type() -> Option<(u8, u8)> {
+    let evaluated = AML.as_ref().and_then(|aml| aml.lock().sleep_type(5).ok());
+    evaluated.or_else(|| parse_s5(ACPI_TABLE.dsdt()?.data()))
+}
+
+/// Find the `_S5_` package in aml code and read its first two values, for when the
+/// interpreter could not load the tables
+fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let name = aml.windows(4).position(|window| window == b"_S5_")?;
+    // the name is defined by a name op, maybe with a root prefix in between
+    let defined = match name {
+        0 => false,
+        1 => aml[0] == AML_NAME_OP,
+        _ => aml[name - 1] == AML_NAME_OP || aml[name - 2..name] == [AML_NAME_OP, b'\\'],
+    };
+    if !defined {
+        return None;
+    }
+
+    let mut bytes = aml[name + 4..].iter().copied();
+    if bytes.next()? != AML_PACKAGE_OP {
+        return None;
+    }
+    // the top two bits of the package length say how many more length bytes follow
+    let length_bytes = bytes.next()? >> 6;
+    let mut bytes = bytes.skip(length_bytes as usize + 1);
+
+    // zero and one have their own opcodes, which are their values
+    let mut value = || match bytes.next()? {
+        AML_BYTE_PREFIX => bytes.next(),
+        op @ (0 | 1) => Some(op),
+        _ => None,
+    };
+    Some((value()?, value()?))
+}
+
+/// Ask the firmware to switch to acpi mode if it is not in it yet
+fn enable_acpi(fadt: &Fadt, pm1a: u16) {
+    let control: Port<u16> = Port::new(pm1a);
+    if unsafe { control.read() } & SCI_EN != 0 {
+        return;
+    }
+    let Some(smi_command) = fadt.smi_command_port() else {
+        return;
+    };
+
+    unsafe { Port::<u8>::new(smi_command).write(fadt.acpi_enable()) };
+    let start = tsc::cycles();
+    let timeout = tsc::nanos_to_cycles(SETTLE_MS * 1_000_000);
+    while unsafe { control.read() } & SCI_EN == 0 && tsc::cycles().wrapping_sub(start) < timeout {
+        core::hint::spin_loop();
+    }
+}
+
+fn enter_sleep_state(block: u16, sleep_type: u8) {
+    let mut control: Port<u16> = Port::new(block);
+    unsafe {
+        let value = control.read() & !SLP_TYP_MASK;
+        control.write(value | (sleep_type as u16) << SLP_TYP_SHIFT | SLP_EN);
+    }
+}
+
+/// Write the fadt reset register, pci config space is always on bus 0
+fn write_register(register: GenericAddressStructure, value: u8) {
+    let address = register.address();
+    match register.address_space() {
+        AddressSpace::SystemIo => unsafe { Port::<u8>::new(address as u16).write(value) },
+        AddressSpace::SystemMemory => unsafe {
+            PhysicalAddress::new(address)
+                .as_mut_ptr::<u8>()
+                .write_volatile(value)
+        },
+        AddressSpace::PciConfig => {
+            let device = (address >> 32) & 0x1F;
+            let function = (address >> 16) & 0x7;
+            let offset = address & 0xFF;
+            let config = 1 << 31 | device << 11 | function << 8 | offset & 0xFC;
+            unsafe {
+                Port::<u32>::new(0xCF8).write(config as u32);
+                Port::<u8>::new(0xCFC + (offset & 0b11) as u16).write(value);
+            }
+        }
+        AddressSpace::Other(_) => {}
+    }
+}
+
+/// Give a reset or power off time to happen
+fn settle() {
+    let start = tsc::cycles();
+    let wait = tsc::nanos_to_cycles(SETTLE_MS * 1_000_000);
+    while tsc::cycles().wrapping_sub(start) < wait {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parse_s5_package() {
+        // Name (_S5_, Package (0x04) { 0x05, 0x05, Zero, Zero })
+        let aml = b"\x10\x08_S5_\x12\x0A\x04\x0A\x05\x0A\x05\x00\x00";
+        assert_eq!(parse_s5(aml), Some((5, 5)));
+        // with a root prefix and zero opcodes
+        let zeros = b"\x08\\_S5_\x12\x06\x04\x00\x00";
+        assert_eq!(parse_s5(zeros), Some((0, 0)));
+
+        // a reference to the name, not its definition
+        assert_eq!(parse_s5(b"\x70_S5_\x12\x06\x04\x00\x00"), None);
+    }
+
+    #[test_case]
+    fn dsdt_has_s5() {
+        assert!(s5_sleep_type().is_some());
+    }
+}
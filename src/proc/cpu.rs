@@ -1,7 +1,9 @@
 use core::arch::asm;
+use x86_64::registers::control::Cr0;
+use x86_64::registers::model_specific::{Efer, EferFlags};
 
 pub struct Cpu {
-    apic_id: u8,
+    apic_id: u32,
 }
 
 impl Cpu {
@@ -23,6 +25,25 @@ impl Cpu {
     }
 }
 
+/// Turn on the cpu features the kernel relies on, must be called on every cpu
+pub fn init() {
+    use x86_64::cpuid::{cpuid, max_extended_leaf};
+
+    // extended feature bits, edx 20 is no execute support
+    let no_execute =
+        max_extended_leaf() >= 0x8000_0001 && cpuid(0x8000_0001, 0).edx & (1 << 20) != 0;
+
+    unsafe {
+        if no_execute {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        // respect read only pages in ring 0 as well
+        Cr0::update(|flags| flags.insert(Cr0::WRITE_PROTECT));
+    }
+
+    crate::kprintln!("CPU features enabled (no execute: {})", no_execute);
+}
+
 bitflags::bitflags! {
     #[repr(C)]
     pub struct Rflags: u64 {
@@ -53,3 +74,20 @@ impl Rflags {
         Self::from_bits_truncate(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// We can not be running without long mode active
+    #[test_case]
+    fn efer_long_mode() {
+        let efer = Efer::read();
+        assert!(efer.contains(EferFlags::LONG_MODE_ENABLE | EferFlags::LONG_MODE_ACTIVE));
+    }
+
+    #[test_case]
+    fn cr0_paging() {
+        assert!(Cr0::read().contains(Cr0::PAGING | Cr0::PROTECTION_MODE_ENABLED));
+    }
+}
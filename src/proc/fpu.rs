@@ -0,0 +1,387 @@
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::arch::asm;
+use core::marker::PhantomData;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use spin::{Lazy, Mutex};
+use x86_64::cpuid::{cpuid, features, FeaturesEcx, FeaturesEdx};
+use x86_64::registers::control::{Cr0, Cr4};
+use x86_64::registers::xcontrol::XCr0;
+
+/// Legacy fxsave area size, also the start of the xsave header
+const FXSAVE_SIZE: usize = 512;
+/// xsave and fxsave both require 64 (fxsave only 16) byte alignment
+const SAVE_ALIGN: usize = 64;
+
+/// Default x87 control word (all exceptions masked, 64 bit precision)
+const DEFAULT_FCW: u16 = 0x037F;
+/// Default mxcsr (all exceptions masked, round to nearest)
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+/// How the fpu state is sized and saved on this cpu
+#[derive(Debug, Clone, Copy)]
+pub struct FpuInfo {
+    xsave: bool,
+    components: XCr0,
+    size: usize,
+}
+
+impl FpuInfo {
+    fn detect() -> Self {
+        let (ecx, edx) = features();
+        assert!(
+            edx.contains(FeaturesEdx::FXSR | FeaturesEdx::SSE),
+            "CPU does not support fxsave and sse"
+        );
+
+        if !ecx.contains(FeaturesEcx::XSAVE) {
+            return Self {
+                xsave: false,
+                components: XCr0::X87 | XCr0::SSE,
+                size: FXSAVE_SIZE,
+            };
+        }
+
+        // leaf 0xD, eax is the supported low components of xcr0
+        let supported = XCr0::from_bits_truncate(cpuid(0xD, 0).eax.into());
+        let mut components = XCr0::X87 | XCr0::SSE;
+        if ecx.contains(FeaturesEcx::AVX) && supported.contains(XCr0::AVX) {
+            components |= XCr0::AVX;
+        }
+
+        // the legacy area and the header, then each extended component at its offset
+        let mut size = FXSAVE_SIZE + 64;
+        for component in (2..64).filter(|bit| components.bits() & (1 << bit) != 0) {
+            // leaf 0xD, eax is the size and ebx the offset of the component
+            let leaf = cpuid(0xD, component);
+            size = size.max((leaf.ebx + leaf.eax) as usize);
+        }
+
+        Self {
+            xsave: true,
+            components,
+            size,
+        }
+    }
+
+    pub fn uses_xsave(&self) -> bool {
+        self.xsave
+    }
+
+    pub fn components(&self) -> XCr0 {
+        self.components
+    }
+
+    /// Size in bytes of a save area
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Every cpu enables the same components, so probing once is enough
+static INFO: Lazy<FpuInfo> = Lazy::new(FpuInfo::detect);
+
+/// Switch fpu state only when a task first uses it (#NM) instead of on every poll
+static LAZY: AtomicBool = AtomicBool::new(false);
+// Tasks move around inside the executor, so track states by their (heap) save area
+/// The save area of the task that is currently being polled
+static CURRENT: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+/// The save area whose contents are currently loaded in the registers (lazy mode only)
+static OWNER: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+/// Enable the fpu, sse and (if supported) xsave/avx on this cpu
+pub fn init() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.insert(Cr0::MONITOR_COPROCESSOR | Cr0::NUMERIC_ERROR);
+            flags.remove(Cr0::EMULATION | Cr0::TASK_SWITCHED);
+        });
+        Cr4::update(|flags| flags.insert(Cr4::OSFXSR | Cr4::OSXMMEXCPT));
+    }
+
+    let info = *INFO;
+    if info.xsave {
+        unsafe {
+            Cr4::update(|flags| flags.insert(Cr4::OSXSAVE));
+            XCr0::write(info.components);
+        }
+    }
+    unsafe { asm!("fninit", options(nomem, nostack)) };
+
+    crate::kprintln!(
+        "FPU initialized (xsave: {}, components: {:?}, save area: {} bytes)",
+        info.xsave,
+        info.components,
+        info.size
+    );
+}
+
+pub fn info() -> FpuInfo {
+    *INFO
+}
+
+/// Select lazy (#NM driven) or eager switching of the task fpu state
+pub fn set_lazy(lazy: bool) {
+    crate::interrupts::without_interrupts(|| {
+        if !lazy {
+            // write back whatever is loaded so eager restores see it
+            clear_task_switched();
+            let owner = OWNER.swap(ptr::null_mut(), Ordering::SeqCst);
+            if !owner.is_null() {
+                unsafe { save_area(owner) };
+            }
+        }
+        LAZY.store(lazy, Ordering::SeqCst);
+    });
+}
+
+pub fn is_lazy() -> bool {
+    LAZY.load(Ordering::Relaxed)
+}
+
+/// Called by the executor before a task is polled
+pub fn switch_to(state: &mut FpuState) {
+    CURRENT.store(state.area.as_ptr(), Ordering::SeqCst);
+
+    if is_lazy() {
+        if OWNER.load(Ordering::SeqCst) == state.area.as_ptr() {
+            clear_task_switched();
+        } else {
+            // the first fpu instruction will trap into `device_not_available`
+            set_task_switched();
+        }
+    } else {
+        state.restore();
+    }
+}
+
+/// Called by the executor after a task has been polled
+pub fn switch_from(state: &mut FpuState) {
+    CURRENT.store(ptr::null_mut(), Ordering::SeqCst);
+    if !is_lazy() {
+        state.save();
+    }
+}
+
+/// Handle a #NM, returns false if the trap was not caused by lazy switching
+pub fn device_not_available() -> bool {
+    if !is_lazy() || !Cr0::read().contains(Cr0::TASK_SWITCHED) {
+        return false;
+    }
+
+    clear_task_switched();
+    let current = CURRENT.load(Ordering::SeqCst);
+    let owner = OWNER.swap(current, Ordering::SeqCst);
+    if owner == current {
+        return true;
+    }
+
+    unsafe {
+        if !owner.is_null() {
+            save_area(owner);
+        }
+        if current.is_null() {
+            // kernel code outside of a task, give it a clean state
+            asm!("fninit", options(nomem, nostack));
+        } else {
+            restore_area(current);
+        }
+    }
+    true
+}
+
+fn set_task_switched() {
+    unsafe { Cr0::update(|flags| flags.insert(Cr0::TASK_SWITCHED)) };
+}
+
+fn clear_task_switched() {
+    unsafe { asm!("clts", options(nomem, nostack)) };
+}
+
+/// An xsave (or fxsave) area holding one task's fpu/sse/avx registers
+pub struct FpuState {
+    area: NonNull<u8>,
+}
+
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// A save area holding the default register state
+    pub fn new() -> Self {
+        let layout = Self::layout();
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) }).expect("fpu state alloc failed");
+
+        // xsave header (xstate_bv) is zero so everything else restores to its init state
+        unsafe {
+            area.as_ptr().cast::<u16>().write(DEFAULT_FCW);
+            area.as_ptr().add(24).cast::<u32>().write(DEFAULT_MXCSR);
+        }
+
+        Self { area }
+    }
+
+    fn layout() -> Layout {
+        let size = INFO.size.max(FXSAVE_SIZE);
+        Layout::from_size_align(size, SAVE_ALIGN).unwrap()
+    }
+
+    /// Save the live registers into this area
+    pub fn save(&mut self) {
+        unsafe { save_area(self.area.as_ptr()) };
+    }
+
+    /// Load this area into the registers
+    pub fn restore(&self) {
+        unsafe { restore_area(self.area.as_ptr()) };
+    }
+
+    /// The raw save area
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.area.as_ptr(), Self::layout().size()) }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // make sure nobody saves into a freed area
+        let this = self.area.as_ptr();
+        let _ = OWNER.compare_exchange(this, ptr::null_mut(), Ordering::SeqCst, Ordering::SeqCst);
+        let _ = CURRENT.compare_exchange(this, ptr::null_mut(), Ordering::SeqCst, Ordering::SeqCst);
+        unsafe { dealloc(self.area.as_ptr(), Self::layout()) };
+    }
+}
+
+/// # Safety
+/// `area` must be a save area from [`FpuState::layout`]
+unsafe fn save_area(area: *mut u8) {
+    let info = *INFO;
+    let mask = info.components.bits();
+    if info.xsave {
+        asm!(
+            "xsave64 [{}]",
+            in(reg) area,
+            in("eax") mask as u32,
+            in("edx") (mask >> 32) as u32,
+            options(nostack, preserves_flags),
+        );
+    } else {
+        asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+    }
+}
+
+/// # Safety
+/// `area` must be a save area from [`FpuState::layout`] holding a valid state
+unsafe fn restore_area(area: *const u8) {
+    let info = *INFO;
+    let mask = info.components.bits();
+    if info.xsave {
+        asm!(
+            "xrstor64 [{}]",
+            in(reg) area,
+            in("eax") mask as u32,
+            in("edx") (mask >> 32) as u32,
+            options(nostack, preserves_flags),
+        );
+    } else {
+        asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags));
+    }
+}
+
+/// Holds the interrupted task state while kernel code uses the fpu
+static KERNEL_FPU: Lazy<Mutex<FpuState>> = Lazy::new(|| Mutex::new(FpuState::new()));
+static IN_KERNEL_FPU: AtomicBool = AtomicBool::new(false);
+
+/// Scoped permission for kernel code to use fpu/sse/avx registers,
+/// the previous register contents are put back when it is dropped.
+/// Only `#[target_feature]` functions or inline asm should use the registers,
+/// the rest of the kernel is built with soft float.
+pub struct KernelFpu {
+    task_switched: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Start using the fpu from kernel code, may not be nested
+pub fn kernel_fpu_begin() -> KernelFpu {
+    if IN_KERNEL_FPU.swap(true, Ordering::SeqCst) {
+        panic!("kernel_fpu_begin can not be nested");
+    }
+
+    let task_switched = Cr0::read().contains(Cr0::TASK_SWITCHED);
+    clear_task_switched();
+    KERNEL_FPU.lock().save();
+
+    KernelFpu {
+        task_switched,
+        _not_send: PhantomData,
+    }
+}
+
+/// Stop using the fpu from kernel code, same as dropping the guard
+pub fn kernel_fpu_end(guard: KernelFpu) {
+    drop(guard);
+}
+
+impl Drop for KernelFpu {
+    fn drop(&mut self) {
+        KERNEL_FPU.lock().restore();
+        if self.task_switched {
+            set_task_switched();
+        }
+        IN_KERNEL_FPU.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_xmm0() -> u64 {
+        let value: u64;
+        unsafe { asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack)) };
+        value
+    }
+
+    fn write_xmm0(value: u64) {
+        unsafe { asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+    }
+
+    #[test_case]
+    fn save_restore() {
+        let _fpu = kernel_fpu_begin();
+        let mut state = FpuState::new();
+
+        write_xmm0(0xdead_beef);
+        state.save();
+        write_xmm0(0);
+        state.restore();
+        assert_eq!(read_xmm0(), 0xdead_beef);
+    }
+
+    #[test_case]
+    fn kernel_fpu_preserves() {
+        write_xmm0(42);
+        {
+            let _fpu = kernel_fpu_begin();
+            write_xmm0(7);
+        }
+        assert_eq!(read_xmm0(), 42);
+    }
+
+    /// Init programs xcr0 on this cpu, and the probed size is what the cpu needs for it
+    #[test_case]
+    fn xsave_enabled() {
+        let info = info();
+        if !info.uses_xsave() {
+            return;
+        }
+        assert_eq!(unsafe { XCr0::read() }, info.components());
+        // leaf 0xD, ebx is the size needed for the components currently in xcr0
+        assert_eq!(cpuid(0xD, 0).ebx as usize, info.size());
+    }
+}
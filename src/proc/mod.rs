@@ -1,16 +1,23 @@
 pub mod cpu;
+pub mod fpu;
+pub mod percpu;
 mod process;
 mod tasks;
+pub mod user;
 
 use cpu::Cpu;
 // use process::Process;
 // use spin::Mutex;
 
+use core::time::Duration;
 use x86_64::PhysicalAddress;
 
 //static CPUS: StaticVec<Cpu, 8> = StaticVec::new();
 //static PTABLE: Mutex<StaticVec<Process, 64>> = Mutex::new(StaticVec::new());
 
+/// How long to wait for an application processor to start
+const AP_TIMEOUT: Duration = Duration::from_millis(100);
+
 pub fn ap_startup() {
     use crate::multiboot::MADT_TABLE;
     use x86_64::paging::allocator::Allocator;
@@ -42,7 +49,12 @@ pub fn ap_startup() {
                 .write_volatile(mp_enter as *const u64 as u64)
         };
 
+        crate::time::tsc::expect_ap(lapic_id);
         unsafe { (*crate::io::LAPIC.as_mut_ptr()).start_ap(lapic_id, code) };
+        // line up the time stamp counter of the ap with ours as soon as it runs
+        if !crate::time::tsc::sync_bsp(lapic_id, AP_TIMEOUT) {
+            crate::kprintln!("SMP: cpu with apic id {} did not come up", lapic_id);
+        }
     }
 }
 
@@ -63,5 +75,11 @@ fn copy_boot_to_addr(addr: PhysicalAddress) {
 
 #[no_mangle]
 extern "C" fn mp_enter() {
+    crate::proc::percpu::init();
+    crate::interrupts::watchpoint::init_cpu();
+    // the bootstrap processor waits in `ap_startup` to run the other half
+    if crate::time::tsc::sync_ap().is_none() {
+        crate::kprintln!("SMP: gave up lining up the time stamp counter");
+    }
     crate::kdbg!("entered");
 }
@@ -0,0 +1,228 @@
+use core::alloc::Layout;
+use core::arch::asm;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase, TscAux};
+use x86_64::tsc;
+use x86_64::VirtualAddress;
+
+use crate::consts::MAX_CPUS;
+use crate::interrupts::gdt::GlobalDescriptorTable;
+use crate::interrupts::tss::TaskStateSegment;
+
+/// Stack a cpu switches to when entering the kernel from ring 3 outside of a task
+const STACK_SIZE: usize = 4096 * 5;
+
+/// Data that belongs to a single cpu, found through the gs base while in the kernel
+#[derive(Debug)]
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this block, so gs relative code can get a normal pointer
+    this: u64,
+    /// Top of the stack the syscall entry switches to
+    kernel_stack: u64,
+    /// The user rsp, saved by the syscall entry
+    user_stack: u64,
+    /// Index of this cpu, 0 is the bootstrap processor
+    index: usize,
+    apic_id: u32,
+    /// Kernel state to return to when the user code running on this cpu exits, or 0
+    user_context: u64,
+    /// Added to the time stamp counter to line it up with the bootstrap processor
+    tsc_offset: i64,
+    /// Nanoseconds spent halted in the executor idle loop
+    idle_nanos: u64,
+    /// Every cpu needs its own tss for the stack it enters the kernel on from ring 3,
+    /// and its own gdt as loading the tss marks its descriptor busy
+    tss: TaskStateSegment,
+    gdt: GlobalDescriptorTable,
+}
+
+impl PerCpu {
+    /// Offsets used by the assembly entry points
+    pub const KERNEL_STACK_OFFSET: usize = offset_of!(PerCpu, kernel_stack);
+    pub const USER_STACK_OFFSET: usize = offset_of!(PerCpu, user_stack);
+
+    const fn empty() -> Self {
+        Self {
+            this: 0,
+            kernel_stack: 0,
+            user_stack: 0,
+            index: 0,
+            apic_id: 0,
+            user_context: 0,
+            tsc_offset: 0,
+            idle_nanos: 0,
+            tss: TaskStateSegment::zero(),
+            gdt: GlobalDescriptorTable::new(),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    pub fn kernel_stack(&self) -> u64 {
+        self.kernel_stack
+    }
+
+    pub fn user_context(&self) -> u64 {
+        self.user_context
+    }
+
+    pub fn tsc_offset(&self) -> i64 {
+        self.tsc_offset
+    }
+
+    pub fn idle_nanos(&self) -> u64 {
+        self.idle_nanos
+    }
+
+    pub fn tss(&self) -> &TaskStateSegment {
+        &self.tss
+    }
+}
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+const EMPTY_STACK: Stack = Stack([0; STACK_SIZE]);
+const EMPTY_BLOCK: PerCpu = PerCpu::empty();
+
+static mut BLOCKS: [PerCpu; MAX_CPUS] = [EMPTY_BLOCK; MAX_CPUS];
+/// The other cpus allocate their stacks as they come up, there can be too many to keep here
+static mut BSP_STACK: Stack = EMPTY_STACK;
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+/// Cpus done with [`init`]
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Set up the per cpu block of this cpu and point the gs base at it,
+/// must be called once on every cpu. The bootstrap processor comes first, before the heap,
+/// the others after it
+pub fn init() {
+    let index = NEXT_INDEX.fetch_add(1, Ordering::SeqCst);
+    assert!(index < MAX_CPUS, "more than {} cpus", MAX_CPUS);
+
+    // the bootstrap processor keeps the double fault stack of the boot tss
+    let (stack, double_fault_stack) = match index {
+        0 => (
+            unsafe { BSP_STACK.0.as_ptr() as u64 } + STACK_SIZE as u64,
+            None,
+        ),
+        _ => {
+            crate::interrupts::stats::init_cpu(index);
+            (allocate_stack(index), Some(allocate_stack(index)))
+        }
+    };
+
+    unsafe {
+        let block = &mut BLOCKS[index];
+        block.this = block as *const PerCpu as u64;
+        block.index = index;
+        block.apic_id = crate::io::LAPIC.id();
+
+        // the kernel runs with the per cpu block in gs, user mode gets its own
+        // (zero) base that `swapgs` puts back when we leave the kernel
+        GsBase::write(VirtualAddress::new(block.this));
+        KernelGsBase::write(VirtualAddress::new(0));
+        // so `rdtscp` tells which cpu the counter was read on
+        if tsc::has_rdtscp() {
+            TscAux::write(index as u32);
+        }
+
+        crate::interrupts::load_cpu_tables(&mut block.gdt, &mut block.tss, double_fault_stack);
+        crate::interrupts::set_kernel_stack(stack);
+    }
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Returns the top of a new stack for the cpu with `index`
+fn allocate_stack(index: usize) -> u64 {
+    let stack = unsafe { alloc::alloc::alloc(Layout::new::<Stack>()) };
+    assert!(
+        !stack.is_null(),
+        "out of memory for the stack of cpu {}",
+        index
+    );
+    stack as u64 + STACK_SIZE as u64
+}
+
+/// Number of cpus that have set up their block
+pub fn count() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+/// Index of the running cpu, also before [`init`] as only the bootstrap processor runs then
+pub fn current_index() -> usize {
+    match count() {
+        0 => 0,
+        _ => current().index(),
+    }
+}
+
+/// The time stamp counter offset of the cpu with `index`, found without the gs base
+pub fn tsc_offset(index: usize) -> i64 {
+    unsafe { BLOCKS.get(index).map_or(0, |block| block.tsc_offset) }
+}
+
+/// The per cpu block of the running cpu
+pub fn current() -> &'static PerCpu {
+    unsafe { &*current_ptr() }
+}
+
+/// The tss of the running cpu
+pub fn current_tss() -> *mut TaskStateSegment {
+    unsafe { core::ptr::addr_of_mut!((*current_ptr()).tss) }
+}
+
+fn current_ptr() -> *mut PerCpu {
+    let this: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags));
+    }
+    this as *mut PerCpu
+}
+
+/// Set the stack used when this cpu enters the kernel through a syscall,
+/// use [`crate::interrupts::set_kernel_stack`] to also update the tss
+/// # Safety
+/// `top` must be the top of a mapped stack that nothing else is using
+pub unsafe fn set_kernel_stack(top: u64) {
+    (*current_ptr()).kernel_stack = top;
+}
+
+/// Set the kernel state user code on this cpu returns to, returns the previous one
+/// # Safety
+/// `context` must stay valid until it is replaced again
+pub unsafe fn set_user_context(context: u64) -> u64 {
+    core::mem::replace(&mut (*current_ptr()).user_context, context)
+}
+
+/// Set the offset that lines the time stamp counter of this cpu up with the others
+/// # Safety
+/// Timestamps taken before and after the change can not be compared
+pub unsafe fn set_tsc_offset(offset: i64) {
+    (*current_ptr()).tsc_offset = offset;
+}
+
+/// Count `nanos` more idle time on this cpu
+pub fn add_idle_nanos(nanos: u64) {
+    // only this cpu writes its block
+    unsafe { (*current_ptr()).idle_nanos += nanos };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn current_block() {
+        let cpu = current();
+        assert_eq!(cpu.index(), 0);
+        assert_ne!(cpu.kernel_stack(), 0);
+    }
+}
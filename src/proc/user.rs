@@ -0,0 +1,298 @@
+//! Running code in ring 3.
+//!
+//! [`run`] saves the kernel registers and `iretq`s into user mode. The user code
+//! leaves through the exit system call or by faulting, both jump back to the saved
+//! registers so `run` returns as if it was a normal call.
+
+use x86_64::registers::control::Cr2;
+use x86_64::VirtualAddress;
+
+use crate::interrupts::trap::TrapFrame;
+use crate::interrupts::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::proc::cpu::Rflags;
+use crate::proc::percpu;
+
+/// How the user code stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// It made the exit system call with this status
+    Exited(u64),
+    /// It raised an exception and was killed
+    Fault(Fault),
+}
+
+/// An exception raised in ring 3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub vector: u8,
+    pub error_code: u64,
+    pub rip: u64,
+    /// The address that was accessed, for page faults
+    pub address: Option<VirtualAddress>,
+}
+
+/// Callee saved registers of the kernel, the layout is used by the assembly below
+#[derive(Default)]
+#[repr(C)]
+struct KernelContext {
+    rsp: u64,
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+}
+
+#[repr(C)]
+struct UserContext {
+    kernel: KernelContext,
+    exit: Option<Exit>,
+}
+
+/// Run user code at `entry` with the stack at `stack` until it exits or faults.
+/// Entries from ring 3 use the stack set with [`crate::interrupts::set_kernel_stack`].
+/// The user code runs with interrupts enabled if they are enabled now.
+/// # Safety
+/// `entry` and `stack` must be mapped user accessible
+pub unsafe fn run(entry: VirtualAddress, stack: VirtualAddress) -> Exit {
+    let interrupts = crate::interrupts::interrupts_enabled();
+    let mut flags = Rflags::empty();
+    flags.set(Rflags::IF, interrupts);
+
+    let mut context = UserContext {
+        kernel: KernelContext::default(),
+        exit: None,
+    };
+    let previous = percpu::set_user_context(&mut context as *mut UserContext as u64);
+    __enter_user(
+        &mut context.kernel,
+        u64::from(entry),
+        u64::from(stack),
+        flags.bits(),
+    );
+    percpu::set_user_context(previous);
+
+    // we come back with interrupts disabled, from the syscall or exception entry
+    if interrupts {
+        crate::interrupts::enable_interrupts();
+    }
+    context.exit.expect("left user mode without a reason")
+}
+
+/// End the user code running on this cpu, returns if there is none
+pub fn exit(status: u64) {
+    leave(Exit::Exited(status));
+}
+
+/// Kill the user code that raised the exception in `frame`
+pub fn fault(frame: &TrapFrame) -> ! {
+    let vector = frame.vector as u8;
+    let fault = Fault {
+        vector,
+        error_code: frame.error_code,
+        rip: frame.rip,
+        address: (vector == 14).then(Cr2::read),
+    };
+    leave(Exit::Fault(fault));
+    panic!("fault in ring 3 without a process\n{:#?}", frame);
+}
+
+/// Return to the kernel that started the user code, if any
+fn leave(exit: Exit) {
+    let context = percpu::current().user_context() as *mut UserContext;
+    if context.is_null() {
+        return;
+    }
+
+    unsafe {
+        (*context).exit = Some(exit);
+        __leave_user(&(*context).kernel);
+    }
+}
+
+// `__enter_user(context, rip, rsp, rflags)` saves the callee saved registers and the
+// stack pointer, then builds an interrupt frame for ring 3 and returns to it.
+// `__leave_user(context)` restores them and returns to the caller of `__enter_user`.
+// Interrupts stay off until the iretq, an interrupt after the swapgs would find the user gs.
+core::arch::global_asm!(
+    ".pushsection .text",
+    ".global __enter_user",
+    "__enter_user:",
+    "cli",
+    "mov [rdi], rsp",
+    "mov [rdi + 8], rbx",
+    "mov [rdi + 16], rbp",
+    "mov [rdi + 24], r12",
+    "mov [rdi + 32], r13",
+    "mov [rdi + 40], r14",
+    "mov [rdi + 48], r15",
+    "push {user_data}", // ss
+    "push rdx",         // rsp
+    "push rcx",         // rflags
+    "push {user_code}", // cs
+    "push rsi",         // rip
+    // nothing from the kernel is left in the registers
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "swapgs",
+    "iretq",
+    ".global __leave_user",
+    "__leave_user:",
+    "mov rsp, [rdi]",
+    "mov rbx, [rdi + 8]",
+    "mov rbp, [rdi + 16]",
+    "mov r12, [rdi + 24]",
+    "mov r13, [rdi + 32]",
+    "mov r14, [rdi + 40]",
+    "mov r15, [rdi + 48]",
+    "ret",
+    ".popsection",
+    user_data = const USER_DATA_SELECTOR.bits(),
+    user_code = const USER_CODE_SELECTOR.bits(),
+);
+
+extern "C" {
+    fn __enter_user(context: *mut KernelContext, rip: u64, rsp: u64, rflags: u64);
+    fn __leave_user(context: *const KernelContext) -> !;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::{SIZE_1KIB, USER_START};
+    use crate::paging::{map_page, unmap_page};
+    use alloc::boxed::Box;
+    use x86_64::paging::page_table::PageFlags;
+    use x86_64::PhysicalAddress;
+
+    #[repr(C, align(4096))]
+    struct Page([u8; 4096]);
+
+    /// Map `code` at the start of user space with a stack page after it and run it
+    fn run_code(code: &[u8]) -> Exit {
+        let mut text = Box::new(Page([0; 4096]));
+        let stack = Box::new(Page([0; 4096]));
+        text.0[..code.len()].copy_from_slice(code);
+
+        let text_page = VirtualAddress::new(USER_START);
+        let stack_page = VirtualAddress::new(USER_START + SIZE_1KIB);
+        let flags = PageFlags::USER_ACCESSIBLE | PageFlags::WRITEABLE;
+        let frame = |page: &Page| PhysicalAddress::new(page.0.as_ptr() as u64);
+        map_page(text_page, frame(&text), flags);
+        map_page(stack_page, frame(&stack), flags);
+
+        let exit = unsafe { run(text_page, stack_page + SIZE_1KIB) };
+
+        unmap_page(text_page);
+        unmap_page(stack_page);
+        exit
+    }
+
+    #[test_case]
+    fn syscall_exit() {
+        #[rustfmt::skip]
+        let code = [
+            0x48, 0xc7, 0xc0, 0x03, 0x00, 0x00, 0x00, // mov rax, SYS_EXIT
+            0x48, 0xc7, 0xc7, 0x2a, 0x00, 0x00, 0x00, // mov rdi, 42
+            0x0f, 0x05,                               // syscall
+        ];
+        assert_eq!(run_code(&code), Exit::Exited(42));
+    }
+
+    #[test_case]
+    fn int80_exit() {
+        #[rustfmt::skip]
+        let code = [
+            0x48, 0xc7, 0xc0, 0x03, 0x00, 0x00, 0x00, // mov rax, SYS_EXIT
+            0x48, 0xc7, 0xc7, 0x07, 0x00, 0x00, 0x00, // mov rdi, 7
+            0xcd, 0x80,                               // int 0x80
+        ];
+        assert_eq!(run_code(&code), Exit::Exited(7));
+    }
+
+    /// Interrupts taken in ring 3 must switch to the kernel gs base before using it
+    #[test_case]
+    fn timer_in_ring3() {
+        #[rustfmt::skip]
+        let code = [
+            0xb9, 0x00, 0x00, 0x00, 0x10,             // mov ecx, 0x10000000
+            0xff, 0xc9,                               // dec ecx
+            0x75, 0xfc,                               // jnz dec
+            0x48, 0xc7, 0xc0, 0x03, 0x00, 0x00, 0x00, // mov rax, SYS_EXIT
+            0x31, 0xff,                               // xor edi, edi
+            0x0f, 0x05,                               // syscall
+        ];
+        let enabled = crate::interrupts::interrupts_enabled();
+        crate::interrupts::enable_interrupts();
+        let ticks = crate::time::ticks();
+        let exit = run_code(&code);
+        let elapsed = crate::time::ticks() - ticks;
+        if !enabled {
+            crate::interrupts::disable_interrupts();
+        }
+
+        assert_eq!(exit, Exit::Exited(0));
+        assert!(elapsed > 0, "the user code was never interrupted");
+    }
+
+    /// Kernel memory is mapped, but user code can not have the kernel read it
+    #[test_case]
+    fn write_kernel_memory() {
+        #[rustfmt::skip]
+        let code = [
+            0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00, // mov rax, SYS_WRITE
+            0x48, 0xc7, 0xc7, 0x01, 0x00, 0x00, 0x00, // mov rdi, 1
+            0x48, 0xc7, 0xc6, 0x00, 0x00, 0x10, 0x00, // mov rsi, 0x100000 (kernel memory)
+            0x48, 0xc7, 0xc2, 0x01, 0x00, 0x00, 0x00, // mov rdx, 1
+            0x0f, 0x05,                               // syscall
+            0x48, 0x89, 0xc7,                         // mov rdi, rax
+            0x48, 0xc7, 0xc0, 0x03, 0x00, 0x00, 0x00, // mov rax, SYS_EXIT
+            0x0f, 0x05,                               // syscall
+        ];
+        let efault = -(crate::syscall::Errno::EFAULT as i64);
+        assert_eq!(run_code(&code), Exit::Exited(efault as u64));
+    }
+
+    /// Privileged instructions and kernel memory must kill the process, not the kernel
+    #[test_case]
+    fn faults_are_routed() {
+        let ud2 = [0x0f, 0x0b];
+        match run_code(&ud2) {
+            Exit::Fault(fault) => {
+                assert_eq!(fault.vector, 6);
+                assert_eq!(fault.rip, USER_START);
+            }
+            exit => panic!("expected a fault, got {:?}", exit),
+        }
+
+        let hlt = [0xf4];
+        match run_code(&hlt) {
+            Exit::Fault(fault) => assert_eq!(fault.vector, 13),
+            exit => panic!("expected a fault, got {:?}", exit),
+        }
+
+        // mov rax, [0x100000] (kernel memory)
+        let read = [0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x10, 0x00];
+        match run_code(&read) {
+            Exit::Fault(fault) => {
+                assert_eq!(fault.vector, 14);
+                assert_eq!(fault.address, Some(VirtualAddress::new(0x10_0000)));
+            }
+            exit => panic!("expected a fault, got {:?}", exit),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! The hardware inventory from the smbios tables, for diagnostics.
+
+use spin::Lazy;
+use x86_64::tables::smbios::{Smbios, SmbiosError};
+
+use crate::multiboot::MULTIBOOT_INFO;
+
+/// The smbios tables, none if the bootloader did not copy them and the bios area has none either
+pub static SMBIOS: Lazy<Option<Smbios>> = Lazy::new(|| {
+    let tagged = MULTIBOOT_INFO
+        .smbios()
+        .map(|tag| Smbios::from_entry_point(tag.tables()));
+    let smbios = match tagged {
+        Some(Ok(smbios)) => Ok(smbios),
+        // uefi firmware has no legacy bios area, but grub always passes the tag there
+        _ => Smbios::find_legacy(),
+    };
+
+    match smbios {
+        Ok(smbios) => Some(smbios),
+        Err(SmbiosError::NotFound) => None,
+        Err(err) => {
+            crate::kprintln!("SMBIOS: {:?}", err);
+            None
+        }
+    }
+});
+
+/// Log the machine model and memory layout
+pub fn init() {
+    let Some(smbios) = SMBIOS.as_ref() else {
+        crate::kprintln!("SMBIOS: no tables");
+        return;
+    };
+    let (major, minor) = smbios.version();
+    let unknown = "unknown";
+
+    if let Some(system) = smbios.system() {
+        crate::kprintln!(
+            "SMBIOS {}.{}: {} {}",
+            major,
+            minor,
+            system.manufacturer.unwrap_or(unknown),
+            system.product.unwrap_or(unknown)
+        );
+    }
+    if let Some(bios) = smbios.bios() {
+        crate::kprintln!(
+            "SMBIOS: bios {} {} ({})",
+            bios.vendor.unwrap_or(unknown),
+            bios.version.unwrap_or(unknown),
+            bios.release_date.unwrap_or(unknown)
+        );
+    }
+    for cpu in smbios.processors().filter(|cpu| cpu.populated) {
+        crate::kprintln!(
+            "SMBIOS: {} {}, {} cores {} threads",
+            cpu.socket.unwrap_or(unknown),
+            cpu.version.unwrap_or(unknown),
+            cpu.cores,
+            cpu.threads
+        );
+    }
+    for device in smbios.memory_devices() {
+        if let Some(size) = device.size {
+            crate::kprintln!(
+                "SMBIOS: {} {} MiB",
+                device.locator.unwrap_or(unknown),
+                size >> 20
+            );
+        }
+    }
+    for range in smbios.memory_ranges() {
+        crate::kprintln!("SMBIOS: memory {:?}..={:?}", range.start, range.end);
+    }
+    crate::kprintln!("SMBIOS: {} MiB installed", smbios.installed_memory() >> 20);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::tables::smbios::{Structures, SystemInfo};
+
+    #[test_case]
+    fn string_sets() {
+        // a system information structure, then an empty string set and the end of table
+        #[rustfmt::skip]
+        let table: &[u8] = &[
+            1, 8, 0x34, 0x12, 1, 0, 2, 0,
+            b'Q', b'E', b'M', b'U', 0, b'P', b'C', 0, 0,
+            127, 4, 0xFF, 0xFF, 0, 0,
+        ];
+        let mut structures = Structures::new(table);
+        let system = structures.next().unwrap();
+        assert_eq!((system.kind, system.handle), (1, 0x1234));
+        assert_eq!(system.string(1), Some("QEMU"));
+        assert_eq!(system.string(2), Some("PC"));
+        assert_eq!(system.string(0), None);
+        assert_eq!(system.string(3), None);
+
+        let system = SystemInfo::new(&system).unwrap();
+        assert_eq!(system.manufacturer, Some("QEMU"));
+        assert_eq!(system.product, None);
+        assert_eq!(system.version, Some("PC"));
+        assert!(structures.next().is_none());
+    }
+
+    #[test_case]
+    fn qemu_inventory() {
+        let smbios = SMBIOS.as_ref().expect("qemu has smbios tables");
+        let system = smbios.system().unwrap();
+        assert_eq!(system.manufacturer, Some("QEMU"));
+        assert!(smbios.bios().is_some());
+        assert!(smbios.processors().count() > 0);
+        assert!(smbios.memory_devices().count() > 0);
+        assert!(smbios.installed_memory() > 0);
+    }
+}
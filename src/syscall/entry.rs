@@ -0,0 +1,90 @@
+use crate::consts::USER_END;
+use crate::interrupts::trap::TrapFrame;
+use crate::interrupts::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::proc::percpu::PerCpu;
+
+/// Called by the entry stub, the frame has the same layout as one from a trap stub
+extern "C" fn syscall_dispatch(frame: &mut TrapFrame) {
+    super::dispatch(frame);
+}
+
+// `syscall` leaves the user rsp in place, puts the return rip in rcx and rflags in r11,
+// and masks the rflags in SFMASK (so interrupts are off until we are on a kernel stack).
+// Build the same frame an `int 0x80` would have pushed so both paths share the dispatcher,
+// then return with `sysretq`. Intel cpus raise the #GP for a return address at or above
+// USER_END in ring 0, still on the user stack, so those return with `iretq` instead.
+core::arch::global_asm!(
+    ".pushsection .text",
+    ".global __syscall_entry",
+    "__syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_stack}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    "push {user_data}",          // ss
+    "push qword ptr gs:[{user_stack}]", // rsp
+    "push r11",                  // rflags
+    "push {user_code}",          // cs
+    "push rcx",                  // rip
+    "push 0",                    // error code
+    "push {vector}",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call {dispatch}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "add rsp, 16", // vector and error code
+    "mov rcx, [rsp]", // rip
+    "mov r11, rcx",
+    "shr r11, {user_end_shift}",
+    "jnz 2f",
+    "add rsp, 16", // rip and cs
+    "pop r11",     // rflags
+    "pop rsp",     // user rsp, ss is left behind on the kernel stack
+    "swapgs",
+    "sysretq",
+    // the rest is an iretq frame, rcx and r11 are left as sysret would leave them
+    "2:",
+    "mov r11, [rsp + 16]",
+    "swapgs",
+    "iretq",
+    ".popsection",
+    user_stack = const PerCpu::USER_STACK_OFFSET,
+    kernel_stack = const PerCpu::KERNEL_STACK_OFFSET,
+    user_data = const USER_DATA_SELECTOR.bits(),
+    user_code = const USER_CODE_SELECTOR.bits(),
+    vector = const super::SYSCALL_VECTOR,
+    user_end_shift = const USER_END.trailing_zeros(),
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    pub fn __syscall_entry();
+}
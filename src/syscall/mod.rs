@@ -0,0 +1,161 @@
+//! System calls, entered with `syscall` or the slower `int 0x80`.
+//!
+//! The number goes in rax and the arguments in rdi, rsi, rdx, r10, r8 and r9
+//! (r10 instead of rcx, `syscall` uses rcx for the return address).
+//! The result is returned in rax, errors as a negative [`Errno`].
+
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::VirtualAddress;
+
+use crate::consts::{USER_END, USER_START};
+use crate::interrupts::trap::TrapFrame;
+use crate::proc::cpu::Rflags;
+
+mod entry;
+
+/// Vector of the `int 0x80` fallback, also stored in syscall frames
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+pub const SYS_WRITE: usize = 1;
+pub const SYS_GETCPU: usize = 2;
+pub const SYS_EXIT: usize = 3;
+
+const NUM_SYSCALLS: usize = 64;
+
+pub type SyscallResult = Result<u64, Errno>;
+pub type SyscallHandler = fn(&[u64; 6]) -> SyscallResult;
+
+/// Error numbers, returned negated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    EBADF = 9,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EINVAL = 22,
+    ENOSYS = 38,
+}
+
+static TABLE: [Option<SyscallHandler>; NUM_SYSCALLS] = {
+    let mut table: [Option<SyscallHandler>; NUM_SYSCALLS] = [None; NUM_SYSCALLS];
+    table[SYS_WRITE] = Some(sys_write);
+    table[SYS_GETCPU] = Some(sys_getcpu);
+    table[SYS_EXIT] = Some(sys_exit);
+    table
+};
+
+/// Enable `syscall`/`sysret` on this cpu, needs the per cpu block for the kernel stack
+pub fn init() {
+    let selectors = &crate::interrupts::GDT.1;
+    unsafe {
+        // sysret uses kernel data + 8 as ss and + 16 as cs, which is the user data and code
+        Star::write(
+            selectors.kernel_data_segment.bits(),
+            selectors.kernel_code_segment.bits(),
+        );
+        let entry: unsafe extern "C" fn() = entry::__syscall_entry;
+        LStar::write(VirtualAddress::new(entry as usize as u64));
+        // enter with interrupts off, a clear direction flag and no single stepping
+        let mask = Rflags::IF | Rflags::TF | Rflags::DF | Rflags::AC | Rflags::NT;
+        SFMask::write(mask.bits());
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+    crate::kprintln!("System calls enabled");
+}
+
+/// Run the system call in the frame and put the result in rax
+pub fn dispatch(frame: &mut TrapFrame) {
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+    let handler = TABLE.get(frame.rax as usize).copied().flatten();
+    let result = match handler {
+        Some(handler) => handler(&args),
+        None => Err(Errno::ENOSYS),
+    };
+
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+
+    // the call was in the last bytes of user space, returning would fault in ring 0
+    if VirtualAddress::try_from(frame.rip).is_err() {
+        frame.vector = 13;
+        frame.error_code = 0;
+        crate::proc::user::fault(frame);
+    }
+}
+
+/// Make sure a buffer passed in from user code is in user space and user code can access it,
+/// so it can not get the kernel to read kernel memory for it
+fn user_buffer<'a>(address: u64, len: u64) -> Result<&'a [u8], Errno> {
+    let in_user_space =
+        address >= USER_START && address.checked_add(len).is_some_and(|end| end <= USER_END);
+    if !in_user_space || !crate::paging::is_user_accessible(address, len as usize) {
+        return Err(Errno::EFAULT);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(address as *const u8, len as usize) })
+}
+
+/// write(fd, buf, len), only stdout and stderr (the console) exist for now
+fn sys_write(args: &[u64; 6]) -> SyscallResult {
+    let [fd, address, len, ..] = *args;
+    if fd != 1 && fd != 2 {
+        return Err(Errno::EBADF);
+    }
+
+    let buf = user_buffer(address, len)?;
+    let string = core::str::from_utf8(buf).map_err(|_| Errno::EINVAL)?;
+    crate::kprint!("{}", string);
+    Ok(len)
+}
+
+/// getcpu(), index of the cpu the caller is running on
+fn sys_getcpu(_args: &[u64; 6]) -> SyscallResult {
+    Ok(crate::proc::percpu::current().index() as u64)
+}
+
+/// exit(status), does not return to user code
+fn sys_exit(args: &[u64; 6]) -> SyscallResult {
+    crate::proc::user::exit(args[0]);
+    // only returns when the kernel itself made the call
+    Err(Errno::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::arch::asm;
+
+    fn int80(number: usize, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+        let ret: u64;
+        unsafe {
+            asm!(
+                "int 0x80",
+                inlateout("rax") number as u64 => ret,
+                in("rdi") arg0,
+                in("rsi") arg1,
+                in("rdx") arg2,
+            );
+        }
+        ret as i64
+    }
+
+    #[test_case]
+    fn int80_dispatch() {
+        assert_eq!(int80(SYS_GETCPU, 0, 0, 0), 0);
+        assert_eq!(int80(NUM_SYSCALLS + 1, 0, 0, 0), -(Errno::ENOSYS as i64));
+        assert_eq!(int80(SYS_EXIT, 0, 0, 0), -(Errno::EPERM as i64));
+    }
+
+    #[test_case]
+    fn write_errors() {
+        let message = "";
+        let address = message.as_ptr() as u64;
+        assert_eq!(int80(SYS_WRITE, 5, address, 0), -(Errno::EBADF as i64));
+        assert_eq!(int80(SYS_WRITE, 1, 0, 1), -(Errno::EFAULT as i64));
+    }
+}
@@ -69,12 +69,27 @@ impl Executor {
         }
     }
 
+    /// Run until every task completed, leaves interrupts enabled
+    pub fn run_until_done(&mut self) {
+        while !self.tasks.is_empty() {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Halt until the next interrupt if no task is ready, the timer is armed for the
+    /// next deadline
     fn sleep_if_idle(&self) {
-        use crate::interrupts::{disable_interrupts, enable_interrupts, enable_interrupts_hlt};
+        use crate::interrupts::{disable_interrupts, enable_interrupts};
+        use crate::time::{idle, timer, Instant};
+
+        timer::wake_expired();
 
         disable_interrupts();
-        if self.task_queue.is_empty() {
-            enable_interrupts_hlt();
+        let deadline = timer::next_deadline();
+        let expired = deadline.is_some_and(|deadline| deadline <= Instant::now());
+        if self.task_queue.is_empty() && !expired {
+            idle::idle(deadline);
         } else {
             enable_interrupts();
         }
@@ -4,11 +4,32 @@ use core::pin::Pin;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 
+use crate::proc::fpu::{self, FpuState};
+
 pub mod executor;
 
+/// Size of the stack used when user code started by a task enters the kernel
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+
+#[repr(C, align(16))]
+struct KernelStack([u8; KERNEL_STACK_SIZE]);
+
+impl KernelStack {
+    fn new() -> Box<Self> {
+        // allocate in place, it is too big to build on the stack first
+        unsafe { Box::new_zeroed().assume_init() }
+    }
+
+    fn top(&self) -> u64 {
+        self.0.as_ptr() as u64 + KERNEL_STACK_SIZE as u64
+    }
+}
+
 pub struct Task {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    fpu: FpuState,
+    kernel_stack: Box<KernelStack>,
 }
 
 impl Task {
@@ -16,11 +37,18 @@ impl Task {
         Self {
             id: TaskId::new(),
             future: Box::pin(future),
+            fpu: FpuState::new(),
+            kernel_stack: KernelStack::new(),
         }
     }
 
+    /// Poll the future with its own fpu state and kernel stack loaded
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        unsafe { crate::interrupts::set_kernel_stack(self.kernel_stack.top()) };
+        fpu::switch_to(&mut self.fpu);
+        let poll = self.future.as_mut().poll(context);
+        fpu::switch_from(&mut self.fpu);
+        poll
     }
 }
 
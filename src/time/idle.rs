@@ -0,0 +1,107 @@
+//! Tickless idle.
+//!
+//! While the executor has nothing to run the periodic tick is stopped and the lapic timer
+//! only fires once, for the next pending timer. It uses tsc-deadline mode when the cpu
+//! has it and a one shot countdown otherwise. The lapic clocksource counts ticks, so with
+//! it the tick keeps running and only a deadline before the next tick cuts it short.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use x86_64::tsc::rdtsc;
+
+use super::{Clocksource, Instant, LAPIC_HZ, NANOS_PER_SEC, TICK_COUNT};
+use crate::interrupts::{disable_interrupts, enable_interrupts, enable_interrupts_hlt};
+use crate::io::lapic::{self, TimerMode};
+use crate::io::LAPIC;
+use crate::proc::percpu;
+
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+/// Check for tsc-deadline mode, called by [`super::init`]
+pub(super) fn init() {
+    TSC_DEADLINE.store(lapic::tsc_deadline_supported(), Ordering::SeqCst);
+}
+
+/// Is the tick stopped while idle
+pub fn is_tickless() -> bool {
+    super::clocksource() != Clocksource::Lapic
+}
+
+/// Does the timer use tsc-deadline mode while idle
+pub fn uses_tsc_deadline() -> bool {
+    TSC_DEADLINE.load(Ordering::Relaxed)
+}
+
+/// Halt until an interrupt or `deadline`, whichever comes first. Must be called with
+/// interrupts disabled, returns with them enabled.
+pub fn idle(deadline: Option<Instant>) {
+    let start = Instant::now();
+    if !is_tickless() {
+        super::halt_until(deadline);
+        percpu::add_idle_nanos(start.elapsed().as_nanos() as u64);
+        return;
+    }
+
+    let lapic = unsafe { &mut *LAPIC.as_mut_ptr() };
+    match deadline {
+        Some(deadline) => arm(lapic, deadline.duration_since(start)),
+        // only another interrupt can give us work
+        None => lapic.stop_timer(),
+    }
+    enable_interrupts_hlt();
+
+    disable_interrupts();
+    let count = TICK_COUNT.load(Ordering::Relaxed) as u32;
+    lapic.start_timer(TimerMode::Periodic, count);
+    enable_interrupts();
+    percpu::add_idle_nanos(start.elapsed().as_nanos() as u64);
+}
+
+/// Interrupt once after `wait`, at least one count from now. A wait longer than the
+/// countdown can hold wakes up early and idles again.
+fn arm(lapic: &mut lapic::Lapic, wait: Duration) {
+    let nanos = wait.as_nanos() as u64;
+    if uses_tsc_deadline() {
+        let cycles = super::tsc::nanos_to_cycles(nanos).max(1);
+        lapic.start_tsc_deadline(rdtsc().saturating_add(cycles));
+    } else {
+        let hz = LAPIC_HZ.load(Ordering::Relaxed);
+        let count = (nanos as u128 * hz as u128 / NANOS_PER_SEC as u128).max(1);
+        lapic.start_timer(TimerMode::OneShot, count.min(u32::MAX as u128) as u32);
+    }
+}
+
+/// Time this cpu spent halted in [`idle`]
+pub fn idle_time() -> Duration {
+    Duration::from_nanos(percpu::current().idle_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::ticks;
+
+    #[test_case]
+    fn wakes_at_deadline() {
+        let idle_before = idle_time();
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(3);
+
+        disable_interrupts();
+        while Instant::now() < deadline {
+            idle(Some(deadline));
+            disable_interrupts();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_millis(50), "{:?}", elapsed);
+        assert!(idle_time() > idle_before);
+
+        // the tick is back after idling
+        let ticks_before = ticks();
+        enable_interrupts();
+        while ticks() < ticks_before + 2 {
+            crate::interrupts::halt();
+        }
+        disable_interrupts();
+    }
+}
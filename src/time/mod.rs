@@ -0,0 +1,352 @@
+//! Time keeping.
+//!
+//! The local apic timer and the time stamp counter are calibrated against the hpet, or the
+//! pit without one, at boot. The timer then interrupts [`TICK_HZ`] times a second.
+//! [`uptime`] reads the time stamp counter if it is invariant, else the hpet main counter
+//! if there is a 64 bit one, else it adds how far the timer is into the current tick.
+//! All have nanosecond resolution. With either of the first two the tick stops while the
+//! cpu is idle, see [`idle`].
+
+use core::fmt;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+
+use crate::io::lapic::TimerMode;
+use crate::io::pit::{self, Pit};
+use crate::io::{HPET, LAPIC};
+
+pub mod idle;
+pub mod timer;
+pub mod tsc;
+pub mod wall;
+
+/// Timer interrupts per second
+pub const TICK_HZ: u64 = 1000;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// How long to measure the lapic timer for
+const CALIBRATION_MS: u64 = 10;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Lapic timer counts per second
+static LAPIC_HZ: AtomicU64 = AtomicU64::new(0);
+/// Counts in a tick, the timer reloads with this
+static TICK_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Last value returned by `nanos`, so it never goes backwards
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+/// Counts the clock is ahead of the ticks, from ticks [`halt_until`] cut short
+static SKEW_COUNTS: AtomicI64 = AtomicI64::new(0);
+/// A [`Clocksource`]
+static CLOCKSOURCE: AtomicU8 = AtomicU8::new(Clocksource::Lapic as u8);
+/// Nanoseconds of the clocksource when the lapic timer started
+static CLOCK_START: AtomicU64 = AtomicU64::new(0);
+
+/// What [`nanos`] reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Clocksource {
+    /// Timer ticks and the current count of the lapic timer
+    Lapic = 0,
+    /// The hpet main counter
+    Hpet = 1,
+    /// The invariant time stamp counter
+    Tsc = 2,
+}
+
+/// Calibrate the lapic timer and start ticking, needs an initialized lapic
+pub fn init() {
+    // only init once
+    static ALREADY_INIT: AtomicBool = AtomicBool::new(false);
+    if ALREADY_INIT.fetch_or(true, Ordering::SeqCst) {
+        panic!("time already init")
+    }
+
+    let hz = crate::interrupts::without_interrupts(|| {
+        tsc::init();
+        idle::init();
+        calibrate_lapic()
+    });
+    let count = hz / TICK_HZ;
+    assert!(count > 0, "lapic timer is too slow ({} Hz)", hz);
+
+    LAPIC_HZ.store(hz, Ordering::SeqCst);
+    TICK_COUNT.store(count, Ordering::SeqCst);
+    crate::interrupts::without_interrupts(|| {
+        unsafe { (*LAPIC.as_mut_ptr()).start_timer(TimerMode::Periodic, count as u32) };
+        let hpet = HPET.as_ref().filter(|hpet| hpet.is_64bit());
+        let (source, start) = match hpet {
+            _ if tsc::is_invariant() => (Clocksource::Tsc, tsc::timestamp()),
+            Some(hpet) => (Clocksource::Hpet, hpet.nanos()),
+            None => (Clocksource::Lapic, 0),
+        };
+        CLOCK_START.store(start, Ordering::SeqCst);
+        CLOCKSOURCE.store(source as u8, Ordering::SeqCst);
+    });
+
+    let invariant = match tsc::is_invariant() {
+        true => " (invariant)",
+        false => "",
+    };
+    crate::kprintln!(
+        "LAPIC timer: {} Hz, ticking at {} Hz, TSC: {} Hz{}, clocksource {:?}",
+        hz,
+        TICK_HZ,
+        tsc::frequency(),
+        invariant,
+        clocksource()
+    );
+
+    wall::init();
+}
+
+/// Count how far the lapic timer gets in a known time
+fn calibrate_lapic() -> u64 {
+    let lapic = unsafe { &mut *LAPIC.as_mut_ptr() };
+
+    lapic.start_free_running();
+    // the timer counts down
+    let hz = measure_frequency(|| (u32::MAX - lapic.timer_count()) as u64);
+    lapic.stop_timer();
+    hz
+}
+
+/// How many times a second `counter` goes up, measured against the hpet or the pit
+/// without one. Should run with interrupts disabled.
+pub fn measure_frequency(mut counter: impl FnMut() -> u64) -> u64 {
+    let (counts, nanos) = match HPET.as_ref() {
+        Some(hpet) => {
+            let wait = CALIBRATION_MS * NANOS_PER_SEC / 1000;
+            let start_nanos = hpet.nanos();
+            let start = counter();
+            while hpet.nanos().wrapping_sub(start_nanos) < wait {}
+            let end = counter();
+            (end - start, hpet.nanos().wrapping_sub(start_nanos))
+        }
+        None => {
+            let mut pit = Pit::new();
+            pit.start_countdown((pit::FREQUENCY * CALIBRATION_MS / 1000) as u16);
+            let start = counter();
+            while !pit.check_done() {}
+            let end = counter();
+            (end - start, CALIBRATION_MS * NANOS_PER_SEC / 1000)
+        }
+    };
+    (counts as u128 * NANOS_PER_SEC as u128 / nanos as u128) as u64
+}
+
+/// Called by the timer interrupt
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Release);
+}
+
+/// Timer interrupts since the timer started
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
+
+/// Frequency of the lapic timer, 0 before [`init`]
+pub fn lapic_frequency() -> u64 {
+    LAPIC_HZ.load(Ordering::Relaxed)
+}
+
+/// What the time is read from
+pub fn clocksource() -> Clocksource {
+    match CLOCKSOURCE.load(Ordering::Relaxed) {
+        1 => Clocksource::Hpet,
+        2 => Clocksource::Tsc,
+        _ => Clocksource::Lapic,
+    }
+}
+
+/// Nanoseconds since the timer started, monotonic
+pub fn nanos() -> u64 {
+    let hz = LAPIC_HZ.load(Ordering::Relaxed);
+    if hz == 0 {
+        return 0;
+    }
+
+    let start = CLOCK_START.load(Ordering::Relaxed);
+    let now = match (clocksource(), HPET.as_ref()) {
+        (Clocksource::Tsc, _) => tsc::timestamp().saturating_sub(start),
+        (Clocksource::Hpet, Some(hpet)) => hpet.nanos().saturating_sub(start),
+        _ => lapic_nanos(hz),
+    };
+    LAST_NANOS.fetch_max(now, Ordering::Relaxed).max(now)
+}
+
+/// Nanoseconds from the timer ticks and how far the lapic timer is into the current one
+fn lapic_nanos(hz: u64) -> u64 {
+    let count = TICK_COUNT.load(Ordering::Relaxed);
+
+    // retry if a tick comes in between reading the counter and the ticks
+    let (ticks, remaining) = loop {
+        let ticks = ticks();
+        let remaining = LAPIC.timer_count() as u64;
+        if ticks == self::ticks() {
+            break (ticks, remaining);
+        }
+    };
+
+    // a reload with the interrupt still pending looks like going back a tick
+    let counts = ticks as i128 * count as i128
+        + SKEW_COUNTS.load(Ordering::Relaxed) as i128
+        + count.saturating_sub(remaining) as i128;
+    (counts.max(0) as u128 * NANOS_PER_SEC as u128 / hz as u128) as u64
+}
+
+/// Halt until an interrupt, or until `deadline` if it comes before the next tick. The
+/// timer fires once at the deadline and ticks again from when the cpu wakes up. Must be
+/// called with interrupts disabled, returns with them enabled.
+pub fn halt_until(deadline: Option<Instant>) {
+    use crate::interrupts::{disable_interrupts, enable_interrupts, enable_interrupts_hlt};
+
+    let lapic = unsafe { &mut *LAPIC.as_mut_ptr() };
+    let vector = crate::consts::IRQ_0 + crate::interrupts::idt::InterruptIndex::Timer as u8;
+    let hz = LAPIC_HZ.load(Ordering::Relaxed) as u128;
+    let count = TICK_COUNT.load(Ordering::Relaxed);
+    let remaining = lapic.timer_count() as u64;
+
+    let wait = deadline.map(|deadline| deadline.duration_since(Instant::now()));
+    let shot = match wait.map(|wait| (wait.as_nanos() * hz / NANOS_PER_SEC as u128) as u64) {
+        Some(counts) if counts < remaining && !lapic.is_pending(vector) => counts.max(1),
+        // the tick comes first
+        _ => return enable_interrupts_hlt(),
+    };
+
+    let ticks_before = ticks();
+    lapic.start_timer(TimerMode::OneShot, shot as u32);
+    // a tick that came in since reading the count ended before the one shot started
+    let before = match lapic.is_pending(vector) {
+        true => count,
+        false => count.saturating_sub(remaining),
+    };
+    enable_interrupts_hlt();
+
+    disable_interrupts();
+    let left = lapic.timer_count() as u64;
+    lapic.start_timer(TimerMode::Periodic, count as u32);
+    // the clock goes on from where it is, the interrupt at the deadline counted a whole tick
+    let elapsed = before + (shot - left);
+    let counted = (ticks() - ticks_before) * count;
+    SKEW_COUNTS.fetch_add(elapsed as i64 - counted as i64, Ordering::Relaxed);
+    enable_interrupts();
+}
+
+/// Time since the timer started
+pub fn uptime() -> Duration {
+    Duration::from_nanos(nanos())
+}
+
+/// Monotonic nanoseconds to stamp log lines with, shown in seconds like `[    1.000042]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    /// Zero before the timer started
+    pub fn now() -> Self {
+        Self(nanos())
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let micros = self.0 % NANOS_PER_SEC / 1000;
+        write!(f, "[{:>5}.{:06}]", self.0 / NANOS_PER_SEC, micros)
+    }
+}
+
+/// A point in time on the monotonic clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(nanos())
+    }
+
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Self(nanos)
+    }
+
+    /// Nanoseconds since the timer started
+    pub const fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// Time from `earlier` to this, zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Self::Output {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        Instant(self.0.saturating_add(nanos))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Self::Output {
+        self.duration_since(earlier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn calibrated() {
+        // anything slower than 1 MHz can not tick at 1 kHz with any precision
+        assert!(lapic_frequency() >= 1_000_000);
+    }
+
+    #[test_case]
+    fn uptime_advances() {
+        let start = uptime();
+        let start_ticks = ticks();
+
+        crate::interrupts::enable_interrupts();
+        while ticks() < start_ticks + 3 {
+            crate::interrupts::halt();
+        }
+        crate::interrupts::disable_interrupts();
+
+        let elapsed = uptime() - start;
+        assert!(elapsed >= Duration::from_millis(2), "{:?}", elapsed);
+    }
+
+    /// A deadline before the next tick cuts it short, the clock keeps going
+    #[test_case]
+    fn halt_until_deadline() {
+        let start = Instant::now();
+        let deadline = start + Duration::from_micros(200);
+
+        crate::interrupts::disable_interrupts();
+        while Instant::now() < deadline {
+            halt_until(Some(deadline));
+            crate::interrupts::disable_interrupts();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_millis(5), "{:?}", elapsed);
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test_case]
+    fn timestamp_format() {
+        use alloc::format;
+
+        assert_eq!(format!("{}", Timestamp(1_000_042_999)), "[    1.000042]");
+        assert_eq!(format!("{}", Timestamp(0)), "[    0.000000]");
+    }
+}
@@ -0,0 +1,273 @@
+//! Timer futures for the executor.
+//!
+//! Pending timers are kept sorted by deadline. The executor wakes the expired ones
+//! between polling tasks, the timer interrupt makes sure it does not halt past a deadline.
+
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_util::stream::Stream;
+use spin::Mutex;
+
+use super::Instant;
+
+/// Timers by deadline, the id keeps timers with the same deadline apart
+static TIMERS: Mutex<BTreeMap<(Instant, u64), Waker>> = Mutex::new(BTreeMap::new());
+
+/// Deadline of the first timer
+pub fn next_deadline() -> Option<Instant> {
+    TIMERS.lock().keys().next().map(|(deadline, _)| *deadline)
+}
+
+/// Wake the tasks of every timer that expired, returns how many there were
+pub fn wake_expired() -> usize {
+    let now = Instant::now();
+    let mut woken = 0;
+    loop {
+        // wake without holding the lock, the waker may poll or register timers
+        let waker = {
+            let mut timers = TIMERS.lock();
+            match timers.first_entry() {
+                Some(entry) if entry.key().0 <= now => entry.remove(),
+                _ => break,
+            }
+        };
+        waker.wake();
+        woken += 1;
+    }
+    woken
+}
+
+/// A registration in the timer list, removed when dropped
+#[derive(Debug)]
+struct Registration {
+    key: Option<(Instant, u64)>,
+}
+
+impl Registration {
+    const fn new() -> Self {
+        Self { key: None }
+    }
+
+    /// Make sure the task behind `waker` is woken at `deadline`
+    fn register(&mut self, deadline: Instant, waker: &Waker) {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let mut timers = TIMERS.lock();
+        if let Some(key) = self.key {
+            if key.0 == deadline {
+                // already registered, the waker might have changed since
+                if let Some(old) = timers.get_mut(&key) {
+                    if !old.will_wake(waker) {
+                        *old = waker.clone();
+                    }
+                    return;
+                }
+            } else {
+                timers.remove(&key);
+            }
+        }
+
+        let key = (deadline, NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        timers.insert(key, waker.clone());
+        self.key = Some(key);
+    }
+
+    fn cancel(&mut self) {
+        if let Some(key) = self.key.take() {
+            TIMERS.lock().remove(&key);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Completes once the deadline has passed
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Instant,
+    registration: Registration,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Move the deadline, also works after it completed
+    pub fn reset(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+        self.registration.cancel();
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            self.registration.cancel();
+            return Poll::Ready(());
+        }
+        let deadline = self.deadline;
+        self.registration.register(deadline, cx.waker());
+        Poll::Pending
+    }
+}
+
+/// Wait for `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Wait until `deadline`
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        registration: Registration::new(),
+    }
+}
+
+/// The future of a [`Timeout`] did not complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs a future until it completes or the time runs out
+#[derive(Debug)]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // the future is never moved out of the pinned timeout
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Run `future`, giving up if it takes longer than `duration`
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// A stream that yields every `period`
+#[derive(Debug)]
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Wait for the next tick
+    pub async fn tick(&mut self) -> Instant {
+        futures_util::StreamExt::next(self).await.unwrap()
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Instant>> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let deadline = self.sleep.deadline();
+                let now = Instant::now();
+                // skip the ticks that were missed instead of bursting through them
+                let mut next = deadline + self.period;
+                if next <= now {
+                    next = now + self.period;
+                }
+                self.sleep.reset(next);
+                Poll::Ready(Some(deadline))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Yield every `period`, starting one period from now
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period must be non zero");
+    Interval {
+        period,
+        sleep: sleep(period),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::executor::Executor;
+    use crate::task::Task;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use futures_util::StreamExt;
+
+    #[test_case]
+    fn sleeps_in_order() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let start = Instant::now();
+
+        let mut executor = Executor::new();
+        for ms in [3, 1, 2] {
+            let order = order.clone();
+            executor.spawn(Task::new(async move {
+                sleep(Duration::from_millis(ms)).await;
+                order.lock().push(ms);
+            }));
+        }
+        executor.run_until_done();
+        crate::interrupts::disable_interrupts();
+
+        assert_eq!(*order.lock(), [1, 2, 3]);
+        assert!(start.elapsed() >= Duration::from_millis(3));
+        assert_eq!(next_deadline(), None);
+    }
+
+    #[test_case]
+    fn timeout_and_interval() {
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            let slow = timeout(Duration::from_millis(1), sleep(Duration::from_secs(5)));
+            assert_eq!(slow.await, Err(Elapsed));
+            let fast = timeout(Duration::from_secs(5), async { 7 });
+            assert_eq!(fast.await, Ok(7));
+
+            let start = Instant::now();
+            let ticks = interval(Duration::from_millis(1)).take(3).count().await;
+            assert_eq!(ticks, 3);
+            assert!(start.elapsed() >= Duration::from_millis(3));
+        }));
+        executor.run_until_done();
+        crate::interrupts::disable_interrupts();
+
+        // the timeout dropped its 5 second sleep
+        assert_eq!(next_deadline(), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Time stamp counter clock.
+//!
+//! Reading the counter takes a few cycles, so it is what tracing and profiling should use
+//! for timestamps. The counter of every cpu is shifted by a per cpu offset so they all
+//! line up with the bootstrap processor, application processors measure theirs with
+//! [`sync_ap`] while the bootstrap processor runs [`sync_bsp`].
+//!
+//! Log lines are stamped with [`cycles`], so it must not fault. It finds the offset
+//! through `rdtscp` instead of the gs base, which is not set up before
+//! [`percpu::init`] and still belongs to user code right after an entry from ring 3.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::tsc::{self, rdtsc_ordered};
+
+use crate::proc::percpu;
+
+/// Round trips used to find the offset, the fastest one wins
+const SYNC_ROUNDS: usize = 16;
+
+/// Frequency of the counter in Hz
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+/// `rdtscp` returns the per cpu index [`percpu::init`] put in TSC_AUX
+static RDTSCP: AtomicBool = AtomicBool::new(false);
+/// Cycles to nanoseconds as a 32.32 fixed point factor
+static NANOS_PER_CYCLE: AtomicU64 = AtomicU64::new(0);
+
+// sync handshake, the ap sets READY, the bsp answers with its counter and DONE.
+// The state holds the apic id of the ap in the high half, an ap that finds another id
+// there or ABORT was given up on and stops.
+const SYNC_IDLE: u32 = 0;
+const SYNC_READY: u32 = 1;
+const SYNC_DONE: u32 = 2;
+const SYNC_ABORT: u32 = 3;
+static SYNC_STATE: AtomicU64 = AtomicU64::new(sync_state(u32::MAX, SYNC_ABORT));
+static SYNC_TSC: AtomicU64 = AtomicU64::new(0);
+
+const fn sync_state(apic_id: u32, step: u32) -> u64 {
+    (apic_id as u64) << 32 | step as u64
+}
+
+/// Find the counter frequency, called by [`super::init`] with interrupts disabled
+pub(super) fn init() {
+    let hz = tsc::cpuid_frequency().unwrap_or_else(|| super::measure_frequency(tsc::rdtsc));
+    assert!(hz > 0, "time stamp counter is not running");
+
+    TSC_HZ.store(hz, Ordering::SeqCst);
+    NANOS_PER_CYCLE.store(
+        ((super::NANOS_PER_SEC as u128) << 32).div_ceil(hz as u128) as u64,
+        Ordering::SeqCst,
+    );
+    INVARIANT.store(tsc::is_invariant(), Ordering::SeqCst);
+    RDTSCP.store(tsc::has_rdtscp(), Ordering::SeqCst);
+}
+
+/// Frequency of the counter, 0 before [`super::init`]
+pub fn frequency() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+/// Does the counter keep a constant rate, otherwise it is only good for rough profiling
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// The counter of this cpu, lined up with the bootstrap processor
+pub fn cycles() -> u64 {
+    if RDTSCP.load(Ordering::Relaxed) {
+        let (counter, index) = tsc::rdtscp();
+        return counter.wrapping_add_signed(percpu::tsc_offset(index as usize));
+    }
+    let offset = match percpu::count() {
+        0 => 0,
+        _ => percpu::current().tsc_offset(),
+    };
+    rdtsc_ordered().wrapping_add_signed(offset)
+}
+
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    let factor = NANOS_PER_CYCLE.load(Ordering::Relaxed);
+    ((cycles as u128 * factor as u128) >> 32) as u64
+}
+
+pub fn nanos_to_cycles(nanos: u64) -> u64 {
+    (nanos as u128 * frequency() as u128 / super::NANOS_PER_SEC as u128) as u64
+}
+
+/// Nanoseconds since the counter was reset, comparable between cpus
+pub fn timestamp() -> u64 {
+    cycles_to_nanos(cycles())
+}
+
+/// Let the application processor with `apic_id` run [`sync_ap`], call before starting it
+pub fn expect_ap(apic_id: u32) {
+    SYNC_STATE.store(sync_state(apic_id, SYNC_IDLE), Ordering::Release);
+}
+
+/// Answer the [`SYNC_ROUNDS`] round trips of the application processor with `apic_id`.
+/// Gives up if it does not show up within `timeout`, and tells it to stop if it still
+/// does later. Returns if it showed up.
+pub fn sync_bsp(apic_id: u32, timeout: Duration) -> bool {
+    let deadline = rdtsc_ordered().saturating_add(nanos_to_cycles(timeout.as_nanos() as u64));
+    for _ in 0..SYNC_ROUNDS {
+        while SYNC_STATE.load(Ordering::Acquire) != sync_state(apic_id, SYNC_READY) {
+            if rdtsc_ordered() > deadline {
+                SYNC_STATE.store(sync_state(apic_id, SYNC_ABORT), Ordering::Release);
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        SYNC_TSC.store(cycles(), Ordering::Relaxed);
+        SYNC_STATE.store(sync_state(apic_id, SYNC_DONE), Ordering::Release);
+    }
+    true
+}
+
+/// Line the counter of this cpu up with the bootstrap processor, which has to run
+/// [`sync_bsp`] at the same time. Returns the offset, none if the bootstrap processor
+/// gave up on this cpu.
+pub fn sync_ap() -> Option<i64> {
+    let apic_id = percpu::current().apic_id();
+    let state = |step| sync_state(apic_id, step);
+    let mut best_round_trip = u64::MAX;
+    let mut offset = 0;
+    for round in 0..SYNC_ROUNDS {
+        let start = rdtsc_ordered();
+        let ready = SYNC_STATE.compare_exchange(
+            state(SYNC_IDLE),
+            state(SYNC_READY),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+        if ready.is_err() {
+            return None;
+        }
+        loop {
+            match SYNC_STATE.load(Ordering::Acquire) {
+                value if value == state(SYNC_DONE) => break,
+                value if value == state(SYNC_READY) => core::hint::spin_loop(),
+                _ => return None,
+            }
+        }
+        let end = rdtsc_ordered();
+        // after the last answer the bootstrap processor may already expect the next cpu
+        let last = round + 1 == SYNC_ROUNDS;
+        let idle = SYNC_STATE.compare_exchange(
+            state(SYNC_DONE),
+            state(SYNC_IDLE),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+        if !last && idle.is_err() {
+            return None;
+        }
+
+        // assume the bsp read its counter halfway through the round trip
+        let round_trip = end.wrapping_sub(start);
+        if round_trip < best_round_trip {
+            best_round_trip = round_trip;
+            let midpoint = start.wrapping_add(round_trip / 2);
+            offset = SYNC_TSC.load(Ordering::Relaxed).wrapping_sub(midpoint) as i64;
+        }
+    }
+
+    unsafe { percpu::set_tsc_offset(offset) };
+    Some(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::{uptime, NANOS_PER_SEC};
+
+    #[test_case]
+    fn calibrated() {
+        assert!(frequency() >= 1_000_000);
+        assert_eq!(percpu::current().tsc_offset(), 0);
+    }
+
+    #[test_case]
+    fn conversions() {
+        let hz = frequency();
+        let second = cycles_to_nanos(hz);
+        // the fixed point factor is rounded up, so it may be a few nanoseconds over
+        assert!((NANOS_PER_SEC..=NANOS_PER_SEC + 2).contains(&second));
+        assert_eq!(nanos_to_cycles(NANOS_PER_SEC), hz);
+    }
+
+    #[test_case]
+    fn timestamps_advance() {
+        let start = timestamp();
+        let start_uptime = uptime();
+        crate::interrupts::enable_interrupts();
+        while uptime() - start_uptime < Duration::from_millis(1) {}
+        crate::interrupts::disable_interrupts();
+        let elapsed = timestamp() - start;
+        // the two clocks were calibrated separately, allow some error
+        assert!(elapsed >= 900_000, "{}", elapsed);
+    }
+}
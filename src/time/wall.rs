@@ -0,0 +1,65 @@
+//! Wall clock time.
+//!
+//! The real time clock is read once at boot, after that the time is the boot time plus
+//! the uptime. It only has second resolution and reading it can take a second.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::io::cmos::RtcDate;
+
+/// Unix time in nanoseconds at uptime zero
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Read the real time clock, called by [`super::init`]
+pub(super) fn init() {
+    let date = crate::interrupts::without_interrupts(crate::io::current_time);
+    set_boot_time(&date);
+    crate::kprintln!("Wall clock: {} ({})", date, date.to_unix());
+}
+
+fn set_boot_time(date: &RtcDate) {
+    let now = date.to_unix() * super::NANOS_PER_SEC;
+    BOOT_TIME.store(now.saturating_sub(super::nanos()), Ordering::SeqCst);
+}
+
+/// Time since the unix epoch
+pub fn unix_time() -> Duration {
+    Duration::from_nanos(BOOT_TIME.load(Ordering::Relaxed) + super::nanos())
+}
+
+/// Seconds since the unix epoch, what ext2 stores in its timestamps
+pub fn unix_seconds() -> u32 {
+    unix_time().as_secs() as u32
+}
+
+/// The current date in UTC
+pub fn now() -> RtcDate {
+    RtcDate::from_unix(unix_time().as_secs())
+}
+
+/// Set the wall clock and the real time clock
+pub fn set(date: &RtcDate) {
+    crate::io::set_current_time(date);
+    set_boot_time(date);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn follows_uptime() {
+        let start = unix_time();
+        // qemu starts the clock at the host time
+        assert!(start.as_secs() > 1_600_000_000);
+
+        let uptime = super::super::uptime();
+        crate::interrupts::enable_interrupts();
+        while super::super::uptime() - uptime < Duration::from_millis(2) {}
+        crate::interrupts::disable_interrupts();
+        assert!(unix_time() - start >= Duration::from_millis(2));
+        let seconds = now().to_unix();
+        assert!(unix_time().as_secs() - seconds <= 1);
+    }
+}
@@ -0,0 +1,43 @@
+//! Virtio devices on pci, the paravirtual hardware of qemu.
+//!
+//! Virtio 1.1 - 2, 3 and 4.1
+
+use bitflags::bitflags;
+
+pub use queue::{Segment, Virtqueue};
+pub use transport::Transport;
+
+pub mod queue;
+pub mod transport;
+
+/// Vendor id of every virtio function
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+/// Feature bits every device type has
+pub const F_VERSION_1: u64 = 1 << 32;
+
+bitflags! {
+    /// How far the driver got setting up the device
+    pub struct Status: u8 {
+        const ACKNOWLEDGE = 1 << 0;
+        const DRIVER = 1 << 1;
+        const DRIVER_OK = 1 << 2;
+        const FEATURES_OK = 1 << 3;
+        const NEEDS_RESET = 1 << 6;
+        const FAILED = 1 << 7;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// Neither the legacy io bar nor the modern capabilities are there
+    NoTransport,
+    /// The device did not accept the features we picked
+    FeaturesRejected,
+    /// The queue does not exist or is already in use
+    QueueUnavailable,
+    /// The queue has fewer descriptors than a single request takes
+    QueueTooSmall(u16),
+    /// No memory for a queue
+    OutOfMemory,
+}
@@ -0,0 +1,275 @@
+//! Split virtqueues, chains of descriptors the driver offers and the device hands back.
+//!
+//! Virtio 1.1 - 2.6
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use x86_64::PhysicalAddress;
+
+use super::VirtioError;
+use crate::memory::dma::DmaBuffer;
+
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+
+/// Legacy devices find the used ring on the page after the available ring
+const LEGACY_ALIGN: usize = 4096;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// A piece of memory in a chain
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub address: PhysicalAddress,
+    pub len: u32,
+    /// The device writes it instead of reading it
+    pub writable: bool,
+}
+
+struct State {
+    /// Descriptors that are in no chain, linked by their next fields
+    free_head: u16,
+    free: u16,
+    /// How far we got in the used ring
+    last_used: u16,
+    /// Bytes written to each finished chain, by head, until the submitter collects it
+    finished: Vec<Option<u32>>,
+}
+
+/// A descriptor table and the available and used rings, in dma memory
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    driver_offset: usize,
+    device_offset: usize,
+    /// Which doorbell of a modern device is ours
+    pub(super) notify_offset: u16,
+    state: Mutex<State>,
+}
+
+impl Virtqueue {
+    /// Queue `index` with `size` descriptors, laid out for a legacy device if `legacy`
+    pub fn new(index: u16, size: u16, legacy: bool) -> Result<Self, VirtioError> {
+        if size == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let count = size as usize;
+        let driver_offset = 16 * count;
+        let device_offset = match legacy {
+            true => (driver_offset + 6 + 2 * count).next_multiple_of(LEGACY_ALIGN),
+            false => (driver_offset + 6 + 2 * count).next_multiple_of(4),
+        };
+        let memory = DmaBuffer::new(device_offset + 6 + 8 * count, LEGACY_ALIGN)
+            .ok_or(VirtioError::OutOfMemory)?;
+
+        let descriptors = memory.as_mut_ptr::<Descriptor>();
+        for i in 0..size {
+            let descriptor = Descriptor {
+                next: i + 1,
+                ..Default::default()
+            };
+            unsafe { descriptors.add(i as usize).write_volatile(descriptor) };
+        }
+        Ok(Self {
+            index,
+            size,
+            memory,
+            driver_offset,
+            device_offset,
+            notify_offset: 0,
+            state: Mutex::new(State {
+                free_head: 0,
+                free: size,
+                last_used: 0,
+                finished: vec![None; count],
+            }),
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn descriptors(&self) -> PhysicalAddress {
+        self.memory.physical()
+    }
+
+    /// The available ring
+    pub fn driver_area(&self) -> PhysicalAddress {
+        self.memory.physical() + self.driver_offset as u64
+    }
+
+    /// The used ring
+    pub fn device_area(&self) -> PhysicalAddress {
+        self.memory.physical() + self.device_offset as u64
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        unsafe { self.memory.as_mut_ptr::<Descriptor>().add(index as usize) }
+    }
+
+    /// Flags, index, then the ring
+    fn available(&self) -> *mut u16 {
+        unsafe { self.memory.as_mut_ptr::<u8>().add(self.driver_offset) as *mut u16 }
+    }
+
+    fn used_index(&self) -> u16 {
+        let used = unsafe { self.memory.as_ptr::<u8>().add(self.device_offset) as *const u16 };
+        unsafe { used.add(1).read_volatile() }
+    }
+
+    fn used_element(&self, slot: u16) -> UsedElement {
+        unsafe {
+            let ring = self.memory.as_ptr::<u8>().add(self.device_offset + 4);
+            let element = (ring as *const UsedElement).add((slot % self.size) as usize);
+            element.read_volatile()
+        }
+    }
+
+    /// Offer `segments` to the device as one chain, returns its head. None if there are
+    /// not enough free descriptors right now. The device has to be notified after
+    pub fn submit(&self, segments: &[Segment]) -> Option<u16> {
+        assert!(!segments.is_empty() && segments.len() <= self.size as usize);
+        let mut state = self.state.lock();
+        if (state.free as usize) < segments.len() {
+            return None;
+        }
+
+        let head = state.free_head;
+        let mut index = head;
+        for (i, segment) in segments.iter().enumerate() {
+            let descriptor = self.descriptor(index);
+            let next = unsafe { (*descriptor).next };
+            let mut flags = if segment.writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < segments.len() {
+                flags |= DESC_F_NEXT;
+            }
+            let filled = Descriptor {
+                address: u64::from(segment.address),
+                len: segment.len,
+                flags,
+                next,
+            };
+            unsafe { descriptor.write_volatile(filled) };
+            state.free_head = next;
+            index = next;
+        }
+        state.free -= segments.len() as u16;
+
+        let available = self.available();
+        unsafe {
+            let idx = available.add(1).read_volatile();
+            available
+                .add(2 + (idx % self.size) as usize)
+                .write_volatile(head);
+            // the chain has to be visible before the index that offers it
+            fence(Ordering::SeqCst);
+            available.add(1).write_volatile(idx.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// The bytes the device wrote to the chain at `head` once it is done with it, its
+    /// descriptors are free again after
+    pub fn poll(&self, head: u16) -> Option<u32> {
+        let mut state = self.state.lock();
+        let used = self.used_index();
+        // the elements are only valid after the index
+        fence(Ordering::SeqCst);
+        while state.last_used != used {
+            let element = self.used_element(state.last_used);
+            // the id comes from the device, one that is not a descriptor is dropped
+            if let Some(finished) = state.finished.get_mut(element.id as usize) {
+                *finished = Some(element.len);
+            }
+            state.last_used = state.last_used.wrapping_add(1);
+        }
+
+        let written = state.finished[head as usize].take()?;
+        let mut tail = head;
+        let mut count = 1;
+        loop {
+            let descriptor = unsafe { self.descriptor(tail).read_volatile() };
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            tail = descriptor.next;
+            count += 1;
+        }
+        unsafe { (*self.descriptor(tail)).next = state.free_head };
+        state.free_head = head;
+        state.free += count;
+        Some(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn layouts() {
+        let legacy = Virtqueue::new(0, 256, true).unwrap();
+        let driver = u64::from(legacy.driver_area()) - u64::from(legacy.descriptors());
+        let device = u64::from(legacy.device_area()) - u64::from(legacy.descriptors());
+        assert_eq!((driver, device), (4096, 8192));
+        assert_eq!(u64::from(legacy.descriptors()) % 4096, 0);
+
+        let modern = Virtqueue::new(0, 8, false).unwrap();
+        let device = u64::from(modern.device_area()) - u64::from(modern.descriptors());
+        assert_eq!(device, 128 + 24);
+    }
+
+    /// Play the device, using chains out of order
+    #[test_case]
+    fn chains() {
+        let queue = Virtqueue::new(0, 4, false).unwrap();
+        let segment = |writable| Segment {
+            address: PhysicalAddress::new(0x1000),
+            len: 512,
+            writable,
+        };
+        let first = queue.submit(&[segment(false), segment(true)]).unwrap();
+        let second = queue.submit(&[segment(true)]).unwrap();
+        assert!(queue.submit(&[segment(true), segment(true)]).is_none());
+        assert_eq!(unsafe { queue.available().add(1).read() }, 2);
+
+        let used = |slot: usize, id: u16, len: u32| unsafe {
+            let base = queue.memory.as_mut_ptr::<u8>().add(queue.device_offset);
+            let element = (base.add(4) as *mut UsedElement).add(slot);
+            element.write_volatile(UsedElement { id: id as u32, len });
+            (base as *mut u16).add(1).write_volatile(slot as u16 + 1);
+        };
+        used(0, second, 512);
+        assert_eq!(queue.poll(first), None);
+        assert_eq!(queue.poll(second), Some(512));
+        used(1, first, 1);
+        assert_eq!(queue.poll(first), Some(1));
+
+        // every descriptor is free again
+        let chain = [segment(true); 4];
+        assert!(queue.submit(&chain).is_some());
+    }
+}
@@ -0,0 +1,357 @@
+//! The pci transport, in both register layouts.
+//!
+//! Legacy and transitional functions have their registers in io bar 0. Modern functions
+//! point at memory regions with vendor capabilities, one for each of the common registers,
+//! the notification doorbells, the interrupt status and the device specific config.
+
+use bit_field::BitField;
+use port::Port;
+
+use super::{Status, VirtioError, Virtqueue, F_VERSION_1};
+use crate::pci::device::Bar;
+use crate::pci::msi::MSI_X_CAPABILITY;
+use crate::pci::PciDevice;
+
+/// Capability id of the modern regions
+const VENDOR_CAPABILITY: u8 = 0x09;
+const COMMON_CFG: u8 = 1;
+const NOTIFY_CFG: u8 = 2;
+const ISR_CFG: u8 = 3;
+const DEVICE_CFG: u8 = 4;
+
+/// Msi-x entry meaning no interrupt
+pub const NO_VECTOR: u16 = 0xFFFF;
+
+/// Legacy registers, relative to the io bar
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+const LEGACY_CONFIG_VECTOR: u16 = 0x14;
+const LEGACY_QUEUE_VECTOR: u16 = 0x16;
+
+/// Modern common registers
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0C;
+const CONFIG_VECTOR: usize = 0x10;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_VECTOR: usize = 0x1A;
+const QUEUE_ENABLE: usize = 0x1C;
+const QUEUE_NOTIFY_OFF: usize = 0x1E;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+enum Layout {
+    Legacy {
+        port: u16,
+    },
+    Modern {
+        common: *mut u8,
+        notify: *mut u8,
+        multiplier: u32,
+        isr: *mut u8,
+        config: *mut u8,
+    },
+}
+
+/// The registers of a virtio function
+pub struct Transport {
+    device: PciDevice,
+    layout: Layout,
+}
+
+// the regions are only reached through the mapping of physical memory
+unsafe impl Send for Transport {}
+unsafe impl Sync for Transport {}
+
+fn read<T>(base: *mut u8, offset: usize) -> T {
+    unsafe { (base.add(offset) as *const T).read_volatile() }
+}
+
+fn write<T>(base: *mut u8, offset: usize, value: T) {
+    unsafe { (base.add(offset) as *mut T).write_volatile(value) }
+}
+
+impl Transport {
+    /// The modern layout if the function has it, the legacy one otherwise
+    pub fn new(device: &PciDevice) -> Result<Self, VirtioError> {
+        let layout = match Self::modern(device) {
+            Some(layout) => layout,
+            None => match device.bar(0) {
+                Some(Bar::Io { port, .. }) => Layout::Legacy { port },
+                _ => return Err(VirtioError::NoTransport),
+            },
+        };
+        Ok(Self {
+            device: device.clone(),
+            layout,
+        })
+    }
+
+    fn modern(device: &PciDevice) -> Option<Layout> {
+        let (mut common, mut notify, mut isr, mut config) = (None, None, None, None);
+        let mut multiplier = 0;
+        for capability in device.capabilities() {
+            if capability.id != VENDOR_CAPABILITY {
+                continue;
+            }
+            let offset = capability.offset;
+            let bar = device.read_u8(offset + 4) as usize;
+            let Some(Bar::Memory { address, .. }) = device.bar(bar) else {
+                continue;
+            };
+            let region = (address + device.read_u32(offset + 8) as u64).as_mut_ptr::<u8>();
+            // the first capability of a type is the preferred one
+            match device.read_u8(offset + 3) {
+                COMMON_CFG => common = common.or(Some(region)),
+                NOTIFY_CFG if notify.is_none() => {
+                    notify = Some(region);
+                    multiplier = device.read_u32(offset + 16);
+                }
+                ISR_CFG => isr = isr.or(Some(region)),
+                DEVICE_CFG => config = config.or(Some(region)),
+                _ => {}
+            }
+        }
+        Some(Layout::Modern {
+            common: common?,
+            notify: notify?,
+            multiplier,
+            isr: isr?,
+            config: config?,
+        })
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        matches!(self.layout, Layout::Legacy { .. })
+    }
+
+    pub fn device(&self) -> &PciDevice {
+        &self.device
+    }
+
+    fn port_read<T: port::PortRead>(port: u16, offset: u16) -> T {
+        unsafe { Port::<T>::new(port + offset).read() }
+    }
+
+    fn port_write<T: port::PortWrite>(port: u16, offset: u16, value: T) {
+        unsafe { Port::<T>::new(port + offset).write(value) }
+    }
+
+    pub fn status(&self) -> Status {
+        let status = match self.layout {
+            Layout::Legacy { port } => Self::port_read(port, LEGACY_STATUS),
+            Layout::Modern { common, .. } => read(common, DEVICE_STATUS),
+        };
+        Status::from_bits_truncate(status)
+    }
+
+    pub fn set_status(&self, status: Status) {
+        match self.layout {
+            Layout::Legacy { port } => Self::port_write(port, LEGACY_STATUS, status.bits()),
+            Layout::Modern { common, .. } => write(common, DEVICE_STATUS, status.bits()),
+        }
+    }
+
+    pub fn add_status(&self, status: Status) {
+        self.set_status(self.status() | status)
+    }
+
+    /// Back to the state after power on, the queues are forgotten
+    pub fn reset(&self) {
+        self.set_status(Status::empty());
+        while !self.status().is_empty() {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn device_features(&self) -> u64 {
+        match self.layout {
+            Layout::Legacy { port } => Self::port_read::<u32>(port, LEGACY_DEVICE_FEATURES) as u64,
+            Layout::Modern { common, .. } => (0..2).fold(0, |features, half| {
+                write(common, DEVICE_FEATURE_SELECT, half as u32);
+                features | (read::<u32>(common, DEVICE_FEATURE) as u64) << (32 * half)
+            }),
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match self.layout {
+            Layout::Legacy { port } => {
+                Self::port_write(port, LEGACY_DRIVER_FEATURES, features as u32)
+            }
+            Layout::Modern { common, .. } => {
+                for half in 0..2 {
+                    write(common, DRIVER_FEATURE_SELECT, half as u32);
+                    write(common, DRIVER_FEATURE, (features >> (32 * half)) as u32);
+                }
+            }
+        }
+    }
+
+    /// Reset the device and agree on the features of `wanted` it has, returns them
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, VirtioError> {
+        self.reset();
+        self.add_status(Status::ACKNOWLEDGE);
+        self.add_status(Status::DRIVER);
+
+        // a modern device has to be driven the modern way
+        let wanted = match self.is_legacy() {
+            true => wanted & !F_VERSION_1,
+            false => wanted | F_VERSION_1,
+        };
+        let features = self.device_features() & wanted;
+        self.set_driver_features(features);
+        if self.is_legacy() {
+            return Ok(features);
+        }
+
+        self.add_status(Status::FEATURES_OK);
+        if !self.status().contains(Status::FEATURES_OK) {
+            self.add_status(Status::FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(features)
+    }
+
+    /// The setup is done, the device may use its queues
+    pub fn driver_ok(&self) {
+        self.add_status(Status::DRIVER_OK)
+    }
+
+    /// Largest size of queue `index`, zero if it does not exist
+    pub fn queue_size(&self, index: u16) -> u16 {
+        match self.layout {
+            Layout::Legacy { port } => {
+                Self::port_write(port, LEGACY_QUEUE_SELECT, index);
+                Self::port_read(port, LEGACY_QUEUE_SIZE)
+            }
+            Layout::Modern { common, .. } => {
+                write(common, QUEUE_SELECT, index);
+                read(common, QUEUE_SIZE)
+            }
+        }
+    }
+
+    /// Hand `queue` to the device, interrupting with msi-x entry `vector` when it uses buffers
+    pub fn setup_queue(&self, queue: &mut Virtqueue, vector: u16) -> Result<(), VirtioError> {
+        let index = queue.index();
+        match self.layout {
+            Layout::Legacy { port } => {
+                Self::port_write(port, LEGACY_QUEUE_SELECT, index);
+                if Self::port_read::<u32>(port, LEGACY_QUEUE_ADDRESS) != 0 {
+                    return Err(VirtioError::QueueUnavailable);
+                }
+                if self.msi_x_enabled() {
+                    Self::port_write(port, LEGACY_QUEUE_VECTOR, vector);
+                }
+                let page = u64::from(queue.descriptors()) >> 12;
+                Self::port_write(port, LEGACY_QUEUE_ADDRESS, page as u32);
+            }
+            Layout::Modern { common, .. } => {
+                write(common, QUEUE_SELECT, index);
+                if read::<u16>(common, QUEUE_ENABLE) != 0 {
+                    return Err(VirtioError::QueueUnavailable);
+                }
+                write(common, QUEUE_SIZE, queue.size());
+                write(common, QUEUE_VECTOR, vector);
+                write(common, QUEUE_DESC, u64::from(queue.descriptors()));
+                write(common, QUEUE_DRIVER, u64::from(queue.driver_area()));
+                write(common, QUEUE_DEVICE, u64::from(queue.device_area()));
+                queue.notify_offset = read(common, QUEUE_NOTIFY_OFF);
+                write(common, QUEUE_ENABLE, 1u16);
+            }
+        }
+        Ok(())
+    }
+
+    /// Interrupt with msi-x entry `vector` when the config changes
+    pub fn set_config_vector(&self, vector: u16) {
+        match self.layout {
+            Layout::Legacy { port } if self.msi_x_enabled() => {
+                Self::port_write(port, LEGACY_CONFIG_VECTOR, vector)
+            }
+            Layout::Legacy { .. } => {}
+            Layout::Modern { common, .. } => write(common, CONFIG_VECTOR, vector),
+        }
+    }
+
+    /// Tell the device there are new buffers in `queue`
+    pub fn notify(&self, queue: &Virtqueue) {
+        match self.layout {
+            Layout::Legacy { port } => Self::port_write(port, LEGACY_QUEUE_NOTIFY, queue.index()),
+            Layout::Modern {
+                notify, multiplier, ..
+            } => {
+                let offset = queue.notify_offset as usize * multiplier as usize;
+                write(notify, offset, queue.index())
+            }
+        }
+    }
+
+    /// Why the device raised its legacy interrupt, reading acknowledges it
+    pub fn isr(&self) -> u8 {
+        match self.layout {
+            Layout::Legacy { port } => Self::port_read(port, LEGACY_ISR),
+            Layout::Modern { isr, .. } => read(isr, 0),
+        }
+    }
+
+    /// The legacy registers grow by two vectors while msi-x is on
+    fn msi_x_enabled(&self) -> bool {
+        self.device
+            .find_capability(MSI_X_CAPABILITY)
+            .is_some_and(|capability| self.device.read_u16(capability.offset + 2).get_bit(15))
+    }
+
+    fn legacy_config(&self, port: u16) -> u16 {
+        match self.msi_x_enabled() {
+            true => port + 0x18,
+            false => port + 0x14,
+        }
+    }
+
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        self.read_config(offset)
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        self.read_config(offset)
+    }
+
+    /// Wider than a register, read it again if the device changed it in between
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        loop {
+            let generation = self.generation();
+            let low = self.read_config_u32(offset) as u64;
+            let high = self.read_config_u32(offset + 4) as u64;
+            if generation == self.generation() {
+                return high << 32 | low;
+            }
+        }
+    }
+
+    fn generation(&self) -> u8 {
+        match self.layout {
+            Layout::Legacy { .. } => 0,
+            Layout::Modern { common, .. } => read(common, CONFIG_GENERATION),
+        }
+    }
+
+    fn read_config<T: port::PortRead>(&self, offset: u16) -> T {
+        match self.layout {
+            Layout::Legacy { port } => Self::port_read(self.legacy_config(port), offset),
+            Layout::Modern { config, .. } => read(config, offset as usize),
+        }
+    }
+}
@@ -13,8 +13,6 @@ QEMU_ARGS = [
     "1",
     "-boot",
     "order=d",
-    "-drive",
-    "file=fs.img,index=1,media=disk,format=raw",
     "-device",
     "isa-debug-exit,iobase=0xf4,iosize=0x04",
 ]
@@ -55,6 +53,18 @@ class X(object):
         parser.add_argument(
             "--nox", help="run without x window", default=False, action="store_true"
         )
+        parser.add_argument(
+            "--gdb",
+            help="build with the gdb stub and attach COM2 to tcp port 1234",
+            default=False,
+            action="store_true",
+        )
+        parser.add_argument(
+            "--virtio",
+            help="attach fs.img as a virtio disk instead of ide",
+            default=False,
+            action="store_true",
+        )
         args = parser.parse_args(sys.argv[2:])
         run(args)
 
@@ -71,7 +81,10 @@ def doctor(args):
 
 
 def build(args):
-    subprocess.run(["cargo", "build"], check=True)
+    command = ["cargo", "build"]
+    if getattr(args, "gdb", False):
+        command.extend(["--features", "gdb"])
+    subprocess.run(command, check=True)
     os.makedirs(f"{target_dir()}/isofiles/boot/grub", exist_ok=True)
     shutil.copy(output_file(), f"{target_dir()}/isofiles/boot/os.bin")
     shutil.copy(
@@ -109,6 +122,12 @@ def run(args):
     if args.nox:
         command.append("-nographic")
     command.extend(QEMU_ARGS)
+    if args.virtio:
+        command.extend(["-drive", "file=fs.img,if=virtio,format=raw"])
+    else:
+        command.extend(["-drive", "file=fs.img,index=1,media=disk,format=raw"])
+    if args.gdb:
+        command.extend(["-serial", "tcp::1234,server,nowait"])
     # append the target iso
 
     command.append("-cdrom")